@@ -0,0 +1,112 @@
+use std::{
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use solana_client::{client_error::Result as ClientResult, rpc_client::RpcClient};
+use solana_sdk::hash::Hash;
+use tokio::{runtime::Runtime, time::interval};
+
+use crate::{logger, shutdown::Shutdown};
+
+// Blockhashes expire after 150 slots, about a minute. Past this age a cached blockhash is deemed
+// too close to expiry and a new one is fetched before signing.
+const MAX_BLOCKHASH_AGE: u64 = 30_000;
+
+// Shares a recent blockhash between the cranking tasks so that signing a transaction doesn't cost
+// an RPC request. Without a refresh period nothing is cached and every call fetches a blockhash.
+pub struct BlockhashCache {
+    connection: Arc<RpcClient>,
+    refresh: Option<Duration>,
+    latest: RwLock<Option<(Hash, Instant)>>,
+}
+
+impl BlockhashCache {
+    pub fn new(connection: Arc<RpcClient>, refresh: Option<Duration>) -> Arc<Self> {
+        Arc::new(Self {
+            connection,
+            refresh,
+            latest: RwLock::new(None),
+        })
+    }
+
+    // Refreshes the cached blockhash in the background until a shutdown is requested
+    pub fn spawn(self: &Arc<Self>, rt: &Runtime, shutdown: &Arc<Shutdown>) {
+        let refresh = match self.refresh {
+            Some(r) => r,
+            None => return,
+        };
+        let cache = Arc::clone(self);
+        let shutdown = Arc::clone(shutdown);
+        rt.spawn(async move {
+            let mut ticker = interval(refresh);
+            while shutdown.tick(&mut ticker).await {
+                if let Err(e) = cache.fetch() {
+                    logger::info(&format!("Failed to refresh the blockhash with {:?}", e));
+                }
+            }
+        });
+    }
+
+    pub fn get(&self) -> ClientResult<Hash> {
+        match self.cached() {
+            Some(h) => Ok(h),
+            None => self.fetch(),
+        }
+    }
+
+    fn cached(&self) -> Option<Hash> {
+        self.refresh?;
+        match *self.latest.read().unwrap() {
+            Some((h, fetched_at))
+                if fetched_at.elapsed() < Duration::from_millis(MAX_BLOCKHASH_AGE) =>
+            {
+                Some(h)
+            }
+            _ => None,
+        }
+    }
+
+    fn fetch(&self) -> ClientResult<Hash> {
+        let fetched_at = Instant::now();
+        let (h, _) = self.connection.get_recent_blockhash()?;
+        if self.refresh.is_some() {
+            *self.latest.write().unwrap() = Some((h, fetched_at));
+        }
+        Ok(h)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use solana_client::rpc_client::RpcClient;
+    use solana_sdk::hash::Hash;
+
+    use super::{BlockhashCache, MAX_BLOCKHASH_AGE};
+
+    #[test]
+    fn test_blockhash_cache_expiry() {
+        // Nothing listens on this port, any live fetch fails
+        let connection = Arc::new(RpcClient::new(String::from("http://127.0.0.1:1")));
+        let cache = BlockhashCache::new(Arc::clone(&connection), Some(Duration::from_secs(5)));
+        let blockhash = Hash::new_unique();
+
+        *cache.latest.write().unwrap() = Some((blockhash, Instant::now()));
+        assert_eq!(cache.get().unwrap(), blockhash);
+
+        // A blockhash close to expiry is fetched again
+        let stale = Instant::now() - Duration::from_millis(MAX_BLOCKHASH_AGE + 1_000);
+        *cache.latest.write().unwrap() = Some((blockhash, stale));
+        assert!(cache.get().is_err());
+
+        // Without a refresh period the cache is bypassed
+        let uncached = BlockhashCache::new(connection, None);
+        *uncached.latest.write().unwrap() = Some((blockhash, Instant::now()));
+        assert!(uncached.get().is_err());
+    }
+}
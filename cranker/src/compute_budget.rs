@@ -0,0 +1,146 @@
+use std::str::FromStr;
+
+use reqwest::Client;
+use serde_json::{json, Value};
+use solana_program::{instruction::Instruction, pubkey::Pubkey};
+use solana_sdk::{
+    hash::Hash,
+    signature::{Keypair, Signer},
+    transaction::Transaction,
+};
+
+use crate::logger;
+
+// The pinned solana-sdk predates the compute budget instructions, which are encoded by hand
+pub const COMPUTE_BUDGET_PROGRAM_ID: &str = "ComputeBudget111111111111111111111111111111";
+
+const SET_COMPUTE_UNIT_LIMIT_TAG: u8 = 2;
+const SET_COMPUTE_UNIT_PRICE_TAG: u8 = 3;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ComputeBudget {
+    pub unit_limit: Option<u32>,
+    pub unit_price: Option<u64>, // In micro-lamports per compute unit
+    pub dynamic_priority_fee: Option<DynamicPriorityFee>,
+}
+
+// Prices transactions from the fees recently paid to write to the same accounts
+#[derive(Clone, Copy, Debug)]
+pub struct DynamicPriorityFee {
+    pub multiplier: f64,
+    pub ceiling: u64, // In micro-lamports per compute unit
+}
+
+impl ComputeBudget {
+    pub fn instructions(&self) -> Vec<Instruction> {
+        let mut instructions = Vec::with_capacity(2);
+        if let Some(units) = self.unit_limit {
+            instructions.push(set_compute_unit_limit(units));
+        }
+        if let Some(micro_lamports) = self.unit_price {
+            instructions.push(set_compute_unit_price(micro_lamports));
+        }
+        instructions
+    }
+
+    // Builds a transaction with the compute budget instructions placed ahead of the given instructions
+    pub fn transaction(&self, instructions: &[Instruction], payer: &Pubkey) -> Transaction {
+        let mut all_instructions = self.instructions();
+        all_instructions.extend_from_slice(instructions);
+        Transaction::new_with_payer(&all_instructions, Some(payer))
+    }
+
+    pub fn signed_transaction(
+        &self,
+        instructions: &[Instruction],
+        payer: &Keypair,
+        recent_blockhash: Hash,
+    ) -> Transaction {
+        let mut transaction = self.transaction(instructions, &payer.pubkey());
+        transaction.sign(&[payer], recent_blockhash);
+        transaction
+    }
+
+    // Resolves the dynamic priority fee for the given instructions, falling back to the static price if the estimation fails
+    pub async fn with_estimated_price(
+        &self,
+        endpoint: &str,
+        instructions: &[Instruction],
+    ) -> ComputeBudget {
+        let dynamic_priority_fee = match self.dynamic_priority_fee {
+            Some(d) => d,
+            None => return *self,
+        };
+        let mut writable_accounts = instructions
+            .iter()
+            .flat_map(|i| i.accounts.iter())
+            .filter(|a| a.is_writable)
+            .map(|a| a.pubkey)
+            .collect::<Vec<_>>();
+        writable_accounts.sort();
+        writable_accounts.dedup();
+        match get_recent_prioritization_fee(endpoint, &writable_accounts).await {
+            Ok(recent_fee) => ComputeBudget {
+                unit_price: Some(std::cmp::min(
+                    ((recent_fee as f64) * dynamic_priority_fee.multiplier) as u64,
+                    dynamic_priority_fee.ceiling,
+                )),
+                ..*self
+            },
+            Err(e) => {
+                logger::info(&format!("Failed to estimate the priority fee with {:?}", e));
+                *self
+            }
+        }
+    }
+}
+
+// Returns the highest prioritization fee paid over the recent slots by transactions locking the given accounts
+pub async fn get_recent_prioritization_fee(
+    endpoint: &str,
+    accounts: &[Pubkey],
+) -> Result<u64, reqwest::Error> {
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "getRecentPrioritizationFees",
+        "params": [accounts.iter().map(|k| k.to_string()).collect::<Vec<_>>()],
+    });
+    let response: Value = Client::new()
+        .post(endpoint)
+        .json(&request)
+        .send()
+        .await?
+        .json()
+        .await?;
+    let fee = response["result"]
+        .as_array()
+        .map(|fees| {
+            fees.iter()
+                .filter_map(|f| f["prioritizationFee"].as_u64())
+                .max()
+                .unwrap_or(0)
+        })
+        .unwrap_or(0);
+    Ok(fee)
+}
+
+pub fn set_compute_unit_limit(units: u32) -> Instruction {
+    let mut data = vec![SET_COMPUTE_UNIT_LIMIT_TAG];
+    data.extend_from_slice(&units.to_le_bytes());
+    Instruction {
+        program_id: Pubkey::from_str(COMPUTE_BUDGET_PROGRAM_ID).unwrap(),
+        accounts: vec![],
+        data,
+    }
+}
+
+pub fn set_compute_unit_price(micro_lamports: u64) -> Instruction {
+    let mut data = vec![SET_COMPUTE_UNIT_PRICE_TAG];
+    data.extend_from_slice(&micro_lamports.to_le_bytes());
+    Instruction {
+        program_id: Pubkey::from_str(COMPUTE_BUDGET_PROGRAM_ID).unwrap(),
+        accounts: vec![],
+        data,
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    rpc_client::RpcClient,
+    rpc_config::RpcSendTransactionConfig,
+};
+use solana_sdk::{commitment_config::CommitmentConfig, signature::Signature, transaction::Transaction};
+use tokio::task;
+
+// Whether the cranker waits for its transactions to reach a given commitment before moving on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Confirmation {
+    None,
+    Processed,
+    Confirmed,
+}
+
+impl FromStr for Confirmation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Confirmation::None),
+            "processed" => Ok(Confirmation::Processed),
+            "confirmed" => Ok(Confirmation::Confirmed),
+            _ => Err(format!(
+                "Invalid confirmation strategy {}, expected one of none, processed or confirmed",
+                s
+            )),
+        }
+    }
+}
+
+const CONFIRMATION_POLL_PERIOD: u64 = 500;
+
+static CONFIRM_TIMEOUT_SECS: AtomicU64 = AtomicU64::new(30);
+
+// Bounds the wait for a transaction to reach the awaited commitment level
+pub fn set_timeout(timeout: Duration) {
+    CONFIRM_TIMEOUT_SECS.store(timeout.as_secs(), Ordering::Relaxed);
+}
+
+fn timeout() -> Duration {
+    Duration::from_secs(CONFIRM_TIMEOUT_SECS.load(Ordering::Relaxed))
+}
+
+impl Confirmation {
+    fn commitment(&self) -> Option<CommitmentConfig> {
+        match self {
+            Confirmation::None => None,
+            Confirmation::Processed => Some(CommitmentConfig::processed()),
+            Confirmation::Confirmed => Some(CommitmentConfig::confirmed()),
+        }
+    }
+
+    pub fn send_transaction(
+        &self,
+        connection: &RpcClient,
+        transaction: &Transaction,
+    ) -> Result<Signature, ClientError> {
+        let signature = connection.send_transaction_with_config(
+            transaction,
+            RpcSendTransactionConfig {
+                skip_preflight: false,
+                ..RpcSendTransactionConfig::default()
+            },
+        )?;
+        if let Some(commitment) = self.commitment() {
+            // The polling can block for as long as the timeout, the cranker's multi-threaded runtimes hand
+            // the other tasks of this worker thread over to the remaining ones in the meantime
+            task::block_in_place(|| wait_for_confirmation(connection, &signature, commitment))?;
+        }
+        Ok(signature)
+    }
+}
+
+fn wait_for_confirmation(
+    connection: &RpcClient,
+    signature: &Signature,
+    commitment: CommitmentConfig,
+) -> Result<(), ClientError> {
+    let start = Instant::now();
+    loop {
+        match connection.get_signature_status_with_commitment(signature, commitment)? {
+            Some(Ok(())) => return Ok(()),
+            Some(Err(e)) => return Err(e.into()),
+            None if start.elapsed() >= timeout() => {
+                return Err(ClientErrorKind::Custom(format!(
+                    "Transaction {} was not confirmed within {:?}",
+                    signature,
+                    timeout()
+                ))
+                .into())
+            }
+            None => thread::sleep(Duration::from_millis(CONFIRMATION_POLL_PERIOD)),
+        }
+    }
+}
@@ -1,8 +1,64 @@
+use audaces_protocol::error::PerpError;
+use solana_client::client_error::ClientError;
+use solana_program::{program_error::ProgramError, pubkey::Pubkey};
 use thiserror::Error;
-#[derive(Clone, Debug, Eq, Error, PartialEq)]
+#[derive(Debug, Error)]
 pub enum CrankError {
-    #[error("Encountered a connection error")]
-    ConnectionError,
+    #[error("Encountered a connection error: {0}")]
+    ConnectionError(#[from] ClientError),
+    #[error("The account {0} doesn't exist")]
+    AccountNotFound(Pubkey),
     #[error("The parsed market state is invalid")]
-    InvalidMarketState
-}
\ No newline at end of file
+    InvalidMarketState,
+    #[error("Failed to fetch the market account {address}: {source}")]
+    MarketFetch {
+        address: Pubkey,
+        source: ClientError,
+    },
+    #[error("Failed to fetch the instance account {address}: {source}")]
+    InstanceFetch {
+        address: Pubkey,
+        source: ClientError,
+    },
+    #[error("Failed to decode the instance account {address}: {source}")]
+    InstanceDecode {
+        address: Pubkey,
+        source: ProgramError,
+    },
+    #[error("Failed to derive the market signer from the market's signer nonce")]
+    SignerDerivation,
+    #[error("Failed to fetch the market vault {address}: {source}")]
+    VaultFetch {
+        address: Pubkey,
+        source: ClientError,
+    },
+    #[error("The market vault {0} is not a token account")]
+    VaultNotTokenAccount(Pubkey),
+    #[error("Failed to parse the market vault's mint {0}")]
+    MintParse(String),
+    #[error("The program {address} is not deployed, check the program id: {source}")]
+    ProgramNotDeployed {
+        address: Pubkey,
+        source: ClientError,
+    },
+    #[error(
+        "The account {0} is not an executable program owned by a BPF loader, check the program id"
+    )]
+    ProgramNotExecutable(Pubkey),
+    #[error("The market {market} is owned by {owner} instead of the program {program_id}")]
+    MarketOwner {
+        market: Pubkey,
+        owner: Pubkey,
+        program_id: Pubkey,
+    },
+    #[error("The fee payer {0} has no lamports to pay for transactions")]
+    FeePayerUnfunded(Pubkey),
+    #[error(transparent)]
+    Perp(#[from] PerpError),
+}
+
+impl From<ProgramError> for CrankError {
+    fn from(_: ProgramError) -> Self {
+        CrankError::InvalidMarketState
+    }
+}
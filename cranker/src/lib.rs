@@ -1,50 +1,75 @@
 use audaces_protocol::{
     instruction::{
-        close_position, collect_garbage, crank_funding, crank_liquidation, extract_funding,
-        InstanceContext, MarketContext, PositionInfo,
+        add_page, close_position, close_position_at_stop_loss, collect_garbage, crank_funding,
+        crank_instance_funding, crank_liquidation, extract_funding_multi, InstanceContext,
+        MarketContext, PositionInfo,
     },
-    processor::FIDA_BNB,
     state::{
-        instance::Instance, instance::PageInfo, market::MarketState, user_account::OpenPosition,
-        user_account::UserAccountState, StateObject,
+        market::{get_instance_address, MarketDataPoint, MarketState},
+        user_account::{UserAccountState, USER_ACCOUNT_VERSION},
+        StateObject,
     },
+    utils::{get_market_data, get_oracle_price, simulate_liquidation},
 };
+use blockhash::BlockhashCache;
+use compute_budget::ComputeBudget;
+use confirmation::Confirmation;
 use error::CrankError;
+use logger::Action;
+use shutdown::Shutdown;
+use subscription::OracleSubscription;
+use tracking::SignatureTracker;
+use user_cache::UserAccountCache;
 use futures::{
     stream::{self, Iter},
     StreamExt,
 };
-use solana_account_decoder::UiAccountEncoding;
+use solana_account_decoder::{UiAccountEncoding, UiDataSliceConfig};
 use solana_client::{
     rpc_client::RpcClient,
     rpc_config::RpcAccountInfoConfig,
-    rpc_config::{RpcProgramAccountsConfig, RpcSendTransactionConfig},
+    rpc_config::RpcProgramAccountsConfig,
     rpc_filter::{self, Memcmp, RpcFilterType},
 };
-use solana_program::{program_pack::Pack, pubkey::Pubkey};
+use solana_program::{program_pack::Pack, pubkey::Pubkey, system_instruction};
 use solana_sdk::{
     account::Account,
+    bpf_loader, bpf_loader_deprecated, bpf_loader_upgradeable,
     signature::{Keypair, Signer},
-    transaction::Transaction,
 };
 use spl_associated_token_account::get_associated_token_address;
 use std::{
     borrow::Borrow,
+    collections::HashMap,
     str::FromStr,
-    sync::Arc,
-    time::{Duration, SystemTime},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
     vec::IntoIter,
 };
 use tokio::{
     runtime::Runtime,
-    sync::Mutex,
-    task::{self, JoinError},
+    sync::{watch, Mutex},
+    task::{self, JoinHandle},
     time::interval,
 };
 
-use crate::utils::{invalid_signature_filter, no_op_filter};
+use crate::utils::{
+    invalid_signature_filter, is_compute_budget_exceeded, is_instance_full, lower_iterations,
+    no_op_filter, stop_loss_filter, terminal_error_filter, SendOutcome,
+};
 
+pub mod blockhash;
+pub mod compute_budget;
+pub mod confirmation;
 pub mod error;
+pub mod logger;
+pub mod shutdown;
+pub mod subscription;
+pub mod tracking;
+pub mod user_cache;
 
 mod utils;
 
@@ -53,22 +78,120 @@ pub struct Context {
     pub market: Pubkey,
     pub fee_payer: Keypair,
     pub endpoint: String,
+    // The client is thread-safe, a single instance is shared by all tasks to reuse its connections
+    pub connection: Arc<RpcClient>,
     pub num_threads: usize,
+    pub compute_budget: ComputeBudget,
+    pub confirmation: Confirmation,
+    pub blockhash: Arc<BlockhashCache>, // Recent blockhash shared by the signing closures
+    pub instances: Option<Vec<usize>>, // Restricts liquidations and garbage collection to these instance indices
+    pub resubmit_timeout: Option<Duration>, // Liquidation and garbage collection transactions unconfirmed after this delay are resubmitted
+    pub gc_max_iterations: AtomicU64, // Lowered when a garbage collection transaction exceeds the compute budget
+    pub once: bool, // Run a single cycle of the crank and exit, leaving the cadence to an external scheduler
 }
 
 const LIQUIDATION_PERIOD: u64 = 1_000;
 const FUNDING_PERIOD: u64 = 1_000;
 const FUNDING_EXTRACTION_PERIOD: u64 = 1_800_000;
 const LIQUIDATION_CLEANUP_PERIOD: u64 = 1_800_000;
+const STOP_LOSS_PERIOD: u64 = 10_000;
 const GARBAGE_COLLECTION_PERIOD: u64 = 10_000;
-const GARBAGE_COLLECT_MAX_ITERATIONS: u64 = 500;
+const MARKET_REFRESH_PERIOD: u64 = 60_000;
+const MAX_PAGES_PER_INSTANCE: usize = 16;
+// Instances are batched in a funding extraction transaction as long as it stays under this number of accounts
+const MAX_FUNDING_EXTRACTION_ACCOUNTS: usize = 30;
+
+// New memory pages are added by the garbage collection loop to the instances which are filling up
+pub struct PageAllocation {
+    pub admin: Keypair,
+    pub threshold: f64, // Fullness ratio that all pages of an instance must exceed before a page is added
+    pub page_size: u64, // In bytes
+}
 
 impl Context {
-    pub fn crank_liquidation(self) {
-        let connection = RpcClient::new(self.endpoint.clone());
+    // Fails fast on a wrong program id, an undeployed program, a market belonging to another program
+    // or an empty fee payer, all of which would otherwise only show up as failing transactions
+    pub fn check_deployment(&self) -> Result<(), CrankError> {
+        let program = self
+            .connection
+            .get_account(&self.program_id)
+            .map_err(|source| CrankError::ProgramNotDeployed {
+                address: self.program_id,
+                source,
+            })?;
+        let bpf_loaders = [
+            bpf_loader::id(),
+            bpf_loader_deprecated::id(),
+            bpf_loader_upgradeable::id(),
+        ];
+        if !program.executable || !bpf_loaders.contains(&program.owner) {
+            return Err(CrankError::ProgramNotExecutable(self.program_id));
+        }
+        let market = self
+            .connection
+            .get_account(&self.market)
+            .map_err(|source| CrankError::MarketFetch {
+                address: self.market,
+                source,
+            })?;
+        if market.owner != self.program_id {
+            return Err(CrankError::MarketOwner {
+                market: self.market,
+                owner: market.owner,
+                program_id: self.program_id,
+            });
+        }
+        let fee_payer = self.fee_payer.pubkey();
+        if self.connection.get_balance(&fee_payer).unwrap_or(0) == 0 {
+            return Err(CrankError::FeePayerUnfunded(fee_payer));
+        }
+        Ok(())
+    }
+
+    fn selected_instances(&self, market: &MarketContext) -> Vec<usize> {
+        match &self.instances {
+            Some(instances) => {
+                for i in instances {
+                    if *i >= market.instances.len() {
+                        panic!(
+                            "Instance index {} is out of range, the market has {} instances",
+                            i,
+                            market.instances.len()
+                        );
+                    }
+                }
+                instances.clone()
+            }
+            None => (0..market.instances.len()).collect(),
+        }
+    }
+
+    fn signature_tracker(
+        &self,
+        rt: &Runtime,
+        shutdown: &Arc<Shutdown>,
+    ) -> Option<Arc<SignatureTracker>> {
+        self.resubmit_timeout.map(|timeout| {
+            let tracker = SignatureTracker::new(
+                Arc::clone(&self.connection),
+                self.endpoint.clone(),
+                // The keypair isn't clonable, the tracker signs the resubmissions with its own copy
+                Arc::new(Keypair::from_bytes(&self.fee_payer.to_bytes()).unwrap()),
+                Arc::clone(&self.blockhash),
+                self.confirmation,
+                timeout,
+            );
+            tracker.spawn(rt, shutdown);
+            tracker
+        })
+    }
+
+    // When a websocket url is given, liquidations are triggered by oracle updates rather than polled
+    pub fn crank_liquidation(self, websocket_url: Option<String>) {
+        let connection = Arc::clone(&self.connection);
         let (market_ctx, quote_mint) =
             get_market(self.program_id, self.market, &connection).unwrap();
-        println!("Market quote mint {:?}", quote_mint);
+        logger::info(&format!("Market quote mint {:?}", quote_mint));
 
         let endpoint = Arc::new(self.endpoint.clone());
         let market = Arc::new(market_ctx);
@@ -77,127 +200,386 @@ impl Context {
             &self.fee_payer.pubkey(),
             &quote_mint,
         ));
-        let fee_payer = Arc::new(self.fee_payer);
+        let compute_budget = self.compute_budget;
+        let confirmation = self.confirmation;
+        let instances = self.selected_instances(&market);
+        let (program_id, market_key) = (self.program_id, self.market);
+        let refresh_instances = self.instances.is_none();
+        let once = self.once;
 
         let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let shutdown = Shutdown::install(&rt);
+        let tracker = self.signature_tracker(&rt, &shutdown);
+        let blockhash = Arc::clone(&self.blockhash);
+        blockhash.spawn(&rt, &shutdown);
+        let fee_payer = Arc::new(self.fee_payer);
 
-        let mut tasks = Vec::with_capacity(market.instances.len());
+        let oracle_subscription =
+            websocket_url.map(|url| OracleSubscription::spawn(url, market.oracle_account));
 
-        println!("Found {} instances", market.instances.len());
+        let (market_sender, market_receiver) = watch::channel(Arc::clone(&market));
+        let mut tasks = Vec::with_capacity(instances.len());
 
-        for i in 0..market.instances.len() {
-            let t = run_liquidation(
+        logger::info(&format!(
+            "Found {} instances, cranking {:?}",
+            market.instances.len(),
+            instances
+        ));
+
+        let spawn_liquidation = |i| {
+            run_liquidation(
                 Arc::clone(&endpoint),
-                Arc::clone(&market),
+                Arc::clone(&connection),
+                market_receiver.clone(),
                 i,
                 Arc::clone(&target_token_account),
                 Arc::clone(&fee_payer),
-            );
-            tasks.push(t);
+                Arc::clone(&blockhash),
+                compute_budget,
+                confirmation,
+                oracle_subscription.clone(),
+                tracker.clone(),
+                Arc::clone(&shutdown),
+                once,
+            )
+        };
+        for i in instances {
+            tasks.push(spawn_liquidation(i));
         }
 
+        // The market is fetched again periodically so that the tasks list the current memory pages.
+        // Unless specific instances were selected, the instances added in the meantime get their own task.
+        let mut number_of_instances = market.instances.len();
+        rt.block_on(async {
+            let mut ticker = interval(Duration::from_millis(MARKET_REFRESH_PERIOD));
+            while !once && shutdown.tick(&mut ticker).await {
+                let market = match get_market(program_id, market_key, &connection) {
+                    Ok((m, _)) => m,
+                    Err(e) => {
+                        logger::info(&format!("Failed to refresh the market with {:?}", e));
+                        continue;
+                    }
+                };
+                if refresh_instances {
+                    for i in number_of_instances..market.instances.len() {
+                        logger::info(&format!("Found new instance {}", i));
+                        tasks.push(spawn_liquidation(i));
+                    }
+                    number_of_instances = market.instances.len();
+                }
+                // Sending only fails once all the tasks are done
+                let _ = market_sender.send(Arc::new(market));
+            }
+        });
+
         for t in tasks {
             rt.block_on(t).unwrap();
         }
+        shutdown.print_summary();
     }
 
     pub fn crank_funding(self) {
-        let connection = RpcClient::new(self.endpoint.clone());
-        let (market_ctx, _) = get_market(self.program_id, self.market, &connection).unwrap();
-        let market = Arc::new(market_ctx);
+        let connection = Arc::clone(&self.connection);
+        let program_id = self.program_id;
+        let market_key = self.market;
+        let compute_budget = self.compute_budget;
+        let confirmation = self.confirmation;
+        let once = self.once;
+        let blockhash = Arc::clone(&self.blockhash);
         let fee_payer = Arc::new(self.fee_payer);
 
         let rt = Runtime::new().unwrap();
         let _guard = rt.enter();
+        let shutdown = Shutdown::install(&rt);
+        blockhash.spawn(&rt, &shutdown);
+        let s = Arc::clone(&shutdown);
 
-        let instruction = crank_funding(&market);
+        let action = Action::new("funding", None);
         let t = task::spawn(async move {
             let mut ticker = interval(Duration::from_millis(FUNDING_PERIOD));
-            loop {
-                ticker.tick().await;
-                let transaction =
-                    Transaction::new_with_payer(&[instruction.clone()], Some(&fee_payer.pubkey()));
+            while s.tick(&mut ticker).await {
+                // The market is fetched again on every period to pick up the instances added in the meantime
+                let (market, _) = utils::retry(
+                    &connection,
+                    |c| get_market(program_id, market_key, c),
+                    |r| r,
+                    Action::new("market_fetch", None),
+                )
+                .await;
+                let instruction = crank_funding(&market);
+                // Each instance then records its own balancing factors for the new funding period
+                let instance_instructions = (0..market.instances.len())
+                    .map(|i| (i, crank_instance_funding(&market, i as u8)))
+                    .collect::<Vec<_>>();
+                // Every attempt signs a new transaction with a recent blockhash
                 let sig = utils::retry(
-                    transaction,
-                    |t| {
-                        let mut tr = t.clone();
-                        let (recent_blockhash, _) = connection.get_recent_blockhash()?;
-                        tr.partial_sign::<Vec<&Keypair>>(
-                            &vec![fee_payer.borrow()],
+                    &instruction,
+                    |i| {
+                        let recent_blockhash = blockhash.get()?;
+                        let tr = compute_budget.signed_transaction(
+                            &[(*i).clone()],
+                            &fee_payer,
                             recent_blockhash,
                         );
-                        connection.send_and_confirm_transaction(&tr)
+                        confirmation.send_transaction(&connection, &tr)
                     },
-                    no_op_filter,
+                    terminal_error_filter,
+                    action,
                 )
                 .await;
-                println!("Sent funding transaction {:?}", sig);
+                match sig {
+                    SendOutcome::Sent(sig) => {
+                        action.sent(&sig);
+                        s.record_transaction();
+                    }
+                    SendOutcome::Dropped(e) => action.dropped(&e),
+                }
+                for (instance_index, instruction) in &instance_instructions {
+                    let action = Action::new("instance_funding", Some(*instance_index));
+                    let sig = utils::retry(
+                        instruction,
+                        |i| {
+                            let recent_blockhash = blockhash.get()?;
+                            let tr = compute_budget.signed_transaction(
+                                &[(*i).clone()],
+                                &fee_payer,
+                                recent_blockhash,
+                            );
+                            confirmation.send_transaction(&connection, &tr)
+                        },
+                        terminal_error_filter,
+                        action,
+                    )
+                    .await;
+                    match sig {
+                        SendOutcome::Sent(sig) => {
+                            action.sent(&sig);
+                            s.record_transaction();
+                        }
+                        SendOutcome::Dropped(e) => action.dropped(&e),
+                    }
+                }
+                if once {
+                    break;
+                }
             }
         });
 
         rt.block_on(t).unwrap();
+        shutdown.print_summary();
     }
-    pub fn crank_funding_extraction(self, swarm_size: u16, node_id: u8) {
+    // With poll_users, the user accounts are cached between cycles instead of being scanned in full every time
+    pub fn crank_funding_extraction(self, swarm_size: u16, node_id: u8, poll_users: bool) {
         let s = Arc::new(self);
         let rt = Runtime::new().unwrap();
         let _guard = rt.enter();
+        let shutdown = Shutdown::install(&rt);
+        s.blockhash.spawn(&rt, &shutdown);
+        let sd = Arc::clone(&shutdown);
+        let mut cache = if poll_users {
+            Some(UserAccountCache::default())
+        } else {
+            None
+        };
         let t = async move {
             let mut ticker = interval(Duration::from_millis(FUNDING_EXTRACTION_PERIOD));
-            loop {
-                ticker.tick().await;
+            while sd.tick(&mut ticker).await {
                 let start_time = SystemTime::now();
-                crank_funding_extraction_iteration(&s, swarm_size, node_id).await;
+                crank_funding_extraction_iteration(&s, swarm_size, node_id, cache.as_mut(), &sd)
+                    .await;
                 let end_time = SystemTime::now();
-                println!(
+                logger::info(&format!(
                     "Finished funding extraction cycle in {:?}s within a funding period of {:?}s",
                     end_time.duration_since(start_time).unwrap().as_secs_f64(),
                     FUNDING_PERIOD / 1000
-                )
+                ));
+                if s.once {
+                    break;
+                }
             }
         };
         rt.block_on(t);
+        shutdown.print_summary();
     }
 
     pub fn crank_liquidation_cleanup(self, swarm_size: u16, node_id: u8) {
         let s = Arc::new(self);
         let rt = Runtime::new().unwrap();
         let _guard = rt.enter();
+        let shutdown = Shutdown::install(&rt);
+        s.blockhash.spawn(&rt, &shutdown);
+        let sd = Arc::clone(&shutdown);
         let t = async move {
             let mut ticker = interval(Duration::from_millis(LIQUIDATION_CLEANUP_PERIOD));
-            loop {
-                ticker.tick().await;
+            while sd.tick(&mut ticker).await {
                 let start_time = SystemTime::now();
-                crank_liquidation_cleanup_iteration(&s, swarm_size, node_id).await;
+                crank_closing_iteration(
+                    &s,
+                    swarm_size,
+                    node_id,
+                    ClosingCrank::LiquidationCleanup,
+                    &sd,
+                )
+                .await;
                 let end_time = SystemTime::now();
-                println!(
+                logger::info(&format!(
                     "Finished liquidation cleanup cycle in {:?}s within a liquidation cleanup period of {:?}s",
                     end_time.duration_since(start_time).unwrap().as_secs_f64(),
                     LIQUIDATION_CLEANUP_PERIOD / 1000
-                )
+                ));
+                if s.once {
+                    break;
+                }
+            }
+        };
+        rt.block_on(t);
+        shutdown.print_summary();
+    }
+
+    pub fn crank_stop_loss(self, swarm_size: u16, node_id: u8) {
+        let s = Arc::new(self);
+        let rt = Runtime::new().unwrap();
+        let _guard = rt.enter();
+        let shutdown = Shutdown::install(&rt);
+        s.blockhash.spawn(&rt, &shutdown);
+        let sd = Arc::clone(&shutdown);
+        let t = async move {
+            let mut ticker = interval(Duration::from_millis(STOP_LOSS_PERIOD));
+            while sd.tick(&mut ticker).await {
+                let start_time = SystemTime::now();
+                crank_closing_iteration(&s, swarm_size, node_id, ClosingCrank::StopLoss, &sd).await;
+                let end_time = SystemTime::now();
+                logger::info(&format!(
+                    "Finished stop-loss cycle in {:?}s within a stop-loss period of {:?}s",
+                    end_time.duration_since(start_time).unwrap().as_secs_f64(),
+                    STOP_LOSS_PERIOD / 1000
+                ));
+                if s.once {
+                    break;
+                }
             }
         };
         rt.block_on(t);
+        shutdown.print_summary();
+    }
+
+    fn fetch_market_data(&self) -> MarketDataPoint {
+        let connection = &self.connection;
+        let market_data = connection.get_account_data(&self.market).unwrap();
+        let market_state = MarketState::unpack_from_slice(&market_data).unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let market_data_point = get_market_data(
+            self.market,
+            &|k| connection.get_account_data(k).unwrap(),
+            market_state.total_user_balances,
+            now,
+        )
+        .unwrap();
+        if market_data_point.funding_stalled {
+            // Printed on stderr to keep the snapshot parsable
+            eprintln!(
+                "Warning: the market funding wasn't cranked for {}s, check the funding cranker",
+                market_data_point.seconds_since_last_funding
+            );
+        }
+        market_data_point
+    }
+
+    pub fn snapshot(self) {
+        let market_data_point = self.fetch_market_data();
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&market_data_point).unwrap()
+        );
+    }
+
+    pub fn capacity(self) {
+        let market_data_point = self.fetch_market_data();
+        for (instance_index, pages) in market_data_point.page_stats.iter().enumerate() {
+            println!(
+                "Instance {}: {} pages, {} slots remaining, {} slots awaiting garbage collection",
+                instance_index,
+                pages.len(),
+                market_data_point.remaining_capacity.per_instance[instance_index],
+                market_data_point.gc_list_lengths[instance_index]
+            );
+            for (page_index, stats) in pages.iter().enumerate() {
+                println!(
+                    "  Page {}: {}/{} slots allocated, {} free, {} never used",
+                    page_index,
+                    stats.allocated_slots,
+                    stats.page_size,
+                    stats.free_slots,
+                    stats.uninitialized_slots
+                );
+            }
+        }
+        println!(
+            "Total: {} slots remaining",
+            market_data_point.remaining_capacity.total
+        );
     }
 
-    pub fn garbage_collect(self) {
+    pub fn garbage_collect(self, page_allocation: Option<PageAllocation>) {
         let s = Arc::new(self);
         let rt = Runtime::new().unwrap();
         let _guard = rt.enter();
-        let connection = RpcClient::new(String::clone(&s.endpoint));
-        let (market, quote_mint) = get_market(s.program_id, s.market, &connection).unwrap();
+        let (market, quote_mint) = get_market(s.program_id, s.market, &s.connection).unwrap();
         let target_token_account = Arc::new(get_associated_token_address(
             &s.fee_payer.pubkey(),
             &quote_mint,
         ));
-        let market = Arc::new(market);
+        let mut instances = s.selected_instances(&market);
+        let mut market = Arc::new(market);
+        let shutdown = Shutdown::install(&rt);
+        let tracker = s.signature_tracker(&rt, &shutdown);
+        s.blockhash.spawn(&rt, &shutdown);
+        let sd = Arc::clone(&shutdown);
         let t = task::spawn(async move {
             let mut ticker = interval(Duration::from_millis(GARBAGE_COLLECTION_PERIOD));
-            loop {
-                ticker.tick().await;
-                crank_garbage_collection(&s, &market, &target_token_account).await;
+            let mut last_refresh = Instant::now();
+            while sd.tick(&mut ticker).await {
+                crank_garbage_collection(
+                    &s,
+                    &market,
+                    &instances,
+                    &target_token_account,
+                    &tracker,
+                    &sd,
+                )
+                .await;
+                let mut refresh =
+                    last_refresh.elapsed() >= Duration::from_millis(MARKET_REFRESH_PERIOD);
+                if let Some(page_allocation) = &page_allocation {
+                    // The garbage collection instructions must list the new pages
+                    refresh |= add_pages(&s, &market, &instances, page_allocation, &sd);
+                }
+                if s.once {
+                    break;
+                }
+                if refresh {
+                    match get_market(s.program_id, s.market, &s.connection) {
+                        Ok((m, _)) => {
+                            // Instances added in the meantime are collected too, unless specific ones were selected
+                            if s.instances.is_none() {
+                                instances = (0..m.instances.len()).collect();
+                            }
+                            market = Arc::new(m);
+                            last_refresh = Instant::now();
+                        }
+                        Err(e) => {
+                            logger::info(&format!("Failed to refresh the market with {:?}", e))
+                        }
+                    }
+                }
             }
         });
         rt.block_on(t).unwrap();
+        shutdown.print_summary();
     }
 }
 
@@ -206,160 +588,318 @@ pub fn get_market(
     market_key: Pubkey,
     connection: &RpcClient,
 ) -> Result<(MarketContext, Pubkey), CrankError> {
-    let market_data = connection
-        .get_account_data(&market_key)
-        .map_err(|_| CrankError::ConnectionError)?;
-    let market_state =
-        MarketState::unpack_from_slice(&market_data).map_err(|_| CrankError::InvalidMarketState)?;
-    let instance_addresses = market_data
-        [MarketState::LEN..MarketState::LEN + (market_state.number_of_instances as usize) * 32]
-        .chunks(32)
-        .into_iter()
-        .map(|s| Pubkey::new(s));
-
-    let instances = instance_addresses
-        .map(|a| {
-            let instance_data = connection
-                .get_account_data(&a)
-                .map_err(|_| CrankError::ConnectionError)?;
-            let instance = Instance::unpack_from_slice(&instance_data)
-                .map_err(|_| CrankError::InvalidMarketState)?;
-            let memory_pages = instance_data[Instance::LEN..]
-                .chunks(PageInfo::LEN)
-                .map(|s| {
-                    Ok(Pubkey::new(
-                        &PageInfo::unpack_from_slice(s)
-                            .map_err(|_| CrankError::InvalidMarketState)?
-                            .address,
-                    ))
-                })
-                .take(instance.number_of_pages as usize)
-                .collect::<Result<Vec<_>, _>>()?;
-
-            Ok(InstanceContext {
-                instance_account: a,
-                memory_pages,
-            })
-        })
-        .collect::<Result<Vec<_>, _>>()?;
+    let market_data =
+        connection
+            .get_account_data(&market_key)
+            .map_err(|source| CrankError::MarketFetch {
+                address: market_key,
+                source,
+            })?;
+    let market_state = MarketState::unpack_from_slice(&market_data)?;
 
-    let market_signer_account = Pubkey::create_program_address(
-        &[&market_key.to_bytes(), &[market_state.signer_nonce]],
-        &program_id,
-    )
-    .map_err(|_| CrankError::InvalidMarketState)?;
+    let mut instances = Vec::with_capacity(market_state.number_of_instances as usize);
+    for i in 0..market_state.number_of_instances {
+        let address = get_instance_address(&market_data, i)?;
+        let instance_data = connection
+            .get_account_data(&address)
+            .map_err(|source| CrankError::InstanceFetch { address, source })?;
+        instances.push(
+            InstanceContext::from_instance_data(address, &instance_data)
+                .map_err(|source| CrankError::InstanceDecode { address, source })?,
+        );
+    }
 
-    let token_account = connection
-        .get_token_account(&Pubkey::new(&market_state.vault_address))
-        .map_err(|_| CrankError::ConnectionError)?
-        .unwrap();
+    let ctx = MarketContext::from_market_state(program_id, market_key, &market_state, instances)
+        .map_err(|_| CrankError::SignerDerivation)?;
 
-    let ctx = MarketContext {
-        audaces_protocol_program_id: program_id,
-        signer_nonce: market_state.signer_nonce,
-        market_signer_account,
-        oracle_account: Pubkey::new(&market_state.oracle_address),
-        market_account: market_key,
-        admin_account: Pubkey::new(&market_state.admin_address),
-        market_vault: Pubkey::new(&market_state.vault_address),
-        bonfida_bnb: Pubkey::from_str(FIDA_BNB).unwrap(),
-        instances,
-    };
+    let token_account = connection
+        .get_token_account(&ctx.market_vault)
+        .map_err(|source| CrankError::VaultFetch {
+            address: ctx.market_vault,
+            source,
+        })?
+        .ok_or(CrankError::VaultNotTokenAccount(ctx.market_vault))?;
+    let mint = Pubkey::from_str(&token_account.mint)
+        .map_err(|_| CrankError::MintParse(token_account.mint.clone()))?;
 
-    Ok((ctx, Pubkey::from_str(&token_account.mint).unwrap()))
+    Ok((ctx, mint))
 }
 
-async fn run_liquidation(
+#[allow(clippy::too_many_arguments)]
+fn run_liquidation(
     endpoint: Arc<String>,
-    market: Arc<MarketContext>,
+    connection: Arc<RpcClient>,
+    market_receiver: watch::Receiver<Arc<MarketContext>>,
     instance_index: usize,
     target_token_account: Arc<Pubkey>,
     fee_payer: Arc<Keypair>,
-) -> Result<(), JoinError> {
+    blockhash: Arc<BlockhashCache>,
+    compute_budget: ComputeBudget,
+    confirmation: Confirmation,
+    oracle_subscription: Option<Arc<OracleSubscription>>,
+    tracker: Option<Arc<SignatureTracker>>,
+    shutdown: Arc<Shutdown>,
+    once: bool,
+) -> JoinHandle<()> {
     task::spawn(async move {
-        let connection = RpcClient::new(String::clone(&endpoint));
-        let liquidation_instruction = crank_liquidation(
-            &market,
-            instance_index as u8,
-            *target_token_account.borrow(),
-        );
-        println!("Starting liquidation task");
+        let action = Action::new("liquidation", Some(instance_index));
+        logger::info("Starting liquidation task");
         let mut ticker = interval(Duration::from_millis(LIQUIDATION_PERIOD));
         loop {
-            ticker.tick().await;
-            println!("Liquidation tick");
-            let transaction = Transaction::new_with_payer(
-                &[liquidation_instruction.clone()],
-                Some(&fee_payer.pubkey()),
+            let proceed = match &oracle_subscription {
+                Some(s) => shutdown.until(s.wait(&mut ticker)).await,
+                None => shutdown.tick(&mut ticker).await,
+            };
+            if !proceed {
+                break;
+            }
+            logger::info("Liquidation tick");
+            let market = Arc::clone(&market_receiver.borrow());
+            let liquidation_instruction = crank_liquidation(
+                &market,
+                instance_index as u8,
+                *target_token_account.borrow(),
             );
+            match is_liquidatable(&connection, &market, instance_index) {
+                Ok(false) if once => break,
+                Ok(false) => continue,
+                Ok(true) => {}
+                // The program is left to decide when the instance can't be simulated
+                Err(e) => logger::info(&format!("Failed to simulate the liquidation with {:?}", e)),
+            }
+            let transaction = compute_budget
+                .with_estimated_price(&endpoint, &[liquidation_instruction.clone()])
+                .await
+                .transaction(&[liquidation_instruction.clone()], &fee_payer.pubkey());
             let sig = utils::retry(
                 transaction,
                 |t| {
-                    let (recent_blockhash, _) = connection.get_recent_blockhash()?;
+                    let recent_blockhash = blockhash.get()?;
                     let mut tr = t.clone();
                     tr.partial_sign::<Vec<&Keypair>>(&vec![fee_payer.borrow()], recent_blockhash);
-                    connection.send_transaction_with_config(
-                        &tr,
-                        RpcSendTransactionConfig {
-                            skip_preflight: false,
-                            preflight_commitment: None,
-                            ..RpcSendTransactionConfig::default()
-                        },
-                    )
+                    confirmation.send_transaction(&connection, &tr)
                 },
-                no_op_filter,
+                terminal_error_filter,
+                action,
             )
             .await;
-            println!(
-                "Sent liquidation transaction for instance {:?} with signature {:?}",
-                instance_index, sig
-            );
+            match sig {
+                SendOutcome::Sent(sig) => {
+                    action.sent(&sig);
+                    if let Some(tracker) = &tracker {
+                        tracker.track(
+                            sig,
+                            action,
+                            vec![liquidation_instruction.clone()],
+                            compute_budget,
+                        );
+                    }
+                    shutdown.record_transaction();
+                }
+                SendOutcome::Dropped(e) => action.dropped(&e),
+            }
+            if once {
+                break;
+            }
         }
     })
-    .await
+}
+
+// Checks against the current oracle price whether a liquidation transaction would liquidate anything
+fn is_liquidatable(
+    connection: &RpcClient,
+    market: &MarketContext,
+    instance_index: usize,
+) -> Result<bool, CrankError> {
+    let instance = &market.instances[instance_index];
+    let mut keys = vec![
+        market.market_account,
+        market.oracle_account,
+        instance.instance_account,
+    ];
+    keys.extend_from_slice(&instance.memory_pages);
+    let accounts = connection.get_multiple_accounts(&keys)?;
+    let mut account_datas = HashMap::with_capacity(keys.len());
+    for (k, a) in keys.iter().zip(accounts) {
+        account_datas.insert(*k, a.ok_or(CrankError::AccountNotFound(*k))?.data);
+    }
+    let market_state = MarketState::unpack_from_slice(&account_datas[&market.market_account])?;
+    let oracle_price = get_oracle_price(
+        &account_datas[&market.oracle_account],
+        market_state.coin_decimals,
+        market_state.quote_decimals,
+    )?;
+    // Pages added since the market context was fetched are read separately
+    let is_liquidatable = simulate_liquidation(
+        &account_datas[&instance.instance_account],
+        &|k| match account_datas.get(k) {
+            Some(d) => d.clone(),
+            None => connection.get_account_data(k).unwrap_or_default(),
+        },
+        oracle_price,
+    )?;
+    Ok(is_liquidatable)
+}
+
+fn get_current_oracle_price(
+    connection: &RpcClient,
+    market: &MarketContext,
+) -> Result<u64, CrankError> {
+    let keys = [market.market_account, market.oracle_account];
+    let accounts = connection.get_multiple_accounts(&keys)?;
+    let mut datas = keys
+        .iter()
+        .zip(accounts)
+        .map(|(k, a)| a.map(|a| a.data).ok_or(CrankError::AccountNotFound(*k)));
+    let market_state = MarketState::unpack_from_slice(&datas.next().unwrap()?)?;
+    let oracle_price = get_oracle_price(
+        &datas.next().unwrap()?,
+        market_state.coin_decimals,
+        market_state.quote_decimals,
+    )?;
+    Ok(oracle_price)
 }
 
 async fn crank_garbage_collection(
     ctx: &Arc<Context>,
     market: &Arc<MarketContext>,
+    instances: &[usize],
     target_token_account: &Arc<Pubkey>,
+    tracker: &Option<Arc<SignatureTracker>>,
+    shutdown: &Arc<Shutdown>,
 ) {
-    let connection = RpcClient::new(String::clone(&ctx.endpoint));
-    for i in 0..(market.instances.len() as u8) {
-        let instruction = collect_garbage(
-            &market,
-            i,
-            GARBAGE_COLLECT_MAX_ITERATIONS,
-            **target_token_account,
-        );
-        let transaction =
-            Transaction::new_with_payer(&[instruction], Some(&ctx.fee_payer.pubkey()));
+    let connection = &ctx.connection;
+    for &i in instances {
+        let action = Action::new("garbage_collection", Some(i));
+        let i = i as u8;
+        if shutdown.is_requested() {
+            break;
+        }
+        // The instruction is built again on each attempt to pick up a lowered number of iterations
+        let build_instruction = || {
+            collect_garbage(
+                &market,
+                i,
+                ctx.gc_max_iterations.load(Ordering::Relaxed),
+                **target_token_account,
+            )
+        };
         let sig = utils::retry(
-            transaction,
-            |t| {
-                let mut tr = t.clone();
-                let (recent_blockhash, _) = connection.get_recent_blockhash()?;
-                tr.partial_sign(&[&ctx.fee_payer], recent_blockhash);
-                connection.send_transaction_with_config(
-                    &tr,
-                    RpcSendTransactionConfig {
-                        skip_preflight: false,
-                        preflight_commitment: None,
-                        ..RpcSendTransactionConfig::default()
-                    },
-                )
+            (),
+            |_| {
+                let recent_blockhash = ctx.blockhash.get()?;
+                let tr = ctx.compute_budget.signed_transaction(
+                    &[build_instruction()],
+                    &ctx.fee_payer,
+                    recent_blockhash,
+                );
+                ctx.confirmation.send_transaction(&connection, &tr)
+            },
+            |r| {
+                if let Err(e) = &r {
+                    if is_compute_budget_exceeded(e) {
+                        let max_iterations = ctx.gc_max_iterations.load(Ordering::Relaxed);
+                        let lowered = lower_iterations(max_iterations);
+                        ctx.gc_max_iterations.store(lowered, Ordering::Relaxed);
+                        logger::info(&format!(
+                            "Garbage collection exceeded the compute budget, lowering the iterations from {} to {}",
+                            max_iterations, lowered
+                        ));
+                    }
+                }
+                no_op_filter(r)
             },
-            no_op_filter,
+            action,
         )
         .await;
-        println!(
-            "Sent garbage collection transaction for isntance {:?} with signature {:?}",
-            i, sig
-        );
+        action.sent(&sig);
+        if let Some(tracker) = tracker {
+            // Only the liquidations are priced dynamically
+            let compute_budget = ComputeBudget {
+                dynamic_priority_fee: None,
+                ..ctx.compute_budget
+            };
+            tracker.track(sig, action, vec![build_instruction()], compute_budget);
+        }
+        shutdown.record_transaction();
     }
 }
 
-async fn crank_funding_extraction_iteration(ctx: &Arc<Context>, swarm_size: u16, node_id: u8) {
+// Adds a memory page to each of the given instances whose pages are all fuller than the threshold.
+// Returns true if any page was added.
+fn add_pages(
+    ctx: &Arc<Context>,
+    market: &MarketContext,
+    instances: &[usize],
+    page_allocation: &PageAllocation,
+    shutdown: &Arc<Shutdown>,
+) -> bool {
+    let connection = &ctx.connection;
+    let market_data = ctx.fetch_market_data();
+    let mut added = false;
+    for &i in instances {
+        if shutdown.is_requested() {
+            break;
+        }
+        let page_full_ratios = &market_data.page_full_ratios[i];
+        if !is_instance_full(page_full_ratios, page_allocation.threshold) {
+            continue;
+        }
+        if page_full_ratios.len() >= MAX_PAGES_PER_INSTANCE {
+            logger::info(&format!(
+                "Instance {} is full but already has the maximum of {} pages",
+                i, MAX_PAGES_PER_INSTANCE
+            ));
+            continue;
+        }
+        let action = Action::new("add_page", Some(i));
+        let page = Keypair::new();
+        let lamports = match connection
+            .get_minimum_balance_for_rent_exemption(page_allocation.page_size as usize)
+        {
+            Ok(l) => l,
+            Err(e) => {
+                action.failed(&e);
+                continue;
+            }
+        };
+        let instructions = [
+            system_instruction::create_account(
+                &ctx.fee_payer.pubkey(),
+                &page.pubkey(),
+                lamports,
+                page_allocation.page_size,
+                &ctx.program_id,
+            ),
+            add_page(market, i as u8, page.pubkey()),
+        ];
+        let mut transaction = ctx
+            .compute_budget
+            .transaction(&instructions, &ctx.fee_payer.pubkey());
+        // A failed attempt isn't retried, the instance is checked again on the next garbage collection tick
+        let result = ctx.blockhash.get().and_then(|h| {
+            transaction.sign(&[&ctx.fee_payer, &page, &page_allocation.admin], h);
+            ctx.confirmation.send_transaction(connection, &transaction)
+        });
+        match result {
+            Ok(sig) => {
+                action.sent(&sig);
+                shutdown.record_transaction();
+                added = true;
+            }
+            Err(e) => action.failed(&e),
+        }
+    }
+    added
+}
+
+async fn crank_funding_extraction_iteration(
+    ctx: &Arc<Context>,
+    swarm_size: u16,
+    node_id: u8,
+    cache: Option<&mut UserAccountCache>,
+    shutdown: &Arc<Shutdown>,
+) {
     if swarm_size == 0 {
         panic!("Swarm size should be non-zero");
     }
@@ -372,30 +912,62 @@ async fn crank_funding_extraction_iteration(ctx: &Arc<Context>, swarm_size: u16,
     if node_id as u16 >= swarm_size {
         panic!("Node id should be less than swarm size.")
     }
-    let configs = get_node_filters(ctx, swarm_size, node_id);
-    let url = ctx.endpoint.clone();
     let program_id = ctx.program_id;
-    let accounts = stream::iter(configs.into_iter())
-        .then(move |c| account_stream(program_id, url.clone(), c))
-        .flatten();
-    let connection = RpcClient::new(ctx.endpoint.to_owned());
+    let connection = Arc::clone(&ctx.connection);
+    let accounts = match cache {
+        Some(cache) => {
+            let mut headers = vec![];
+            for c in get_node_filters(ctx, swarm_size, node_id, Some(user_cache::header_slice())) {
+                headers.extend(
+                    account_stream(program_id, Arc::clone(&connection), c)
+                        .await
+                        .collect::<Vec<_>>()
+                        .await,
+                );
+            }
+            let fetched = utils::retry(
+                cache.stale_keys(&headers),
+                |k| user_cache::fetch_accounts(&connection, k),
+                |r| r,
+                Action::new("account_fetch", None),
+            )
+            .await;
+            logger::info(&format!(
+                "Downloaded {} of {} active user accounts",
+                fetched.len(),
+                headers.len()
+            ));
+            stream::iter(cache.update(&headers, fetched)).boxed()
+        }
+        None => {
+            let configs = get_node_filters(ctx, swarm_size, node_id, None);
+            let stream_connection = Arc::clone(&ctx.connection);
+            stream::iter(configs.into_iter())
+                .then(move |c| account_stream(program_id, Arc::clone(&stream_connection), c))
+                .flatten()
+                .boxed()
+        }
+    };
 
-    let accounts_mutex = Arc::new(Mutex::new(Box::pin(accounts)));
+    let accounts_mutex = Arc::new(Mutex::new(accounts));
     let (market, _) = utils::retry(
         &connection,
         |c| get_market(ctx.program_id, ctx.market, &c),
         |r| r,
+        Action::new("market_fetch", None),
     )
     .await;
     let market = Arc::new(market);
     let mut tasks = Vec::with_capacity(num_cpus::get());
     for _ in 0..tasks.capacity() {
         let task_mutex = Arc::clone(&accounts_mutex);
-        let connection = RpcClient::new(ctx.endpoint.to_owned());
+        let connection = Arc::clone(&ctx.connection);
         let c = Arc::clone(&ctx);
         let m = Arc::clone(&market);
+        let sd = Arc::clone(shutdown);
         let t = async move {
-            loop {
+            // Accounts are processed one at a time so that a shutdown never interrupts a transaction
+            while !sd.is_requested() {
                 // Can't use if let here due to borrow checker in an async context
                 let next = {
                     let mut f = task_mutex.lock().await;
@@ -405,53 +977,60 @@ async fn crank_funding_extraction_iteration(ctx: &Arc<Context>, swarm_size: u16,
                     break;
                 };
                 let (k, a): (Pubkey, Account) = next.unwrap();
-                println!("Processing funding for {:?}", k);
+                logger::info(&format!("Processing funding for {:?}", k));
                 let fee_payer_pk = c.fee_payer.pubkey();
                 let transactions = {
-                    let mut position_offset = UserAccountState::LEN;
                     let header =
                         UserAccountState::unpack_from_slice(&a.data[..UserAccountState::LEN])
                             .unwrap();
-                    let mut cranked_instance_indices: Vec<u8> = vec![0; m.instances.len()];
-                    let mut instructions = vec![];
-                    for _ in 0..header.number_of_open_positions {
-                        let position = OpenPosition::unpack_from_slice(
-                            &a.data[position_offset..position_offset + OpenPosition::LEN],
-                        )
-                        .unwrap();
-                        cranked_instance_indices[position.instance_index as usize] = 1;
-                        instructions.push(extract_funding(&m, position.instance_index, k));
-                        position_offset += OpenPosition::LEN;
-                    }
-                    for (i, l) in cranked_instance_indices.iter().enumerate() {
-                        if *l == 0 {
-                            continue;
+                    let mut instance_indices: Vec<u8> = vec![];
+                    for position in header.positions(&a.data) {
+                        let position = position.unwrap();
+                        if !instance_indices.contains(&position.instance_index) {
+                            instance_indices.push(position.instance_index);
                         }
-                        instructions.push(extract_funding(&m, i as u8, k))
                     }
-                    instructions
-                        .into_iter()
-                        .map(|i| Transaction::new_with_payer(&[i], Some(&fee_payer_pk)))
+                    utils::batch_instances(
+                        &instance_indices,
+                        |i| m.instances[i as usize].memory_pages.len() + 1,
+                        MAX_FUNDING_EXTRACTION_ACCOUNTS - 4,
+                    )
+                    .into_iter()
+                    .map(|batch| {
+                        // Single instance transactions keep being logged with their instance
+                        let instance = match batch.as_slice() {
+                            [i] => Some(*i as usize),
+                            _ => None,
+                        };
+                        (
+                            Action::new("funding_extraction", instance),
+                            c.compute_budget.transaction(
+                                &[extract_funding_multi(&m, &batch, k)],
+                                &fee_payer_pk,
+                            ),
+                        )
+                    })
                 };
-                for t in transactions {
+                for (action, t) in transactions {
                     let sig = utils::retry(
                         t,
                         |t| {
                             let mut tr = t.clone();
-                            let (recent_blockhash, _) = connection.get_recent_blockhash()?;
+                            let recent_blockhash = c.blockhash.get()?;
                             tr.partial_sign::<Vec<&Keypair>>(&vec![&c.fee_payer], recent_blockhash);
-                            connection.send_transaction_with_config(
-                                &tr,
-                                RpcSendTransactionConfig {
-                                    skip_preflight: false,
-                                    ..RpcSendTransactionConfig::default()
-                                },
-                            )
+                            c.confirmation.send_transaction(&connection, &tr)
                         },
-                        no_op_filter,
+                        terminal_error_filter,
+                        action,
                     )
                     .await;
-                    println!("Sent funding extraction transaction {:?}", sig);
+                    match sig {
+                        SendOutcome::Sent(sig) => {
+                            action.sent(&sig);
+                            sd.record_transaction();
+                        }
+                        SendOutcome::Dropped(e) => action.dropped(&e),
+                    }
                 }
             }
         };
@@ -466,6 +1045,7 @@ fn get_node_filters(
     ctx: &Arc<Context>,
     swarm_size: u16,
     node_id: u8,
+    data_slice: Option<UiDataSliceConfig>,
 ) -> Vec<RpcProgramAccountsConfig> {
     if swarm_size > 1 {
         let mut res = Vec::with_capacity((256 / swarm_size + 1) as usize);
@@ -485,6 +1065,14 @@ fn get_node_filters(
                         ),
                         encoding: None,
                     }),
+                    // Filter out the user accounts which still have to be migrated
+                    RpcFilterType::Memcmp(Memcmp {
+                        offset: 1,
+                        bytes: rpc_filter::MemcmpEncodedBytes::Binary(
+                            bs58::encode(vec![USER_ACCOUNT_VERSION]).into_string(),
+                        ),
+                        encoding: None,
+                    }),
                     // Filter for a subset of owners
                     RpcFilterType::Memcmp(Memcmp {
                         offset: 2,
@@ -510,7 +1098,7 @@ fn get_node_filters(
                 ]),
                 account_config: RpcAccountInfoConfig {
                     encoding: Some(UiAccountEncoding::Base64),
-                    data_slice: None,
+                    data_slice,
                     commitment: None,
                 },
                 with_context: None,
@@ -533,6 +1121,14 @@ fn get_node_filters(
                     ),
                     encoding: None,
                 }),
+                // Filter out the user accounts which still have to be migrated
+                RpcFilterType::Memcmp(Memcmp {
+                    offset: 1,
+                    bytes: rpc_filter::MemcmpEncodedBytes::Binary(
+                        bs58::encode(&[USER_ACCOUNT_VERSION]).into_string(),
+                    ),
+                    encoding: None,
+                }),
                 // Filter for active user accounts (with open positions)
                 RpcFilterType::Memcmp(Memcmp {
                     offset: 34,
@@ -548,7 +1144,7 @@ fn get_node_filters(
             ]),
             account_config: RpcAccountInfoConfig {
                 encoding: Some(UiAccountEncoding::Base64),
-                data_slice: None,
+                data_slice,
                 commitment: None,
             },
             with_context: None,
@@ -556,7 +1152,22 @@ fn get_node_filters(
     }
 }
 
-async fn crank_liquidation_cleanup_iteration(ctx: &Arc<Context>, swarm_size: u16, node_id: u8) {
+// The permissionless cranks which close positions on behalf of their owners
+#[derive(Clone, Copy)]
+enum ClosingCrank {
+    // Purges the positions which were liquidated from the user accounts
+    LiquidationCleanup,
+    // Closes at market the positions which crossed their stop-loss index
+    StopLoss,
+}
+
+async fn crank_closing_iteration(
+    ctx: &Arc<Context>,
+    swarm_size: u16,
+    node_id: u8,
+    crank: ClosingCrank,
+    shutdown: &Arc<Shutdown>,
+) {
     if swarm_size == 0 {
         panic!("Swarm size should be non-zero");
     }
@@ -569,30 +1180,33 @@ async fn crank_liquidation_cleanup_iteration(ctx: &Arc<Context>, swarm_size: u16
     if node_id as u16 >= swarm_size {
         panic!("Node id should be less than swarm size.")
     }
-    let configs = get_node_filters(ctx, swarm_size, node_id);
-    let url = ctx.endpoint.clone();
+    let configs = get_node_filters(ctx, swarm_size, node_id, None);
+    let stream_connection = Arc::clone(&ctx.connection);
     let program_id = ctx.program_id;
     let accounts = stream::iter(configs.into_iter())
-        .then(move |c| account_stream(program_id, url.clone(), c))
+        .then(move |c| account_stream(program_id, Arc::clone(&stream_connection), c))
         .flatten();
-    let connection = RpcClient::new(ctx.endpoint.to_owned());
+    let connection = Arc::clone(&ctx.connection);
 
     let accounts_mutex = Arc::new(Mutex::new(Box::pin(accounts)));
     let (market, _) = utils::retry(
         &connection,
         |c| get_market(ctx.program_id, ctx.market, &c),
         |r| r,
+        Action::new("market_fetch", None),
     )
     .await;
     let market = Arc::new(market);
     let mut tasks = Vec::with_capacity(num_cpus::get());
     for _ in 0..tasks.capacity() {
         let task_mutex = Arc::clone(&accounts_mutex);
-        let connection = RpcClient::new(ctx.endpoint.to_owned());
+        let connection = Arc::clone(&ctx.connection);
         let c = Arc::clone(&ctx);
         let m = Arc::clone(&market);
+        let sd = Arc::clone(shutdown);
         let t = async move {
-            loop {
+            // Accounts are processed one at a time so that a shutdown never interrupts a transaction
+            while !sd.is_requested() {
                 // Can't use if let here due to borrow checker in an async context
                 let next = {
                     let mut f = task_mutex.lock().await;
@@ -602,63 +1216,116 @@ async fn crank_liquidation_cleanup_iteration(ctx: &Arc<Context>, swarm_size: u16
                     break;
                 };
                 let (k, a): (Pubkey, Account) = next.unwrap();
-                println!("Processing funding for {:?}", k);
+                logger::info(&format!("Processing positions for {:?}", k));
                 let fee_payer_pk = c.fee_payer.pubkey();
+                let event = match crank {
+                    ClosingCrank::LiquidationCleanup => "liquidation_cleanup",
+                    ClosingCrank::StopLoss => "stop_loss",
+                };
                 let transactions = {
-                    let mut position_offset = UserAccountState::LEN;
                     let header =
                         UserAccountState::unpack_from_slice(&a.data[..UserAccountState::LEN])
                             .unwrap();
-                    let mut cranked_instance_indices: Vec<u8> = vec![0; m.instances.len()];
+                    let positions = header
+                        .positions(&a.data)
+                        .map(|p| p.unwrap())
+                        .enumerate()
+                        .collect::<Vec<_>>();
+                    // The oracle price is only fetched for the accounts holding a stop-loss
+                    let oracle_price = match crank {
+                        ClosingCrank::StopLoss
+                            if positions.iter().any(|(_, p)| p.has_stop_loss()) =>
+                        {
+                            utils::retry(
+                                &connection,
+                                |c| get_current_oracle_price(c, &m),
+                                |r| r,
+                                Action::new("oracle_fetch", None),
+                            )
+                            .await
+                        }
+                        _ => 0,
+                    };
                     let mut instructions = vec![];
-                    for position_index in 0..(header.number_of_open_positions as u16) {
-                        let position = OpenPosition::unpack_from_slice(
-                            &a.data[position_offset..position_offset + OpenPosition::LEN],
-                        )
-                        .unwrap();
-                        cranked_instance_indices[position.instance_index as usize] = 1;
-                        let position_info = PositionInfo {
-                            user_account: k,
-                            user_account_owner: c.fee_payer.pubkey(), // This makes sense for the permissionless crank
-                            instance_index: position.instance_index,
-                            side: position.side,
+                    for (position_index, position) in positions {
+                        let position_index = position_index as u16;
+                        let instruction = match crank {
+                            ClosingCrank::LiquidationCleanup => {
+                                let position_info = PositionInfo {
+                                    user_account: k,
+                                    user_account_owner: c.fee_payer.pubkey(), // This makes sense for the permissionless crank
+                                    instance_index: position.instance_index,
+                                    side: position.side,
+                                };
+                                close_position(
+                                    &m,
+                                    &position_info,
+                                    0,
+                                    0,
+                                    position_index,
+                                    0,
+                                    u64::MAX,
+                                    None,
+                                    None,
+                                )
+                            }
+                            // Stop-losses only allow the positions to be closed entirely
+                            ClosingCrank::StopLoss => {
+                                if !position.is_stop_loss_triggered(oracle_price) {
+                                    continue;
+                                }
+                                match close_position_at_stop_loss(
+                                    &m,
+                                    k,
+                                    &a.data,
+                                    position_index,
+                                    fee_payer_pk,
+                                ) {
+                                    Ok(i) => i,
+                                    Err(e) => {
+                                        logger::info(&format!(
+                                            "Skipping the stop-loss of position {} of {:?}: {:?}",
+                                            position_index, k, e
+                                        ));
+                                        continue;
+                                    }
+                                }
+                            }
                         };
-                        instructions.push(close_position(
-                            &m,
-                            &position_info,
-                            0,
-                            0,
-                            position_index,
-                            0,
-                            u64::MAX,
-                            None,
-                            None,
-                        ));
-                        position_offset += OpenPosition::LEN;
+                        instructions.push((position.instance_index, instruction));
                     }
-                    instructions
-                        .into_iter()
-                        .map(|i| Transaction::new_with_payer(&[i], Some(&fee_payer_pk)))
+                    instructions.into_iter().map(|(instance_index, i)| {
+                        (
+                            Action::new(event, Some(instance_index as usize)),
+                            c.compute_budget.transaction(&[i], &fee_payer_pk),
+                        )
+                    })
                 };
-                for t in transactions {
+                for (action, t) in transactions {
                     let sig = utils::retry(
                         t,
                         |t| {
                             let mut tr = t.clone();
-                            let (recent_blockhash, _) = connection.get_recent_blockhash()?;
+                            let recent_blockhash = c.blockhash.get()?;
                             tr.partial_sign::<Vec<&Keypair>>(&vec![&c.fee_payer], recent_blockhash);
-                            connection.send_transaction_with_config(
-                                &tr,
-                                RpcSendTransactionConfig {
-                                    skip_preflight: false,
-                                    ..RpcSendTransactionConfig::default()
-                                },
-                            )
+                            c.confirmation.send_transaction(&connection, &tr)
+                        },
+                        |r| match crank {
+                            ClosingCrank::LiquidationCleanup => {
+                                terminal_error_filter(invalid_signature_filter(r))
+                            }
+                            ClosingCrank::StopLoss => stop_loss_filter(r),
                         },
-                        invalid_signature_filter,
+                        action,
                     )
                     .await;
-                    println!("Sent liquidation cleanup transaction {:?}", sig);
+                    match sig {
+                        SendOutcome::Sent(sig) => {
+                            action.sent(&sig);
+                            sd.record_transaction();
+                        }
+                        SendOutcome::Dropped(e) => action.dropped(&e),
+                    }
                 }
             }
         };
@@ -671,16 +1338,14 @@ async fn crank_liquidation_cleanup_iteration(ctx: &Arc<Context>, swarm_size: u16
 
 async fn account_stream(
     program_id: Pubkey,
-    url: String,
+    connection: Arc<RpcClient>,
     c: RpcProgramAccountsConfig,
 ) -> Iter<IntoIter<(Pubkey, Account)>> {
     let k: Vec<(Pubkey, Account)> = utils::retry(
         c,
-        move |conf| {
-            let conn = RpcClient::new(url.clone());
-            conn.get_program_accounts_with_config(&program_id, conf.to_owned())
-        },
+        move |conf| connection.get_program_accounts_with_config(&program_id, conf.to_owned()),
         |r| r,
+        Action::new("account_fetch", None),
     )
     .await;
     stream::iter(k)
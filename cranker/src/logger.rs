@@ -0,0 +1,146 @@
+use std::{
+    fmt::Debug,
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+use solana_sdk::signature::Signature;
+
+// Whether the cranker's activity is logged as free-form text or as JSON lines
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!(
+                "Invalid log format {}, expected one of text or json",
+                s
+            )),
+        }
+    }
+}
+
+static JSON_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+pub fn set_format(format: LogFormat) {
+    JSON_OUTPUT.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+fn is_json() -> bool {
+    JSON_OUTPUT.load(Ordering::Relaxed)
+}
+
+// A cranking action, identified in the logs by its event name and the instance it targets if any
+#[derive(Clone, Copy, Debug)]
+pub struct Action {
+    pub event: &'static str,
+    pub instance: Option<usize>,
+}
+
+impl Action {
+    pub fn new(event: &'static str, instance: Option<usize>) -> Self {
+        Self { event, instance }
+    }
+
+    pub fn sent(&self, signature: &Signature) {
+        if is_json() {
+            println!(
+                "{}",
+                entry("info", self.event, self.instance, Some(signature), None)
+            );
+            return;
+        }
+        match self.instance {
+            Some(i) => println!(
+                "Sent {} transaction for instance {:?} with signature {:?}",
+                self.event.replace('_', " "),
+                i,
+                signature
+            ),
+            None => println!(
+                "Sent {} transaction with signature {:?}",
+                self.event.replace('_', " "),
+                signature
+            ),
+        }
+    }
+
+    // The transaction was given up on without landing, it is neither retried nor counted as sent
+    pub fn dropped<E: Debug>(&self, error: &E) {
+        if is_json() {
+            let error = format!("{:?}", error);
+            println!(
+                "{}",
+                entry("error", self.event, self.instance, None, Some(error))
+            );
+            return;
+        }
+        match self.instance {
+            Some(i) => println!(
+                "Gave up on {} transaction for instance {:?} with {:?}",
+                self.event.replace('_', " "),
+                i,
+                error
+            ),
+            None => println!(
+                "Gave up on {} transaction with {:?}",
+                self.event.replace('_', " "),
+                error
+            ),
+        }
+    }
+
+    pub fn failed<E: Debug>(&self, error: &E) {
+        if is_json() {
+            let error = format!("{:?}", error);
+            println!(
+                "{}",
+                entry("error", self.event, self.instance, None, Some(error))
+            );
+        } else {
+            println!("Failed task with {:#?}, retrying", error);
+        }
+    }
+}
+
+// Logs a message which isn't tied to a specific action
+pub fn info(message: &str) {
+    if is_json() {
+        let mut line = entry("info", "message", None, None, None);
+        line["message"] = json!(message);
+        println!("{}", line);
+    } else {
+        println!("{}", message);
+    }
+}
+
+fn entry(
+    level: &str,
+    event: &str,
+    instance: Option<usize>,
+    signature: Option<&Signature>,
+    error: Option<String>,
+) -> Value {
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64();
+    json!({
+        "ts": ts,
+        "level": level,
+        "event": event,
+        "instance": instance,
+        "signature": signature.map(|s| s.to_string()),
+        "error": error,
+    })
+}
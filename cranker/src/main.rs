@@ -1,9 +1,20 @@
 use clap::{value_t_or_exit, App, Arg, SubCommand};
-use perps_crank::Context;
+use perps_crank::{
+    blockhash::BlockhashCache,
+    compute_budget::{ComputeBudget, DynamicPriorityFee},
+    confirmation::{self, Confirmation},
+    logger::{self, LogFormat},
+    Context, PageAllocation,
+};
 use solana_clap_utils::{
     fee_payer::{fee_payer_arg, FEE_PAYER_ARG},
     input_parsers::{keypair_of, pubkey_of},
-    input_validators::is_pubkey,
+    input_validators::{is_keypair, is_pubkey},
+};
+use solana_client::rpc_client::RpcClient;
+use std::{
+    sync::{atomic::AtomicU64, Arc},
+    time::Duration,
 };
 
 fn main() {
@@ -12,10 +23,65 @@ fn main() {
         .version("0.1")
         .author("Audaces Protocol")
         .about("Distributed Audaces Protocol cranking runtime")
-        .subcommand(SubCommand::with_name("liquidate").about("Crank liquidation operations"))
+        .subcommand(
+            SubCommand::with_name("liquidate")
+                .about("Crank liquidation operations")
+                .arg(
+                    Arg::with_name("websocket_url")
+                        .long("websocket-url")
+                        .help("A Solana websocket endpoint url, liquidations are then triggered by oracle updates")
+                        .takes_value(true),
+                ),
+        )
         .subcommand(SubCommand::with_name("funding").about("Crank liquidation operations"))
         .subcommand(
-            SubCommand::with_name("garbage-collect").about("Crank garbage collection operations"),
+            SubCommand::with_name("garbage-collect")
+                .about("Crank garbage collection operations")
+                .arg(
+                    Arg::with_name("auto_add_pages")
+                        .long("auto-add-pages")
+                        .help("Add a memory page to the instances whose pages are all fuller than the page threshold")
+                        .requires_all(&["admin_keypair", "page_size"]),
+                )
+                .arg(
+                    Arg::with_name("page_threshold")
+                        .long("page-threshold")
+                        .help("The fullness ratio of the pages above which a new page is added")
+                        .takes_value(true)
+                        .default_value("0.9")
+                        .validator(|s| match s.parse::<f64>() {
+                            Ok(r) if (0.0..=1.0).contains(&r) => Ok(()),
+                            _ => Err(String::from(
+                                "The page threshold must be a number between 0 and 1",
+                            )),
+                        }),
+                )
+                .arg(
+                    Arg::with_name("page_size")
+                        .long("page-size")
+                        .help("The size in bytes of the added memory pages")
+                        .takes_value(true)
+                        .validator(|s| {
+                            s.parse::<u64>()
+                                .map(|_| ())
+                                .map_err(|_| String::from("The page size must be an integer"))
+                        }),
+                )
+                .arg(
+                    Arg::with_name("admin_keypair")
+                        .long("admin-keypair")
+                        .help("The market admin keypair which signs the added pages")
+                        .takes_value(true)
+                        .validator(is_keypair),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .about("Print a JSON snapshot of the market's current state"),
+        )
+        .subcommand(
+            SubCommand::with_name("capacity")
+                .about("Print the slot usage of the market's memory pages"),
         )
         .subcommand(
             SubCommand::with_name("funding-extraction")
@@ -43,6 +109,11 @@ fn main() {
                                 String::from("The integer node identifer  must be an integer")
                             })
                         }),
+                )
+                .arg(
+                    Arg::with_name("poll_users")
+                        .long("poll-users")
+                        .help("Cache the user accounts between cycles and only download again those whose header changed"),
                 ),
         )
         .subcommand(
@@ -73,6 +144,34 @@ fn main() {
                         }),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("stop-loss")
+                .about("Crank the closing of positions which crossed their stop-loss")
+                .arg(
+                    Arg::with_name("swarm_size")
+                        .long("swarm-size")
+                        .help("The number of nodes in the current cranking swarm")
+                        .takes_value(true)
+                        .default_value("1")
+                        .validator(|s| {
+                            s.parse::<u32>()
+                                .map(|_| ())
+                                .map_err(|_| String::from("The swarm size must be an integer"))
+                        }),
+                )
+                .arg(
+                    Arg::with_name("node_id")
+                        .long("node-id")
+                        .help("The integer node identifer within the swarm")
+                        .takes_value(true)
+                        .default_value("0")
+                        .validator(|s| {
+                            s.parse::<u32>().map(|_| ()).map_err(|_| {
+                                String::from("The integer node identifer  must be an integer")
+                            })
+                        }),
+                ),
+        )
         .arg(
             Arg::with_name("url")
                 .short("u")
@@ -107,7 +206,158 @@ fn main() {
                 .takes_value(true)
                 .default_value(&default_threads),
         )
+        .arg(
+            Arg::with_name("compute_unit_limit")
+                .long("compute-unit-limit")
+                .help("The compute unit limit requested for each cranking transaction")
+                .takes_value(true)
+                .validator(|s| {
+                    s.parse::<u32>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("The compute unit limit must be an integer"))
+                }),
+        )
+        .arg(
+            Arg::with_name("priority_fee")
+                .long("priority-fee")
+                .help("The compute unit price of each cranking transaction, in micro-lamports")
+                .takes_value(true)
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("The priority fee must be an integer"))
+                }),
+        )
+        .arg(
+            Arg::with_name("dynamic_priority_fee")
+                .long("dynamic-priority-fee")
+                .help("Price liquidation transactions from the recent prioritization fees of the accounts they lock"),
+        )
+        .arg(
+            Arg::with_name("priority_fee_multiplier")
+                .long("priority-fee-multiplier")
+                .help("The factor applied to the recent prioritization fee when using dynamic priority fees")
+                .takes_value(true)
+                .default_value("1.2")
+                .validator(|s| {
+                    s.parse::<f64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("The priority fee multiplier must be a number"))
+                }),
+        )
+        .arg(
+            Arg::with_name("max_priority_fee")
+                .long("max-priority-fee")
+                .help("The highest compute unit price in micro-lamports when using dynamic priority fees")
+                .takes_value(true)
+                .default_value("1000000")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("The maximum priority fee must be an integer"))
+                }),
+        )
+        .arg(
+            Arg::with_name("instances")
+                .long("instances")
+                .help("Comma separated indices of the instances to liquidate and garbage collect, all of them by default")
+                .takes_value(true)
+                .use_delimiter(true)
+                .validator(|s| {
+                    s.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("Instance indices must be integers"))
+                }),
+        )
+        .arg(
+            Arg::with_name("confirm")
+                .long("confirm")
+                .help("The commitment level awaited for each cranking transaction before moving on")
+                .takes_value(true)
+                .possible_values(&["none", "processed", "confirmed"])
+                .default_value("none"),
+        )
+        .arg(
+            Arg::with_name("rpc_timeout_secs")
+                .long("rpc-timeout-secs")
+                .help("The timeout of each RPC request in seconds")
+                .takes_value(true)
+                .default_value("30")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("The RPC timeout must be an integer"))
+                }),
+        )
+        .arg(
+            Arg::with_name("confirm_timeout_secs")
+                .long("confirm-timeout-secs")
+                .help("How long to wait in seconds for a transaction to reach the --confirm commitment level")
+                .takes_value(true)
+                .default_value("30")
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("The confirmation timeout must be an integer"))
+                }),
+        )
+        .arg(
+            Arg::with_name("resubmit_timeout")
+                .long("resubmit-timeout")
+                .help("Resubmit the liquidation and garbage collection transactions which are still unknown to the cluster after this many seconds")
+                .takes_value(true)
+                .validator(|s| {
+                    s.parse::<u64>()
+                        .map(|_| ())
+                        .map_err(|_| String::from("The resubmission timeout must be an integer"))
+                }),
+        )
+        .arg(
+            Arg::with_name("blockhash_refresh")
+                .long("blockhash-refresh")
+                .help("Refresh a cached blockhash every this many seconds instead of fetching one for each transaction")
+                .takes_value(true)
+                .validator(|s| match s.parse::<u64>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err(String::from(
+                        "The blockhash refresh period must be a positive integer",
+                    )),
+                }),
+        )
+        .arg(
+            Arg::with_name("gc_max_iterations")
+                .long("gc-max-iterations")
+                .help("The maximum number of iterations of each garbage collection transaction, halved whenever a transaction exceeds the compute budget")
+                .takes_value(true)
+                .default_value("500")
+                .validator(|s| match s.parse::<u64>() {
+                    Ok(n) if n > 0 => Ok(()),
+                    _ => Err(String::from(
+                        "The maximum number of garbage collection iterations must be a positive integer",
+                    )),
+                }),
+        )
+        .arg(
+            Arg::with_name("once")
+                .long("once")
+                .help("Run a single cycle of the crank across all instances, then exit"),
+        )
+        .arg(
+            Arg::with_name("log_format")
+                .long("log-format")
+                .help("Log the cranking activity as free-form text or as JSON lines")
+                .takes_value(true)
+                .possible_values(&["text", "json"])
+                .default_value("text"),
+        )
         .get_matches();
+    logger::set_format(value_t_or_exit!(matches.value_of("log_format"), LogFormat));
+    confirmation::set_timeout(Duration::from_secs(value_t_or_exit!(
+        matches.value_of("confirm_timeout_secs"),
+        u64
+    )));
+    let rpc_timeout =
+        Duration::from_secs(value_t_or_exit!(matches.value_of("rpc_timeout_secs"), u64));
     let endpoint = matches
         .value_of("url")
         .unwrap_or("https://solana-api.projectserum.com");
@@ -115,17 +365,81 @@ fn main() {
     let market = pubkey_of(&matches, "market").expect("Invalid market Pubkey");
     let fee_payer = keypair_of(&matches, FEE_PAYER_ARG.name).unwrap();
     let num_threads = value_t_or_exit!(matches.value_of("threads"), usize);
+    let compute_budget = ComputeBudget {
+        unit_limit: matches
+            .value_of("compute_unit_limit")
+            .map(|s| s.parse().unwrap()),
+        unit_price: matches.value_of("priority_fee").map(|s| s.parse().unwrap()),
+        dynamic_priority_fee: if matches.is_present("dynamic_priority_fee") {
+            Some(DynamicPriorityFee {
+                multiplier: value_t_or_exit!(matches.value_of("priority_fee_multiplier"), f64),
+                ceiling: value_t_or_exit!(matches.value_of("max_priority_fee"), u64),
+            })
+        } else {
+            None
+        },
+    };
+    let connection = Arc::new(RpcClient::new_with_timeout(
+        String::from(endpoint),
+        rpc_timeout,
+    ));
+    let blockhash_refresh = matches
+        .value_of("blockhash_refresh")
+        .map(|s| Duration::from_secs(s.parse().unwrap()));
+    let blockhash = BlockhashCache::new(Arc::clone(&connection), blockhash_refresh);
     let context = Context {
         market,
         fee_payer,
         endpoint: String::from(endpoint),
+        connection,
         program_id,
         num_threads,
+        compute_budget,
+        confirmation: value_t_or_exit!(matches.value_of("confirm"), Confirmation),
+        blockhash,
+        instances: matches
+            .values_of("instances")
+            .map(|v| v.map(|i| i.parse().unwrap()).collect()),
+        resubmit_timeout: matches
+            .value_of("resubmit_timeout")
+            .map(|s| Duration::from_secs(s.parse().unwrap())),
+        gc_max_iterations: AtomicU64::new(value_t_or_exit!(
+            matches.value_of("gc_max_iterations"),
+            u64
+        )),
+        once: matches.is_present("once"),
     };
+    // The snapshot and capacity subcommands only read the market, they don't need a funded fee payer
+    let read_only = matches!(
+        matches.subcommand_name(),
+        Some("snapshot") | Some("capacity")
+    );
+    if !read_only {
+        if let Err(e) = context.check_deployment() {
+            logger::info(&format!("Startup check failed: {}", e));
+            std::process::exit(1);
+        }
+    }
     match matches.subcommand() {
-        ("liquidate", _) => context.crank_liquidation(),
+        ("liquidate", m) => {
+            context.crank_liquidation(m.unwrap().value_of("websocket_url").map(String::from))
+        }
         ("funding", _) => context.crank_funding(),
-        ("garbage-collect", _) => context.garbage_collect(),
+        ("garbage-collect", m) => {
+            let m = m.unwrap();
+            let page_allocation = if m.is_present("auto_add_pages") {
+                Some(PageAllocation {
+                    admin: keypair_of(m, "admin_keypair").unwrap(),
+                    threshold: value_t_or_exit!(m.value_of("page_threshold"), f64),
+                    page_size: value_t_or_exit!(m.value_of("page_size"), u64),
+                })
+            } else {
+                None
+            };
+            context.garbage_collect(page_allocation)
+        }
+        ("snapshot", _) => context.snapshot(),
+        ("capacity", _) => context.capacity(),
         ("funding-extraction", m) => {
             let swarm_size = m
                 .unwrap()
@@ -139,7 +453,11 @@ fn main() {
                 .unwrap()
                 .parse::<u8>()
                 .unwrap();
-            context.crank_funding_extraction(swarm_size, node_id);
+            context.crank_funding_extraction(
+                swarm_size,
+                node_id,
+                m.unwrap().is_present("poll_users"),
+            );
         }
         ("liquidation-cleanup", m) => {
             let swarm_size = m
@@ -156,6 +474,21 @@ fn main() {
                 .unwrap();
             context.crank_liquidation_cleanup(swarm_size, node_id);
         }
+        ("stop-loss", m) => {
+            let swarm_size = m
+                .unwrap()
+                .value_of("swarm_size")
+                .unwrap()
+                .parse::<u16>()
+                .unwrap();
+            let node_id = m
+                .unwrap()
+                .value_of("node_id")
+                .unwrap()
+                .parse::<u8>()
+                .unwrap();
+            context.crank_stop_loss(swarm_size, node_id);
+        }
         _ => panic!("Invalid subcommand"),
     }
 }
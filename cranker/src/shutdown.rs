@@ -0,0 +1,81 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use tokio::{runtime::Runtime, signal::ctrl_c, sync::Notify, time::Interval};
+
+use crate::logger;
+
+// Lets the cranking loops finish their in-flight transaction before exiting on Ctrl-C or SIGTERM
+pub struct Shutdown {
+    requested: AtomicBool,
+    notify: Notify,
+    transactions_sent: AtomicU64,
+}
+
+impl Shutdown {
+    pub fn install(rt: &Runtime) -> Arc<Self> {
+        let shutdown = Arc::new(Self {
+            requested: AtomicBool::new(false),
+            notify: Notify::new(),
+            transactions_sent: AtomicU64::new(0),
+        });
+        let s = Arc::clone(&shutdown);
+        rt.spawn(async move {
+            wait_for_signal().await;
+            logger::info("Shutdown requested, finishing in-flight transactions");
+            s.requested.store(true, Ordering::SeqCst);
+            s.notify.notify_waiters();
+        });
+        shutdown
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    // Waits for the given future, returns false if a shutdown was requested in the meantime
+    pub async fn until<F: Future>(&self, f: F) -> bool {
+        if self.is_requested() {
+            return false;
+        }
+        tokio::select! {
+            _ = f => !self.is_requested(),
+            _ = self.notify.notified() => false,
+        }
+    }
+
+    pub async fn tick(&self, ticker: &mut Interval) -> bool {
+        self.until(ticker.tick()).await
+    }
+
+    pub fn record_transaction(&self) {
+        self.transactions_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn print_summary(&self) {
+        logger::info(&format!(
+            "Shut down cleanly after sending {} transactions",
+            self.transactions_sent.load(Ordering::Relaxed)
+        ));
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    tokio::select! {
+        _ = ctrl_c() => {},
+        _ = sigterm.recv() => {},
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() {
+    ctrl_c().await.unwrap();
+}
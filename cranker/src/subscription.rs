@@ -0,0 +1,71 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use solana_client::pubsub_client::PubsubClient;
+use solana_program::pubkey::Pubkey;
+use tokio::{
+    sync::Notify,
+    time::{timeout, Interval},
+};
+
+use crate::logger;
+
+// Delay before trying to reopen a dropped websocket
+const RECONNECTION_DELAY: u64 = 5_000;
+// Even with a live subscription, a liquidation is attempted at least this often
+const SUBSCRIBED_LIQUIDATION_PERIOD: u64 = 30_000;
+
+// Wakes up the liquidation tasks whenever the oracle account is updated
+pub struct OracleSubscription {
+    notify: Notify,
+    connected: AtomicBool,
+}
+
+impl OracleSubscription {
+    pub fn spawn(websocket_url: String, oracle_account: Pubkey) -> Arc<Self> {
+        let subscription = Arc::new(Self {
+            notify: Notify::new(),
+            connected: AtomicBool::new(false),
+        });
+        let s = Arc::clone(&subscription);
+        // The pubsub client is blocking, it gets its own thread
+        thread::spawn(move || loop {
+            match PubsubClient::account_subscribe(&websocket_url, &oracle_account, None) {
+                Ok((_subscription, receiver)) => {
+                    logger::info(&format!("Subscribed to oracle account {:?}", oracle_account));
+                    s.connected.store(true, Ordering::Relaxed);
+                    while receiver.recv().is_ok() {
+                        s.notify.notify_waiters();
+                    }
+                    logger::info("Oracle subscription dropped, falling back to polling");
+                }
+                Err(e) => logger::info(&format!(
+                    "Failed to subscribe to the oracle account with {:?}",
+                    e
+                )),
+            }
+            s.connected.store(false, Ordering::Relaxed);
+            thread::sleep(Duration::from_millis(RECONNECTION_DELAY));
+        });
+        subscription
+    }
+
+    // Resolves on the next oracle update, or on the next tick of the polling interval while the websocket is down
+    pub async fn wait(&self, ticker: &mut Interval) {
+        if self.connected.load(Ordering::Relaxed) {
+            let _ = timeout(
+                Duration::from_millis(SUBSCRIBED_LIQUIDATION_PERIOD),
+                self.notify.notified(),
+            )
+            .await;
+        } else {
+            ticker.tick().await;
+        }
+    }
+}
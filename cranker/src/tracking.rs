@@ -0,0 +1,238 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use solana_client::rpc_client::RpcClient;
+use solana_program::instruction::Instruction;
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    signature::{Keypair, Signature},
+};
+use tokio::{runtime::Runtime, time::interval};
+
+use crate::{
+    blockhash::BlockhashCache,
+    compute_budget::ComputeBudget,
+    confirmation::Confirmation,
+    logger::{self, Action},
+    shutdown::Shutdown,
+};
+
+const POLL_PERIOD: u64 = 2_000;
+// Upper bound on the number of signatures accepted by getSignatureStatuses
+const MAX_SIGNATURES_PER_REQUEST: usize = 256;
+const MAX_RESUBMISSIONS: u8 = 3;
+
+struct Pending {
+    action: Action,
+    instructions: Vec<Instruction>,
+    compute_budget: ComputeBudget, // Resolved again on each resubmission when it has a dynamic priority fee
+    sent_at: Instant,
+    resubmissions: u8,
+}
+
+// Keeps track of the sent transactions and resubmits those which were dropped before reaching the cluster
+pub struct SignatureTracker {
+    connection: Arc<RpcClient>,
+    endpoint: String,
+    fee_payer: Arc<Keypair>,
+    blockhash: Arc<BlockhashCache>,
+    confirmation: Confirmation,
+    timeout: Duration,
+    pending: Mutex<HashMap<Signature, Pending>>,
+}
+
+impl SignatureTracker {
+    pub fn new(
+        connection: Arc<RpcClient>,
+        endpoint: String,
+        fee_payer: Arc<Keypair>,
+        blockhash: Arc<BlockhashCache>,
+        confirmation: Confirmation,
+        timeout: Duration,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            connection,
+            endpoint,
+            fee_payer,
+            blockhash,
+            confirmation,
+            timeout,
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Polls the signature statuses in the background until a shutdown is requested
+    pub fn spawn(self: &Arc<Self>, rt: &Runtime, shutdown: &Arc<Shutdown>) {
+        let tracker = Arc::clone(self);
+        let shutdown = Arc::clone(shutdown);
+        rt.spawn(async move {
+            let mut ticker = interval(Duration::from_millis(POLL_PERIOD));
+            while shutdown.tick(&mut ticker).await {
+                tracker.poll().await;
+            }
+        });
+    }
+
+    pub fn track(
+        &self,
+        signature: Signature,
+        action: Action,
+        instructions: Vec<Instruction>,
+        compute_budget: ComputeBudget,
+    ) {
+        // No-op operations are reported with a null signature and were never sent
+        if signature == Signature::new(&[0; 64]) {
+            return;
+        }
+        self.pending.lock().unwrap().insert(
+            signature,
+            Pending {
+                action,
+                instructions,
+                compute_budget,
+                sent_at: Instant::now(),
+                resubmissions: 0,
+            },
+        );
+    }
+
+    async fn poll(&self) {
+        let mut pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        let signatures = pending.keys().cloned().collect::<Vec<_>>();
+        let mut still_pending = HashMap::with_capacity(pending.len());
+        for chunk in signatures.chunks(MAX_SIGNATURES_PER_REQUEST) {
+            let statuses = match self.connection.get_signature_statuses(chunk) {
+                Ok(r) => r.value,
+                Err(e) => {
+                    logger::info(&format!("Failed to fetch signature statuses with {:?}", e));
+                    chunk.iter().for_each(|s| {
+                        still_pending.insert(*s, pending.remove(s).unwrap());
+                    });
+                    continue;
+                }
+            };
+            for (signature, status) in chunk.iter().zip(statuses) {
+                let p = pending.remove(signature).unwrap();
+                match status {
+                    Some(s) if s.satisfies_commitment(CommitmentConfig::confirmed()) => {
+                        if let Some(e) = s.err {
+                            p.action.failed(&e);
+                        }
+                    }
+                    // The transaction reached the cluster, it only needs more time to be confirmed
+                    Some(_) => {
+                        still_pending.insert(*signature, p);
+                    }
+                    None if p.sent_at.elapsed() < self.timeout => {
+                        still_pending.insert(*signature, p);
+                    }
+                    None => {
+                        if let Some((s, p)) = self.resubmit(signature, p).await {
+                            still_pending.insert(s, p);
+                        }
+                    }
+                }
+            }
+        }
+        self.pending.lock().unwrap().extend(still_pending);
+    }
+
+    async fn resubmit(
+        &self,
+        signature: &Signature,
+        mut p: Pending,
+    ) -> Option<(Signature, Pending)> {
+        if p.resubmissions >= MAX_RESUBMISSIONS {
+            logger::info(&format!(
+                "Giving up on transaction {:?} after {} resubmissions",
+                signature, p.resubmissions
+            ));
+            return None;
+        }
+        let recent_blockhash = match self.blockhash.get() {
+            Ok(h) => h,
+            Err(e) => {
+                p.action.failed(&e);
+                return Some((*signature, p));
+            }
+        };
+        let transaction = p
+            .compute_budget
+            .with_estimated_price(&self.endpoint, &p.instructions)
+            .await
+            .signed_transaction(&p.instructions, &self.fee_payer, recent_blockhash);
+        match self
+            .confirmation
+            .send_transaction(&self.connection, &transaction)
+        {
+            Ok(s) => {
+                p.action.sent(&s);
+                p.sent_at = Instant::now();
+                p.resubmissions += 1;
+                Some((s, p))
+            }
+            // The operation most likely became a no-op in the meantime
+            Err(e) => {
+                p.action.failed(&e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use solana_client::rpc_client::RpcClient;
+    use solana_sdk::signature::{Keypair, Signature};
+
+    use super::{SignatureTracker, MAX_RESUBMISSIONS};
+    use crate::{
+        blockhash::BlockhashCache, compute_budget::ComputeBudget, confirmation::Confirmation,
+        logger::Action,
+    };
+
+    #[tokio::test]
+    async fn test_resubmit_dropped_transaction() {
+        // The mock client knows none of the signatures, so the tracked transactions are deemed dropped
+        let connection = Arc::new(RpcClient::new_mock(String::from("sig_not_found")));
+        let tracker = SignatureTracker::new(
+            Arc::clone(&connection),
+            String::from("sig_not_found"),
+            Arc::new(Keypair::new()),
+            BlockhashCache::new(connection, None),
+            Confirmation::None,
+            Duration::from_secs(0),
+        );
+        let signature = Signature::new(&[1; 64]);
+        let compute_budget = ComputeBudget {
+            unit_limit: Some(200_000),
+            unit_price: Some(1_000),
+            dynamic_priority_fee: None,
+        };
+        tracker.track(
+            signature,
+            Action::new("liquidation", Some(0)),
+            vec![],
+            compute_budget,
+        );
+
+        for resubmissions in 1..=MAX_RESUBMISSIONS {
+            tracker.poll().await;
+            let pending = tracker.pending.lock().unwrap();
+            assert_eq!(pending.len(), 1);
+            // The transaction was signed again with a new blockhash and is tracked under its new signature
+            let (s, p) = pending.iter().next().unwrap();
+            assert_ne!(*s, signature);
+            assert_eq!(p.resubmissions, resubmissions);
+        }
+
+        // The tracker gives up after the last resubmission
+        tracker.poll().await;
+        assert!(tracker.pending.lock().unwrap().is_empty());
+    }
+}
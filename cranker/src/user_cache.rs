@@ -0,0 +1,135 @@
+use std::collections::{HashMap, HashSet};
+
+use audaces_protocol::state::user_account::UserAccountState;
+use solana_account_decoder::UiDataSliceConfig;
+use solana_client::{client_error::ClientError, rpc_client::RpcClient};
+use solana_program::{program_pack::Pack, pubkey::Pubkey};
+use solana_sdk::account::Account;
+
+// Upper bound on the number of accounts accepted by getMultipleAccounts
+const MAX_ACCOUNTS_PER_REQUEST: usize = 100;
+
+// Keeps the user accounts between funding extraction cycles. Each cycle only scans the account headers,
+// the full accounts being downloaded again when their header changed. Opening, increasing, closing or
+// transferring a position always changes the balance or the number of open positions in the header.
+#[derive(Default)]
+pub struct UserAccountCache {
+    accounts: HashMap<Pubkey, Account>,
+}
+
+impl UserAccountCache {
+    // Returns the accounts whose cached positions can't be relied on given their current headers
+    pub fn stale_keys(&self, headers: &[(Pubkey, Account)]) -> Vec<Pubkey> {
+        headers
+            .iter()
+            .filter(|(k, header)| match self.accounts.get(k) {
+                Some(a) => header_data(a) != header_data(header),
+                None => true,
+            })
+            .map(|(k, _)| *k)
+            .collect()
+    }
+
+    // Stores the downloaded accounts, evicts those which are no longer active and returns the full accounts
+    // matching the current headers
+    pub fn update(
+        &mut self,
+        headers: &[(Pubkey, Account)],
+        fetched: Vec<(Pubkey, Account)>,
+    ) -> Vec<(Pubkey, Account)> {
+        self.accounts.extend(fetched);
+        let active = headers.iter().map(|(k, _)| *k).collect::<HashSet<_>>();
+        self.accounts.retain(|k, _| active.contains(k));
+        headers
+            .iter()
+            .filter_map(|(k, _)| self.accounts.get(k).map(|a| (*k, a.clone())))
+            .collect()
+    }
+}
+
+// Restricts a program account scan to the user account headers
+pub fn header_slice() -> UiDataSliceConfig {
+    UiDataSliceConfig {
+        offset: 0,
+        length: UserAccountState::LEN,
+    }
+}
+
+// Accounts which were closed in the meantime are left out
+pub fn fetch_accounts(
+    connection: &RpcClient,
+    keys: &[Pubkey],
+) -> Result<Vec<(Pubkey, Account)>, ClientError> {
+    let mut accounts = Vec::with_capacity(keys.len());
+    for chunk in keys.chunks(MAX_ACCOUNTS_PER_REQUEST) {
+        let fetched = connection.get_multiple_accounts(chunk)?;
+        accounts.extend(
+            chunk
+                .iter()
+                .zip(fetched)
+                .filter_map(|(k, a)| a.map(|a| (*k, a))),
+        );
+    }
+    Ok(accounts)
+}
+
+fn header_data(account: &Account) -> Option<&[u8]> {
+    account.data.get(..UserAccountState::LEN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use audaces_protocol::state::user_account::USER_ACCOUNT_VERSION;
+
+    fn user_account(number_of_open_positions: u32, balance: u64, data_len: usize) -> Account {
+        let mut data = vec![0; data_len];
+        UserAccountState {
+            version: USER_ACCOUNT_VERSION,
+            owner: [0; 32],
+            active: true,
+            market: [0; 32],
+            balance,
+            last_funding_offset: 0,
+            number_of_open_positions,
+        }
+        .pack_into_slice(&mut data);
+        Account {
+            data,
+            ..Account::default()
+        }
+    }
+
+    #[test]
+    fn test_user_account_cache() {
+        let mut cache = UserAccountCache::default();
+        let (a, b) = (Pubkey::new_unique(), Pubkey::new_unique());
+
+        let headers = vec![
+            (a, user_account(1, 0, UserAccountState::LEN)),
+            (b, user_account(2, 0, UserAccountState::LEN)),
+        ];
+        assert_eq!(cache.stale_keys(&headers), vec![a, b]);
+        let accounts = cache.update(
+            &headers,
+            vec![(a, user_account(1, 0, 200)), (b, user_account(2, 0, 300))],
+        );
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[1].1.data.len(), 300);
+
+        // Unchanged accounts are served from the cache
+        assert!(cache.stale_keys(&headers).is_empty());
+        assert_eq!(cache.update(&headers, vec![]).len(), 2);
+
+        // Only the account which opened a position is downloaded again, and b is no longer active
+        let headers = vec![(a, user_account(2, 0, UserAccountState::LEN))];
+        assert_eq!(cache.stale_keys(&headers), vec![a]);
+        let accounts = cache.update(&headers, vec![(a, user_account(2, 0, 300))]);
+        assert_eq!(accounts.len(), 1);
+        assert!(!cache.accounts.contains_key(&b));
+
+        // Closing a position and opening another one keeps the count but pays fees from the balance
+        let headers = vec![(a, user_account(2, 1_000, UserAccountState::LEN))];
+        assert_eq!(cache.stale_keys(&headers), vec![a]);
+    }
+}
@@ -1,10 +1,20 @@
+use audaces_protocol::error::PerpError;
 use dotenv::var;
 use reqwest::Client;
-use solana_client::client_error::ClientError;
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    rpc_request::{RpcError, RpcResponseErrorData},
+};
 use solana_program::instruction::InstructionError;
-use solana_sdk::signature::Signature;
-use std::fmt::Debug;
-use tokio::task;
+use solana_sdk::{signature::Signature, transaction::TransactionError};
+use std::{fmt::Debug, time::Duration};
+use tokio::time::sleep;
+
+use crate::logger::{self, Action};
+
+// Delay before the first retry of a failed task, doubled after each consecutive failure
+const RETRY_DELAY: u64 = 100;
+const MAX_RETRY_DELAY: u64 = 5_000;
 
 pub struct SlackClient {
     pub client: Client,
@@ -31,31 +41,37 @@ impl SlackClient {
     }
 }
 
-pub async fn retry<F, T, K, E, R>(arg: T, f: F, e: R) -> K
+pub async fn retry<F, T, K, L, E, R>(arg: T, f: F, e: R, action: Action) -> L
 where
     F: Fn(&T) -> Result<K, E>,
     E: Debug,
-    R: Fn(Result<K, E>) -> Result<K, E>,
+    R: Fn(Result<K, E>) -> Result<L, E>,
 {
+    let mut failures = 0;
     loop {
         let res = e(f(&arg));
-        let mut counter = 1;
         if res.is_ok() {
             return res.unwrap();
         }
-        counter += 1;
         let error = res.err().unwrap();
-        if counter % 10 == 0 {
+        if (failures + 1) % 10 == 0 {
             SlackClient::new()
                 .send_message(format!("Failed task with {:#?}, retrying", error))
                 .await;
         }
 
-        println!("Failed task with {:#?}, retrying", error);
-        task::yield_now().await;
+        action.failed(&error);
+        sleep(retry_delay(failures)).await;
+        failures += 1;
     }
 }
 
+// Time to wait after the given number of consecutive failures, before the next attempt
+fn retry_delay(failures: u32) -> Duration {
+    let delay = RETRY_DELAY.saturating_mul(2u64.saturating_pow(failures));
+    Duration::from_millis(delay.min(MAX_RETRY_DELAY))
+}
+
 pub fn no_op_filter(r: Result<Signature, ClientError>) -> Result<Signature, ClientError> {
     if let Err(e) = &r {
         match &e.kind {
@@ -69,7 +85,7 @@ pub fn no_op_filter(r: Result<Signature, ClientError>) -> Result<Signature, Clie
                 if let solana_client::rpc_request::RpcResponseErrorData::SendTransactionPreflightFailure(f) = data {
                     match f.err {
                         Some(solana_sdk::transaction::TransactionError::InstructionError(_, InstructionError::Custom(0x7))) => {
-                            println!("Operation was a no-op");
+                            logger::info("Operation was a no-op");
                             Ok(Signature::new(&[0;64]))
                         }
                         _ => r
@@ -85,6 +101,92 @@ pub fn no_op_filter(r: Result<Signature, ClientError>) -> Result<Signature, Clie
     }
 }
 
+// Program errors which the same transaction would run into again however many times it is retried.
+// The slippage errors are decided by the predicted price and margin carried by the instruction.
+const TERMINAL_PROGRAM_ERRORS: [PerpError; 13] = [
+    PerpError::PositionNotFound,
+    PerpError::Overflow,
+    PerpError::CorruptedPositionsBook,
+    PerpError::MarketMismatch,
+    PerpError::InstanceMismatch,
+    PerpError::OracleMismatch,
+    PerpError::OutdatedAccount,
+    PerpError::MemoryError,
+    PerpError::SlippageMarginTooLarge,
+    PerpError::NetworkSlippageTooLarge,
+    PerpError::MarginTooLow,
+    PerpError::AmountTooLarge,
+    PerpError::TooManyOpenPositions,
+];
+
+// Transaction errors which would happen again however many times the transaction is retried. Other
+// instruction errors, such as a program running out of compute units, may not.
+fn is_terminal_transaction_error(e: &TransactionError) -> bool {
+    if let TransactionError::InstructionError(_, InstructionError::Custom(code)) = e {
+        return TERMINAL_PROGRAM_ERRORS
+            .iter()
+            .any(|p| p.clone() as u32 == *code);
+    }
+    matches!(
+        e,
+        TransactionError::AccountNotFound
+            | TransactionError::ProgramAccountNotFound
+            | TransactionError::InsufficientFundsForFee
+            | TransactionError::InvalidAccountForFee
+            | TransactionError::InvalidAccountIndex
+            | TransactionError::InvalidProgramForExecution
+    )
+}
+
+// Outcome of a transaction retried through the terminal error filter
+#[derive(Debug, PartialEq)]
+pub enum SendOutcome {
+    Sent(Signature),
+    // Given up on without landing, since retrying cannot fix its error
+    Dropped(TransactionError),
+}
+
+// Stops the retries of a transaction which cannot succeed, on top of the no-ops. Transient errors such as
+// an expired blockhash, an unhealthy node or a connection failure are left to be retried.
+pub fn terminal_error_filter(
+    r: Result<Signature, ClientError>,
+) -> Result<SendOutcome, ClientError> {
+    let e = match no_op_filter(r) {
+        Ok(signature) => return Ok(SendOutcome::Sent(signature)),
+        Err(e) => e,
+    };
+    match transaction_error(&e) {
+        Some(te) if is_terminal_transaction_error(te) => Ok(SendOutcome::Dropped(te.clone())),
+        _ => Err(e),
+    }
+}
+
+// A stop-loss close is rejected with an invalid argument once the oracle price is back on the safe side
+// of the stop-loss index. It isn't retried, the next iteration of the crank checks the position again.
+pub fn stop_loss_filter(r: Result<Signature, ClientError>) -> Result<SendOutcome, ClientError> {
+    let e = match terminal_error_filter(r) {
+        Err(e) => e,
+        outcome => return outcome,
+    };
+    match transaction_error(&e) {
+        Some(te @ TransactionError::InstructionError(_, InstructionError::InvalidArgument)) => {
+            Ok(SendOutcome::Dropped(te.clone()))
+        }
+        _ => Err(e),
+    }
+}
+
+fn transaction_error(e: &ClientError) -> Option<&TransactionError> {
+    match &e.kind {
+        ClientErrorKind::RpcError(RpcError::RpcResponseError {
+            data: RpcResponseErrorData::SendTransactionPreflightFailure(f),
+            ..
+        }) => f.err.as_ref(),
+        ClientErrorKind::TransactionError(te) => Some(te),
+        _ => None,
+    }
+}
+
 pub fn invalid_signature_filter(
     r: Result<Signature, ClientError>,
 ) -> Result<Signature, ClientError> {
@@ -100,7 +202,7 @@ pub fn invalid_signature_filter(
                 if let solana_client::rpc_request::RpcResponseErrorData::SendTransactionPreflightFailure(f) = data {
                     match f.err {
                         Some(solana_sdk::transaction::TransactionError::InstructionError(_, InstructionError::InvalidArgument)) => {
-                            println!("The position has not been liquidated.");
+                            logger::info("The position has not been liquidated.");
                             Ok(Signature::new(&[0;64]))
                         }
                         _ => r
@@ -115,3 +217,297 @@ pub fn invalid_signature_filter(
         r
     }
 }
+
+// Log messages of a program interrupted for running out of compute units
+const COMPUTE_BUDGET_EXCEEDED_LOGS: [&str; 2] = [
+    "exceeded maximum number of instructions allowed",
+    "exceeded CUs meter",
+];
+
+// Preflight failure of a transaction doing more work than its compute budget allows. The runtime
+// reports running out of instructions as a program failing to complete, which other program
+// failures also map to, so the logs tell them apart.
+pub fn is_compute_budget_exceeded(e: &ClientError) -> bool {
+    let f = match &e.kind {
+        ClientErrorKind::RpcError(RpcError::RpcResponseError {
+            data: RpcResponseErrorData::SendTransactionPreflightFailure(f),
+            ..
+        }) => f,
+        _ => return false,
+    };
+    match f.err {
+        Some(TransactionError::InstructionError(
+            _,
+            InstructionError::ComputationalBudgetExceeded,
+        )) => true,
+        Some(TransactionError::InstructionError(_, InstructionError::ProgramFailedToComplete)) => f
+            .logs
+            .iter()
+            .flatten()
+            .any(|l| COMPUTE_BUDGET_EXCEEDED_LOGS.iter().any(|m| l.contains(m))),
+        _ => false,
+    }
+}
+
+// Halves the number of iterations of an instruction, keeping at least one
+pub fn lower_iterations(max_iterations: u64) -> u64 {
+    (max_iterations / 2).max(1)
+}
+
+// An instance is full once every one of its pages exceeds the fullness threshold
+pub fn is_instance_full(page_full_ratios: &[f64], threshold: f64) -> bool {
+    !page_full_ratios.is_empty() && page_full_ratios.iter().all(|r| *r > threshold)
+}
+
+// Groups the instances in batches whose total number of accounts stays within the given maximum.
+// An instance which exceeds the maximum on its own is sent in its own batch.
+pub fn batch_instances<F>(
+    instance_indices: &[u8],
+    accounts_len: F,
+    max_accounts: usize,
+) -> Vec<Vec<u8>>
+where
+    F: Fn(u8) -> usize,
+{
+    let mut batches: Vec<Vec<u8>> = vec![];
+    let mut batch_accounts = 0;
+    for &i in instance_indices {
+        let len = accounts_len(i);
+        match batches.last_mut() {
+            Some(batch) if batch_accounts + len <= max_accounts => {
+                batch.push(i);
+                batch_accounts += len;
+            }
+            _ => {
+                batches.push(vec![i]);
+                batch_accounts = len;
+            }
+        }
+    }
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    use solana_sdk::{hash::Hash, signature::Keypair};
+
+    use super::{
+        batch_instances, is_compute_budget_exceeded, is_instance_full, lower_iterations, retry,
+        retry_delay, stop_loss_filter, terminal_error_filter, SendOutcome,
+    };
+    use crate::{compute_budget::ComputeBudget, logger::Action};
+
+    #[tokio::test]
+    async fn test_retry_backoff() {
+        let payer = Keypair::new();
+        // Every fetch returns a new blockhash, the first two are expired by the time they are sent
+        let blockhashes = Mutex::new((0..3).map(|_| Hash::new_unique()).collect::<Vec<_>>());
+        let expired = blockhashes.lock().unwrap()[..2].to_vec();
+        let sent = Mutex::new(vec![]);
+        let start = Instant::now();
+        let transaction = retry(
+            (),
+            |_| {
+                let blockhash = blockhashes.lock().unwrap().remove(0);
+                let transaction =
+                    ComputeBudget::default().signed_transaction(&[], &payer, blockhash);
+                sent.lock().unwrap().push(start.elapsed());
+                if expired.contains(&blockhash) {
+                    return Err("Blockhash not found");
+                }
+                Ok(transaction)
+            },
+            |r| r,
+            Action::new("funding", None),
+        )
+        .await;
+
+        let sent = sent.into_inner().unwrap();
+        assert_eq!(sent.len(), 3);
+        assert!(blockhashes.into_inner().unwrap().is_empty());
+        assert!(!expired.contains(&transaction.message.recent_blockhash));
+        assert!(transaction.verify().is_ok());
+        // The delay between two attempts doubles after each failure
+        assert!(sent[1] - sent[0] >= retry_delay(0));
+        assert!(sent[2] - sent[1] >= retry_delay(1));
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(0), Duration::from_millis(100));
+        assert_eq!(retry_delay(1), Duration::from_millis(200));
+        assert_eq!(retry_delay(5), Duration::from_millis(3_200));
+        assert_eq!(retry_delay(6), Duration::from_millis(5_000));
+        assert_eq!(retry_delay(60), Duration::from_millis(5_000));
+        assert_eq!(retry_delay(u32::MAX), Duration::from_millis(5_000));
+    }
+
+    #[test]
+    fn test_is_instance_full() {
+        assert!(is_instance_full(&[0.95, 0.91], 0.9));
+        assert!(!is_instance_full(&[0.95, 0.5], 0.9));
+        assert!(!is_instance_full(&[0.9], 0.9));
+        assert!(!is_instance_full(&[], 0.9));
+    }
+
+    #[test]
+    fn test_is_compute_budget_exceeded() {
+        use serde_json::json;
+        use solana_client::{
+            client_error::{ClientError, ClientErrorKind},
+            rpc_request::{RpcError, RpcResponseErrorData},
+            rpc_response::RpcSimulateTransactionResult,
+        };
+        use solana_program::instruction::InstructionError;
+        use solana_sdk::transaction::TransactionError;
+
+        // The simulation result is deserialized since its fields depend on the client version
+        let preflight_failure = |err: InstructionError, logs: &[&str]| {
+            let simulation: RpcSimulateTransactionResult = serde_json::from_value(json!({
+                "err": TransactionError::InstructionError(0, err),
+                "logs": logs,
+            }))
+            .unwrap();
+            ClientError::from(ClientErrorKind::RpcError(RpcError::RpcResponseError {
+                code: -32002,
+                message: String::new(),
+                data: RpcResponseErrorData::SendTransactionPreflightFailure(simulation),
+            }))
+        };
+
+        assert!(is_compute_budget_exceeded(&preflight_failure(
+            InstructionError::ComputationalBudgetExceeded,
+            &[]
+        )));
+        assert!(is_compute_budget_exceeded(&preflight_failure(
+            InstructionError::ProgramFailedToComplete,
+            &["Program failed: exceeded maximum number of instructions allowed (200000) at instruction #1234"]
+        )));
+        // A program which panics also fails to complete
+        assert!(!is_compute_budget_exceeded(&preflight_failure(
+            InstructionError::ProgramFailedToComplete,
+            &["Program log: panicked at 'attempt to subtract with overflow'"]
+        )));
+        assert!(!is_compute_budget_exceeded(&preflight_failure(
+            InstructionError::InvalidArgument,
+            &["exceeded CUs meter"]
+        )));
+    }
+
+    #[test]
+    fn test_lower_iterations() {
+        assert_eq!(lower_iterations(500), 250);
+        assert_eq!(lower_iterations(3), 1);
+        assert_eq!(lower_iterations(1), 1);
+    }
+
+    #[test]
+    fn test_batch_instances() {
+        let accounts_len = |i: u8| i as usize + 1;
+        assert!(batch_instances(&[], accounts_len, 10).is_empty());
+        assert_eq!(
+            batch_instances(&[0, 1, 2, 3], accounts_len, 6),
+            vec![vec![0, 1, 2], vec![3]]
+        );
+        // An instance larger than the maximum still gets a batch
+        assert_eq!(
+            batch_instances(&[9, 0], accounts_len, 6),
+            vec![vec![9], vec![0]]
+        );
+    }
+
+    #[test]
+    fn test_terminal_error_filter() {
+        use audaces_protocol::error::PerpError;
+        use solana_client::client_error::{ClientError, ClientErrorKind};
+        use solana_program::instruction::InstructionError;
+        use solana_sdk::{signature::Signature, transaction::TransactionError};
+
+        let filter = |e: TransactionError| {
+            terminal_error_filter(Err(ClientError::from(ClientErrorKind::TransactionError(e))))
+        };
+        let signature = Signature::new(&[1; 64]);
+        assert_eq!(
+            terminal_error_filter(Ok(signature)).unwrap(),
+            SendOutcome::Sent(signature)
+        );
+
+        // Known program errors and fee payer issues won't go away by retrying
+        let mismatch = TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(PerpError::InstanceMismatch as u32),
+        );
+        assert_eq!(
+            filter(mismatch.clone()).unwrap(),
+            SendOutcome::Dropped(mismatch)
+        );
+        for e in vec![
+            PerpError::SlippageMarginTooLarge,
+            PerpError::NetworkSlippageTooLarge,
+        ] {
+            let slippage =
+                TransactionError::InstructionError(0, InstructionError::Custom(e as u32));
+            assert_eq!(
+                filter(slippage.clone()).unwrap(),
+                SendOutcome::Dropped(slippage)
+            );
+        }
+        assert_eq!(
+            filter(TransactionError::InsufficientFundsForFee).unwrap(),
+            SendOutcome::Dropped(TransactionError::InsufficientFundsForFee)
+        );
+        // Other instruction errors may not happen again
+        assert!(filter(TransactionError::InstructionError(
+            0,
+            InstructionError::ProgramFailedToComplete
+        ))
+        .is_err());
+        assert!(filter(TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(PerpError::OraclePriceZero as u32)
+        ))
+        .is_err());
+        // Transient errors are retried
+        assert!(filter(TransactionError::BlockhashNotFound).is_err());
+        assert!(
+            terminal_error_filter(Err(ClientError::from(ClientErrorKind::Io(
+                std::io::Error::new(std::io::ErrorKind::ConnectionReset, "connection reset")
+            ))))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_stop_loss_filter() {
+        use audaces_protocol::error::PerpError;
+        use solana_client::client_error::{ClientError, ClientErrorKind};
+        use solana_program::instruction::InstructionError;
+        use solana_sdk::transaction::TransactionError;
+
+        let filter = |e: TransactionError| {
+            stop_loss_filter(Err(ClientError::from(ClientErrorKind::TransactionError(e))))
+        };
+
+        // The position is no longer past its stop-loss
+        let not_triggered =
+            TransactionError::InstructionError(0, InstructionError::InvalidArgument);
+        assert_eq!(
+            filter(not_triggered.clone()).unwrap(),
+            SendOutcome::Dropped(not_triggered)
+        );
+        let not_found = TransactionError::InstructionError(
+            0,
+            InstructionError::Custom(PerpError::PositionNotFound as u32),
+        );
+        assert_eq!(
+            filter(not_found.clone()).unwrap(),
+            SendOutcome::Dropped(not_found)
+        );
+        assert!(filter(TransactionError::BlockhashNotFound).is_err());
+    }
+}
@@ -761,17 +761,21 @@ export async function closeAccount(
  * @param userAccountOwner The owner of the user account. This account will need to sign the eventual transaction.
  * @param userAccount The user account's address.
  * @param newUserAccountOwner The new owner of the user account.
+ * @param allowImmediate The transfer isn't signed by the new owner and is rejected by the program unless this is set.
  * @returns An array of signer accounts and an array of instructions. The user account owner should sign the resulting transaction.
  */
 export async function transferUserAccount(
   userAccountOwner: PublicKey,
   userAccount: PublicKey,
-  newUserAccountOwner: PublicKey
+  newUserAccountOwner: PublicKey,
+  allowImmediate: boolean
 ): Promise<PrimedTransaction> {
   let instructions: TransactionInstruction[] = [];
   let signers: Keypair[] = [];
 
-  let instruction = new transferUserAccountInstruction().getInstruction(
+  let instruction = new transferUserAccountInstruction({
+    allowImmediate,
+  }).getInstruction(
     PERPS_PROGRAM_ID,
     userAccount,
     userAccountOwner,
@@ -789,6 +793,7 @@ export async function transferUserAccount(
  * @param sourceUserAccount The source user account's address.
  * @param destinationUserAccountOwner The owner of the destination user account. This account will need to sign the eventual transaction.
  * @param destinationUserAccount The source user account's address.
+ * @param marketAccount The market both user accounts belong to. Both accounts need to be up to date on funding.
  * @returns An array of signer accounts and an array of instructions. The user account owner should sign the resulting transaction.
  */
 export async function transferPosition(
@@ -796,7 +801,8 @@ export async function transferPosition(
   sourceUserAccountOwner: PublicKey,
   sourceUserAccount: PublicKey,
   destinationUserAccountOwner: PublicKey,
-  destinationUserAccount: PublicKey
+  destinationUserAccount: PublicKey,
+  marketAccount: PublicKey
 ): Promise<PrimedTransaction> {
   let instructions: TransactionInstruction[] = [];
   let signers: Keypair[] = [];
@@ -808,7 +814,8 @@ export async function transferPosition(
     sourceUserAccount,
     sourceUserAccountOwner,
     destinationUserAccount,
-    destinationUserAccountOwner
+    destinationUserAccountOwner,
+    marketAccount
   );
   instructions.push(instruction);
 
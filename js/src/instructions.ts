@@ -250,6 +250,7 @@ export class openPositionInstruction {
   leverage: Numberu64;
   predictedEntryPrice: Numberu64;
   maximumSlippageMargin: Numberu64;
+  hasDiscountAccount: number;
   static schema: Schema = new Map([
     [
       openPositionInstruction,
@@ -263,6 +264,7 @@ export class openPositionInstruction {
           ["leverage", "u64"],
           ["predictedEntryPrice", "u64"],
           ["maximumSlippageMargin", "u64"],
+          ["hasDiscountAccount", "u8"],
         ],
       },
     ],
@@ -283,6 +285,7 @@ export class openPositionInstruction {
     this.leverage = obj.leverage;
     this.predictedEntryPrice = obj.predictedEntryPrice;
     this.maximumSlippageMargin = obj.maximumSlippageMargin;
+    this.hasDiscountAccount = 0;
   }
 
   serialize(): Uint8Array {
@@ -304,6 +307,8 @@ export class openPositionInstruction {
     discountAccountOwner?: PublicKey,
     referrerAccount?: PublicKey
   ): TransactionInstruction {
+    // The program is told whether the discount account and its owner are given
+    this.hasDiscountAccount = !!discountAccount ? 1 : 0;
     const data = Buffer.from(this.serialize());
     let keys = [
       {
@@ -570,6 +575,7 @@ export class increasePositionInstruction {
   leverage: Numberu64;
   predictedEntryPrice: Numberu64;
   maximumSlippageMargin: Numberu64;
+  hasDiscountAccount: number;
   static schema: Schema = new Map([
     [
       increasePositionInstruction,
@@ -583,6 +589,7 @@ export class increasePositionInstruction {
           ["positionIndex", [2]],
           ["predictedEntryPrice", "u64"],
           ["maximumSlippageMargin", "u64"],
+          ["hasDiscountAccount", "u8"],
         ],
       },
     ],
@@ -603,6 +610,7 @@ export class increasePositionInstruction {
     this.positionIndex = obj.positionIndex;
     this.predictedEntryPrice = obj.predictedEntryPrice;
     this.maximumSlippageMargin = obj.maximumSlippageMargin;
+    this.hasDiscountAccount = 0;
   }
 
   serialize(): Uint8Array {
@@ -625,6 +633,8 @@ export class increasePositionInstruction {
     discountAccountOwner?: PublicKey,
     referrerAccount?: PublicKey
   ): TransactionInstruction {
+    // The program is told whether the discount account and its owner are given
+    this.hasDiscountAccount = !!discountAccount ? 1 : 0;
     const data = Buffer.from(this.serialize());
     let keys = [
       {
@@ -732,6 +742,7 @@ export class closePositionInstruction {
   closingVCoin: Numberu64;
   predictedEntryPrice: Numberu64;
   maximumSlippageMargin: Numberu64;
+  hasDiscountAccount: number;
   static schema: Schema = new Map([
     [
       closePositionInstruction,
@@ -744,6 +755,7 @@ export class closePositionInstruction {
           ["closingVCoin", "u64"],
           ["predictedEntryPrice", "u64"],
           ["maximumSlippageMargin", "u64"],
+          ["hasDiscountAccount", "u8"],
         ],
       },
     ],
@@ -762,6 +774,7 @@ export class closePositionInstruction {
     this.closingVCoin = obj.closingVCoin;
     this.predictedEntryPrice = obj.predictedEntryPrice;
     this.maximumSlippageMargin = obj.maximumSlippageMargin;
+    this.hasDiscountAccount = 0;
   }
 
   serialize(): Uint8Array {
@@ -783,6 +796,8 @@ export class closePositionInstruction {
     discountAccountOwner?: PublicKey,
     referrerAccount?: PublicKey
   ): TransactionInstruction {
+    // The program is told whether the discount account and its owner are given
+    this.hasDiscountAccount = !!discountAccount ? 1 : 0;
     const data = Buffer.from(this.serialize());
     let keys = [
       {
@@ -1262,22 +1277,27 @@ export class closeAccountInstruction {
 
 export class transferUserAccountInstruction {
   tag: number;
+  allowImmediate: number;
   static schema: Schema = new Map([
     [
-      withdrawBudgetInstruction,
+      transferUserAccountInstruction,
       {
         kind: "struct",
-        fields: [["tag", "u8"]],
+        fields: [
+          ["tag", "u8"],
+          ["allowImmediate", "u8"],
+        ],
       },
     ],
   ]);
 
-  constructor() {
+  constructor(obj: { allowImmediate: boolean }) {
     this.tag = 16;
+    this.allowImmediate = obj.allowImmediate ? 1 : 0;
   }
 
   serialize(): Uint8Array {
-    return serialize(withdrawBudgetInstruction.schema, this);
+    return serialize(transferUserAccountInstruction.schema, this);
   }
 
   getInstruction(
@@ -1343,7 +1363,8 @@ export class transferPositionInstruction {
     sourceUserAccount: PublicKey,
     sourceUserAccountOwner: PublicKey,
     destinationUserAccount: PublicKey,
-    destinationUserAccountOwner: PublicKey
+    destinationUserAccountOwner: PublicKey,
+    marketAccount: PublicKey
   ): TransactionInstruction {
     const data = Buffer.from(this.serialize());
     let keys = [
@@ -1367,6 +1388,11 @@ export class transferPositionInstruction {
         isSigner: false,
         isWritable: true,
       },
+      {
+        pubkey: marketAccount,
+        isSigner: false,
+        isWritable: false,
+      },
     ];
 
     return new TransactionInstruction({
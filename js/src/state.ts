@@ -49,7 +49,10 @@ export class Instance {
   garbagePointer?: number;
   pages: PageInfo[];
   numberOfPages: number;
-  static headerSize = 21;
+  fundingHistoryOffset: number;
+  fundingBalancingFactors: number[];
+  static VERSION = 1;
+  static headerSize = 150;
   //@ts-ignore
   static schema: Schema = new Map([
     [
@@ -62,6 +65,8 @@ export class Instance {
           ["longsPointer", PointerOption],
           ["garbagePointer", PointerOption],
           ["numberOfPages", "u32"],
+          ["fundingHistoryOffset", "u8"],
+          ["fundingBalancingFactors", [128]],
         ],
       },
     ],
@@ -100,12 +105,25 @@ export class Instance {
     longsPointer: PointerOption;
     garbagePointer: PointerOption;
     numberOfPages: number;
+    fundingHistoryOffset: number;
+    fundingBalancingFactors: Uint8Array;
   }) {
     this.numberOfPages = obj.numberOfPages;
     this.pages = [];
     this.shortsPointer = obj.shortsPointer.pointer;
     this.longsPointer = obj.longsPointer.pointer;
     this.garbagePointer = obj.garbagePointer.pointer;
+    this.fundingHistoryOffset = obj.fundingHistoryOffset;
+    this.fundingBalancingFactors = [];
+    for (let i = 0; i < 16; i++) {
+      let offset = 8 * i;
+      this.fundingBalancingFactors.push(
+        new BN(
+          obj.fundingBalancingFactors.slice(offset, offset + 8),
+          "le"
+        ).toNumber()
+      );
+    }
   }
 
   static async retrieve(
@@ -122,6 +140,9 @@ export class Instance {
     if (instanceData.data[0] !== StateTag.Instance) {
       throw new Error("The provided account isn't an instance account");
     }
+    if (instanceData.data[1] !== this.VERSION) {
+      throw new Error("The instance account has to be migrated");
+    }
     let res: Instance = deserializeUnchecked(
       this.schema,
       Instance,
@@ -163,8 +184,22 @@ export class MarketState {
   fundingHistoryOffset: number;
   fundingHistory: number[];
   fundingBalancingFactors: number[];
-  instanceAddresses: PublicKey[];
+  numberOfInstances: number;
+  maxAllowedSlippageMargin: BN;
+  liquidationInsuranceShareBps: number;
+  paused: boolean;
+  minCollateral: number;
+  maxOpenLongsVCoin: BN;
+  maxOpenShortsVCoin: BN;
+  lastRebalanceTimestamp: number;
+  liquidationRewardMode: number;
+  liquidationRewardBps: number;
+  protocolFeeBalance: BN;
+  instanceAddresses!: PublicKey[];
   instances!: Instance[];
+  static VERSION = 1;
+  // The instance addresses are stored after the header
+  static LEN = 617;
   static schema: Schema = new Map([
     [
       MarketState,
@@ -197,7 +232,18 @@ export class MarketState {
           ["fundingHistoryOffset", "u8"],
           ["fundingHistory", [128]],
           ["fundingBalancingFactors", [128]],
-          ["instanceAddresses", [[32]]],
+          ["numberOfInstances", "u32"],
+          ["maxAllowedSlippageMargin", "u64"],
+          ["liquidationInsuranceShareBps", "u16"],
+          ["paused", "u8"],
+          ["minCollateral", "u64"],
+          ["maxOpenLongsVCoin", "u64"],
+          ["maxOpenShortsVCoin", "u64"],
+          ["lastRebalanceTimestamp", "u64"],
+          ["liquidationRewardMode", "u8"],
+          ["liquidationRewardBps", "u16"],
+          ["protocolFeeBalance", "u64"],
+          ["reserved", [56]],
         ],
       },
     ],
@@ -228,7 +274,17 @@ export class MarketState {
     fundingHistoryOffset: number;
     fundingHistory: Uint8Array;
     fundingBalancingFactors: Uint8Array;
-    instanceAddresses: Uint8Array[];
+    numberOfInstances: number;
+    maxAllowedSlippageMargin: BN;
+    liquidationInsuranceShareBps: number;
+    paused: number;
+    minCollateral: BN;
+    maxOpenLongsVCoin: BN;
+    maxOpenShortsVCoin: BN;
+    lastRebalanceTimestamp: BN;
+    liquidationRewardMode: number;
+    liquidationRewardBps: number;
+    protocolFeeBalance: BN;
   }) {
     this.signerNonce = obj.signerNonce;
     this.marketSymbol = obj.marketSymbol.toString();
@@ -269,7 +325,18 @@ export class MarketState {
         ).toNumber()
       );
     }
-    this.instanceAddresses = obj.instanceAddresses.map((s) => new PublicKey(s));
+    this.numberOfInstances = obj.numberOfInstances;
+    // Unbounded values don't fit in a number
+    this.maxAllowedSlippageMargin = obj.maxAllowedSlippageMargin;
+    this.liquidationInsuranceShareBps = obj.liquidationInsuranceShareBps;
+    this.paused = obj.paused !== 0;
+    this.minCollateral = obj.minCollateral.toNumber();
+    this.maxOpenLongsVCoin = obj.maxOpenLongsVCoin;
+    this.maxOpenShortsVCoin = obj.maxOpenShortsVCoin;
+    this.lastRebalanceTimestamp = obj.lastRebalanceTimestamp.toNumber();
+    this.liquidationRewardMode = obj.liquidationRewardMode;
+    this.liquidationRewardBps = obj.liquidationRewardBps;
+    this.protocolFeeBalance = obj.protocolFeeBalance;
   }
 
   static async retrieve(
@@ -286,11 +353,21 @@ export class MarketState {
     if (marketStateData.data[0] !== StateTag.MarketState) {
       throw new Error("The provided account isn't a market account");
     }
+    if (marketStateData.data[1] !== this.VERSION) {
+      throw new Error("The market account has to be migrated");
+    }
     let res: MarketState = deserializeUnchecked(
       this.schema,
       MarketState,
-      marketStateData.data.slice(1)
+      marketStateData.data.slice(1, this.LEN)
     );
+    res.instanceAddresses = [];
+    for (let i = 0; i < res.numberOfInstances; i++) {
+      let offset = this.LEN + 32 * i;
+      res.instanceAddresses.push(
+        new PublicKey(marketStateData.data.slice(offset, offset + 32))
+      );
+    }
     res.marketAccount = marketAccount;
     res.instances = await Promise.all(
       res.instanceAddresses.map((s) => Instance.retrieve(connection, s))
@@ -380,7 +457,7 @@ export class MarketState {
 }
 
 export class OpenPosition {
-  static LEN = 43;
+  static LEN = 51;
   side: PositionType;
   instanceIndex: number;
   lastFundingOffset: number;
@@ -389,6 +466,7 @@ export class OpenPosition {
   slotNumber: number;
   vCoinAmount: number;
   vPcAmount: number;
+  stopLossIndex: number;
 
   constructor(obj: {
     lastFundingOffset: number;
@@ -399,6 +477,7 @@ export class OpenPosition {
     slotNumber: BN;
     vCoinAmount: BN;
     vPcAmount: BN;
+    stopLossIndex: BN;
   }) {
     this.lastFundingOffset = obj.lastFundingOffset;
     this.instanceIndex = obj.instanceIndex;
@@ -410,10 +489,14 @@ export class OpenPosition {
     this.slotNumber = obj.slotNumber.toNumber();
     this.vCoinAmount = obj.vCoinAmount.toNumber();
     this.vPcAmount = obj.vPcAmount.toNumber();
+    this.stopLossIndex =
+      obj.stopLossIndex.ushrn(32).toNumber() +
+      obj.stopLossIndex.maskn(32).toNumber() / 2 ** 32;
   }
 }
 
 export class UserAccount {
+  static VERSION = 1;
   static LEN = 80;
   address!: PublicKey;
   owner: PublicKey;
@@ -453,6 +536,7 @@ export class UserAccount {
           ["slotNumber", "u64"],
           ["vCoinAmount", "u64"],
           ["vPcAmount", "u64"],
+          ["stopLossIndex", "u64"],
         ],
       },
     ],
@@ -489,6 +573,9 @@ export class UserAccount {
     if (data[0] !== StateTag.UserAccount) {
       throw new Error("The provided account isn't a user account");
     }
+    if (data[1] !== this.VERSION) {
+      throw new Error("The user account has to be migrated");
+    }
     let res: UserAccount = deserializeUnchecked(
       this.schema,
       UserAccount,
@@ -0,0 +1,180 @@
+#![no_main]
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+use arbitrary::Arbitrary;
+use audaces_protocol::{
+    error::PerpError,
+    positions_book::{
+        memory::{Memory, SLOT_SIZE},
+        page::Page,
+        positions_book_tree::PositionsBook,
+    },
+    state::PositionType,
+};
+use libfuzzer_sys::fuzz_target;
+
+const NB_PAGES: usize = 4;
+const PAGE_SIZE: usize = 256; // In slots
+
+#[derive(Arbitrary, Debug)]
+enum Operation {
+    Open {
+        liquidation_index: u64,
+        collateral: u32,
+        v_coin: u32,
+        v_pc: u32,
+        side: PositionType,
+    },
+    Close {
+        position: u8,
+        partial: bool,
+    },
+    Liquidate {
+        liquidation_index: u64,
+        side: PositionType,
+    },
+}
+
+#[derive(Clone, Copy)]
+struct ModelPosition {
+    collateral: u64,
+    v_coin: u64,
+    v_pc: u64,
+    slot: u64,
+}
+
+// Positions which should currently be in the book, indexed by side and liquidation index
+type Model = BTreeMap<(u8, u64), ModelPosition>;
+
+fuzz_target!(|operations: Vec<Operation>| {
+    let mut datas = vec![vec![0u8; PAGE_SIZE * SLOT_SIZE]; NB_PAGES];
+    let pages = datas
+        .iter_mut()
+        .map(|d| Page {
+            page_size: PAGE_SIZE as u32,
+            data: Rc::new(RefCell::new(&mut d[..])),
+            free_slot_list_hd: None,
+            uninitialized_memory: 0,
+        })
+        .collect();
+    let mut book = PositionsBook::new(None, None, Memory::new(pages, None));
+    let mut model = Model::new();
+
+    for (current_slot, operation) in operations.into_iter().enumerate() {
+        let result = match operation {
+            Operation::Open {
+                liquidation_index,
+                collateral,
+                v_coin,
+                v_pc,
+                side,
+            } => {
+                // Liquidation index 0 and empty collateral are reserved for liquidated positions
+                let liquidation_index = liquidation_index.max(1);
+                let collateral = (collateral as u64).max(1);
+                book.open_position(
+                    liquidation_index,
+                    collateral,
+                    v_coin as u64,
+                    v_pc as u64,
+                    side,
+                    current_slot as u64,
+                )
+                .and_then(|leaf| {
+                    let slot = leaf.get_slot_number(&book.memory)?;
+                    let p = model
+                        .entry((side as u8, liquidation_index))
+                        .or_insert(ModelPosition {
+                            collateral: 0,
+                            v_coin: 0,
+                            v_pc: 0,
+                            slot,
+                        });
+                    p.collateral += collateral;
+                    p.v_coin += v_coin as u64;
+                    p.v_pc += v_pc as u64;
+                    assert_eq!(p.slot, slot);
+                    Ok(())
+                })
+            }
+            Operation::Close { position, partial } => {
+                if model.is_empty() {
+                    continue;
+                }
+                let key = *model.keys().nth(position as usize % model.len()).unwrap();
+                let p = model[&key];
+                let side = if key.0 == PositionType::Long as u8 {
+                    PositionType::Long
+                } else {
+                    PositionType::Short
+                };
+                let (collateral, v_coin, v_pc) = match partial {
+                    true => (p.collateral / 2, p.v_coin / 2, p.v_pc / 2),
+                    false => (p.collateral, p.v_coin, p.v_pc),
+                };
+                book.close_position(key.1, collateral, v_coin, v_pc, side, p.slot)
+                    .map(|_| {
+                        if (collateral, v_coin) == (0, 0) {
+                            // The book only checks for the position's existence
+                            return;
+                        }
+                        if collateral == p.collateral {
+                            model.remove(&key);
+                        } else {
+                            let p = model.get_mut(&key).unwrap();
+                            p.collateral -= collateral;
+                            p.v_coin -= v_coin;
+                            p.v_pc -= v_pc;
+                        }
+                    })
+            }
+            Operation::Liquidate {
+                liquidation_index,
+                side,
+            } => book.liquidate(liquidation_index, side).map(|_| {
+                model.retain(|(s, l), _| {
+                    *s != side as u8
+                        || match side {
+                            PositionType::Long => *l < liquidation_index,
+                            PositionType::Short => *l > liquidation_index,
+                        }
+                })
+            }),
+        };
+
+        match result {
+            Ok(()) => check_book(&book, &model),
+            // The in-memory pages are full, the book cannot be extended any further
+            Err(PerpError::OutOfSpace) => return,
+            Err(e) => panic!("Unexpected error {:?}", e),
+        }
+    }
+});
+
+fn check_book(book: &PositionsBook, model: &Model) {
+    for side in [PositionType::Short, PositionType::Long] {
+        let (expected_collateral, expected_v_coin, expected_v_pc) = model
+            .iter()
+            .filter(|((s, _), _)| *s == side as u8)
+            .fold((0, 0, 0), |(c, v_coin, v_pc), (_, p)| {
+                (c + p.collateral, v_coin + p.v_coin, v_pc + p.v_pc)
+            });
+
+        let (longs_v_coin, shorts_v_coin) = book.get_v_coin().unwrap();
+        let (longs_v_pc, shorts_v_pc) = book.get_v_pc().unwrap();
+        let (root_v_coin, root_v_pc) = match side {
+            PositionType::Long => (longs_v_coin, longs_v_pc),
+            PositionType::Short => (shorts_v_coin, shorts_v_pc),
+        };
+        assert_eq!(root_v_coin, expected_v_coin);
+        assert_eq!(root_v_pc, expected_v_pc);
+
+        // Walks the leaves of the tree
+        let (_, leaves_v_coin, leaves_collateral) = book.compute_aggregate_position(side).unwrap();
+        assert_eq!(leaves_v_coin, expected_v_coin);
+        assert_eq!(leaves_collateral, expected_collateral);
+    }
+
+    let expected_collateral: u64 = model.values().map(|p| p.collateral).sum();
+    assert_eq!(book.get_collateral().unwrap(), expected_collateral);
+}
@@ -50,7 +50,18 @@ impl PrintProgramError for PerpError {
             ),
             PerpError::NegativePayout => msg!("Error: This open position cannot be closed as it should be liquidated."),
             PerpError::ImbalancedMarket => msg!("Error: The market is imbalanced."),
-            PerpError::NetworkSlippageTooLarge => msg!("Error: The price slippage due to execution latency exceeds the specified margin")
+            PerpError::NetworkSlippageTooLarge => msg!("Error: The price slippage due to execution latency exceeds the specified margin"),
+            PerpError::OraclePriceZero => msg!("Error: The oracle returned a zero price."),
+            PerpError::SlippageMarginTooLarge => msg!("Error: The requested slippage margin exceeds the market's maximum allowed slippage margin."),
+            PerpError::OutdatedAccount => msg!("Error: The account has to be migrated to the current layout."),
+            PerpError::MarketPaused => msg!("Error: Opening or increasing positions is paused on this market."),
+            PerpError::OpenInterestTooLarge => msg!("Error: The market's maximum open interest has been reached on this side."),
+            PerpError::InsuranceFundDepleted => msg!("Error: The operation would leave the market's insurance fund negative."),
+            PerpError::RebalancingCooldown => msg!("Error: The market was rebalanced too recently for a permissionless rebalancing."),
+            PerpError::CorruptedPositionsBook => msg!("Error: An inner node of the positions book doesn't match its children."),
+            PerpError::MarketMismatch => msg!("Error: The user account belongs to another market."),
+            PerpError::InstanceMismatch => msg!("Error: The instance account doesn't match the market's instance at this index."),
+            PerpError::OracleMismatch => msg!("Error: The oracle account isn't the market's oracle."),
         }
     }
 }
@@ -32,6 +32,28 @@ pub enum PerpError {
     ImbalancedMarket,
     #[error("The price slippage due to execution latency exceeds the provided margin")]
     NetworkSlippageTooLarge,
+    #[error("The oracle price is zero")]
+    OraclePriceZero,
+    #[error("The requested slippage margin exceeds the market's maximum")]
+    SlippageMarginTooLarge,
+    #[error("The account has to be migrated to the current layout")]
+    OutdatedAccount,
+    #[error("Trading is paused on this market")]
+    MarketPaused,
+    #[error("The market's open interest cap has been reached on this side")]
+    OpenInterestTooLarge,
+    #[error("The insurance fund cannot cover this operation")]
+    InsuranceFundDepleted,
+    #[error("The market was rebalanced too recently")]
+    RebalancingCooldown,
+    #[error("The positions book tree is inconsistent")]
+    CorruptedPositionsBook,
+    #[error("The user account belongs to another market")]
+    MarketMismatch,
+    #[error("The instance account doesn't match the market's instance at this index")]
+    InstanceMismatch,
+    #[error("The oracle account isn't the market's oracle")]
+    OracleMismatch,
 }
 
 pub type PerpResult = Result<(), PerpError>;
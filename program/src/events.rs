@@ -0,0 +1,65 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::log::sol_log_data;
+
+use crate::state::PositionType;
+
+/// Structured events logged at the end of the main trading instructions.
+/// Each event is Borsh-serialized and emitted as a `Program data:` log line so that indexers
+/// don't need to parse the free-form `msg!` output.
+#[derive(BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub enum PerpEvent {
+    OpenedPosition {
+        market: [u8; 32],
+        user_account: [u8; 32],
+        instance_index: u8,
+        side: PositionType,
+        collateral: u64,
+        v_coin_amount: u64,
+        v_pc_amount: u64,
+        liquidation_index: u64,
+        fees: i64,
+    },
+    ClosedPosition {
+        market: [u8; 32],
+        user_account: [u8; 32],
+        instance_index: u8,
+        side: PositionType,
+        closed_collateral: u64,
+        closed_v_coin_amount: u64,
+        closed_v_pc_amount: u64,
+        payout: i64,
+        fees: i64,
+    },
+    Liquidated {
+        market: [u8; 32],
+        instance_index: u8,
+        collateral: u64,
+        longs_v_coin_amount: u64,
+        shorts_v_coin_amount: u64,
+        reward: u64,
+    },
+    FundingExtracted {
+        market: [u8; 32],
+        user_account: [u8; 32],
+        instance_index: u8,
+        // Negative when the user received funding
+        amount: i64,
+    },
+    IncreasedPosition {
+        market: [u8; 32],
+        user_account: [u8; 32],
+        instance_index: u8,
+        side: PositionType,
+        added_collateral: u64,
+        added_v_coin_amount: u64,
+        added_v_pc_amount: u64,
+        liquidation_index: u64, // Of the whole position after the increase
+        fees: i64,
+    },
+}
+
+impl PerpEvent {
+    pub fn emit(&self) {
+        sol_log_data(&[&self.try_to_vec().unwrap()]);
+    }
+}
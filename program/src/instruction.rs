@@ -1,10 +1,23 @@
 use borsh::{BorshDeserialize, BorshSerialize};
-use solana_program::{instruction::Instruction, pubkey::Pubkey};
+use solana_program::{
+    instruction::Instruction, program_error::ProgramError, program_pack::Pack, pubkey::Pubkey,
+    rent::Rent, system_instruction,
+};
+use std::str::FromStr;
 
 #[cfg(feature = "fuzz")]
 use arbitrary::Arbitrary;
 
-use crate::state::PositionType;
+use crate::{
+    error::PerpError,
+    processor::FIDA_BNB,
+    state::{
+        instance::parse_instance,
+        market::{get_instance_address, MarketState},
+        user_account::{OpenPosition, UserAccountState},
+        LiquidationRewardMode, PositionType,
+    },
+};
 #[repr(C)]
 #[cfg_attr(feature = "fuzz", derive(Arbitrary))]
 #[derive(Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize)]
@@ -18,12 +31,20 @@ pub enum PerpInstruction {
     ///   2. `[]` The oracle account that will provide the index price for the coin (the current price account in the case of Pyth)
     ///   3. `[]` The admin account that will be able to add leverages to the market
     ///   4. `[writable]` The market vault account that will hold the funds, owned by the Market signer account
+    ///   5. `[]` The oracle's Pyth product account, whose symbol has to match the market symbol. Optional with a mock oracle
     CreateMarket {
         signer_nonce: u8,
         market_symbol: String,
         initial_v_pc_amount: u64,
         coin_decimals: u8,
         quote_decimals: u8,
+        max_allowed_slippage_margin: u64,
+        liquidation_insurance_share_bps: u16,
+        min_collateral: u64,
+        max_open_longs_v_coin: u64,
+        max_open_shorts_v_coin: u64,
+        liquidation_reward_mode: LiquidationRewardMode,
+        liquidation_reward_bps: u16,
     },
     /// Adds a new leverage to the existing market
     ///
@@ -57,9 +78,10 @@ pub enum PerpInstruction {
     ///   8. `[signer]` The owner account of the open positions account
     ///   9. `[writable]` The open positions account
     ///   10..N `[writable]` The positions book page accounts
-    ///   N+1. `[]` (Optional) The discount account to calculate the fee tiers
+    ///   N+1. `[]` (Optional) The discount account to calculate the fee tiers, given along with has_discount_account
     ///   N+2. `[signer]` (Optional) The owner account of the discount account
     ///   N+3. `[writable]` (Optional) The referrer USDC account which receives 10 percent of the fees
+    ///   N+4. `[writable]` (Optional) The referrer record of the referrer USDC account, which accrues its lifetime fees if registered
     OpenPosition {
         side: PositionType,
         collateral: u64,
@@ -67,6 +89,7 @@ pub enum PerpInstruction {
         leverage: u64,
         predicted_entry_price: u64,   // 32 bit FP
         maximum_slippage_margin: u64, // 32 bit FP
+        has_discount_account: bool,   // Whether the discount account and its owner are given
     },
     /// Add USDC tokens to the user budget. The current budget is saved in the open position
     /// accounts state while the tokens are stored in the market vault. When opening, closing (etc)
@@ -115,9 +138,10 @@ pub enum PerpInstruction {
     ///   8. `[signer]` The open position owner account
     ///   9. `[writable]` The corresponding open positions account
     ///   10... `[writable]` The positions book page accounts
-    ///   N+1. `[]` (Optional) The discount account to calculate the fee tiers
+    ///   N+1. `[]` (Optional) The discount account to calculate the fee tiers, given along with has_discount_account
     ///   N+2. `[signer]` (Optional) The owner account of the discount account
     ///   N+3. `[writable]` (Optional) The referrer USDC account which receives 10 percent of the fees
+    ///   N+4. `[writable]` (Optional) The referrer record of the referrer USDC account, which accrues its lifetime fees if registered
     IncreasePosition {
         add_collateral: u64,
         instance_index: u8,
@@ -125,8 +149,11 @@ pub enum PerpInstruction {
         position_index: u16,
         predicted_entry_price: u64,   // 32 bit FP
         maximum_slippage_margin: u64, // 32 bit FP
+        has_discount_account: bool,   // Whether the discount account and its owner are given
     },
-    /// Close a position
+    /// Close a position. Once the oracle price crosses the position's stop-loss index, any signer can
+    /// close the position entirely in place of its owner, the payout is credited to the user account.
+    /// Such closes aren't checked against the predicted entry price.
     ///
     /// Accounts expected by this instruction:
     ///
@@ -141,15 +168,17 @@ pub enum PerpInstruction {
     ///   9. `[signer]` The open position owner account
     ///   10. `[writable]` The corresponding open positions account
     ///   11..N `[writable]` The positions book page accounts
-    ///   N+1. `[]` (Optional) The discount account to calculate the fee tiers
+    ///   N+1. `[]` (Optional) The discount account to calculate the fee tiers, given along with has_discount_account
     ///   N+2. `[signer]` (Optional) The owner account of the discount account
     ///   N+3. `[writable]` (Optional) The referrer USDC account which receives 10 percent of the fees
+    ///   N+4. `[writable]` (Optional) The referrer record of the referrer USDC account, which accrues its lifetime fees if registered
     ClosePosition {
         position_index: u16,
         closing_collateral: u64,
         closing_v_coin: u64,
         predicted_entry_price: u64,   // 32 bit FP
         maximum_slippage_margin: u64, // 32 bit FP
+        has_discount_account: bool,   // Whether the discount account and its owner are given
     },
     /// Garbage collection in the distributed positons database.
     /// Reward is flat fee per freed slot
@@ -204,6 +233,10 @@ pub enum PerpInstruction {
     /// is maintained and can be used for the funding ratio calculation
     /// which uses an average over this period.
     ///
+    /// The market only keeps the funding of its last 16 funding periods. The funding owed by a position is counted
+    /// from its funding offset in this ring buffer, so a position left unprocessed for 16 periods or more is only
+    /// charged for the number of missed periods modulo 16, the most recent ones. The older periods are never collected.
+    ///
     /// Accounts expected by this instruction:
     ///
     ///   1. `[writable]` The market account
@@ -228,18 +261,37 @@ pub enum PerpInstruction {
     AddPage {
         instance_index: u8,
     },
+    /// Rebalance the market by opening a position against the current imbalance, signed by the market admin.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[]` The spl token program account
+    ///   2. `[]` The sysvar clock account
+    ///   3. `[writable]` The market account
+    ///   4. `[writable]` The instance account
+    ///   5. `[]` The market signer account
+    ///   6. `[writable]` The market vault account
+    ///   7. `[writable]` The Bonfida buy and burn account
+    ///   8. `[signer]` The user account owner
+    ///   9. `[writable]` The user account
+    ///   10. `[signer]` The market admin account
+    ///   11..N. `[writable]` The instance's memory pages
     Rebalance {
         collateral: u64,
         instance_index: u8,
     },
     /// Transfer a user account ownership to a new address.
+    /// The transfer is immediate and isn't signed by the new owner, see ProposeUserAccountTransfer for a recoverable alternative.
+    /// It is rejected unless allow_immediate is set.
     ///
     /// Accounts expected by this instruction:
     ///
     ///   1. `[signer]` The user account owner
     ///   2. `[writable]` The user account
     ///   3. `[]` The new user account owner
-    TransferUserAccount {},
+    TransferUserAccount {
+        allow_immediate: bool,
+    },
     /// Transfer a position from one user account to another.
     ///
     /// Accounts expected by this instruction:
@@ -248,6 +300,7 @@ pub enum PerpInstruction {
     ///   2. `[writable]` The source user account
     ///   3. `[signer]` The destination user account owner
     ///   4. `[writable]` The destination user account
+    ///   5. `[]` The market account
     TransferPosition {
         position_index: u16,
     },
@@ -258,6 +311,275 @@ pub enum PerpInstruction {
         additional_withdraw_amount: u64,
         predicted_entry_price: u64,   // 32 bit FP
         maximum_slippage_margin: u64, // 32 bit FP
+        has_discount_account: bool,   // Whether the discount account and its owner are given
+    },
+    /// Move a version 0 market account to the current layout. The instance addresses are shifted behind
+    /// the larger header, which is zero-extended. The fields which didn't exist in version 0 keep the
+    /// market's behaviour unchanged, except for the maximum slippage margin which has to be bounded.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[]` The system program account
+    ///   2. `[writable]` The market account
+    ///   3. `[writable, signer]` The market admin account, which funds the market account's additional rent
+    MigrateMarket {
+        max_allowed_slippage_margin: u64, // 32 bit FP
+    },
+    /// Grow an existing memory page of the given instance in place.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[]` The system program account
+    ///   2. `[]` The market account
+    ///   3. `[writable, signer]` The market admin account, which funds the page's additional rent
+    ///   4. `[]` The instance account
+    ///   5. `[writable]` The memory page account
+    GrowPage {
+        instance_index: u8,
+        page_index: u8,
+        additional_bytes: u64,
+    },
+    /// Pause or resume the opening and increasing of positions on the market.
+    /// Closing positions, liquidations and funding remain available while the market is paused.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[writable]` The market account
+    ///   2. `[signer]` The market admin account
+    SetPause {
+        paused: bool,
+    },
+    /// Hand over the market's admin rights to a new address.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[writable]` The market account
+    ///   2. `[signer]` The current market admin account
+    ///   3. `[]` The new market admin account
+    ChangeAdmin {},
+    /// Open a position with a given notional size instead of a leverage.
+    /// The leverage is derived as target_v_pc / collateral and is subject to the same limits.
+    ///
+    /// Expects the same accounts as OpenPosition
+    OpenPositionBySize {
+        side: PositionType,
+        collateral: u64,
+        instance_index: u8,
+        target_v_pc: u64,
+        predicted_entry_price: u64,   // 32 bit FP
+        maximum_slippage_margin: u64, // 32 bit FP
+        has_discount_account: bool,   // Whether the discount account and its owner are given
+    },
+    /// Rename the market, the symbol is used to match the market with its Pyth product.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[writable]` The market account
+    ///   2. `[signer]` The market admin account
+    ///   3. `[]` The market's oracle account
+    ///   4. `[]` The oracle's Pyth product account, whose symbol has to match the new symbol. Optional with a mock oracle
+    UpdateMarketSymbol {
+        new_symbol: String,
+    },
+    /// Propose to transfer a user account ownership to a new address, which has to accept it.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[signer]` The user account owner
+    ///   2. `[]` The user account
+    ///   3. `[writable]` The transfer proposal account, uninitialized and owned by the program
+    ///   4. `[]` The proposed new user account owner
+    ProposeUserAccountTransfer {},
+    /// Accept a proposed user account transfer and close the transfer proposal account.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[signer]` The new user account owner
+    ///   2. `[writable]` The user account
+    ///   3. `[writable]` The transfer proposal account
+    ///   4. `[writable]` The previous user account owner who proposed the transfer, receiving the transfer proposal account's lamports
+    AcceptUserAccountTransfer {},
+    /// Cancel a proposed user account transfer and close the transfer proposal account.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[signer]` The user account owner
+    ///   2. `[]` The user account
+    ///   3. `[writable]` The transfer proposal account
+    ///   4. `[writable]` The account receiving the transfer proposal account's lamports
+    CancelUserAccountTransfer {},
+    /// Create the referrer record of a referrer USDC account, which then accrues the referrer fees paid to it.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[]` The system program account
+    ///   2. `[writable, signer]` The fee payer account
+    ///   3. `[]` The market account
+    ///   4. `[]` The referrer USDC account
+    ///   5. `[writable]` The referrer record account, derived from the market and referrer USDC account
+    RegisterReferrer {},
+    /// Read-only instruction which sets the program return data to the Borsh-encoded `PositionSnapshot`
+    /// of an open position, along with its current equity, margin ratio and distance to liquidation.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[]` The market account
+    ///   2. `[]` The user account
+    ///   3. `[]` The oracle account
+    GetPosition {
+        position_index: u16,
+    },
+    /// Rebalance the market without the admin's signature, by opening a position against the current imbalance.
+    /// This is only possible when the market bias exceeds the rebalancing margin, and once per rebalancing cooldown.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[]` The spl token program account
+    ///   2. `[]` The sysvar clock account
+    ///   3. `[writable]` The market account
+    ///   4. `[writable]` The instance account
+    ///   5. `[]` The market signer account
+    ///   6. `[writable]` The market vault account
+    ///   7. `[writable]` The Bonfida buy and burn account
+    ///   8. `[signer]` The user account owner
+    ///   9. `[writable]` The user account
+    ///   10. `[]` The oracle account
+    ///   11..N. `[writable]` The instance's memory pages
+    PermissionlessRebalance {
+        collateral: u64,
+        instance_index: u8,
+    },
+    /// Withdraw part of the market's protocol fee balance, as long as the insurance fund stays positive.
+    /// The allocation fees are not withdrawable since they are owed as refunds or garbage collection rewards.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[]` The spl token program account
+    ///   2. `[writable]` The market account
+    ///   3. `[]` The market signer account
+    ///   4. `[writable]` The market vault account
+    ///   5. `[signer]` The market admin account
+    ///   6. `[writable]` The target token account
+    WithdrawFees {
+        amount: u64,
+    },
+    /// Add wrapped SOL to the user budget of a market quoted in wrapped SOL, directly from the owner's lamports.
+    /// The lamports are wrapped in a temporary token account which is closed once deposited.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[]` The spl token program account
+    ///   2. `[]` The system program account
+    ///   3. `[]` The rent sysvar account
+    ///   4. `[]` The native mint account
+    ///   5. `[writable]` The market account
+    ///   6. `[writable]` The market vault account
+    ///   7. `[writable]` The user account
+    ///   8. `[writable, signer]` The user account owner, which pays for the deposited lamports
+    ///   9. `[writable, signer]` The uninitialized temporary wrapped SOL account
+    AddBudgetWrapSol {
+        amount: u64,
+    },
+    /// Open a position, failing if the total fees charged exceed max_fee.
+    ///
+    /// Expects the same accounts as OpenPosition
+    OpenPositionWithMaxFee {
+        side: PositionType,
+        collateral: u64,
+        instance_index: u8,
+        leverage: u64,
+        predicted_entry_price: u64,   // 32 bit FP
+        maximum_slippage_margin: u64, // 32 bit FP
+        max_fee: u64,
+        has_discount_account: bool, // Whether the discount account and its owner are given
+    },
+    /// Increase a position, failing if the total fees charged exceed max_fee.
+    ///
+    /// Expects the same accounts as IncreasePosition
+    IncreasePositionWithMaxFee {
+        add_collateral: u64,
+        instance_index: u8,
+        leverage: u64,
+        position_index: u16,
+        predicted_entry_price: u64,   // 32 bit FP
+        maximum_slippage_margin: u64, // 32 bit FP
+        max_fee: u64,
+        has_discount_account: bool, // Whether the discount account and its owner are given
+    },
+    /// Set the oracle price at which a position can be closed at market by anyone, before it reaches its
+    /// liquidation index. The stop-loss index must lie between the liquidation index and the current oracle price.
+    /// A null index removes the stop-loss. Since the liquidation index moves when the position is modified,
+    /// a stop-loss which ends up beyond it is preempted by the liquidation.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[]` The market account
+    ///   2. `[]` The oracle account
+    ///   3. `[signer]` The user account owner
+    ///   4. `[writable]` The user account
+    SetStopLoss {
+        position_index: u16,
+        stop_loss_index: u64, // 32 bit FP
+    },
+    /// Move a version 0 user account to the current layout. Each open position is shifted to make room
+    /// for its stop-loss index, which is left unset. The account grows when its open positions no longer fit.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[]` The system program account
+    ///   2. `[writable, signer]` The user account owner, which funds the account's additional rent
+    ///   3. `[writable]` The user account
+    MigrateUserAccount,
+    /// Process the funding of a user account on several instances at once, as FundingExtraction does for each of them.
+    /// Instances on which the account has no funding to process are skipped.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[writable]` The market account
+    ///   2. `[writable]` The user account
+    ///   3. `[]` The funding extraction label account
+    ///   4. `[]` The price oracle account
+    ///   5..N. For each of the given instances in order, the `[writable]` instance account followed by its `[writable]` memory pages
+    ExtractFundingMulti {
+        instance_indices: Vec<u8>,
+    },
+    /// Record the funding balancing factors of an instance for the funding periods cranked since its last update.
+    /// The factors are computed from the long and short open interest of the instance so that the funding
+    /// received on an instance never exceeds the funding paid on it. The funding extraction catches up
+    /// on its own when this crank is late.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[]` The market account
+    ///   2. `[writable]` The instance account
+    ///   3. `[]` The funding label account
+    ///   4..N. `[]` The memory pages of the instance
+    CrankInstanceFunding {
+        instance_index: u8,
+    },
+    /// Move a version 0 instance account to the current layout. The page infos are shifted behind the
+    /// larger header, and the instance starts from the market-wide funding balancing factors, which
+    /// are no longer updated. Instances have to be migrated before the market's next funding crank.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[]` The system program account
+    ///   2. `[]` The market account
+    ///   3. `[writable, signer]` The market admin account, which funds the instance account's additional rent
+    ///   4. `[writable]` The instance account
+    MigrateInstance {
+        instance_index: u8,
+    },
+    /// Grow a user account in place so that it fits more open positions.
+    ///
+    /// Accounts expected by this instruction:
+    ///
+    ///   1. `[]` The system program account
+    ///   2. `[]` The market account
+    ///   3. `[writable, signer]` The user account owner, which funds the account's additional rent
+    ///   4. `[writable]` The user account
+    GrowUserAccount {
+        additional_positions: u32,
     },
 }
 
@@ -278,11 +600,83 @@ pub struct MarketContext {
     pub instances: Vec<InstanceContext>,
 }
 
+impl MarketContext {
+    /// Builds the context of an existing market from its on-chain accounts, fetched with the given closure
+    pub fn from_accounts<E, F>(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        get_account_data: F,
+    ) -> Result<Self, E>
+    where
+        E: From<ProgramError>,
+        F: Fn(&Pubkey) -> Result<Vec<u8>, E>,
+    {
+        let market_data = get_account_data(&market_account)?;
+        let market_state = MarketState::unpack_from_slice(&market_data)?;
+
+        let mut instances = Vec::with_capacity(market_state.number_of_instances as usize);
+        for i in 0..market_state.number_of_instances {
+            let instance_account = get_instance_address(&market_data, i)?;
+            let instance_data = get_account_data(&instance_account)?;
+            instances.push(InstanceContext::from_instance_data(
+                instance_account,
+                &instance_data,
+            )?);
+        }
+
+        Self::from_market_state(
+            audaces_protocol_program_id,
+            market_account,
+            &market_state,
+            instances,
+        )
+        .map_err(E::from)
+    }
+
+    pub fn from_market_state(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        market_state: &MarketState,
+        instances: Vec<InstanceContext>,
+    ) -> Result<Self, ProgramError> {
+        let market_signer_account = Pubkey::create_program_address(
+            &[&market_account.to_bytes(), &[market_state.signer_nonce]],
+            &audaces_protocol_program_id,
+        )
+        .map_err(|_| ProgramError::InvalidSeeds)?;
+
+        Ok(Self {
+            audaces_protocol_program_id,
+            signer_nonce: market_state.signer_nonce,
+            market_signer_account,
+            oracle_account: Pubkey::new(&market_state.oracle_address),
+            market_account,
+            admin_account: Pubkey::new(&market_state.admin_address),
+            market_vault: Pubkey::new(&market_state.vault_address),
+            bonfida_bnb: Pubkey::from_str(FIDA_BNB).unwrap(),
+            instances,
+        })
+    }
+}
+
 pub struct InstanceContext {
     pub instance_account: Pubkey,
     pub memory_pages: Vec<Pubkey>,
 }
 
+impl InstanceContext {
+    pub fn from_instance_data(
+        instance_account: Pubkey,
+        instance_data: &[u8],
+    ) -> Result<Self, ProgramError> {
+        let (_, page_infos) = parse_instance(instance_data)?;
+        Ok(Self {
+            instance_account,
+            memory_pages: page_infos.iter().map(|p| Pubkey::new(&p.address)).collect(),
+        })
+    }
+}
+
 pub struct DiscountAccount {
     pub owner: Pubkey,
     pub address: Pubkey,
@@ -295,12 +689,38 @@ pub struct PositionInfo {
     pub side: PositionType,
 }
 
+// Creates a program owned account holding enough lamports to be rent exempt, used for market, instance, page and user accounts
+pub fn create_rent_exempt_account(
+    ctx: &MarketContext,
+    rent: &Rent,
+    payer: Pubkey,
+    new_account: Pubkey,
+    space: u64,
+) -> Instruction {
+    system_instruction::create_account(
+        &payer,
+        &new_account,
+        rent.minimum_balance(space as usize),
+        space,
+        &ctx.audaces_protocol_program_id,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn create_market(
     ctx: &MarketContext,
     market_symbol: String,
     initial_v_pc_amount: u64,
     coin_decimals: u8,
     quote_decimals: u8,
+    max_allowed_slippage_margin: u64,
+    liquidation_insurance_share_bps: u16,
+    min_collateral: u64,
+    max_open_longs_v_coin: u64,
+    max_open_shorts_v_coin: u64,
+    liquidation_reward_mode: LiquidationRewardMode,
+    liquidation_reward_bps: u16,
+    pyth_product_account: Option<Pubkey>,
 ) -> Instruction {
     cpi::create_market(
         ctx.audaces_protocol_program_id,
@@ -308,11 +728,19 @@ pub fn create_market(
         ctx.oracle_account,
         ctx.admin_account,
         ctx.market_vault,
+        pyth_product_account,
         market_symbol,
         ctx.signer_nonce,
         initial_v_pc_amount,
         coin_decimals,
         quote_decimals,
+        max_allowed_slippage_margin,
+        liquidation_insurance_share_bps,
+        min_collateral,
+        max_open_longs_v_coin,
+        max_open_shorts_v_coin,
+        liquidation_reward_mode,
+        liquidation_reward_bps,
     )
 }
 
@@ -363,6 +791,24 @@ pub fn add_budget(
     )
 }
 
+pub fn add_budget_wrap_sol(
+    ctx: &MarketContext,
+    amount: u64,
+    source_owner: Pubkey,
+    wrapped_sol_account: Pubkey,
+    open_positions_account: Pubkey,
+) -> Instruction {
+    cpi::add_budget_wrap_sol(
+        ctx.audaces_protocol_program_id,
+        ctx.market_account,
+        ctx.market_vault,
+        amount,
+        source_owner,
+        wrapped_sol_account,
+        open_positions_account,
+    )
+}
+
 pub fn withdraw_budget(
     ctx: &MarketContext,
     amount: u64,
@@ -416,6 +862,40 @@ pub fn open_position(
     )
 }
 
+#[allow(clippy::too_many_arguments)]
+pub fn open_position_by_size(
+    ctx: &MarketContext,
+    position: &PositionInfo,
+    collateral: u64,
+    target_v_pc: u64,
+    predicted_entry_price: u64,                     // 32 bit FP
+    maximum_slippage_margin: u64,                   // 32 bit FP
+    discount_account_opt: Option<&DiscountAccount>, // To specify if discount account is present
+    referrer_account_opt: Option<Pubkey>,
+) -> Instruction {
+    let instance = &ctx.instances[position.instance_index as usize];
+    cpi::open_position_by_size(
+        ctx.audaces_protocol_program_id,
+        ctx.market_account,
+        ctx.market_signer_account,
+        ctx.market_vault,
+        ctx.oracle_account,
+        instance.instance_account,
+        position.user_account,
+        position.user_account_owner,
+        ctx.bonfida_bnb,
+        &instance.memory_pages,
+        position.side,
+        position.instance_index,
+        collateral,
+        target_v_pc,
+        predicted_entry_price,
+        maximum_slippage_margin,
+        discount_account_opt,
+        referrer_account_opt,
+    )
+}
+
 #[allow(clippy::too_many_arguments)]
 pub fn increase_position(
     ctx: &MarketContext,
@@ -454,59 +934,211 @@ pub fn increase_position(
 }
 
 #[allow(clippy::too_many_arguments)]
-pub fn close_position(
+pub fn open_position_with_max_fee(
     ctx: &MarketContext,
-    position_info: &PositionInfo,
-    closing_collateral: u64,
-    closing_v_coin: u64,
-    position_index: u16,
-    predicted_entry_price: u64,                 // 32 bit FP
-    maximum_slippage_margin: u64,               // 32 bit FP
-    discount_account: Option<&DiscountAccount>, // To specify if discount account is present
+    position: &PositionInfo,
+    collateral: u64,
+    leverage: u64,
+    predicted_entry_price: u64,   // 32 bit FP
+    maximum_slippage_margin: u64, // 32 bit FP
+    max_fee: u64,
+    discount_account_opt: Option<&DiscountAccount>, // To specify if discount account is present
     referrer_account_opt: Option<Pubkey>,
 ) -> Instruction {
-    let instance = &ctx.instances[position_info.instance_index as usize];
-    cpi::close_position(
+    let instance = &ctx.instances[position.instance_index as usize];
+    cpi::open_position_with_max_fee(
         ctx.audaces_protocol_program_id,
         ctx.market_account,
         ctx.market_signer_account,
         ctx.market_vault,
         ctx.oracle_account,
         instance.instance_account,
-        position_info.user_account,
-        position_info.user_account_owner,
+        position.user_account,
+        position.user_account_owner,
         ctx.bonfida_bnb,
         &instance.memory_pages,
-        closing_collateral,
-        closing_v_coin,
-        position_index,
+        position.side,
+        position.instance_index,
+        collateral,
+        leverage,
         predicted_entry_price,
         maximum_slippage_margin,
-        discount_account,
+        max_fee,
+        discount_account_opt,
         referrer_account_opt,
     )
 }
 
-pub fn collect_garbage(
+#[allow(clippy::too_many_arguments)]
+pub fn increase_position_with_max_fee(
     ctx: &MarketContext,
+    add_collateral: u64,
+    leverage: u64, // 32 bit FP
     instance_index: u8,
-    max_iterations: u64,
-    target_token_account: Pubkey,
+    position_index: u16,
+    user_account_owner: Pubkey,
+    user_account: Pubkey,
+    predicted_entry_price: u64,   // 32 bit FP
+    maximum_slippage_margin: u64, // 32 bit FP
+    max_fee: u64,
+    discount_account_opt: Option<&DiscountAccount>, // To specify if discount account is present
+    referrer_account_opt: Option<Pubkey>,
 ) -> Instruction {
     let instance = &ctx.instances[instance_index as usize];
-    cpi::collect_garbage(
+    cpi::increase_position_with_max_fee(
         ctx.audaces_protocol_program_id,
         ctx.market_account,
         ctx.market_signer_account,
         ctx.market_vault,
+        ctx.oracle_account,
         instance.instance_account,
+        user_account,
+        user_account_owner,
+        ctx.bonfida_bnb,
         &instance.memory_pages,
+        add_collateral,
+        leverage,
         instance_index,
-        max_iterations,
-        target_token_account,
-    )
-}
-
+        position_index,
+        predicted_entry_price,
+        maximum_slippage_margin,
+        max_fee,
+        discount_account_opt,
+        referrer_account_opt,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn close_position(
+    ctx: &MarketContext,
+    position_info: &PositionInfo,
+    closing_collateral: u64,
+    closing_v_coin: u64,
+    position_index: u16,
+    predicted_entry_price: u64,                 // 32 bit FP
+    maximum_slippage_margin: u64,               // 32 bit FP
+    discount_account: Option<&DiscountAccount>, // To specify if discount account is present
+    referrer_account_opt: Option<Pubkey>,
+) -> Instruction {
+    let instance = &ctx.instances[position_info.instance_index as usize];
+    cpi::close_position(
+        ctx.audaces_protocol_program_id,
+        ctx.market_account,
+        ctx.market_signer_account,
+        ctx.market_vault,
+        ctx.oracle_account,
+        instance.instance_account,
+        position_info.user_account,
+        position_info.user_account_owner,
+        ctx.bonfida_bnb,
+        &instance.memory_pages,
+        closing_collateral,
+        closing_v_coin,
+        position_index,
+        predicted_entry_price,
+        maximum_slippage_margin,
+        discount_account,
+        referrer_account_opt,
+    )
+}
+
+/// Closes a position of the given user account, resolving its owner, instance and side from the account's data
+#[allow(clippy::too_many_arguments)]
+pub fn close_position_from_account(
+    ctx: &MarketContext,
+    user_account: Pubkey,
+    user_account_data: &[u8],
+    closing_collateral: u64,
+    closing_v_coin: u64,
+    position_index: u16,
+    predicted_entry_price: u64,   // 32 bit FP
+    maximum_slippage_margin: u64, // 32 bit FP
+    discount_account: Option<&DiscountAccount>,
+    referrer_account_opt: Option<Pubkey>,
+) -> Result<Instruction, ProgramError> {
+    let (header, position) = read_position(ctx, user_account_data, position_index)?;
+    Ok(close_position(
+        ctx,
+        &PositionInfo {
+            user_account,
+            user_account_owner: Pubkey::new(&header.owner),
+            instance_index: position.instance_index,
+            side: position.side,
+        },
+        closing_collateral,
+        closing_v_coin,
+        position_index,
+        predicted_entry_price,
+        maximum_slippage_margin,
+        discount_account,
+        referrer_account_opt,
+    ))
+}
+
+// Closes entirely a position which crossed its stop-loss index, signed by the closing account in place
+// of the owner. The program doesn't check the slippage of such closes, no entry price is predicted.
+pub fn close_position_at_stop_loss(
+    ctx: &MarketContext,
+    user_account: Pubkey,
+    user_account_data: &[u8],
+    position_index: u16,
+    closing_account: Pubkey,
+) -> Result<Instruction, ProgramError> {
+    let (_, position) = read_position(ctx, user_account_data, position_index)?;
+    Ok(close_position(
+        ctx,
+        &PositionInfo {
+            user_account,
+            user_account_owner: closing_account,
+            instance_index: position.instance_index,
+            side: position.side,
+        },
+        u64::MAX,
+        u64::MAX,
+        position_index,
+        0,
+        0,
+        None,
+        None,
+    ))
+}
+
+fn read_position(
+    ctx: &MarketContext,
+    user_account_data: &[u8],
+    position_index: u16,
+) -> Result<(UserAccountState, OpenPosition), ProgramError> {
+    let header = UserAccountState::unpack_from_slice(&user_account_data[..UserAccountState::LEN])?;
+    if Pubkey::new(&header.market) != ctx.market_account {
+        return Err(PerpError::MarketMismatch.into());
+    }
+    let position = header.position_at(user_account_data, position_index)?;
+    if position.instance_index as usize >= ctx.instances.len() {
+        return Err(PerpError::InstanceMismatch.into());
+    }
+    Ok((header, position))
+}
+
+pub fn collect_garbage(
+    ctx: &MarketContext,
+    instance_index: u8,
+    max_iterations: u64,
+    target_token_account: Pubkey,
+) -> Instruction {
+    let instance = &ctx.instances[instance_index as usize];
+    cpi::collect_garbage(
+        ctx.audaces_protocol_program_id,
+        ctx.market_account,
+        ctx.market_signer_account,
+        ctx.market_vault,
+        instance.instance_account,
+        &instance.memory_pages,
+        instance_index,
+        max_iterations,
+        target_token_account,
+    )
+}
+
 pub fn crank_liquidation(
     ctx: &MarketContext,
     instance_index: u8,
@@ -535,6 +1167,27 @@ pub fn crank_funding(ctx: &MarketContext) -> Instruction {
     )
 }
 
+pub fn crank_instance_funding(ctx: &MarketContext, instance_index: u8) -> Instruction {
+    let instance = &ctx.instances[instance_index as usize];
+    cpi::crank_instance_funding(
+        ctx.audaces_protocol_program_id,
+        ctx.market_account,
+        instance.instance_account,
+        &instance.memory_pages,
+        instance_index,
+    )
+}
+
+pub fn migrate_instance(ctx: &MarketContext, instance_index: u8) -> Instruction {
+    cpi::migrate_instance(
+        ctx.audaces_protocol_program_id,
+        ctx.market_account,
+        ctx.admin_account,
+        ctx.instances[instance_index as usize].instance_account,
+        instance_index,
+    )
+}
+
 pub fn extract_funding(
     ctx: &MarketContext,
     instance_index: u8,
@@ -552,6 +1205,49 @@ pub fn extract_funding(
     )
 }
 
+pub fn extract_funding_multi(
+    ctx: &MarketContext,
+    instance_indices: &[u8],
+    open_positions_account: Pubkey,
+) -> Instruction {
+    let instances = instance_indices
+        .iter()
+        .map(|i| &ctx.instances[*i as usize])
+        .collect::<Vec<_>>();
+    cpi::extract_funding_multi(
+        ctx.audaces_protocol_program_id,
+        ctx.market_account,
+        ctx.oracle_account,
+        instance_indices,
+        &instances,
+        open_positions_account,
+    )
+}
+
+/// Returns the `extract_funding` instructions which need to be executed before the given user account can trade again.
+/// The list is empty when the account is already up to date with the market's funding history.
+pub fn ensure_funding_processed(
+    ctx: &MarketContext,
+    market_state: &MarketState,
+    user_account: Pubkey,
+    user_account_data: &[u8],
+) -> Result<Vec<Instruction>, ProgramError> {
+    let header = UserAccountState::unpack_from_slice(&user_account_data[..UserAccountState::LEN])?;
+    let mut instance_indices = Vec::new();
+    for position in header.positions(user_account_data) {
+        let position = position?;
+        if position.last_funding_offset != market_state.funding_history_offset
+            && !instance_indices.contains(&position.instance_index)
+        {
+            instance_indices.push(position.instance_index);
+        }
+    }
+    Ok(instance_indices
+        .into_iter()
+        .map(|i| extract_funding(ctx, i, user_account))
+        .collect())
+}
+
 pub fn change_k(ctx: &MarketContext, factor: u64) -> Instruction {
     cpi::change_k(
         ctx.audaces_protocol_program_id,
@@ -610,17 +1306,55 @@ pub fn rebalance(
     )
 }
 
+pub fn permissionless_rebalance(
+    ctx: &MarketContext,
+    user_account: Pubkey,
+    user_account_owner: Pubkey,
+    instance_index: u8,
+    collateral: u64,
+) -> Instruction {
+    let instance = &ctx.instances[instance_index as usize];
+    cpi::permissionless_rebalance(
+        ctx.audaces_protocol_program_id,
+        ctx.market_account,
+        ctx.market_signer_account,
+        ctx.market_vault,
+        ctx.oracle_account,
+        instance.instance_account,
+        user_account,
+        user_account_owner,
+        ctx.bonfida_bnb,
+        &instance.memory_pages,
+        instance_index,
+        collateral,
+    )
+}
+
+pub fn withdraw_fees(ctx: &MarketContext, target_account: Pubkey, amount: u64) -> Instruction {
+    cpi::withdraw_fees(
+        ctx.audaces_protocol_program_id,
+        ctx.market_account,
+        ctx.market_signer_account,
+        ctx.market_vault,
+        ctx.admin_account,
+        target_account,
+        amount,
+    )
+}
+
 pub fn transfer_user_account(
     ctx: &MarketContext,
     user_account: Pubkey,
     user_account_owner: Pubkey,
     new_user_account_owner: Pubkey,
+    allow_immediate: bool,
 ) -> Instruction {
     cpi::transfer_user_account(
         ctx.audaces_protocol_program_id,
         user_account,
         user_account_owner,
         new_user_account_owner,
+        allow_immediate,
     )
 }
 
@@ -634,6 +1368,7 @@ pub fn transfer_position(
 ) -> Instruction {
     cpi::transfer_position(
         ctx.audaces_protocol_program_id,
+        ctx.market_account,
         position_index,
         source_user_account,
         source_user_account_owner,
@@ -642,66 +1377,262 @@ pub fn transfer_position(
     )
 }
 
-pub mod cpi {
-    use std::str::FromStr;
+pub fn migrate_market(ctx: &MarketContext, max_allowed_slippage_margin: u64) -> Instruction {
+    cpi::migrate_market(
+        ctx.audaces_protocol_program_id,
+        ctx.market_account,
+        ctx.admin_account,
+        max_allowed_slippage_margin,
+    )
+}
 
-    use crate::{
-        processor::{FUNDING_EXTRACTION_LABEL, FUNDING_LABEL, LIQUIDATION_LABEL, TRADE_LABEL},
-        state::PositionType,
-    };
+pub fn set_stop_loss(
+    ctx: &MarketContext,
+    position_index: u16,
+    stop_loss_index: u64, // 32 bit FP
+    user_account: Pubkey,
+    user_account_owner: Pubkey,
+) -> Instruction {
+    cpi::set_stop_loss(
+        ctx.audaces_protocol_program_id,
+        ctx.market_account,
+        ctx.oracle_account,
+        position_index,
+        stop_loss_index,
+        user_account,
+        user_account_owner,
+    )
+}
 
-    use super::{DiscountAccount, PerpInstruction};
-    use borsh::BorshSerialize;
-    use solana_program::{
-        instruction::{AccountMeta, Instruction},
-        pubkey::Pubkey,
-        sysvar::clock,
-    };
+pub fn migrate_user_account(
+    ctx: &MarketContext,
+    user_account: Pubkey,
+    user_account_owner: Pubkey,
+) -> Instruction {
+    cpi::migrate_user_account(
+        ctx.audaces_protocol_program_id,
+        user_account,
+        user_account_owner,
+    )
+}
 
-    #[allow(clippy::clippy::too_many_arguments)]
-    pub fn create_market(
-        audaces_protocol_program_id: Pubkey,
-        market_account: Pubkey,
-        oracle_account: Pubkey,
-        admin_account: Pubkey,
-        market_vault: Pubkey,
-        market_symbol: String,
-        signer_nonce: u8,
-        initial_v_pc_amount: u64,
-        coin_decimals: u8,
-        quote_decimals: u8,
-    ) -> Instruction {
-        let instruction_data = PerpInstruction::CreateMarket {
-            signer_nonce,
-            market_symbol,
-            initial_v_pc_amount,
-            coin_decimals,
-            quote_decimals,
-        };
-        let data = instruction_data.try_to_vec().unwrap();
-        let accounts = vec![
-            AccountMeta::new(market_account, false),
-            AccountMeta::new_readonly(clock::id(), false),
-            AccountMeta::new_readonly(oracle_account, false),
-            AccountMeta::new_readonly(admin_account, false),
-            AccountMeta::new_readonly(market_vault, false),
-        ];
+pub fn grow_page(
+    ctx: &MarketContext,
+    instance_index: u8,
+    page_index: u8,
+    additional_bytes: u64,
+) -> Instruction {
+    let instance = &ctx.instances[instance_index as usize];
+    cpi::grow_page(
+        ctx.audaces_protocol_program_id,
+        ctx.market_account,
+        ctx.admin_account,
+        instance.instance_account,
+        instance.memory_pages[page_index as usize],
+        instance_index,
+        page_index,
+        additional_bytes,
+    )
+}
 
-        Instruction {
-            program_id: audaces_protocol_program_id,
-            accounts,
-            data,
-        }
-    }
+pub fn grow_user_account(
+    ctx: &MarketContext,
+    user_account: Pubkey,
+    user_account_owner: Pubkey,
+    additional_positions: u32,
+) -> Instruction {
+    cpi::grow_user_account(
+        ctx.audaces_protocol_program_id,
+        ctx.market_account,
+        user_account,
+        user_account_owner,
+        additional_positions,
+    )
+}
 
-    pub fn update_oracle_account(
-        audaces_protocol_program_id: Pubkey,
-        market_account: Pubkey,
-        pyth_oracle_mapping_account: Pubkey,
-        pyth_oracle_product_account: Pubkey,
-        pyth_oracle_price_account: Pubkey,
-    ) -> Instruction {
-        let instruction_data = PerpInstruction::UpdateOracleAccount;
+pub fn set_pause(ctx: &MarketContext, paused: bool) -> Instruction {
+    cpi::set_pause(
+        ctx.audaces_protocol_program_id,
+        ctx.market_account,
+        ctx.admin_account,
+        paused,
+    )
+}
+
+pub fn change_admin(ctx: &MarketContext, new_admin_account: Pubkey) -> Instruction {
+    cpi::change_admin(
+        ctx.audaces_protocol_program_id,
+        ctx.market_account,
+        ctx.admin_account,
+        new_admin_account,
+    )
+}
+
+pub fn update_market_symbol(
+    ctx: &MarketContext,
+    new_symbol: String,
+    pyth_product_account: Option<Pubkey>,
+) -> Instruction {
+    cpi::update_market_symbol(
+        ctx.audaces_protocol_program_id,
+        ctx.market_account,
+        ctx.admin_account,
+        ctx.oracle_account,
+        pyth_product_account,
+        new_symbol,
+    )
+}
+
+pub fn propose_user_account_transfer(
+    ctx: &MarketContext,
+    user_account: Pubkey,
+    user_account_owner: Pubkey,
+    transfer_proposal: Pubkey,
+    new_user_account_owner: Pubkey,
+) -> Instruction {
+    cpi::propose_user_account_transfer(
+        ctx.audaces_protocol_program_id,
+        user_account,
+        user_account_owner,
+        transfer_proposal,
+        new_user_account_owner,
+    )
+}
+
+pub fn accept_user_account_transfer(
+    ctx: &MarketContext,
+    user_account: Pubkey,
+    new_user_account_owner: Pubkey,
+    transfer_proposal: Pubkey,
+    lamports_target: Pubkey,
+) -> Instruction {
+    cpi::accept_user_account_transfer(
+        ctx.audaces_protocol_program_id,
+        user_account,
+        new_user_account_owner,
+        transfer_proposal,
+        lamports_target,
+    )
+}
+
+pub fn cancel_user_account_transfer(
+    ctx: &MarketContext,
+    user_account: Pubkey,
+    user_account_owner: Pubkey,
+    transfer_proposal: Pubkey,
+    lamports_target: Pubkey,
+) -> Instruction {
+    cpi::cancel_user_account_transfer(
+        ctx.audaces_protocol_program_id,
+        user_account,
+        user_account_owner,
+        transfer_proposal,
+        lamports_target,
+    )
+}
+
+pub fn register_referrer(
+    ctx: &MarketContext,
+    fee_payer: Pubkey,
+    referrer_account: Pubkey,
+) -> Instruction {
+    cpi::register_referrer(
+        ctx.audaces_protocol_program_id,
+        fee_payer,
+        ctx.market_account,
+        referrer_account,
+    )
+}
+
+pub fn get_position(ctx: &MarketContext, user_account: Pubkey, position_index: u16) -> Instruction {
+    cpi::get_position(
+        ctx.audaces_protocol_program_id,
+        ctx.market_account,
+        user_account,
+        ctx.oracle_account,
+        position_index,
+    )
+}
+
+pub mod cpi {
+    use std::str::FromStr;
+
+    use crate::{
+        processor::{FUNDING_EXTRACTION_LABEL, FUNDING_LABEL, LIQUIDATION_LABEL, TRADE_LABEL},
+        state::{referral::get_referrer_record_address, PositionType},
+    };
+
+    use super::{DiscountAccount, InstanceContext, PerpInstruction};
+    use borsh::BorshSerialize;
+    use solana_program::{
+        instruction::{AccountMeta, Instruction},
+        pubkey::Pubkey,
+        system_program,
+        sysvar::{clock, rent},
+    };
+
+    #[allow(clippy::clippy::too_many_arguments)]
+    pub fn create_market(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        oracle_account: Pubkey,
+        admin_account: Pubkey,
+        market_vault: Pubkey,
+        pyth_product_account: Option<Pubkey>,
+        market_symbol: String,
+        signer_nonce: u8,
+        initial_v_pc_amount: u64,
+        coin_decimals: u8,
+        quote_decimals: u8,
+        max_allowed_slippage_margin: u64,
+        liquidation_insurance_share_bps: u16,
+        min_collateral: u64,
+        max_open_longs_v_coin: u64,
+        max_open_shorts_v_coin: u64,
+        liquidation_reward_mode: LiquidationRewardMode,
+        liquidation_reward_bps: u16,
+    ) -> Instruction {
+        let instruction_data = PerpInstruction::CreateMarket {
+            signer_nonce,
+            market_symbol,
+            initial_v_pc_amount,
+            coin_decimals,
+            quote_decimals,
+            max_allowed_slippage_margin,
+            liquidation_insurance_share_bps,
+            min_collateral,
+            max_open_longs_v_coin,
+            max_open_shorts_v_coin,
+            liquidation_reward_mode,
+            liquidation_reward_bps,
+        };
+        let data = instruction_data.try_to_vec().unwrap();
+        let mut accounts = vec![
+            AccountMeta::new(market_account, false),
+            AccountMeta::new_readonly(clock::id(), false),
+            AccountMeta::new_readonly(oracle_account, false),
+            AccountMeta::new_readonly(admin_account, false),
+            AccountMeta::new_readonly(market_vault, false),
+        ];
+        if let Some(pyth_product_account) = pyth_product_account {
+            accounts.push(AccountMeta::new_readonly(pyth_product_account, false));
+        }
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    pub fn update_oracle_account(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        pyth_oracle_mapping_account: Pubkey,
+        pyth_oracle_product_account: Pubkey,
+        pyth_oracle_price_account: Pubkey,
+    ) -> Instruction {
+        let instruction_data = PerpInstruction::UpdateOracleAccount;
         let data = instruction_data.try_to_vec().unwrap();
         let accounts = vec![
             AccountMeta::new(market_account, false),
@@ -826,6 +1757,7 @@ pub mod cpi {
             leverage,
             predicted_entry_price,
             maximum_slippage_margin,
+            has_discount_account: discount_account_opt.is_some(),
         };
         let data = instruction_data.try_to_vec().unwrap();
         let mut accounts = Vec::with_capacity(13);
@@ -855,6 +1787,12 @@ pub mod cpi {
         }
         if let Some(referrer_account) = referrer_account_opt {
             accounts.push(AccountMeta::new(referrer_account, false));
+            let (referrer_record, _) = get_referrer_record_address(
+                &audaces_protocol_program_id,
+                &market_account,
+                &referrer_account,
+            );
+            accounts.push(AccountMeta::new(referrer_record, false));
         }
 
         Instruction {
@@ -892,6 +1830,7 @@ pub mod cpi {
             leverage,
             predicted_entry_price,
             maximum_slippage_margin,
+            has_discount_account: discount_account_opt.is_some(),
         };
         let data = instruction_data.try_to_vec().unwrap();
         let mut accounts = Vec::with_capacity(5 + memory_pages.len());
@@ -921,6 +1860,12 @@ pub mod cpi {
         }
         if let Some(referrer_account) = referrer_account_opt {
             accounts.push(AccountMeta::new(referrer_account, false));
+            let (referrer_record, _) = get_referrer_record_address(
+                &audaces_protocol_program_id,
+                &market_account,
+                &referrer_account,
+            );
+            accounts.push(AccountMeta::new(referrer_record, false));
         }
 
         Instruction {
@@ -956,6 +1901,7 @@ pub mod cpi {
             position_index,
             predicted_entry_price,
             maximum_slippage_margin,
+            has_discount_account: discount_account.is_some(),
         };
         let data = instruction_data.try_to_vec().unwrap();
         let mut accounts = Vec::with_capacity(13 + memory_pages.len());
@@ -983,6 +1929,12 @@ pub mod cpi {
         }
         if let Some(referrer_account) = referrer_account_opt {
             accounts.push(AccountMeta::new(referrer_account, false));
+            let (referrer_record, _) = get_referrer_record_address(
+                &audaces_protocol_program_id,
+                &market_account,
+                &referrer_account,
+            );
+            accounts.push(AccountMeta::new(referrer_record, false));
         }
 
         Instruction {
@@ -1223,13 +2175,57 @@ pub mod cpi {
         }
     }
     #[allow(clippy::clippy::too_many_arguments)]
+    pub fn permissionless_rebalance(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        market_signer_account: Pubkey,
+        market_vault: Pubkey,
+        oracle_account: Pubkey,
+        instance_account: Pubkey,
+        user_account: Pubkey,
+        user_account_owner: Pubkey,
+        bonfida_bnb: Pubkey,
+        memory_pages: &[Pubkey],
+        instance_index: u8,
+        collateral: u64,
+    ) -> Instruction {
+        let data = PerpInstruction::PermissionlessRebalance {
+            collateral,
+            instance_index,
+        }
+        .try_to_vec()
+        .unwrap();
+        let mut accounts = vec![
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(clock::id(), false),
+            AccountMeta::new(market_account, false),
+            AccountMeta::new(instance_account, false),
+            AccountMeta::new_readonly(market_signer_account, false),
+            AccountMeta::new(market_vault, false),
+            AccountMeta::new(bonfida_bnb, false),
+            AccountMeta::new_readonly(user_account_owner, true),
+            AccountMeta::new(user_account, false),
+            AccountMeta::new_readonly(oracle_account, false),
+        ];
+
+        for p in memory_pages {
+            accounts.push(AccountMeta::new(*p, false))
+        }
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+    #[allow(clippy::clippy::too_many_arguments)]
     pub fn transfer_user_account(
         audaces_protocol_program_id: Pubkey,
         user_account: Pubkey,
         user_account_owner: Pubkey,
         new_user_account_owner: Pubkey,
+        allow_immediate: bool,
     ) -> Instruction {
-        let data = PerpInstruction::TransferUserAccount {}
+        let data = PerpInstruction::TransferUserAccount { allow_immediate }
             .try_to_vec()
             .unwrap();
         let accounts = vec![
@@ -1247,6 +2243,7 @@ pub mod cpi {
     #[allow(clippy::clippy::too_many_arguments)]
     pub fn transfer_position(
         audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
         position_index: u16,
         source_user_account: Pubkey,
         source_user_account_owner: Pubkey,
@@ -1261,6 +2258,7 @@ pub mod cpi {
             AccountMeta::new(source_user_account, false),
             AccountMeta::new_readonly(destination_user_account_owner, true),
             AccountMeta::new(destination_user_account, false),
+            AccountMeta::new_readonly(market_account, false),
         ];
 
         Instruction {
@@ -1299,6 +2297,7 @@ pub mod cpi {
             predicted_entry_price,
             maximum_slippage_margin,
             additional_withdraw_amount,
+            has_discount_account: discount_account.is_some(),
         };
         let data = instruction_data.try_to_vec().unwrap();
         let mut accounts = Vec::with_capacity(13 + memory_pages.len());
@@ -1327,7 +2326,648 @@ pub mod cpi {
         }
         if let Some(referrer_account) = referrer_account_opt {
             accounts.push(AccountMeta::new(referrer_account, false));
+            let (referrer_record, _) = get_referrer_record_address(
+                &audaces_protocol_program_id,
+                &market_account,
+                &referrer_account,
+            );
+            accounts.push(AccountMeta::new(referrer_record, false));
+        }
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    pub fn migrate_market(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        admin_account: Pubkey,
+        max_allowed_slippage_margin: u64,
+    ) -> Instruction {
+        let data = PerpInstruction::MigrateMarket {
+            max_allowed_slippage_margin,
+        }
+        .try_to_vec()
+        .unwrap();
+        let accounts = vec![
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(market_account, false),
+            AccountMeta::new(admin_account, true),
+        ];
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn grow_page(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        admin_account: Pubkey,
+        instance_account: Pubkey,
+        memory_page: Pubkey,
+        instance_index: u8,
+        page_index: u8,
+        additional_bytes: u64,
+    ) -> Instruction {
+        let data = PerpInstruction::GrowPage {
+            instance_index,
+            page_index,
+            additional_bytes,
+        }
+        .try_to_vec()
+        .unwrap();
+        let accounts = vec![
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(market_account, false),
+            AccountMeta::new(admin_account, true),
+            AccountMeta::new_readonly(instance_account, false),
+            AccountMeta::new(memory_page, false),
+        ];
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    pub fn set_pause(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        admin_account: Pubkey,
+        paused: bool,
+    ) -> Instruction {
+        let data = PerpInstruction::SetPause { paused }.try_to_vec().unwrap();
+        let accounts = vec![
+            AccountMeta::new(market_account, false),
+            AccountMeta::new_readonly(admin_account, true),
+        ];
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    pub fn change_admin(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        admin_account: Pubkey,
+        new_admin_account: Pubkey,
+    ) -> Instruction {
+        let data = PerpInstruction::ChangeAdmin {}.try_to_vec().unwrap();
+        let accounts = vec![
+            AccountMeta::new(market_account, false),
+            AccountMeta::new_readonly(admin_account, true),
+            AccountMeta::new_readonly(new_admin_account, false),
+        ];
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
         }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn open_position_by_size(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        market_signer_account: Pubkey,
+        market_vault: Pubkey,
+        oracle_account: Pubkey,
+        instance_account: Pubkey,
+        user_account: Pubkey,
+        user_account_owner: Pubkey,
+        bonfida_bnb: Pubkey,
+        memory_pages: &[Pubkey],
+        side: PositionType,
+        instance_index: u8,
+        collateral: u64,
+        target_v_pc: u64,
+        predicted_entry_price: u64,                     // 32 bit FP
+        maximum_slippage_margin: u64,                   // 32 bit FP
+        discount_account_opt: Option<&DiscountAccount>, // To specify if discount account is present
+        referrer_account_opt: Option<Pubkey>,
+    ) -> Instruction {
+        // The accounts are the same as for OpenPosition
+        let mut instruction = open_position(
+            audaces_protocol_program_id,
+            market_account,
+            market_signer_account,
+            market_vault,
+            oracle_account,
+            instance_account,
+            user_account,
+            user_account_owner,
+            bonfida_bnb,
+            memory_pages,
+            side,
+            instance_index,
+            collateral,
+            0,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            discount_account_opt,
+            referrer_account_opt,
+        );
+        instruction.data = PerpInstruction::OpenPositionBySize {
+            side,
+            collateral,
+            instance_index,
+            target_v_pc,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            has_discount_account: discount_account_opt.is_some(),
+        }
+        .try_to_vec()
+        .unwrap();
+        instruction
+    }
+
+    pub fn update_market_symbol(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        admin_account: Pubkey,
+        oracle_account: Pubkey,
+        pyth_product_account: Option<Pubkey>,
+        new_symbol: String,
+    ) -> Instruction {
+        let data = PerpInstruction::UpdateMarketSymbol { new_symbol }
+            .try_to_vec()
+            .unwrap();
+        let mut accounts = vec![
+            AccountMeta::new(market_account, false),
+            AccountMeta::new_readonly(admin_account, true),
+            AccountMeta::new_readonly(oracle_account, false),
+        ];
+        if let Some(pyth_product_account) = pyth_product_account {
+            accounts.push(AccountMeta::new_readonly(pyth_product_account, false));
+        }
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    pub fn propose_user_account_transfer(
+        audaces_protocol_program_id: Pubkey,
+        user_account: Pubkey,
+        user_account_owner: Pubkey,
+        transfer_proposal: Pubkey,
+        new_user_account_owner: Pubkey,
+    ) -> Instruction {
+        let data = PerpInstruction::ProposeUserAccountTransfer {}
+            .try_to_vec()
+            .unwrap();
+        let accounts = vec![
+            AccountMeta::new_readonly(user_account_owner, true),
+            AccountMeta::new_readonly(user_account, false),
+            AccountMeta::new(transfer_proposal, false),
+            AccountMeta::new_readonly(new_user_account_owner, false),
+        ];
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    pub fn accept_user_account_transfer(
+        audaces_protocol_program_id: Pubkey,
+        user_account: Pubkey,
+        new_user_account_owner: Pubkey,
+        transfer_proposal: Pubkey,
+        lamports_target: Pubkey,
+    ) -> Instruction {
+        let data = PerpInstruction::AcceptUserAccountTransfer {}
+            .try_to_vec()
+            .unwrap();
+        let accounts = vec![
+            AccountMeta::new_readonly(new_user_account_owner, true),
+            AccountMeta::new(user_account, false),
+            AccountMeta::new(transfer_proposal, false),
+            AccountMeta::new(lamports_target, false),
+        ];
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    pub fn cancel_user_account_transfer(
+        audaces_protocol_program_id: Pubkey,
+        user_account: Pubkey,
+        user_account_owner: Pubkey,
+        transfer_proposal: Pubkey,
+        lamports_target: Pubkey,
+    ) -> Instruction {
+        let data = PerpInstruction::CancelUserAccountTransfer {}
+            .try_to_vec()
+            .unwrap();
+        let accounts = vec![
+            AccountMeta::new_readonly(user_account_owner, true),
+            AccountMeta::new_readonly(user_account, false),
+            AccountMeta::new(transfer_proposal, false),
+            AccountMeta::new(lamports_target, false),
+        ];
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    pub fn register_referrer(
+        audaces_protocol_program_id: Pubkey,
+        fee_payer: Pubkey,
+        market_account: Pubkey,
+        referrer_account: Pubkey,
+    ) -> Instruction {
+        let data = PerpInstruction::RegisterReferrer {}.try_to_vec().unwrap();
+        let (referrer_record, _) = get_referrer_record_address(
+            &audaces_protocol_program_id,
+            &market_account,
+            &referrer_account,
+        );
+        let accounts = vec![
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(fee_payer, true),
+            AccountMeta::new_readonly(market_account, false),
+            AccountMeta::new_readonly(referrer_account, false),
+            AccountMeta::new(referrer_record, false),
+        ];
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    pub fn get_position(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        user_account: Pubkey,
+        oracle_account: Pubkey,
+        position_index: u16,
+    ) -> Instruction {
+        let data = PerpInstruction::GetPosition { position_index }
+            .try_to_vec()
+            .unwrap();
+        let accounts = vec![
+            AccountMeta::new_readonly(market_account, false),
+            AccountMeta::new_readonly(user_account, false),
+            AccountMeta::new_readonly(oracle_account, false),
+        ];
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    pub fn withdraw_fees(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        market_signer_account: Pubkey,
+        market_vault: Pubkey,
+        admin_account: Pubkey,
+        target_account: Pubkey,
+        amount: u64,
+    ) -> Instruction {
+        let data = PerpInstruction::WithdrawFees { amount }
+            .try_to_vec()
+            .unwrap();
+        let accounts = vec![
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new(market_account, false),
+            AccountMeta::new_readonly(market_signer_account, false),
+            AccountMeta::new(market_vault, false),
+            AccountMeta::new_readonly(admin_account, true),
+            AccountMeta::new(target_account, false),
+        ];
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    pub fn add_budget_wrap_sol(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        market_vault: Pubkey,
+        amount: u64,
+        source_owner: Pubkey,
+        wrapped_sol_account: Pubkey,
+        open_positions_account: Pubkey,
+    ) -> Instruction {
+        let data = PerpInstruction::AddBudgetWrapSol { amount }
+            .try_to_vec()
+            .unwrap();
+        let accounts = vec![
+            AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(rent::id(), false),
+            AccountMeta::new_readonly(spl_token::native_mint::id(), false),
+            AccountMeta::new(market_account, false),
+            AccountMeta::new(market_vault, false),
+            AccountMeta::new(open_positions_account, false),
+            AccountMeta::new(source_owner, true),
+            AccountMeta::new(wrapped_sol_account, true),
+        ];
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn open_position_with_max_fee(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        market_signer_account: Pubkey,
+        market_vault: Pubkey,
+        oracle_account: Pubkey,
+        instance_account: Pubkey,
+        user_account: Pubkey,
+        user_account_owner: Pubkey,
+        bonfida_bnb: Pubkey,
+        memory_pages: &[Pubkey],
+        side: PositionType,
+        instance_index: u8,
+        collateral: u64,
+        leverage: u64,
+        predicted_entry_price: u64,   // 32 bit FP
+        maximum_slippage_margin: u64, // 32 bit FP
+        max_fee: u64,
+        discount_account_opt: Option<&DiscountAccount>, // To specify if discount account is present
+        referrer_account_opt: Option<Pubkey>,
+    ) -> Instruction {
+        // The accounts are the same as for OpenPosition
+        let mut instruction = open_position(
+            audaces_protocol_program_id,
+            market_account,
+            market_signer_account,
+            market_vault,
+            oracle_account,
+            instance_account,
+            user_account,
+            user_account_owner,
+            bonfida_bnb,
+            memory_pages,
+            side,
+            instance_index,
+            collateral,
+            leverage,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            discount_account_opt,
+            referrer_account_opt,
+        );
+        instruction.data = PerpInstruction::OpenPositionWithMaxFee {
+            side,
+            collateral,
+            instance_index,
+            leverage,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            max_fee,
+            has_discount_account: discount_account_opt.is_some(),
+        }
+        .try_to_vec()
+        .unwrap();
+        instruction
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn increase_position_with_max_fee(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        market_signer_account: Pubkey,
+        market_vault: Pubkey,
+        oracle_account: Pubkey,
+        instance_account: Pubkey,
+        user_account: Pubkey,
+        user_account_owner: Pubkey,
+        bonfida_bnb: Pubkey,
+        memory_pages: &[Pubkey],
+        add_collateral: u64,
+        leverage: u64, // 32 bit FP
+        instance_index: u8,
+        position_index: u16,
+        predicted_entry_price: u64,   // 32 bit FP
+        maximum_slippage_margin: u64, // 32 bit FP
+        max_fee: u64,
+        discount_account_opt: Option<&DiscountAccount>, // To specify if discount account is present
+        referrer_account_opt: Option<Pubkey>,
+    ) -> Instruction {
+        // The accounts are the same as for IncreasePosition
+        let mut instruction = increase_position(
+            audaces_protocol_program_id,
+            market_account,
+            market_signer_account,
+            market_vault,
+            oracle_account,
+            instance_account,
+            user_account,
+            user_account_owner,
+            bonfida_bnb,
+            memory_pages,
+            add_collateral,
+            leverage,
+            instance_index,
+            position_index,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            discount_account_opt,
+            referrer_account_opt,
+        );
+        instruction.data = PerpInstruction::IncreasePositionWithMaxFee {
+            add_collateral,
+            instance_index,
+            leverage,
+            position_index,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            max_fee,
+            has_discount_account: discount_account_opt.is_some(),
+        }
+        .try_to_vec()
+        .unwrap();
+        instruction
+    }
+
+    pub fn set_stop_loss(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        oracle_account: Pubkey,
+        position_index: u16,
+        stop_loss_index: u64, // 32 bit FP
+        user_account: Pubkey,
+        user_account_owner: Pubkey,
+    ) -> Instruction {
+        let data = PerpInstruction::SetStopLoss {
+            position_index,
+            stop_loss_index,
+        }
+        .try_to_vec()
+        .unwrap();
+        let accounts = vec![
+            AccountMeta::new_readonly(market_account, false),
+            AccountMeta::new_readonly(oracle_account, false),
+            AccountMeta::new_readonly(user_account_owner, true),
+            AccountMeta::new(user_account, false),
+        ];
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    pub fn migrate_user_account(
+        audaces_protocol_program_id: Pubkey,
+        user_account: Pubkey,
+        user_account_owner: Pubkey,
+    ) -> Instruction {
+        let data = PerpInstruction::MigrateUserAccount.try_to_vec().unwrap();
+        let accounts = vec![
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new(user_account_owner, true),
+            AccountMeta::new(user_account, false),
+        ];
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    pub fn extract_funding_multi(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        oracle_account: Pubkey,
+        instance_indices: &[u8],
+        instances: &[&InstanceContext],
+        open_positions_account: Pubkey,
+    ) -> Instruction {
+        let data = PerpInstruction::ExtractFundingMulti {
+            instance_indices: instance_indices.to_vec(),
+        }
+        .try_to_vec()
+        .unwrap();
+        let mut accounts = vec![
+            AccountMeta::new(market_account, false),
+            AccountMeta::new(open_positions_account, false),
+            AccountMeta::new_readonly(Pubkey::from_str(FUNDING_EXTRACTION_LABEL).unwrap(), false),
+            AccountMeta::new_readonly(oracle_account, false),
+        ];
+        for instance in instances {
+            accounts.push(AccountMeta::new(instance.instance_account, false));
+            for p in &instance.memory_pages {
+                accounts.push(AccountMeta::new(*p, false));
+            }
+        }
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    pub fn crank_instance_funding(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        instance_account: Pubkey,
+        memory_pages: &[Pubkey],
+        instance_index: u8,
+    ) -> Instruction {
+        let data = PerpInstruction::CrankInstanceFunding { instance_index }
+            .try_to_vec()
+            .unwrap();
+        let mut accounts = Vec::with_capacity(3 + memory_pages.len());
+        accounts.push(AccountMeta::new_readonly(market_account, false));
+        accounts.push(AccountMeta::new(instance_account, false));
+        accounts.push(AccountMeta::new_readonly(
+            Pubkey::from_str(FUNDING_LABEL).unwrap(),
+            false,
+        ));
+        for p in memory_pages {
+            accounts.push(AccountMeta::new_readonly(*p, false))
+        }
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    pub fn migrate_instance(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        admin_account: Pubkey,
+        instance_account: Pubkey,
+        instance_index: u8,
+    ) -> Instruction {
+        let data = PerpInstruction::MigrateInstance { instance_index }
+            .try_to_vec()
+            .unwrap();
+        let accounts = vec![
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(market_account, false),
+            AccountMeta::new(admin_account, true),
+            AccountMeta::new(instance_account, false),
+        ];
+
+        Instruction {
+            program_id: audaces_protocol_program_id,
+            accounts,
+            data,
+        }
+    }
+
+    pub fn grow_user_account(
+        audaces_protocol_program_id: Pubkey,
+        market_account: Pubkey,
+        user_account: Pubkey,
+        user_account_owner: Pubkey,
+        additional_positions: u32,
+    ) -> Instruction {
+        let data = PerpInstruction::GrowUserAccount {
+            additional_positions,
+        }
+        .try_to_vec()
+        .unwrap();
+        let accounts = vec![
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(market_account, false),
+            AccountMeta::new(user_account_owner, true),
+            AccountMeta::new(user_account, false),
+        ];
 
         Instruction {
             program_id: audaces_protocol_program_id,
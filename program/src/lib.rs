@@ -1,5 +1,7 @@
 pub mod entrypoint;
 
+pub mod events;
+
 pub mod instruction;
 pub mod positions_book;
 pub mod processor;
@@ -27,6 +27,16 @@ pub enum GarbageNodeSchema {
     PointerToNext = GarbageNodeSchema::IsLastToCollect as isize + 1,
 }
 
+/// Slot usage of a memory page, slots are either allocated, on the page's free list or have never been used yet.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PageStats {
+    pub page_size: u32,
+    pub allocated_slots: u32,
+    pub free_slots: u32,
+    pub uninitialized_slots: u32,
+}
+
 pub struct Memory<'a> {
     pub pages: Vec<Page<'a>>,
     pub gc_list_hd: Option<Pointer>,
@@ -37,6 +47,23 @@ impl<'a> Memory<'a> {
         Memory { pages, gc_list_hd }
     }
 
+    /// Returns the slot usage of each memory page, in order.
+    #[cfg(not(target_arch = "bpf"))]
+    pub fn stats(&self) -> Result<Vec<PageStats>, PerpError> {
+        self.pages
+            .iter()
+            .map(|p| {
+                let free_slots = p.get_nb_free_slots()? as u32;
+                Ok(PageStats {
+                    page_size: p.page_size,
+                    allocated_slots: p.uninitialized_memory - free_slots,
+                    free_slots,
+                    uninitialized_slots: p.page_size.saturating_sub(p.uninitialized_memory),
+                })
+            })
+            .collect()
+    }
+
     pub fn crank_garbage_collector(&mut self, max_iterations: u64) -> Result<u64, PerpError> {
         let mut freed_slots = 0;
         for _ in 0..max_iterations {
@@ -157,18 +184,27 @@ impl<'a> Memory<'a> {
     }
 }
 
+/// Takes as many accounts as the instance has memory pages, which can be provided in any order.
 pub fn parse_memory<'a>(
     instance: &Instance,
     pages_infos: &[PageInfo],
     accounts_iter: &mut Iter<AccountInfo<'a>>,
 ) -> Result<Memory<'a>, ProgramError> {
-    let mut pages = vec![];
-    for page_info in pages_infos {
-        let account = next_account_info(accounts_iter)?;
-        if account.key != &Pubkey::new(&page_info.address) {
-            msg!("An invalid memory page was provided");
-            return Err(ProgramError::InvalidArgument);
-        }
+    let mut accounts = Vec::with_capacity(pages_infos.len());
+    for _ in pages_infos {
+        accounts.push(next_account_info(accounts_iter)?);
+    }
+    let mut pages = Vec::with_capacity(pages_infos.len());
+    for (page_index, page_info) in pages_infos.iter().enumerate() {
+        let address = Pubkey::new(&page_info.address);
+        let account = accounts.iter().find(|a| a.key == &address).ok_or_else(|| {
+            msg!(
+                "The memory page {} at index {} of the instance was not provided",
+                address,
+                page_index
+            );
+            ProgramError::InvalidArgument
+        })?;
         pages.push(Page::new(account, page_info)?);
     }
     Ok(Memory::new(pages, instance.garbage_pointer))
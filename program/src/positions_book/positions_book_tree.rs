@@ -67,6 +67,46 @@ impl<'a> PositionsBook<'a> {
         Ok((longs_v_pc, shorts_v_pc))
     }
 
+    /// Returns the lowest and highest liquidation indices of the given side, or None if the side is empty.
+    pub fn get_liquidation_index_range(
+        &self,
+        position_type: PositionType,
+    ) -> Result<Option<(u64, u64)>, PerpError> {
+        let root = match position_type {
+            PositionType::Short => self.shorts_root,
+            PositionType::Long => self.longs_root,
+        };
+        match root {
+            Some(pt) => Ok(Some((
+                self.get_outermost_liquidation_index(pt, InnerNodeSchema::LeftPointer)?,
+                self.get_outermost_liquidation_index(pt, InnerNodeSchema::RightPointer)?,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    // Children are ordered by liquidation index, so the extreme leaves are reached by always taking the same side
+    fn get_outermost_liquidation_index(
+        &self,
+        mut pt: Pointer,
+        side: InnerNodeSchema,
+    ) -> Result<u64, PerpError> {
+        loop {
+            match self.get_node(pt)? {
+                Node::InnerNode(_) => pt = self.memory.read_u32_le(pt, side as usize)?,
+                Node::Leaf(leaf) => return leaf.get_liquidation_index(&self.memory),
+            }
+        }
+    }
+
+    /// Whether a liquidation at the given oracle price would remove any position from the book.
+    pub fn should_liquidate(&self, liquidation_index: u64) -> Result<bool, PerpError> {
+        let longs = self.get_liquidation_index_range(PositionType::Long)?;
+        let shorts = self.get_liquidation_index_range(PositionType::Short)?;
+        Ok(matches!(longs, Some((_, max)) if max >= liquidation_index)
+            || matches!(shorts, Some((min, _)) if min <= liquidation_index))
+    }
+
     fn walk(
         &self,
         pt: Pointer,
@@ -226,10 +266,14 @@ impl<'a> PositionsBook<'a> {
         Ok(pt)
     }
 
+    /// Removes every position of the given side which is liquidated at `liquidation_index`:
+    /// longs with a liquidation index greater than or equal to it, shorts with a liquidation index lower than or equal to it.
+    /// Positions sitting exactly at the liquidation index are liquidated on both sides, consistently with the
+    /// preliquidation check performed when opening or increasing a position.
     pub fn liquidate(&mut self, liquidation_index: u64, position_type: PositionType) -> PerpResult {
-        let (root, is_short) = match position_type {
-            PositionType::Short => (self.shorts_root, true),
-            PositionType::Long => (self.longs_root, false),
+        let root = match position_type {
+            PositionType::Short => self.shorts_root,
+            PositionType::Long => self.longs_root,
         };
         if root.is_none() {
             println!("Early return");
@@ -248,7 +292,7 @@ impl<'a> PositionsBook<'a> {
                         inner_node.get_liquidation_index_min_max(critbit, &self.memory)?;
                     println!("On Inner node : critbit {:#4x}", 1u64 << critbit);
                     if liquidation_index > liq_index_max || liquidation_index < liq_index_min {
-                        if is_short ^ (liquidation_index < liq_index_min) {
+                        if position_type.is_liquidation_side(liquidation_index < liq_index_min) {
                             // The walk ends here; Liquidate current pt
                             println!("Liquidating current node");
                             collateral_to_liquidate += inner_node.get_collateral(&self.memory)?;
@@ -294,7 +338,7 @@ impl<'a> PositionsBook<'a> {
                                         let (direction, next_offset, next_pt, sibling_pt) =
                                             self.walk(pt, &liquidation_index, &critbit)?;
 
-                                        if direction ^ is_short {
+                                        if position_type.is_liquidation_side(direction) {
                                             let sibling_node = self.get_node(sibling_pt)?;
                                             collateral_to_liquidate -=
                                                 sibling_node.get_collateral(&self.memory)?;
@@ -360,7 +404,7 @@ impl<'a> PositionsBook<'a> {
                                             &current_v_pc.checked_sub(v_pc_to_liquidate).unwrap(),
                                         )?;
 
-                                        if direction ^ is_short {
+                                        if position_type.is_liquidation_side(direction) {
                                             let sibling_node = self.get_node(sibling_pt)?;
                                             collateral_to_liquidate -=
                                                 sibling_node.get_collateral(&self.memory)?;
@@ -418,7 +462,8 @@ impl<'a> PositionsBook<'a> {
                                 .read_u32_le(pt, InnerNodeSchema::RightPointer as usize)?;
                         }
                     };
-                    if direction ^ is_short {
+                    // Heading left leaves the higher liquidation indices to the sibling
+                    if position_type.is_liquidation_side(direction) {
                         // We liquidate positions which are lower than the liquidation index in the shorts tree and vice versa.
                         collateral_to_liquidate +=
                             self.get_node(sibling_pt)?.get_collateral(&self.memory)?;
@@ -429,7 +474,7 @@ impl<'a> PositionsBook<'a> {
                 }
                 Node::Leaf(leaf) => {
                     let leaf_liquidation_index = leaf.get_liquidation_index(&self.memory)?;
-                    if ((liquidation_index < leaf_liquidation_index) ^ is_short)
+                    if position_type.is_liquidation_side(liquidation_index < leaf_liquidation_index)
                         || liquidation_index == leaf_liquidation_index
                     {
                         println!("Liquidating this leaf");
@@ -471,7 +516,7 @@ impl<'a> PositionsBook<'a> {
                                     self.walk(pt, &liquidation_index, &critbit)?;
                                 println!("Headed towards {:?}", !direction);
 
-                                if direction ^ is_short {
+                                if position_type.is_liquidation_side(direction) {
                                     println!("Liquidating sibling");
                                     let sibling_node = self.get_node(sibling_pt)?;
                                     collateral_to_liquidate -=
@@ -876,6 +921,79 @@ impl<'a> PositionsBook<'a> {
 
         Ok((total_v_pc, total_v_coin, total_collateral))
     }
+
+    /// Walks the given side of the book and checks that every inner node holds the sum of its children's
+    /// collateral, v_coin and v_pc, and that its critbit and minimum liquidation index bound the leaves below it.
+    #[cfg(not(target_arch = "bpf"))]
+    pub fn verify_integrity(&self, side: PositionType) -> Result<(), PerpError> {
+        let root = match side {
+            PositionType::Short => self.shorts_root,
+            PositionType::Long => self.longs_root,
+        };
+        if let Some(pt) = root {
+            self.verify_subtree(pt, 64)?;
+        }
+        Ok(())
+    }
+
+    // Returns the collateral, v_coin and v_pc of the subtree along with its lowest and highest liquidation indices
+    #[cfg(not(target_arch = "bpf"))]
+    fn verify_subtree(
+        &self,
+        pt: Pointer,
+        parent_critbit: u8,
+    ) -> Result<([u64; 3], u64, u64), PerpError> {
+        let inner_node = match self.get_node(pt)? {
+            Node::Leaf(leaf) => {
+                let liquidation_index = leaf.get_liquidation_index(&self.memory)?;
+                let aggregates = [
+                    leaf.get_collateral(&self.memory)?,
+                    leaf.get_v_coin(&self.memory)?,
+                    leaf.get_v_pc(&self.memory)?,
+                ];
+                return Ok((aggregates, liquidation_index, liquidation_index));
+            }
+            Node::InnerNode(inner_node) => inner_node,
+        };
+        let critbit = inner_node.get_critbit(&self.memory)?;
+        if critbit >= parent_critbit {
+            return Err(PerpError::CorruptedPositionsBook);
+        }
+        let left_pt = self
+            .memory
+            .read_u32_le(pt, InnerNodeSchema::LeftPointer as usize)?;
+        let right_pt = self
+            .memory
+            .read_u32_le(pt, InnerNodeSchema::RightPointer as usize)?;
+        let (left, left_min, left_max) = self.verify_subtree(left_pt, critbit)?;
+        let (right, right_min, right_max) = self.verify_subtree(right_pt, critbit)?;
+
+        // The left subtree has the critbit cleared and the right one has it set, both sharing the node's prefix
+        let (liq_index_min, liq_index_max) =
+            inner_node.get_liquidation_index_min_max(critbit, &self.memory)?;
+        let right_subtree_min = liq_index_min | (1u64 << critbit);
+        if left_min < liq_index_min
+            || left_max >= right_subtree_min
+            || right_min < right_subtree_min
+            || right_max > liq_index_max
+        {
+            return Err(PerpError::CorruptedPositionsBook);
+        }
+
+        let mut aggregates = [0; 3];
+        for (a, (l, r)) in aggregates.iter_mut().zip(left.iter().zip(&right)) {
+            *a = l.checked_add(*r).ok_or(PerpError::Overflow)?;
+        }
+        let stored = [
+            inner_node.get_collateral(&self.memory)?,
+            inner_node.get_v_coin(&self.memory)?,
+            inner_node.get_v_pc(&self.memory)?,
+        ];
+        if aggregates != stored {
+            return Err(PerpError::CorruptedPositionsBook);
+        }
+        Ok((aggregates, left_min, right_max))
+    }
 }
 
 fn find_critbit(first_liquidation_index: &u64, second_liquidation_index: &u64) -> u8 {
@@ -886,7 +1004,7 @@ fn find_critbit(first_liquidation_index: &u64, second_liquidation_index: &u64) -
 #[cfg(test)]
 mod tests {
 
-    use std::{cell::RefCell, rc::Rc};
+    use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
 
     use super::*;
     use crate::{
@@ -950,6 +1068,7 @@ mod tests {
         for (liq_index, coll, v_coin, v_pc) in &positions {
             book.open_position(*liq_index, *coll, *v_coin, *v_pc, position_type, 0)
                 .unwrap();
+            book.verify_integrity(position_type).unwrap();
             total_coll += coll;
             total_v_coin += v_coin;
             total_v_pc += v_pc;
@@ -1013,6 +1132,7 @@ mod tests {
         for (liq_index, coll, v_coin, v_pc) in &positions {
             book.open_position(*liq_index, *coll, *v_coin, *v_pc, position_type, 0)
                 .unwrap();
+            book.verify_integrity(position_type).unwrap();
             total_coll += coll;
             total_v_coin += v_coin;
             total_v_pc += v_pc;
@@ -1026,6 +1146,7 @@ mod tests {
             0,
         )
         .unwrap();
+        book.verify_integrity(position_type).unwrap();
         let root = match position_type {
             PositionType::Long => book.longs_root,
             PositionType::Short => book.shorts_root,
@@ -1078,9 +1199,12 @@ mod tests {
         let mut total_v_pc = 0;
         let mut total_v_pc_after_liquidation = 0;
 
+        let mut any_liquidated = false;
+
         for (liq_index, coll, v_coin, v_pc) in &positions {
             book.open_position(*liq_index, *coll, *v_coin, *v_pc, position_type, 0)
                 .unwrap();
+            book.verify_integrity(position_type).unwrap();
             total_coll += coll;
             total_v_coin += v_coin;
             total_v_pc += v_pc;
@@ -1088,6 +1212,7 @@ mod tests {
                 PositionType::Long => *liq_index >= liquidation_index,
                 PositionType::Short => *liq_index <= liquidation_index,
             };
+            any_liquidated |= will_be_liquidated;
             if !will_be_liquidated {
                 total_coll_after_liquidation += coll;
                 total_v_coin_after_liquidation += v_coin;
@@ -1103,7 +1228,12 @@ mod tests {
 
         print_tree(root.unwrap(), &book.memory, 0);
 
+        assert_eq!(
+            book.should_liquidate(liquidation_index).unwrap(),
+            any_liquidated
+        );
         book.liquidate(liquidation_index, position_type).unwrap();
+        book.verify_integrity(position_type).unwrap();
         println!("============AFTER=============");
 
         let root = match position_type {
@@ -1183,6 +1313,56 @@ mod tests {
         test_liquidate(4299262263296, PositionType::Short, positions);
     }
 
+    #[test]
+    fn test_liquidation_boundary() {
+        let positions = vec![
+            (0x84, 100, 42, 908),
+            (0xfe, 101, 75, 98),
+            (0x0f, 107, 4500, 708),
+            (0x9b, 123, 78000, 408),
+            (0x52, 144, 9685, 958),
+            (0xc1, 177, 7584, 108),
+        ];
+        // Crank at the exact liquidation index of every position in the book
+        for (liquidation_index, _, _, _) in &positions {
+            test_liquidate(*liquidation_index, PositionType::Short, positions.clone());
+            test_liquidate(*liquidation_index, PositionType::Long, positions.clone());
+        }
+
+        for position_type in [PositionType::Long, PositionType::Short] {
+            let (mut data0, mut data1) = ([0u8; 1024], [0u8; 1024]);
+            let data: Vec<Rc<RefCell<&mut [u8]>>> = vec![
+                Rc::new(RefCell::new(&mut data0)),
+                Rc::new(RefCell::new(&mut data1)),
+            ];
+            let mut book = init_tree(&data);
+            for (liq_index, coll, v_coin, v_pc) in
+                &[(0x9a, 1, 1, 1), (0x9b, 1, 1, 1), (0x9c, 1, 1, 1)]
+            {
+                book.open_position(*liq_index, *coll, *v_coin, *v_pc, position_type, 0)
+                    .unwrap();
+            }
+
+            book.liquidate(0x9b, position_type).unwrap();
+            book.verify_integrity(position_type).unwrap();
+
+            // A closing order without any amount only checks whether the position is still in the book
+            let is_open = |book: &mut PositionsBook, liq_index| {
+                book.close_position(liq_index, 0, 0, 0, position_type, 0)
+                    .is_ok()
+            };
+            assert!(!is_open(&mut book, 0x9b));
+            assert_eq!(
+                is_open(&mut book, 0x9a),
+                position_type == PositionType::Long
+            );
+            assert_eq!(
+                is_open(&mut book, 0x9c),
+                position_type == PositionType::Short
+            );
+        }
+    }
+
     #[test]
     fn test_builds() {
         test_build(PositionType::Long);
@@ -1197,50 +1377,133 @@ mod tests {
         }
     }
 
-    // #[test]
-    // fn test_aggregate_position() {
-    //     let (mut data0, mut data1, mut data2, mut data3) =
-    //         ([0u8; 1024], [0u8; 1024], [0u8; 1024], [0u8; 1024]);
-    //     let data: Vec<Rc<RefCell<&mut [u8]>>> = vec![
-    //         Rc::new(RefCell::new(&mut data0)),
-    //         Rc::new(RefCell::new(&mut data1)),
-    //         Rc::new(RefCell::new(&mut data2)),
-    //         Rc::new(RefCell::new(&mut data3)),
-    //     ];
-    //     let positions = vec![
-    //         (0x84, 100, 42, 908),
-    //         (0xfe, 101, 75, 98),
-    //         (0x0f, 107, 4500, 708),
-    //         (0x9b, 123, 78000, 408),
-    //         (0x52, 144, 9685, 958),
-    //         (0xc1, 177, 7584, 108),
-    //         (0xaf, 295, 4681, 444),
-    //         (0x2f, 1045, 12346, 322),
-    //         (0xfb, 4049, 47958413, 2),
-    //         (0xb7, 7940, 42, 907),
-    //     ];
-    //     let mut book = init_tree(&data);
-    //     let position_type = PositionType::Short;
-
-    //     let mut total_coll = 0;
-    //     let mut total_v_coin = 0;
-    //     let mut total_v_pc = 0;
-
-    //     let k = 10u128.pow(14);
-
-    //     for (v_pc, coll, v_coin) in &positions {
-    //         let liq_index = compute_liquidation_index(*coll, *v_coin, *v_pc, position_type, k);
-    //         book.open_position(liq_index, *coll, *v_coin, position_type, 0)
-    //             .unwrap();
-    //         total_coll += coll;
-    //         total_v_pc +=
-    //             compute_liquidation_index_inverse(*coll, *v_coin, liq_index, position_type);
-    //         total_v_coin += v_coin;
-    //     }
-    //     let (res_v_pc, res_v_coin, res_collateral) =
-    //         book.compute_aggregate_position(position_type).unwrap();
-    //     assert_eq!(total_v_pc, res_v_pc);
-    //     assert_eq!(total_v_coin, res_v_coin);
-    //     assert_eq!(total_coll, res_collateral);
-    // }
+    fn assert_aggregates(
+        book: &PositionsBook,
+        position_type: PositionType,
+        open_positions: &BTreeMap<u64, (u64, u64, u64)>,
+    ) {
+        let root = match position_type {
+            PositionType::Long => book.longs_root,
+            PositionType::Short => book.shorts_root,
+        };
+        book.verify_integrity(position_type).unwrap();
+        if open_positions.is_empty() {
+            assert!(root.is_none());
+            return;
+        }
+        let (total_coll, total_v_coin, total_v_pc) = open_positions.values().fold(
+            (0, 0, 0),
+            |(coll, v_coin, v_pc), (p_coll, p_v_coin, p_v_pc)| {
+                (coll + p_coll, v_coin + p_v_coin, v_pc + p_v_pc)
+            },
+        );
+        let root = book.get_node(root.unwrap()).unwrap();
+        assert_eq!(root.get_collateral(&book.memory).unwrap(), total_coll);
+        assert_eq!(root.get_v_coin(&book.memory).unwrap(), total_v_coin);
+        assert_eq!(root.get_v_pc(&book.memory).unwrap(), total_v_pc);
+    }
+
+    fn test_round_trip(position_type: PositionType, positions: Vec<(u64, u64, u64, u64)>) {
+        let mut data = vec![vec![0u8; 4096]; 4];
+        let data: Vec<Rc<RefCell<&mut [u8]>>> = data
+            .iter_mut()
+            .map(|d| Rc::new(RefCell::new(&mut d[..])))
+            .collect();
+        let mut book = init_tree(&data);
+
+        // Positions sharing a liquidation index are merged into the same leaf
+        let mut open_positions = BTreeMap::new();
+        for (liq_index, coll, v_coin, v_pc) in positions {
+            book.open_position(liq_index, coll, v_coin, v_pc, position_type, 0)
+                .unwrap();
+            let p = open_positions.entry(liq_index).or_insert((0, 0, 0));
+            *p = (p.0 + coll, p.1 + v_coin, p.2 + v_pc);
+            assert_aggregates(&book, position_type, &open_positions);
+        }
+
+        for (liq_index, (coll, v_coin, v_pc)) in open_positions.clone() {
+            book.close_position(liq_index, coll, v_coin, v_pc, position_type, 0)
+                .unwrap();
+            open_positions.remove(&liq_index);
+            assert_aggregates(&book, position_type, &open_positions);
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn proptest_open_close_round_trip(
+            is_long in proptest::bool::ANY,
+            positions in proptest::collection::vec(
+                (
+                    // Small indices collide and share long prefixes, large ones spread the tree
+                    proptest::prop_oneof![1..256u64, 1..u64::MAX],
+                    1..(1u64 << 32),
+                    0..(1u64 << 32),
+                    0..(1u64 << 32),
+                ),
+                1..40,
+            ),
+        ) {
+            let position_type = match is_long {
+                true => PositionType::Long,
+                false => PositionType::Short,
+            };
+            test_round_trip(position_type, positions);
+        }
+    }
+
+    #[test]
+    fn test_aggregate_position() {
+        use crate::utils::compute_liquidation_index;
+
+        let positions = vec![
+            (0x84, 100, 42),
+            (0xfe, 101, 75),
+            (0x0f, 107, 4500),
+            (0x9b, 123, 78000),
+            (0x52, 144, 9685),
+            (0xc1, 177, 7584),
+            (0xaf, 295, 4681),
+            (0x2f, 1045, 12346),
+            (0xfb, 4049, 47958413),
+            (0xb7, 7940, 42),
+        ];
+        let k = 10u128.pow(14);
+
+        for position_type in [PositionType::Long, PositionType::Short] {
+            let mut data = vec![vec![0u8; 1024]; 4];
+            let data: Vec<Rc<RefCell<&mut [u8]>>> = data
+                .iter_mut()
+                .map(|d| Rc::new(RefCell::new(&mut d[..])))
+                .collect();
+            let mut book = init_tree(&data);
+
+            // Positions sharing a liquidation index are merged into the same leaf
+            let mut leaves = BTreeMap::new();
+            for (v_pc, coll, v_coin) in &positions {
+                let liq_index = compute_liquidation_index(*coll, *v_coin, *v_pc, position_type, k);
+                book.open_position(liq_index, *coll, *v_coin, *v_pc, position_type, 0)
+                    .unwrap();
+                book.verify_integrity(position_type).unwrap();
+                let l = leaves.entry(liq_index).or_insert((0, 0));
+                *l = (l.0 + coll, l.1 + v_coin);
+            }
+
+            // Leaves with a null liquidation index, such as longs which can't be liquidated, are left out
+            let (mut total_v_pc, mut total_v_coin, mut total_coll) = (0, 0, 0);
+            for (liq_index, (coll, v_coin)) in leaves.into_iter().filter(|(l, _)| *l != 0) {
+                total_v_pc +=
+                    compute_liquidation_index_inverse(coll, v_coin, liq_index, position_type);
+                total_v_coin += v_coin;
+                total_coll += coll;
+            }
+            assert_ne!(total_coll, 0);
+
+            let (res_v_pc, res_v_coin, res_collateral) =
+                book.compute_aggregate_position(position_type).unwrap();
+            assert_eq!(total_v_pc, res_v_pc);
+            assert_eq!(total_v_coin, res_v_coin);
+            assert_eq!(total_coll, res_collateral);
+        }
+    }
 }
@@ -7,30 +7,56 @@ use solana_program::{
 use crate::{
     instruction::PerpInstruction,
     processor::{
-        add_budget::process_add_budget, add_instance::process_add_instance,
-        add_page::process_add_page, change_k::process_change_k,
-        close_account::process_close_account, close_position::process_close_position,
-        close_withdraw::process_close_withdraw, create_market::process_create_market,
-        funding::process_funding, funding_extraction::process_funding_extraction,
+        accept_user_account_transfer::process_accept_user_account_transfer,
+        add_budget::process_add_budget,
+        add_budget_wrap_sol::process_add_budget_wrap_sol,
+        add_instance::process_add_instance,
+        add_page::process_add_page,
+        cancel_user_account_transfer::process_cancel_user_account_transfer,
+        change_admin::process_change_admin,
+        change_k::process_change_k,
+        close_account::process_close_account,
+        close_position::process_close_position,
+        close_withdraw::process_close_withdraw,
+        create_market::process_create_market,
+        funding::process_funding,
+        funding_extraction::{process_funding_extraction, process_funding_extraction_multi},
         garbage_collection::process_garbage_collection,
-        increase_position::process_increase_position, liquidation::process_liquidation,
-        open_position::process_open_position, rebalance::process_rebalance,
+        get_position::process_get_position,
+        grow_page::process_grow_page,
+        grow_user_account::process_grow_user_account,
+        increase_position::process_increase_position,
+        instance_funding::process_instance_funding,
+        liquidation::process_liquidation,
+        migrate_instance::process_migrate_instance,
+        migrate_market::process_migrate_market,
+        migrate_user_account::process_migrate_user_account,
+        open_position::{process_open_position, process_open_position_by_size},
+        propose_user_account_transfer::process_propose_user_account_transfer,
+        rebalance::process_rebalance,
+        register_referrer::process_register_referrer,
+        set_pause::process_set_pause,
+        set_stop_loss::process_set_stop_loss,
         transfer_position::process_transfer_position,
         transfer_user_account::process_transfer_user_account,
+        update_market_symbol::process_update_market_symbol,
         update_oracle_account::process_update_oracle_account,
         withdraw_budget::process_withdraw_budget,
+        withdraw_fees::process_withdraw_fees,
     },
 };
 
 ////////////////////////////////////////////////////////////
 
 pub(crate) const MARGIN_RATIO: u64 = ((1u128 << 64) / 20) as u64; // 64 fixed point
-const FUNDING_PERIOD: u64 = 3_600; // in s
-const FUNDING_NORMALIZATION: u64 = 86400 / FUNDING_PERIOD; // in s
-const MINIMAL_FUNDING: u64 = (1 << 32) * FUNDING_NORMALIZATION / 100; // FP32 the minimum fraction of funding in an imbalanced market
-const HISTORY_PERIOD: u64 = 300; // in s
+pub const FUNDING_PERIOD: u64 = 3_600; // in s
+pub(crate) const FUNDING_NORMALIZATION: u64 = 86400 / FUNDING_PERIOD; // in s
+pub(crate) const MINIMAL_FUNDING: u64 = (1 << 32) * FUNDING_NORMALIZATION / 100; // FP32 the minimum fraction of funding in an imbalanced market
+pub(crate) const HISTORY_PERIOD: u64 = 300; // in s
+pub const FUNDING_STALENESS_PERIODS: u64 = 2; // Number of funding periods without a funding crank after which the funding is reported as stalled
 pub const REBALANCING_MARGIN: i64 = 429496729; // FP32 the relative difference in longs vs shorts open interests which enables rebalancing.
 pub const REBALANCING_LEVERAGE: u64 = 1;
+pub const REBALANCING_COOLDOWN: u64 = 3_600; // in s, minimum delay between two rebalancing operations that aren't signed by the admin
 
 pub const FIDA_MINT: &str = "EchesyfXePKdLtoiZSL8pBe8Myagyy8ZRqsACNCFGnvp"; // Mainnet
 pub const FIDA_BNB: &str = "4qZA7RixzEgQ53cc6ittMeUtkaXgCnjZYkP8L1nxFD25"; // Bonfida buy and burn mainnet address
@@ -42,6 +68,8 @@ pub const FUNDING_EXTRACTION_LABEL: &str = "FundingExtraction1111111111111111111
 
 pub const MAX_LEVERAGE: u64 = 20 << 32;
 pub const MAX_POSITION_SIZE: u64 = 500_000_000_000; // in USDC
+pub const MAX_SLIPPAGE_MARGIN_BPS: u64 = 1_000; // Upper bound on the maximum slippage margin of a market, in basis points of its mark price
+pub const MAX_DECIMALS: u8 = 18; // Upper bound on the coin and quote decimals, which are used as powers of ten in price conversions
 #[cfg(not(feature = "mock-oracle"))]
 pub const MAX_OPEN_POSITONS_PER_USER: u32 = 20;
 #[cfg(feature = "mock-oracle")]
@@ -49,7 +77,7 @@ pub const MAX_OPEN_POSITONS_PER_USER: u32 = u32::MAX;
 
 // Fees
 pub const FEE_BUY_BURN_BONFIDA: u64 = 30; // Percentage of total fee
-pub const _FEE_INSURANCE_FUND: u64 = 30; // Percentage of total fee
+pub const FEE_PROTOCOL: u64 = 30; // Percentage of total fee, kept in the insurance fund until the admin withdraws it
 pub const FEE_REBALANCING_FUND: u64 = 30; // Percentage of total fee
 pub const FEE_REFERRER: u64 = 10; // Percentage of total fee, gets split up between Insurance fund and BNB if referrer is not specified
 pub const ALLOCATION_FEE: u64 = 10_000; // Flat fee that balances out the rewards, refunded if closing without liquidation
@@ -76,9 +104,13 @@ pub const FEE_TIERS: [u64; 5] = [
 
 ////////////////////////////////////////////////////////////
 
+pub mod accept_user_account_transfer;
 pub mod add_budget;
+pub mod add_budget_wrap_sol;
 pub mod add_instance;
 pub mod add_page;
+pub mod cancel_user_account_transfer;
+pub mod change_admin;
 pub mod change_k;
 pub mod close_account;
 pub mod close_position;
@@ -87,14 +119,27 @@ pub mod create_market;
 pub mod funding;
 pub mod funding_extraction;
 pub mod garbage_collection;
+pub mod get_position;
+pub mod grow_page;
+pub mod grow_user_account;
 pub mod increase_position;
+pub mod instance_funding;
 pub mod liquidation;
+pub mod migrate_instance;
+pub mod migrate_market;
+pub mod migrate_user_account;
 pub mod open_position;
+pub mod propose_user_account_transfer;
 pub mod rebalance;
+pub mod register_referrer;
+pub mod set_pause;
+pub mod set_stop_loss;
 pub mod transfer_position;
 pub mod transfer_user_account;
+pub mod update_market_symbol;
 pub mod update_oracle_account;
 pub mod withdraw_budget;
+pub mod withdraw_fees;
 
 pub struct Processor {}
 
@@ -116,6 +161,13 @@ impl Processor {
                 initial_v_pc_amount,
                 coin_decimals,
                 quote_decimals,
+                max_allowed_slippage_margin,
+                liquidation_insurance_share_bps,
+                min_collateral,
+                max_open_longs_v_coin,
+                max_open_shorts_v_coin,
+                liquidation_reward_mode,
+                liquidation_reward_bps,
             } => {
                 msg!("Instruction: Create Market");
                 process_create_market(
@@ -126,6 +178,13 @@ impl Processor {
                     initial_v_pc_amount,
                     coin_decimals,
                     quote_decimals,
+                    max_allowed_slippage_margin,
+                    liquidation_insurance_share_bps,
+                    min_collateral,
+                    max_open_longs_v_coin,
+                    max_open_shorts_v_coin,
+                    liquidation_reward_mode,
+                    liquidation_reward_bps,
                 )?;
             }
 
@@ -140,6 +199,7 @@ impl Processor {
                 leverage,
                 predicted_entry_price,
                 maximum_slippage_margin,
+                has_discount_account,
             } => {
                 msg!("Instruction: Open Position");
                 process_open_position(
@@ -151,6 +211,8 @@ impl Processor {
                     leverage,
                     predicted_entry_price,
                     maximum_slippage_margin,
+                    None,
+                    has_discount_account,
                 )?;
             }
             PerpInstruction::IncreasePosition {
@@ -160,6 +222,7 @@ impl Processor {
                 position_index,
                 predicted_entry_price,
                 maximum_slippage_margin,
+                has_discount_account,
             } => {
                 msg!("Instruction: Increase Position");
                 process_increase_position(
@@ -171,6 +234,8 @@ impl Processor {
                     add_collateral,
                     predicted_entry_price,
                     maximum_slippage_margin,
+                    None,
+                    has_discount_account,
                 )?;
             }
             PerpInstruction::ClosePosition {
@@ -179,6 +244,7 @@ impl Processor {
                 closing_v_coin,
                 predicted_entry_price,
                 maximum_slippage_margin,
+                has_discount_account,
             } => {
                 msg!("Instruction: Close Position");
                 process_close_position(
@@ -189,6 +255,7 @@ impl Processor {
                     closing_v_coin,
                     predicted_entry_price,
                     maximum_slippage_margin,
+                    has_discount_account,
                 )?;
             }
             PerpInstruction::CollectGarbage {
@@ -241,11 +308,11 @@ impl Processor {
                 instance_index,
             } => {
                 msg!("Instruction: Rebalance");
-                process_rebalance(program_id, accounts, instance_index, collateral)?;
+                process_rebalance(program_id, accounts, instance_index, collateral, false)?;
             }
-            PerpInstruction::TransferUserAccount {} => {
+            PerpInstruction::TransferUserAccount { allow_immediate } => {
                 msg!("Instruction: Transfer User Account");
-                process_transfer_user_account(program_id, accounts)?;
+                process_transfer_user_account(program_id, accounts, allow_immediate)?;
             }
             PerpInstruction::TransferPosition { position_index } => {
                 msg!("Instruction: Transfer Position");
@@ -258,6 +325,7 @@ impl Processor {
                 additional_withdraw_amount,
                 predicted_entry_price,
                 maximum_slippage_margin,
+                has_discount_account,
             } => {
                 msg!("Instruction: Close Position");
                 process_close_withdraw(
@@ -269,8 +337,175 @@ impl Processor {
                     closing_v_coin,
                     predicted_entry_price,
                     maximum_slippage_margin,
+                    has_discount_account,
                 )?;
             }
+            PerpInstruction::MigrateMarket {
+                max_allowed_slippage_margin,
+            } => {
+                msg!("Instruction: Migrate Market");
+                process_migrate_market(program_id, accounts, max_allowed_slippage_margin)?;
+            }
+            PerpInstruction::GrowPage {
+                instance_index,
+                page_index,
+                additional_bytes,
+            } => {
+                msg!("Instruction: Grow Page");
+                process_grow_page(
+                    program_id,
+                    accounts,
+                    instance_index,
+                    page_index,
+                    additional_bytes,
+                )?;
+            }
+            PerpInstruction::SetPause { paused } => {
+                msg!("Instruction: Set Pause");
+                process_set_pause(program_id, accounts, paused)?;
+            }
+            PerpInstruction::ChangeAdmin {} => {
+                msg!("Instruction: Change Admin");
+                process_change_admin(program_id, accounts)?;
+            }
+            PerpInstruction::OpenPositionBySize {
+                side,
+                collateral,
+                instance_index,
+                target_v_pc,
+                predicted_entry_price,
+                maximum_slippage_margin,
+                has_discount_account,
+            } => {
+                msg!("Instruction: Open Position By Size");
+                process_open_position_by_size(
+                    program_id,
+                    accounts,
+                    side,
+                    instance_index,
+                    collateral,
+                    target_v_pc,
+                    predicted_entry_price,
+                    maximum_slippage_margin,
+                    has_discount_account,
+                )?;
+            }
+            PerpInstruction::UpdateMarketSymbol { new_symbol } => {
+                msg!("Instruction: Update Market Symbol");
+                process_update_market_symbol(program_id, accounts, new_symbol)?;
+            }
+            PerpInstruction::ProposeUserAccountTransfer {} => {
+                msg!("Instruction: Propose User Account Transfer");
+                process_propose_user_account_transfer(program_id, accounts)?;
+            }
+            PerpInstruction::AcceptUserAccountTransfer {} => {
+                msg!("Instruction: Accept User Account Transfer");
+                process_accept_user_account_transfer(program_id, accounts)?;
+            }
+            PerpInstruction::CancelUserAccountTransfer {} => {
+                msg!("Instruction: Cancel User Account Transfer");
+                process_cancel_user_account_transfer(program_id, accounts)?;
+            }
+            PerpInstruction::RegisterReferrer {} => {
+                msg!("Instruction: Register Referrer");
+                process_register_referrer(program_id, accounts)?;
+            }
+            PerpInstruction::GetPosition { position_index } => {
+                msg!("Instruction: Get Position");
+                process_get_position(program_id, accounts, position_index)?;
+            }
+            PerpInstruction::PermissionlessRebalance {
+                collateral,
+                instance_index,
+            } => {
+                msg!("Instruction: Permissionless Rebalance");
+                process_rebalance(program_id, accounts, instance_index, collateral, true)?;
+            }
+            PerpInstruction::WithdrawFees { amount } => {
+                msg!("Instruction: Withdraw Fees");
+                process_withdraw_fees(program_id, accounts, amount)?;
+            }
+            PerpInstruction::AddBudgetWrapSol { amount } => {
+                msg!("Instruction: Add budget wrap SOL");
+                process_add_budget_wrap_sol(program_id, amount, accounts)?;
+            }
+            PerpInstruction::OpenPositionWithMaxFee {
+                side,
+                collateral,
+                instance_index,
+                leverage,
+                predicted_entry_price,
+                maximum_slippage_margin,
+                max_fee,
+                has_discount_account,
+            } => {
+                msg!("Instruction: Open Position With Max Fee");
+                process_open_position(
+                    program_id,
+                    accounts,
+                    side,
+                    instance_index,
+                    collateral,
+                    leverage,
+                    predicted_entry_price,
+                    maximum_slippage_margin,
+                    Some(max_fee),
+                    has_discount_account,
+                )?;
+            }
+            PerpInstruction::IncreasePositionWithMaxFee {
+                add_collateral,
+                instance_index,
+                leverage,
+                position_index,
+                predicted_entry_price,
+                maximum_slippage_margin,
+                max_fee,
+                has_discount_account,
+            } => {
+                msg!("Instruction: Increase Position With Max Fee");
+                process_increase_position(
+                    program_id,
+                    accounts,
+                    instance_index,
+                    leverage,
+                    position_index,
+                    add_collateral,
+                    predicted_entry_price,
+                    maximum_slippage_margin,
+                    Some(max_fee),
+                    has_discount_account,
+                )?;
+            }
+            PerpInstruction::SetStopLoss {
+                position_index,
+                stop_loss_index,
+            } => {
+                msg!("Instruction: Set Stop Loss");
+                process_set_stop_loss(program_id, accounts, position_index, stop_loss_index)?;
+            }
+            PerpInstruction::MigrateUserAccount => {
+                msg!("Instruction: Migrate User Account");
+                process_migrate_user_account(program_id, accounts)?;
+            }
+            PerpInstruction::ExtractFundingMulti { instance_indices } => {
+                msg!("Instruction: Extract funding multi");
+                process_funding_extraction_multi(program_id, &instance_indices, accounts)?;
+            }
+            PerpInstruction::CrankInstanceFunding { instance_index } => {
+                msg!("Instruction: Crank Instance Funding");
+                process_instance_funding(program_id, instance_index, accounts)?;
+            }
+            PerpInstruction::MigrateInstance { instance_index } => {
+                msg!("Instruction: Migrate Instance");
+                process_migrate_instance(program_id, accounts, instance_index)?;
+            }
+            PerpInstruction::GrowUserAccount {
+                additional_positions,
+            } => {
+                msg!("Instruction: Grow User Account");
+                process_grow_user_account(program_id, accounts, additional_positions)?;
+            }
         }
         Ok(())
     }
@@ -0,0 +1,83 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+};
+
+use crate::{
+    state::user_account::{close_transfer_proposal, TransferProposal, UserAccountState},
+    utils::{check_account_owner, check_signer},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    new_user_account_owner: &'a AccountInfo<'b>,
+    user_account: &'a AccountInfo<'b>,
+    transfer_proposal: &'a AccountInfo<'b>,
+    lamports_target: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let new_user_account_owner = next_account_info(accounts_iter)?;
+        let user_account = next_account_info(accounts_iter)?;
+        let transfer_proposal = next_account_info(accounts_iter)?;
+        let lamports_target = next_account_info(accounts_iter)?;
+
+        check_signer(new_user_account_owner)?;
+        check_account_owner(user_account, program_id)?;
+        check_account_owner(transfer_proposal, program_id)?;
+
+        Ok(Self {
+            new_user_account_owner,
+            user_account,
+            transfer_proposal,
+            lamports_target,
+        })
+    }
+}
+
+pub fn process_accept_user_account_transfer(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let transfer_proposal =
+        TransferProposal::unpack_from_slice(&accounts.transfer_proposal.data.borrow())?;
+    let mut user_account_header =
+        UserAccountState::unpack_from_slice(&accounts.user_account.data.borrow())?;
+
+    // Verifications
+    if transfer_proposal.user_account != accounts.user_account.key.to_bytes() {
+        msg!("The transfer proposal doesn't concern this user account");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if transfer_proposal.new_owner != accounts.new_user_account_owner.key.to_bytes() {
+        msg!("The transfer was proposed to a different owner");
+        return Err(ProgramError::InvalidArgument);
+    }
+    // The account could have changed hands since the proposal was made
+    if transfer_proposal.current_owner != user_account_header.owner {
+        msg!("The transfer proposal is outdated");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if transfer_proposal.current_owner != accounts.lamports_target.key.to_bytes() {
+        msg!("The transfer proposal account's lamports have to be returned to the proposer");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    user_account_header.owner = transfer_proposal.new_owner;
+    user_account_header.pack_into_slice(&mut accounts.user_account.data.borrow_mut());
+
+    close_transfer_proposal(accounts.transfer_proposal, accounts.lamports_target);
+
+    Ok(())
+}
@@ -10,7 +10,12 @@ use solana_program::{
 use spl_token::instruction::transfer;
 
 use crate::{
-    state::{is_initialized, market::MarketState, user_account::UserAccountState},
+    error::PerpError,
+    state::{
+        is_initialized,
+        market::MarketState,
+        user_account::{UserAccountState, USER_ACCOUNT_VERSION},
+    },
     utils::{check_account_key, check_account_owner, check_signer},
 };
 
@@ -60,13 +65,18 @@ pub fn process_add_budget(
 ) -> ProgramResult {
     let accounts = Accounts::parse(program_id, accounts)?;
 
+    if amount == 0 {
+        msg!("The budget amount cannot be zero");
+        return Err(PerpError::Nop.into());
+    }
+
     // Parsing
     let mut market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
 
     let mut user_account_header = match is_initialized(accounts.user_account) {
         true => UserAccountState::unpack_from_slice(&accounts.user_account.data.borrow())?,
         false => UserAccountState {
-            version: 0,
+            version: USER_ACCOUNT_VERSION,
             owner: accounts.source_owner.key.to_bytes(),
             active: false,
             market: accounts.market.key.to_bytes(),
@@ -0,0 +1,153 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction, system_program,
+    sysvar::{self, Sysvar},
+};
+use spl_token::{
+    instruction::{close_account, initialize_account},
+    native_mint,
+    state::Account,
+};
+
+use crate::{
+    processor::add_budget::process_add_budget,
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    spl_token_program: &'a AccountInfo<'b>,
+    system_program: &'a AccountInfo<'b>,
+    rent_sysvar: &'a AccountInfo<'b>,
+    native_mint: &'a AccountInfo<'b>,
+    market: &'a AccountInfo<'b>,
+    market_vault: &'a AccountInfo<'b>,
+    user_account: &'a AccountInfo<'b>,
+    source_owner: &'a AccountInfo<'b>,
+    wrapped_sol_account: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let spl_token_program = next_account_info(accounts_iter)?;
+        let system_program = next_account_info(accounts_iter)?;
+        let rent_sysvar = next_account_info(accounts_iter)?;
+        let native_mint = next_account_info(accounts_iter)?;
+        let market = next_account_info(accounts_iter)?;
+        let market_vault = next_account_info(accounts_iter)?;
+        let user_account = next_account_info(accounts_iter)?;
+        let source_owner = next_account_info(accounts_iter)?;
+        let wrapped_sol_account = next_account_info(accounts_iter)?;
+
+        check_account_key(spl_token_program, &spl_token::id())?;
+        check_account_key(system_program, &system_program::ID)?;
+        check_account_key(rent_sysvar, &sysvar::rent::ID)?;
+        check_account_key(native_mint, &native_mint::id())?;
+        check_account_owner(market, program_id)?;
+        check_account_owner(market_vault, &spl_token::id())?;
+        check_signer(source_owner)?;
+        check_signer(wrapped_sol_account)?;
+
+        Ok(Self {
+            spl_token_program,
+            system_program,
+            rent_sysvar,
+            native_mint,
+            market,
+            market_vault,
+            user_account,
+            source_owner,
+            wrapped_sol_account,
+        })
+    }
+}
+
+pub fn process_add_budget_wrap_sol(
+    program_id: &Pubkey,
+    amount: u64,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let vault = Account::unpack(&accounts.market_vault.data.borrow())?;
+    if vault.mint != native_mint::id() {
+        msg!("The market isn't quoted in wrapped SOL");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // The temporary account holds the deposited lamports on top of its rent exemption
+    invoke(
+        &system_instruction::create_account(
+            accounts.source_owner.key,
+            accounts.wrapped_sol_account.key,
+            Rent::get()?
+                .minimum_balance(Account::LEN)
+                .checked_add(amount)
+                .ok_or(ProgramError::InsufficientFunds)?,
+            Account::LEN as u64,
+            &spl_token::id(),
+        ),
+        &[
+            accounts.system_program.clone(),
+            accounts.source_owner.clone(),
+            accounts.wrapped_sol_account.clone(),
+        ],
+    )?;
+    invoke(
+        &initialize_account(
+            &spl_token::id(),
+            accounts.wrapped_sol_account.key,
+            &native_mint::id(),
+            accounts.source_owner.key,
+        )?,
+        &[
+            accounts.spl_token_program.clone(),
+            accounts.wrapped_sol_account.clone(),
+            accounts.native_mint.clone(),
+            accounts.source_owner.clone(),
+            accounts.rent_sysvar.clone(),
+        ],
+    )?;
+
+    process_add_budget(
+        program_id,
+        amount,
+        &[
+            accounts.spl_token_program.clone(),
+            accounts.market.clone(),
+            accounts.market_vault.clone(),
+            accounts.user_account.clone(),
+            accounts.source_owner.clone(),
+            accounts.wrapped_sol_account.clone(),
+        ],
+    )?;
+
+    // Returns the rent exemption of the emptied account to its owner
+    invoke(
+        &close_account(
+            &spl_token::id(),
+            accounts.wrapped_sol_account.key,
+            accounts.source_owner.key,
+            accounts.source_owner.key,
+            &[],
+        )?,
+        &[
+            accounts.spl_token_program.clone(),
+            accounts.wrapped_sol_account.clone(),
+            accounts.source_owner.clone(),
+        ],
+    )?;
+
+    Ok(())
+}
@@ -9,11 +9,11 @@ use solana_program::{
 
 use crate::{
     state::{
-        instance::{write_instance, write_page_info, Instance, PageInfo},
+        instance::{write_instance, write_page_info, Instance, PageInfo, INSTANCE_VERSION},
         is_initialized,
         market::{write_instance_address, MarketState},
     },
-    utils::{check_account_owner, check_signer},
+    utils::{check_account_owner, check_distinct_accounts, check_rent_exempt, check_signer},
 };
 
 struct Accounts<'a, 'b: 'a> {
@@ -42,6 +42,11 @@ impl<'a, 'b: 'a> Accounts<'a, 'b> {
         check_signer(admin).unwrap();
         check_account_owner(instance, program_id).unwrap();
         check_account_owner(market, program_id).unwrap();
+        check_rent_exempt(instance)?;
+        for page in memory_pages.iter() {
+            check_rent_exempt(page)?;
+        }
+        check_distinct_accounts(&memory_pages.iter().collect::<Vec<_>>())?;
 
         if is_initialized(instance) {
             msg!("Instance account is already initialized!");
@@ -69,16 +74,19 @@ pub fn process_add_instance(program_id: &Pubkey, accounts: &[AccountInfo]) -> Pr
         )?;
     }
 
+    let mut market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
+
+    // The funding periods cranked before the instance existed are never extracted from it
     let instance = Instance {
-        version: 0,
+        version: INSTANCE_VERSION,
         shorts_pointer: None,
         longs_pointer: None,
         garbage_pointer: None,
         number_of_pages: accounts.memory_pages.len() as u32,
+        funding_history_offset: market_state.funding_history_offset,
+        funding_balancing_factors: [0; 16],
     };
 
-    let mut market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
-
     if &Pubkey::new(&market_state.admin_address) != accounts.admin.key {
         msg!("Invalid admin account for the current market");
         return Err(ProgramError::InvalidArgument);
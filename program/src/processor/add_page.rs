@@ -13,7 +13,7 @@ use crate::{
         is_initialized,
         market::{get_instance_address, MarketState},
     },
-    utils::{check_account_owner, check_signer},
+    utils::{check_account_owner, check_rent_exempt, check_signer},
 };
 
 struct Accounts<'a, 'b: 'a> {
@@ -39,6 +39,7 @@ impl<'a, 'b: 'a> Accounts<'a, 'b> {
         check_account_owner(new_memory_page, program_id).unwrap();
         check_account_owner(market, program_id).unwrap();
         check_account_owner(instance, program_id).unwrap();
+        check_rent_exempt(new_memory_page)?;
 
         if is_initialized(new_memory_page) {
             msg!("Memory page account is already initialized!");
@@ -74,7 +75,14 @@ pub fn process_add_page(
         return Err(ProgramError::InvalidArgument);
     }
 
-    let (mut instance, _) = parse_instance(&accounts.instance.data.borrow())?;
+    let (mut instance, page_infos) = parse_instance(&accounts.instance.data.borrow())?;
+    if page_infos
+        .iter()
+        .any(|p| &Pubkey::new(&p.address) == accounts.new_memory_page.key)
+    {
+        msg!("The memory page already belongs to the instance");
+        return Err(ProgramError::InvalidArgument);
+    }
 
     let page_info = PageInfo::new(accounts.new_memory_page.key);
     write_page_info(
@@ -0,0 +1,71 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+};
+
+use crate::{
+    state::user_account::{close_transfer_proposal, TransferProposal, UserAccountState},
+    utils::{check_account_owner, check_signer},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    user_account_owner: &'a AccountInfo<'b>,
+    user_account: &'a AccountInfo<'b>,
+    transfer_proposal: &'a AccountInfo<'b>,
+    lamports_target: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let user_account_owner = next_account_info(accounts_iter)?;
+        let user_account = next_account_info(accounts_iter)?;
+        let transfer_proposal = next_account_info(accounts_iter)?;
+        let lamports_target = next_account_info(accounts_iter)?;
+
+        check_signer(user_account_owner)?;
+        check_account_owner(user_account, program_id)?;
+        check_account_owner(transfer_proposal, program_id)?;
+
+        Ok(Self {
+            user_account_owner,
+            user_account,
+            transfer_proposal,
+            lamports_target,
+        })
+    }
+}
+
+pub fn process_cancel_user_account_transfer(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let transfer_proposal =
+        TransferProposal::unpack_from_slice(&accounts.transfer_proposal.data.borrow())?;
+    let user_account_header =
+        UserAccountState::unpack_from_slice(&accounts.user_account.data.borrow())?;
+
+    // Verifications
+    if transfer_proposal.user_account != accounts.user_account.key.to_bytes() {
+        msg!("The transfer proposal doesn't concern this user account");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if user_account_header.owner != accounts.user_account_owner.key.to_bytes() {
+        msg!("Invalid user account owner provided");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    close_transfer_proposal(accounts.transfer_proposal, accounts.lamports_target);
+
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+};
+
+use crate::{
+    state::market::MarketState,
+    utils::{check_account_owner, check_signer},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    market: &'a AccountInfo<'b>,
+    admin: &'a AccountInfo<'b>,
+    new_admin: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let market = next_account_info(accounts_iter)?;
+        let admin = next_account_info(accounts_iter)?;
+        let new_admin = next_account_info(accounts_iter)?;
+        check_account_owner(market, program_id)?;
+        check_signer(admin)?;
+        Ok(Self {
+            market,
+            admin,
+            new_admin,
+        })
+    }
+}
+
+pub fn process_change_admin(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let mut market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
+
+    if &Pubkey::new(&market_state.admin_address) != accounts.admin.key {
+        msg!("Invalid admin account for the current market");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    msg!("New market admin: {:?}", accounts.new_admin.key);
+    market_state.admin_address = accounts.new_admin.key.to_bytes();
+
+    market_state.pack_into_slice(&mut accounts.market.data.borrow_mut());
+
+    Ok(())
+}
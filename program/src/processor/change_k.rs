@@ -52,12 +52,24 @@ pub fn process_change_k(
         return Err(ProgramError::InvalidArgument);
     }
 
-    market_state.v_coin_amount =
-        (((market_state.v_coin_amount as u128) * (factor as u128)) >> 32) as u64;
-    market_state.v_pc_amount =
-        (((market_state.v_pc_amount as u128) * (factor as u128)) >> 32) as u64;
+    market_state.v_coin_amount = scale_reserve(market_state.v_coin_amount, factor)?;
+    market_state.v_pc_amount = scale_reserve(market_state.v_pc_amount, factor)?;
 
     market_state.pack_into_slice(&mut accounts.market.data.borrow_mut());
 
     Ok(())
 }
+
+// The vAMM reserves are handled as i64 when trading, which bounds the scaled amounts
+fn scale_reserve(amount: u64, factor: u64) -> Result<u64, PerpError> {
+    let scaled = ((amount as u128) * (factor as u128)) >> 32;
+    if scaled > i64::MAX as u128 {
+        msg!("The given factor is too large for the current vAMM reserves");
+        return Err(PerpError::AmountTooLarge);
+    }
+    if scaled == 0 {
+        msg!("The given factor would empty the vAMM reserves");
+        return Err(PerpError::AmountTooLow);
+    }
+    Ok(scaled as u64)
+}
@@ -13,8 +13,9 @@ use solana_program::{
 
 use crate::{
     error::PerpError,
+    events::PerpEvent,
     positions_book::{memory::parse_memory, positions_book_tree::PositionsBook},
-    processor::{FUNDING_NORMALIZATION, FUNDING_PERIOD, MAX_LEVERAGE},
+    processor::{FUNDING_PERIOD, MAX_LEVERAGE},
     state::{
         instance::{parse_instance, write_instance_and_memory},
         market::{get_instance_address, MarketState},
@@ -23,7 +24,7 @@ use crate::{
     state::{user_account::UserAccountState, PositionType},
     utils::{
         check_account_key, check_account_owner, check_signer, compute_fee_tier, compute_fees,
-        compute_liquidation_index, get_oracle_price,
+        compute_leverage, compute_liquidation_index, get_oracle_price, get_vault_balance,
     },
 };
 
@@ -96,14 +97,13 @@ pub fn process_close_position(
     closing_v_coin: u64,
     predicted_entry_price: u64,   // 32 bit FP
     maximum_slippage_margin: u64, // 32 bit FP
+    has_discount_account: bool,
 ) -> ProgramResult {
     let mut accounts = Accounts::parse(program_id, accounts)?;
 
     // Parsing
     let mut market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
 
-    market_state.slippage_protection(predicted_entry_price, maximum_slippage_margin)?;
-
     let mut user_account_header =
         UserAccountState::unpack_from_slice(&accounts.user_account.data.borrow())?;
     let mut open_position = get_position(
@@ -118,29 +118,25 @@ pub fn process_close_position(
     )?;
     if &instance_address != accounts.instance.key {
         msg!("Invalid instance account or instance index provided");
-        return Err(ProgramError::InvalidArgument);
+        return Err(PerpError::InstanceMismatch.into());
     }
 
     let (mut instance, mut page_infos) = parse_instance(&accounts.instance.data.borrow())?;
 
-    if user_account_header.number_of_open_positions <= (position_index as u32) {
-        msg!("Position index is invalid");
-        return Err(ProgramError::InvalidArgument);
-    }
-
     let memory = parse_memory(&instance, &page_infos, &mut accounts.remaining)?;
     let mut positions_book =
         PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
+    instance.record_funding(&positions_book, &market_state)?;
 
     // Verifications
     if market_state.oracle_address != accounts.oracle.key.to_bytes() {
         msg!("Provided oracle account is incorrect.");
-        return Err(ProgramError::InvalidArgument);
+        return Err(PerpError::OracleMismatch.into());
     }
 
     if &Pubkey::new(&user_account_header.market) != accounts.market.key {
         msg!("The user account market doesn't match the given market account");
-        return Err(ProgramError::InvalidArgument);
+        return Err(PerpError::MarketMismatch.into());
     }
     if user_account_header.last_funding_offset != market_state.funding_history_offset {
         msg!("Funding must be processed for this account.");
@@ -172,21 +168,43 @@ pub fn process_close_position(
         Err(e) => Err(e).unwrap(),
     }
 
+    let oracle_price = get_oracle_price(
+        &accounts.oracle.data.borrow(),
+        market_state.coin_decimals,
+        market_state.quote_decimals,
+    )?;
+
     // User account owner verification delay to allow for permissionless purging of liquidated positions.
 
-    if *accounts.user_account_owner.key != Pubkey::new(&user_account_header.owner) {
-        msg!("The user account owner is invalid");
-        return Err(ProgramError::InvalidArgument);
+    let stop_loss_close =
+        *accounts.user_account_owner.key != Pubkey::new(&user_account_header.owner);
+    if stop_loss_close {
+        // Positions which crossed their stop-loss can be closed entirely by anyone
+        let full_close = closing_collateral >= open_position.collateral
+            && closing_v_coin >= open_position.v_coin_amount;
+        if !(full_close && open_position.is_stop_loss_triggered(oracle_price)) {
+            msg!("The user account owner is invalid");
+            return Err(ProgramError::InvalidArgument);
+        }
+        msg!(
+            "Closing the position at its stop-loss index: {:?}",
+            open_position.stop_loss_index
+        );
+    }
+
+    // Slippage is only checked for actual trades so that liquidated positions can be purged with any margin.
+    // Stop-loss closes are market orders triggered by the oracle price, whoever sends them can't predict
+    // their fill price on behalf of the owner.
+    if !stop_loss_close {
+        market_state.slippage_protection(predicted_entry_price, maximum_slippage_margin)?;
     }
 
+    let insurance_fund_before = market_state
+        .get_insurance_fund(get_vault_balance(&market_state, accounts.market_vault)?)?;
+
     let clock = Clock::from_account_info(accounts.clock_sysvar)?;
     let current_timestamp = clock.unix_timestamp;
 
-    let oracle_price = get_oracle_price(
-        &accounts.oracle.data.borrow(),
-        market_state.coin_decimals,
-        market_state.quote_decimals,
-    )?;
     let mut closing_collateral_ltd = core::cmp::min(closing_collateral, open_position.collateral);
 
     let closing_v_coin_ltd = core::cmp::min(closing_v_coin, open_position.v_coin_amount);
@@ -250,6 +268,14 @@ pub fn process_close_position(
         closing_v_coin_ltd,
         open_position.side,
     );
+    if closing_v_coin_ltd != 0 && !stop_loss_close {
+        market_state.fill_price_protection(
+            predicted_entry_price,
+            maximum_slippage_margin,
+            v_pc_closing_amount.abs() as u64,
+            closing_v_coin_ltd,
+        )?;
+    }
 
     let payout_ltd = core::cmp::max(payout, 0) as u64;
 
@@ -264,9 +290,7 @@ pub fn process_close_position(
         // The position doesn't have to pay funding when it happens before the current cycle's funding crank (unlikely)
         // We calculate the funding ratio for the current funding cycle until now
 
-        let s = market_state.funding_samples_sum;
-        let denom = (market_state.funding_samples_count as u64) * FUNDING_NORMALIZATION;
-        let funding_ratio = s.signum() * ((s.abs() as u64).checked_div(denom).unwrap_or(0)) as i64;
+        let funding_ratio = market_state.current_funding_rate_fp32();
 
         let position_v_coin = open_position.side.get_sign() * (open_position.v_coin_amount as i64);
         let mut funding_ratio = (position_v_coin.signum() * funding_ratio) as i128;
@@ -295,7 +319,14 @@ pub fn process_close_position(
             msg!("There is some collateral left on this position. Zero-leverage positions are not supported.");
             return Err(PerpError::AmountTooLow.into());
         }
-        // TODO: We don't need to compute the liquidation index here. Optimize
+        if open_position.collateral < market_state.min_collateral {
+            msg!(
+                "The remaining collateral is below the market's minimum of {:?}, the position should be closed entirely",
+                market_state.min_collateral
+            );
+            return Err(PerpError::AmountTooLow.into());
+        }
+        // Only partially closed positions get here, they are re-inserted in the book at their new liquidation index
         let new_liquidation_index = compute_liquidation_index(
             open_position.collateral,
             open_position.v_coin_amount,
@@ -336,9 +367,7 @@ pub fn process_close_position(
         )?;
     }
 
-    let new_leverage = ((open_position.v_pc_amount << 32) as u128)
-        .checked_div(open_position.collateral as u128)
-        .unwrap_or(0) as u64; // In the case in which there is no collateral (closing the position), the leverage is 0
+    let new_leverage = compute_leverage(open_position.v_pc_amount, open_position.collateral);
     if new_leverage > MAX_LEVERAGE {
         msg!(
             "New leverage cannot be higher than: {:?}. Found: {:?}",
@@ -349,7 +378,7 @@ pub fn process_close_position(
     }
 
     // Fees for the partial closing
-    let fee_tier = compute_fee_tier(&mut accounts.remaining)?;
+    let fee_tier = compute_fee_tier(&mut accounts.remaining, has_discount_account)?;
     let mut closing_fees = compute_fees(fee_tier, v_pc_closing_amount.abs() as u64, new_leverage)?;
 
     msg!(
@@ -359,6 +388,7 @@ pub fn process_close_position(
     );
 
     let referrer_account_opt = next_account_info(&mut accounts.remaining).ok();
+    let referrer_record_opt = next_account_info(&mut accounts.remaining).ok();
     market_state.transfer_fees(
         &mut closing_fees,
         accounts.spl_token_program,
@@ -367,6 +397,7 @@ pub fn process_close_position(
         accounts.market_signer,
         accounts.bnb_bonfida,
         referrer_account_opt,
+        referrer_record_opt,
     )?;
 
     market_state.apply_fees(&closing_fees, open_position.collateral == 0, false)?;
@@ -403,7 +434,24 @@ pub fn process_close_position(
         &page_infos,
         &instance,
     )?;
+    market_state.check_insurance_fund(
+        insurance_fund_before,
+        get_vault_balance(&market_state, accounts.market_vault)?,
+    )?;
     market_state.pack_into_slice(&mut accounts.market.data.borrow_mut());
 
+    PerpEvent::ClosedPosition {
+        market: accounts.market.key.to_bytes(),
+        user_account: accounts.user_account.key.to_bytes(),
+        instance_index: open_position.instance_index,
+        side: open_position.side,
+        closed_collateral: closing_collateral_ltd,
+        closed_v_coin_amount: closing_v_coin_ltd,
+        closed_v_pc_amount: v_pc_to_settle as u64,
+        payout,
+        fees: closing_fees.total,
+    }
+    .emit();
+
     Ok(())
 }
@@ -11,9 +11,12 @@ use solana_program::{
 use spl_token::state::Account;
 
 use crate::{
-    processor::{FUNDING_PERIOD, HISTORY_PERIOD},
-    state::market::MarketState,
-    utils::get_oracle_price,
+    processor::{FUNDING_PERIOD, HISTORY_PERIOD, MAX_DECIMALS},
+    state::{
+        market::{MarketState, MARKET_STATE_VERSION},
+        LiquidationRewardMode,
+    },
+    utils::{check_pyth_market_symbol, check_rent_exempt, get_oracle_price, pack_market_symbol},
 };
 
 pub struct Accounts<'a, 'b: 'a> {
@@ -22,6 +25,7 @@ pub struct Accounts<'a, 'b: 'a> {
     oracle: &'a AccountInfo<'b>,
     admin: &'a AccountInfo<'b>,
     vault: &'a AccountInfo<'b>,
+    pyth_product: Option<&'a AccountInfo<'b>>,
 }
 
 impl<'a, 'b: 'a> Accounts<'a, 'b> {
@@ -33,6 +37,9 @@ impl<'a, 'b: 'a> Accounts<'a, 'b> {
         let oracle = next_account_info(accounts_iter)?;
         let admin = next_account_info(accounts_iter)?;
         let vault = next_account_info(accounts_iter)?;
+        let pyth_product = next_account_info(accounts_iter).ok();
+
+        check_rent_exempt(market)?;
 
         if market.data.borrow()[0] != 0 {
             msg!("Market account is already initialized.");
@@ -45,10 +52,12 @@ impl<'a, 'b: 'a> Accounts<'a, 'b> {
             oracle,
             admin,
             vault,
+            pyth_product,
         })
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn process_create_market(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
@@ -57,9 +66,38 @@ pub fn process_create_market(
     initial_v_pc_amount: u64,
     coin_decimals: u8,
     quote_decimals: u8,
+    max_allowed_slippage_margin: u64,
+    liquidation_insurance_share_bps: u16,
+    min_collateral: u64,
+    max_open_longs_v_coin: u64,
+    max_open_shorts_v_coin: u64,
+    liquidation_reward_mode: LiquidationRewardMode,
+    liquidation_reward_bps: u16,
 ) -> ProgramResult {
     let accounts = Accounts::parse(accounts)?;
 
+    if liquidation_insurance_share_bps > 10_000 {
+        msg!("The liquidation insurance share cannot exceed 10000 basis points");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if liquidation_reward_bps > 10_000 {
+        msg!("The liquidation reward cannot exceed 10000 basis points of the liquidated notional");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if coin_decimals > MAX_DECIMALS || quote_decimals > MAX_DECIMALS {
+        msg!(
+            "The coin and quote decimals cannot exceed {:?}",
+            MAX_DECIMALS
+        );
+        return Err(ProgramError::InvalidArgument);
+    }
+    if market_symbol.is_empty() || market_symbol.len() > 32 {
+        msg!("The market symbol must be between 1 and 32 bytes long");
+        return Err(ProgramError::InvalidArgument);
+    }
+    check_pyth_market_symbol(accounts.oracle, accounts.pyth_product, &market_symbol)?;
+
     let oracle_price = get_oracle_price(
         &accounts.oracle.data.borrow(),
         coin_decimals,
@@ -92,19 +130,13 @@ pub fn process_create_market(
         return Err(ProgramError::InvalidArgument);
     }
 
-    let mut market_symbol_slice = [0u8; 32];
-    let market_symbol_bytes = market_symbol.as_bytes();
-    if market_symbol_bytes.len() > 32 {
-        msg!("Given market symbol is too long.");
-        return Err(ProgramError::InvalidAccountData);
-    }
-    market_symbol_slice[..market_symbol_bytes.len()].copy_from_slice(market_symbol_bytes);
+    let market_symbol_slice = pack_market_symbol(&market_symbol)?;
     msg!("Creating Market {:?}", market_symbol);
 
     let current_timestamp = Clock::from_account_info(accounts.clock_sysvar)?.unix_timestamp as u64;
 
     let market_state = MarketState {
-        version: 0,
+        version: MARKET_STATE_VERSION,
         signer_nonce,
         market_symbol: market_symbol_slice,
         oracle_address: accounts.oracle.key.to_bytes(),
@@ -131,7 +163,19 @@ pub fn process_create_market(
         rebalancing_funds: 0,
         rebalanced_v_coin: 0,
         number_of_instances: 0,
+        max_allowed_slippage_margin,
+        liquidation_insurance_share_bps,
+        paused: false,
+        min_collateral,
+        max_open_longs_v_coin,
+        max_open_shorts_v_coin,
+        last_rebalance_timestamp: 0,
+        liquidation_reward_mode,
+        liquidation_reward_bps,
+        protocol_fee_balance: 0,
+        reserved: [0; 7],
     };
+    market_state.check_max_allowed_slippage_margin(max_allowed_slippage_margin)?;
 
     market_state.pack_into_slice(&mut accounts.market.data.borrow_mut());
 
@@ -13,9 +13,10 @@ use solana_program::{
 
 use crate::{
     error::PerpError,
+    events::PerpEvent,
     positions_book::{memory::parse_memory, positions_book_tree::PositionsBook},
     state::{
-        instance::{parse_instance, write_instance_and_memory},
+        instance::{parse_instance, write_instance, write_instance_and_memory},
         market::{get_instance_address, MarketState},
         user_account::{get_position, remove_position, write_position},
     },
@@ -26,7 +27,7 @@ use crate::{
     },
 };
 
-use super::{FUNDING_EXTRACTION_LABEL, MINIMAL_FUNDING};
+use super::FUNDING_EXTRACTION_LABEL;
 
 pub struct Accounts<'a, 'b: 'a> {
     market: &'a AccountInfo<'b>,
@@ -85,24 +86,28 @@ pub fn process_funding_extraction(
 
     if &Pubkey::new(&user_account_header.market) != accounts.market.key {
         msg!("The user account market doesn't match the given market account");
-        return Err(ProgramError::InvalidArgument);
+        return Err(PerpError::MarketMismatch.into());
     }
 
     let instance_address =
         get_instance_address(&accounts.market.data.borrow(), instance_index as u32)?;
     if &instance_address != accounts.instance.key {
         msg!("Invalid instance account or instance index provided");
-        return Err(ProgramError::InvalidArgument);
+        return Err(PerpError::InstanceMismatch.into());
     }
 
     if market_state.oracle_address != accounts.oracle.key.to_bytes() {
         msg!("Provided oracle account is incorrect.");
-        return Err(ProgramError::InvalidArgument);
+        return Err(PerpError::OracleMismatch.into());
     }
 
     let (mut instance, mut page_infos) = parse_instance(&accounts.instance.data.borrow())?;
     let memory = parse_memory(&instance, &page_infos, &mut accounts.remaining)?;
     let mut book = PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
+    // Catches up with the funding periods the instance crank hasn't recorded yet
+    if instance.record_funding(&book, &market_state)? {
+        write_instance(&mut accounts.instance.data.borrow_mut(), &instance)?;
+    }
 
     let mut positions_v_coin = 0i64;
     let mut positions_collateral = 0u64;
@@ -141,12 +146,7 @@ pub fn process_funding_extraction(
             )?;
         } else {
             last_funding_offset_total = cmp::max(
-                market_state
-                    .funding_history_offset
-                    .checked_add(market_state.funding_history.len() as u8)
-                    .and_then(|a| a.checked_sub(p.last_funding_offset))
-                    .map(|a| a % (market_state.funding_history.len() as u8))
-                    .unwrap(),
+                market_state.funding_cycles_since(p.last_funding_offset),
                 last_funding_offset_total,
             );
         }
@@ -157,30 +157,34 @@ pub fn process_funding_extraction(
         return Err(PerpError::Nop.into());
     }
 
-    let mut balanced_funding_ratio = 0;
-    let mut i = last_funding_offset.unwrap();
-    let cycle = market_state.funding_history.len();
-    while i != funding_history_offset {
-        // Iterate to account for missed extractions
-        // Market Price is included in funding_history
-        let mut delta = (positions_v_coin.signum() * market_state.funding_history[i]) as i128;
-        if delta.is_negative() {
-            // In the case where the funding is positive for the user, inflate it for arbitrage incentive.
-            delta = (delta
-                * (std::cmp::max(market_state.funding_balancing_factors[i], MINIMAL_FUNDING)
-                    as i128))
-                >> 32;
-        }
-        // Add all missed funding ratios together
-        balanced_funding_ratio += delta;
-        i = (i + 1) % cycle;
+    // All the funding cycles missed since the last extraction are added together
+    let balanced_funding_ratio = market_state.accrued_funding_ratio(
+        &instance.funding_balancing_factors,
+        last_funding_offset.unwrap() as u8,
+        positions_v_coin.signum(),
+    );
+
+    // The account stays behind, at the oldest cycle pending on its other instances, until they are all extracted
+    user_account_header.last_funding_offset =
+        market_state.funding_offset_before(last_funding_offset_total);
+
+    if balanced_funding_ratio == 0 || positions_v_coin == 0 {
+        // Only the funding offsets have to be caught up, the positions book and the market are left untouched.
+        // Returning Nop would revert these offsets and leave the account with pending funding.
+        msg!("No funding accumulated for this account on this instance");
+        user_account_header.pack_into_slice(&mut accounts.user_account.data.borrow_mut());
+        return Ok(());
     }
 
     let balanced_debt =
         (((positions_v_coin.abs() as i128) * (balanced_funding_ratio)) >> 32) as i64;
 
+    let mut extracted_funding = balanced_debt;
+
     if balanced_debt > (user_account_header.balance as i64) {
         msg!("This account has insufficient funds and must be liquidated");
+        // The balance is used up first, then what the positions on this instance can cover
+        extracted_funding = user_account_header.balance as i64;
         // Liquidate all positions.
         let mut remaining_debt = balanced_debt - (user_account_header.balance as i64);
         for position_index in (0..user_account_header.number_of_open_positions).rev() {
@@ -190,6 +194,8 @@ pub fn process_funding_extraction(
                 position_index as u16,
             )?;
             if p.instance_index == instance_index {
+                // The collateral is reduced below when the position is kept open
+                let position_collateral = p.collateral;
                 let res = book.close_position(
                     p.liquidation_index,
                     p.collateral,
@@ -245,10 +251,14 @@ pub fn process_funding_extraction(
                             &p,
                             true,
                         )?;
+                        extracted_funding += remaining_debt;
                         break;
                     }
                 }
+                // A position missing from the book was already liquidated by the crank, which accounted for its
+                // collateral and open interest. It is only removed from the user account and doesn't cover any debt.
                 if res.is_ok() {
+                    extracted_funding += position_payout.max(0).min(remaining_debt);
                     remaining_debt -= position_payout;
                     let (balanced_v_pc, balanced_v_coin) =
                         market_state.balance_operation(v_pc_amount, v_coin_amount, oracle_price)?;
@@ -256,7 +266,7 @@ pub fn process_funding_extraction(
                     market_state.add_v_coin(balanced_v_coin)?;
                     market_state.total_collateral = market_state
                         .total_collateral
-                        .checked_sub(p.collateral)
+                        .checked_sub(position_collateral)
                         .unwrap();
                     market_state.sub_open_interest(p.v_coin_amount, p.v_pc_amount, p.side)?;
                 }
@@ -291,10 +301,6 @@ pub fn process_funding_extraction(
         );
     }
 
-    user_account_header.last_funding_offset = market_state
-        .funding_history_offset
-        .wrapping_sub(last_funding_offset_total);
-
     user_account_header.pack_into_slice(&mut accounts.user_account.data.borrow_mut());
     instance.update(&book, &mut page_infos);
     write_instance_and_memory(
@@ -304,5 +310,57 @@ pub fn process_funding_extraction(
     )?;
     market_state.pack_into_slice(&mut accounts.market.data.borrow_mut());
 
+    PerpEvent::FundingExtracted {
+        market: accounts.market.key.to_bytes(),
+        user_account: accounts.user_account.key.to_bytes(),
+        instance_index,
+        amount: extracted_funding,
+    }
+    .emit();
+
+    Ok(())
+}
+
+// Processes the funding of a user account on several instances in a single instruction.
+// The instances without any funding to process for this account are skipped.
+pub fn process_funding_extraction_multi(
+    program_id: &Pubkey,
+    instance_indices: &[u8],
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let mut accounts_iter = accounts.iter();
+    let market = next_account_info(&mut accounts_iter)?;
+    let user_account = next_account_info(&mut accounts_iter)?;
+    let label_account = next_account_info(&mut accounts_iter)?;
+    let oracle = next_account_info(&mut accounts_iter)?;
+
+    let mut processed = false;
+    for &instance_index in instance_indices {
+        let instance = next_account_info(&mut accounts_iter)?;
+        let number_of_pages = parse_instance(&instance.data.borrow())?.1.len();
+        let mut instance_accounts = Vec::with_capacity(5 + number_of_pages);
+        instance_accounts.extend_from_slice(&[
+            market.clone(),
+            instance.clone(),
+            user_account.clone(),
+            label_account.clone(),
+            oracle.clone(),
+        ]);
+        for _ in 0..number_of_pages {
+            instance_accounts.push(next_account_info(&mut accounts_iter)?.clone());
+        }
+        match process_funding_extraction(program_id, instance_index, &instance_accounts) {
+            Err(e) if e == ProgramError::from(PerpError::Nop) => {}
+            r => {
+                r?;
+                processed = true;
+            }
+        }
+    }
+
+    if !processed {
+        msg!("No funding to process for this account on the given instances");
+        return Err(PerpError::Nop.into());
+    }
     Ok(())
 }
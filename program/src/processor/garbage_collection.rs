@@ -18,7 +18,9 @@ use crate::{
         instance::{parse_instance, write_instance_and_memory},
         market::{get_instance_address, MarketState},
     },
-    utils::{check_account_key, check_account_owner},
+    utils::{
+        check_account_key, check_account_owner, check_distinct_accounts, check_target_token_account,
+    },
 };
 
 use super::ALLOCATION_FEE;
@@ -49,6 +51,8 @@ impl<'a, 'b: 'a> Accounts<'a, 'b> {
 
         check_account_key(spl_token_program, &spl_token::id()).unwrap();
         check_account_owner(market, program_id).unwrap();
+        check_distinct_accounts(&[market_vault, target_fee])?;
+        check_target_token_account(target_fee, market_vault)?;
 
         Ok(Self {
             spl_token_program,
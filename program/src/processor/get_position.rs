@@ -0,0 +1,112 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::set_return_data,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+};
+
+use borsh::BorshSerialize;
+
+use crate::{
+    state::{
+        market::MarketState,
+        user_account::{
+            get_position, PositionSnapshot, UserAccountState, POSITION_SNAPSHOT_VERSION,
+        },
+        PositionType,
+    },
+    utils::{check_account_owner, compute_payout, get_oracle_price},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    market: &'a AccountInfo<'b>,
+    user_account: &'a AccountInfo<'b>,
+    oracle: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let market = next_account_info(accounts_iter)?;
+        let user_account = next_account_info(accounts_iter)?;
+        let oracle = next_account_info(accounts_iter)?;
+
+        check_account_owner(market, program_id)?;
+        check_account_owner(user_account, program_id)?;
+
+        Ok(Self {
+            market,
+            user_account,
+            oracle,
+        })
+    }
+}
+
+pub fn process_get_position(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    position_index: u16,
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
+    let user_account_header =
+        UserAccountState::unpack_from_slice(&accounts.user_account.data.borrow())?;
+
+    // Verifications
+    if market_state.oracle_address != accounts.oracle.key.to_bytes() {
+        msg!("Provided oracle account is incorrect.");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if user_account_header.market != accounts.market.key.to_bytes() {
+        msg!("The user account market doesn't match the given market account");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let position = get_position(
+        &accounts.user_account.data.borrow(),
+        &user_account_header,
+        position_index,
+    )?;
+
+    let oracle_price = get_oracle_price(
+        &accounts.oracle.data.borrow(),
+        market_state.coin_decimals,
+        market_state.quote_decimals,
+    )?;
+
+    // Value of the position if it were closed entirely at the current mark price
+    let signed_v_coin_amount = position.side.get_sign() * (position.v_coin_amount as i64);
+    let closing_v_pc_amount = market_state.compute_add_v_pc(signed_v_coin_amount)?.abs() as u64;
+    let equity = compute_payout(
+        closing_v_pc_amount,
+        position.v_pc_amount,
+        position.collateral,
+        &position.side,
+    );
+    let margin_ratio = ((equity as i128) << 32)
+        .checked_div(closing_v_pc_amount as i128)
+        .unwrap_or(0) as i64;
+    let liquidation_distance = match position.side {
+        PositionType::Long => (oracle_price as i64) - (position.liquidation_index as i64),
+        PositionType::Short => (position.liquidation_index as i64) - (oracle_price as i64),
+    };
+
+    let snapshot = PositionSnapshot {
+        version: POSITION_SNAPSHOT_VERSION,
+        position,
+        equity,
+        margin_ratio,
+        liquidation_distance,
+    };
+    set_return_data(&snapshot.try_to_vec().unwrap());
+
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
+    msg,
+    program::invoke,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction, system_program,
+    sysvar::Sysvar,
+};
+
+use crate::{
+    error::PerpError,
+    positions_book::memory::{SLOT_SIZE, TAG_SIZE},
+    state::{
+        instance::parse_instance,
+        market::{get_instance_address, MarketState},
+    },
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    system_program: &'a AccountInfo<'b>,
+    market: &'a AccountInfo<'b>,
+    admin: &'a AccountInfo<'b>,
+    instance: &'a AccountInfo<'b>,
+    memory_page: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let system_program = next_account_info(accounts_iter)?;
+        let market = next_account_info(accounts_iter)?;
+        let admin = next_account_info(accounts_iter)?;
+        let instance = next_account_info(accounts_iter)?;
+        let memory_page = next_account_info(accounts_iter)?;
+
+        check_account_key(system_program, &system_program::ID).unwrap();
+        check_signer(admin).unwrap();
+        check_account_owner(market, program_id).unwrap();
+        check_account_owner(instance, program_id).unwrap();
+        check_account_owner(memory_page, program_id).unwrap();
+
+        Ok(Self {
+            system_program,
+            market,
+            admin,
+            instance,
+            memory_page,
+        })
+    }
+}
+
+pub fn process_grow_page(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instance_index: u8,
+    page_index: u8,
+    additional_bytes: u64,
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let instance_address =
+        get_instance_address(&accounts.market.data.borrow(), instance_index as u32)?;
+    let market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
+    // Verifications
+    if &instance_address != accounts.instance.key {
+        msg!("Invalid instance account or instance index provided");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if &Pubkey::new(&market_state.admin_address) != accounts.admin.key {
+        msg!("Invalid admin account for the current market");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let (_, page_infos) = parse_instance(&accounts.instance.data.borrow())?;
+    let page_info = page_infos.get(page_index as usize).ok_or_else(|| {
+        msg!("Invalid page index provided");
+        ProgramError::InvalidArgument
+    })?;
+    if &Pubkey::new(&page_info.address) != accounts.memory_page.key {
+        msg!("Invalid memory page account or page index provided");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    if additional_bytes > MAX_PERMITTED_DATA_INCREASE as u64 {
+        msg!(
+            "A page can grow by at most {:?} bytes per instruction",
+            MAX_PERMITTED_DATA_INCREASE
+        );
+        return Err(PerpError::AmountTooLarge.into());
+    }
+    let new_len = accounts
+        .memory_page
+        .data_len()
+        .checked_add(additional_bytes as usize)
+        .ok_or(PerpError::Overflow)?;
+
+    // The page size is derived from the account's length when the memory is parsed. The uninitialized memory index
+    // and free slots of the page are left untouched as they remain valid in the larger page.
+    let new_page_size = (new_len - TAG_SIZE) / SLOT_SIZE;
+    if new_page_size < page_info.unitialized_memory_index as usize {
+        msg!("The page's uninitialized memory index is invalid");
+        return Err(PerpError::MemoryError.into());
+    }
+
+    let rent_top_up = Rent::get()?
+        .minimum_balance(new_len)
+        .saturating_sub(accounts.memory_page.lamports());
+    if rent_top_up > 0 {
+        invoke(
+            &system_instruction::transfer(
+                accounts.admin.key,
+                accounts.memory_page.key,
+                rent_top_up,
+            ),
+            &[
+                accounts.system_program.clone(),
+                accounts.admin.clone(),
+                accounts.memory_page.clone(),
+            ],
+        )?;
+    }
+
+    accounts.memory_page.realloc(new_len, true)?;
+
+    msg!("Memory page now holds {:?} slots", new_page_size);
+
+    Ok(())
+}
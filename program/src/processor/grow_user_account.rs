@@ -0,0 +1,128 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
+    msg,
+    program::invoke,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction, system_program,
+    sysvar::Sysvar,
+};
+
+use crate::{
+    error::PerpError,
+    processor::MAX_OPEN_POSITONS_PER_USER,
+    state::user_account::{max_positions, OpenPosition, UserAccountState},
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    system_program: &'a AccountInfo<'b>,
+    market: &'a AccountInfo<'b>,
+    user_account_owner: &'a AccountInfo<'b>,
+    user_account: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let system_program = next_account_info(accounts_iter)?;
+        let market = next_account_info(accounts_iter)?;
+        let user_account_owner = next_account_info(accounts_iter)?;
+        let user_account = next_account_info(accounts_iter)?;
+
+        check_account_key(system_program, &system_program::ID)?;
+        check_account_owner(market, program_id)?;
+        check_signer(user_account_owner)?;
+        check_account_owner(user_account, program_id)?;
+
+        Ok(Self {
+            system_program,
+            market,
+            user_account_owner,
+            user_account,
+        })
+    }
+}
+
+pub fn process_grow_user_account(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    additional_positions: u32,
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let user_account_header =
+        UserAccountState::unpack_from_slice(&accounts.user_account.data.borrow())?;
+
+    // Verifications
+    if &Pubkey::new(&user_account_header.market) != accounts.market.key {
+        msg!("The user account market doesn't match the given market account");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if *accounts.user_account_owner.key != Pubkey::new(&user_account_header.owner) {
+        msg!("The user account owner is invalid");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let additional_bytes = (additional_positions as usize)
+        .checked_mul(OpenPosition::LEN)
+        .ok_or(PerpError::Overflow)?;
+    if additional_bytes == 0 {
+        msg!("The user account has to grow by at least one position");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if additional_bytes > MAX_PERMITTED_DATA_INCREASE {
+        msg!(
+            "A user account can grow by at most {:?} positions per instruction",
+            MAX_PERMITTED_DATA_INCREASE / OpenPosition::LEN
+        );
+        return Err(PerpError::AmountTooLarge.into());
+    }
+    let new_len = accounts
+        .user_account
+        .data_len()
+        .checked_add(additional_bytes)
+        .ok_or(PerpError::Overflow)?;
+    if max_positions(new_len) > MAX_OPEN_POSITONS_PER_USER as usize {
+        msg!(
+            "A user account holds at most {:?} positions",
+            MAX_OPEN_POSITONS_PER_USER
+        );
+        return Err(PerpError::AmountTooLarge.into());
+    }
+
+    let rent_top_up = Rent::get()?
+        .minimum_balance(new_len)
+        .saturating_sub(accounts.user_account.lamports());
+    if rent_top_up > 0 {
+        invoke(
+            &system_instruction::transfer(
+                accounts.user_account_owner.key,
+                accounts.user_account.key,
+                rent_top_up,
+            ),
+            &[
+                accounts.system_program.clone(),
+                accounts.user_account_owner.clone(),
+                accounts.user_account.clone(),
+            ],
+        )?;
+    }
+
+    // The existing positions stay in place, the new space is zeroed
+    accounts.user_account.realloc(new_len, true)?;
+
+    msg!(
+        "The user account now holds up to {:?} positions",
+        max_positions(new_len)
+    );
+
+    Ok(())
+}
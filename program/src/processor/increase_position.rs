@@ -13,6 +13,7 @@ use solana_program::{
 
 use crate::{
     error::PerpError,
+    events::PerpEvent,
     positions_book::{memory::parse_memory, positions_book_tree::PositionsBook},
     processor::{MAX_LEVERAGE, MAX_POSITION_SIZE},
     state::{
@@ -22,8 +23,8 @@ use crate::{
     },
     state::{user_account::UserAccountState, PositionType},
     utils::{
-        check_account_key, check_account_owner, check_signer, compute_fee_tier, compute_fees,
-        compute_liquidation_index, get_oracle_price,
+        check_account_key, check_account_owner, check_max_fee, check_signer, compute_fee_tier,
+        compute_fees, compute_leverage, compute_liquidation_index, get_oracle_price,
     },
 };
 
@@ -96,12 +97,19 @@ pub fn process_increase_position(
     add_collateral: u64,
     predicted_entry_price: u64,   // 32 bit FP
     maximum_slippage_margin: u64, // 32 bit FP
+    max_fee: Option<u64>,
+    has_discount_account: bool,
 ) -> ProgramResult {
     let mut accounts = Accounts::parse(program_id, accounts)?;
 
     // Parsing
     let mut market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
 
+    if market_state.paused {
+        msg!("Opening or increasing positions is paused on this market");
+        return Err(PerpError::MarketPaused.into());
+    }
+
     msg!(
         "Market_state before: v_coin {:?} - v_pc {:?}",
         market_state.v_coin_amount,
@@ -116,12 +124,13 @@ pub fn process_increase_position(
         get_instance_address(&accounts.market.data.borrow(), instance_index as u32)?;
     if &instance_address != accounts.instance.key {
         msg!("Invalid instance account or instance index provided");
-        return Err(ProgramError::InvalidArgument);
+        return Err(PerpError::InstanceMismatch.into());
     }
 
     let (mut instance, mut page_infos) = parse_instance(&accounts.instance.data.borrow())?;
     let memory = parse_memory(&instance, &page_infos, &mut accounts.remaining)?;
     let mut book = PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
+    instance.record_funding(&book, &market_state)?;
 
     let mut open_position = get_position(
         &accounts.user_account.data.borrow_mut(),
@@ -144,7 +153,7 @@ pub fn process_increase_position(
     }
     if &Pubkey::new(&user_account_header.market) != accounts.market.key {
         msg!("The user account market doesn't match the given market account");
-        return Err(ProgramError::InvalidArgument);
+        return Err(PerpError::MarketMismatch.into());
     }
     if user_account_header.balance < add_collateral {
         msg!("The user budget is not sufficient");
@@ -158,7 +167,7 @@ pub fn process_increase_position(
 
     if market_state.oracle_address != accounts.oracle.key.to_bytes() {
         msg!("Provided oracle account is incorrect.");
-        return Err(ProgramError::InvalidArgument);
+        return Err(PerpError::OracleMismatch.into());
     }
 
     user_account_header.balance -= add_collateral;
@@ -179,10 +188,30 @@ pub fn process_increase_position(
     let add_v_pc_amount_signed = open_position.side.get_sign() * (add_v_pc_amount as i64);
     let add_v_coin_amount = market_state.compute_add_v_coin(add_v_pc_amount_signed)?;
 
+    if add_v_pc_amount != 0 && add_v_coin_amount == 0 {
+        msg!("The given order size is not sufficient!");
+        return Err(PerpError::AmountTooLow.into());
+    }
+
     let new_collateral = add_collateral + open_position.collateral;
     let new_v_pc_amount = add_v_pc_amount + open_position.v_pc_amount;
     let new_v_coin_amount = (add_v_coin_amount.abs() as u64) + open_position.v_coin_amount;
 
+    if new_v_coin_amount == 0 {
+        msg!("Zero-leverage positions are not supported.");
+        return Err(PerpError::AmountTooLow.into());
+    }
+    // Funding payments may have brought the existing position above the maximum leverage
+    let new_leverage = compute_leverage(new_v_pc_amount, new_collateral);
+    if new_leverage > MAX_LEVERAGE {
+        msg!(
+            "New leverage cannot be higher than: {:?}. Found: {:?}",
+            MAX_LEVERAGE >> 32,
+            new_leverage >> 32
+        );
+        return Err(PerpError::MarginTooLow.into());
+    }
+
     msg!(
         "Transaction info: v_coin_amount {:?}, v_pc_amount {:?}",
         add_v_coin_amount.abs(),
@@ -212,6 +241,12 @@ pub fn process_increase_position(
         add_v_coin_amount.abs(),
         open_position.side
     );
+    market_state.fill_price_protection(
+        predicted_entry_price,
+        maximum_slippage_margin,
+        add_v_pc_amount,
+        add_v_coin_amount.abs() as u64,
+    )?;
 
     let new_liquidation_index = compute_liquidation_index(
         new_collateral,
@@ -260,10 +295,12 @@ pub fn process_increase_position(
     )?;
 
     // Fees
-    let fee_tier = compute_fee_tier(&mut accounts.remaining)?;
+    let fee_tier = compute_fee_tier(&mut accounts.remaining, has_discount_account)?;
     let mut fees = compute_fees(fee_tier, add_v_pc_amount, leverage)?;
+    check_max_fee(&fees, max_fee)?;
 
     let referrer_account_opt = next_account_info(&mut accounts.remaining).ok();
+    let referrer_record_opt = next_account_info(&mut accounts.remaining).ok();
     market_state.transfer_fees(
         &mut fees,
         accounts.spl_token_program,
@@ -272,6 +309,7 @@ pub fn process_increase_position(
         accounts.market_signer,
         accounts.bnb_bonfida,
         referrer_account_opt,
+        referrer_record_opt,
     )?;
 
     market_state.apply_fees(&fees, false, false)?;
@@ -310,5 +348,18 @@ pub fn process_increase_position(
     )?;
     market_state.pack_into_slice(&mut accounts.market.data.borrow_mut());
 
+    PerpEvent::IncreasedPosition {
+        market: accounts.market.key.to_bytes(),
+        user_account: accounts.user_account.key.to_bytes(),
+        instance_index,
+        side: open_position.side,
+        added_collateral: add_collateral,
+        added_v_coin_amount: add_v_coin_amount.abs() as u64,
+        added_v_pc_amount: add_v_pc_amount,
+        liquidation_index: new_liquidation_index,
+        fees: fees.fixed as i64,
+    }
+    .emit();
+
     Ok(())
 }
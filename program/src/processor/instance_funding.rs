@@ -0,0 +1,80 @@
+use std::{slice::Iter, str::FromStr};
+
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+};
+
+use crate::{
+    error::PerpError,
+    positions_book::{memory::parse_memory, positions_book_tree::PositionsBook},
+    state::{
+        instance::{parse_instance, write_instance},
+        market::{get_instance_address, MarketState},
+    },
+    utils::{check_account_key, check_account_owner},
+};
+
+use super::FUNDING_LABEL;
+
+pub struct Accounts<'a, 'b: 'a> {
+    market: &'a AccountInfo<'b>,
+    instance: &'a AccountInfo<'b>,
+    remaining: Iter<'a, AccountInfo<'b>>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let mut accounts_iter = accounts.iter();
+        let market = next_account_info(&mut accounts_iter)?;
+        let instance = next_account_info(&mut accounts_iter)?;
+        let label = next_account_info(&mut accounts_iter)?;
+
+        check_account_owner(market, program_id)?;
+        check_account_owner(instance, program_id)?;
+        check_account_key(label, &Pubkey::from_str(FUNDING_LABEL).unwrap())?;
+
+        Ok(Self {
+            market,
+            instance,
+            remaining: accounts_iter,
+        })
+    }
+}
+
+pub fn process_instance_funding(
+    program_id: &Pubkey,
+    instance_index: u8,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let mut accounts = Accounts::parse(program_id, accounts)?;
+
+    let market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
+
+    let instance_address =
+        get_instance_address(&accounts.market.data.borrow(), instance_index as u32)?;
+    if &instance_address != accounts.instance.key {
+        msg!("Invalid instance account or instance index provided");
+        return Err(PerpError::InstanceMismatch.into());
+    }
+
+    let (mut instance, page_infos) = parse_instance(&accounts.instance.data.borrow())?;
+    let memory = parse_memory(&instance, &page_infos, &mut accounts.remaining)?;
+    let book = PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
+
+    if !instance.record_funding(&book, &market_state)? {
+        msg!("The instance funding is already up to date");
+        return Err(PerpError::Nop.into());
+    }
+
+    write_instance(&mut accounts.instance.data.borrow_mut(), &instance)?;
+
+    Ok(())
+}
@@ -11,6 +11,7 @@ use solana_program::{
 
 use crate::{
     error::PerpError,
+    events::PerpEvent,
     positions_book::{memory::parse_memory, positions_book_tree::PositionsBook},
     processor::{FEE_REBALANCING_FUND, LIQUIDATION_LABEL},
     state::{
@@ -18,7 +19,10 @@ use crate::{
         market::{get_instance_address, MarketState},
     },
     state::{Fees, PositionType},
-    utils::{check_account_key, check_account_owner, get_oracle_price},
+    utils::{
+        check_account_key, check_account_owner, check_distinct_accounts,
+        check_target_token_account, get_oracle_price, get_vault_balance,
+    },
 };
 
 pub struct Accounts<'a, 'b: 'a> {
@@ -53,6 +57,8 @@ impl<'a, 'b: 'a> Accounts<'a, 'b> {
         check_account_key(spl_token_program, &spl_token::id()).unwrap();
         check_account_key(label, &Pubkey::from_str(LIQUIDATION_LABEL).unwrap()).unwrap();
         check_account_owner(market, program_id).unwrap();
+        check_distinct_accounts(&[market_vault, bnb_bonfida, target])?;
+        check_target_token_account(target, market_vault)?;
 
         Ok(Self {
             spl_token_program,
@@ -89,6 +95,7 @@ pub fn process_liquidation(
     let (mut instance, mut page_infos) = parse_instance(&accounts.instance.data.borrow())?;
     let memory = parse_memory(&instance, &page_infos, &mut accounts.remaining)?;
     let mut book = PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
+    instance.record_funding(&book, &market_state)?;
 
     let liquidation_index = get_oracle_price(
         &accounts.oracle.data.borrow(),
@@ -144,11 +151,26 @@ pub fn process_liquidation(
     market_state.add_v_pc(balanced_v_pc)?;
     market_state.add_v_coin(balanced_v_coin)?;
 
-    let mut liq_payout =
+    let remaining_value =
         (liquidated_shorts_v_pc as i64) - (liquidated_longs_v_pc as i64) - total_v_pc_difference
             + (liquidated_collateral as i64);
 
-    liq_payout = std::cmp::max(0, liq_payout);
+    let mut liq_payout = market_state.compute_liquidation_reward(
+        remaining_value,
+        liquidated_longs_v_pc + liquidated_shorts_v_pc,
+    );
+
+    // The liquidated collateral is now part of the insurance fund, which caps the reward so that the vault isn't drained below solvency
+    let insurance_fund = market_state
+        .get_insurance_fund(get_vault_balance(&market_state, accounts.market_vault)?)?;
+    if liq_payout > insurance_fund {
+        msg!(
+            "The liquidation reward of {:?} is capped by the insurance fund: {:?}",
+            liq_payout,
+            insurance_fund
+        );
+        liq_payout = std::cmp::max(0, insurance_fund);
+    }
 
     // Transfer the Reward using the fees structure
     let mut liq_payout_wrapped = Fees {
@@ -159,7 +181,8 @@ pub fn process_liquidation(
     market_state.rebalancing_funds +=
         ((liq_payout_wrapped.fixed as u128) * (FEE_REBALANCING_FUND as u128) / 100) as u64 + 1;
 
-    market_state.transfer_fees(
+    let insurance_share_bps = market_state.liquidation_insurance_share_bps;
+    let reward = market_state.transfer_fees_with_insurance_share(
         &mut liq_payout_wrapped,
         accounts.spl_token_program,
         accounts.market,
@@ -167,6 +190,7 @@ pub fn process_liquidation(
         accounts.market_signer,
         accounts.bnb_bonfida,
         Some(accounts.target),
+        insurance_share_bps,
     )?;
 
     instance.update(&book, &mut page_infos);
@@ -176,5 +200,16 @@ pub fn process_liquidation(
         &instance,
     )?;
     market_state.pack_into_slice(&mut accounts.market.data.borrow_mut());
+
+    PerpEvent::Liquidated {
+        market: accounts.market.key.to_bytes(),
+        instance_index,
+        collateral: liquidated_collateral,
+        longs_v_coin_amount: liquidated_longs,
+        shorts_v_coin_amount: liquidated_shorts,
+        reward,
+    }
+    .emit();
+
     Ok(())
 }
@@ -0,0 +1,128 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction, system_program,
+    sysvar::Sysvar,
+};
+
+use crate::{
+    error::PerpError,
+    state::{
+        instance::{write_instance, Instance, PageInfo, INSTANCE_V0_LEN},
+        market::{get_instance_address, MarketState},
+    },
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    system_program: &'a AccountInfo<'b>,
+    market: &'a AccountInfo<'b>,
+    admin: &'a AccountInfo<'b>,
+    instance: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let system_program = next_account_info(accounts_iter)?;
+        let market = next_account_info(accounts_iter)?;
+        let admin = next_account_info(accounts_iter)?;
+        let instance = next_account_info(accounts_iter)?;
+
+        check_account_key(system_program, &system_program::ID)?;
+        check_account_owner(market, program_id)?;
+        check_signer(admin)?;
+        check_account_owner(instance, program_id)?;
+
+        Ok(Self {
+            system_program,
+            market,
+            admin,
+            instance,
+        })
+    }
+}
+
+pub fn process_migrate_instance(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    instance_index: u8,
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
+
+    if &Pubkey::new(&market_state.admin_address) != accounts.admin.key {
+        msg!("Invalid admin account for the current market");
+        return Err(ProgramError::InvalidArgument);
+    }
+    let instance_address =
+        get_instance_address(&accounts.market.data.borrow(), instance_index as u32)?;
+    if &instance_address != accounts.instance.key {
+        msg!("Invalid instance account for the current market");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut instance = Instance::unpack_v0(&accounts.instance.data.borrow())?;
+
+    // Version 0 instances were settled with the market-wide balancing factors, which are only up to
+    // date when the instance is migrated before the market's next funding crank
+    instance.funding_history_offset = market_state.funding_history_offset;
+    instance.funding_balancing_factors = market_state.funding_balancing_factors;
+
+    let page_infos_len = (instance.number_of_pages as usize)
+        .checked_mul(PageInfo::LEN)
+        .ok_or(PerpError::Overflow)?;
+    let new_len = Instance::LEN
+        .checked_add(page_infos_len)
+        .ok_or(PerpError::Overflow)?;
+
+    // Instance accounts are usually allocated with room for more pages, they only grow when the
+    // page infos don't fit behind the larger header
+    if new_len > accounts.instance.data_len() {
+        let rent_top_up = Rent::get()?
+            .minimum_balance(new_len)
+            .saturating_sub(accounts.instance.lamports());
+        if rent_top_up > 0 {
+            invoke(
+                &system_instruction::transfer(
+                    accounts.admin.key,
+                    accounts.instance.key,
+                    rent_top_up,
+                ),
+                &[
+                    accounts.system_program.clone(),
+                    accounts.admin.clone(),
+                    accounts.instance.clone(),
+                ],
+            )?;
+        }
+        accounts.instance.realloc(new_len, true)?;
+    }
+
+    let mut instance_data = accounts.instance.data.borrow_mut();
+    instance_data.copy_within(
+        INSTANCE_V0_LEN..INSTANCE_V0_LEN + page_infos_len,
+        Instance::LEN,
+    );
+    // Clears what is left of the page infos which overlapped the new header
+    instance_data[..Instance::LEN].fill(0);
+    write_instance(&mut instance_data, &instance)?;
+
+    msg!(
+        "Migrated the instance and its {:?} pages",
+        instance.number_of_pages
+    );
+
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction, system_program,
+    sysvar::Sysvar,
+};
+
+use crate::{
+    error::PerpError,
+    state::market::{MarketState, MARKET_STATE_V0_LEN},
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    system_program: &'a AccountInfo<'b>,
+    market: &'a AccountInfo<'b>,
+    admin: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let system_program = next_account_info(accounts_iter)?;
+        let market = next_account_info(accounts_iter)?;
+        let admin = next_account_info(accounts_iter)?;
+
+        check_account_key(system_program, &system_program::ID)?;
+        check_account_owner(market, program_id)?;
+        check_signer(admin)?;
+
+        Ok(Self {
+            system_program,
+            market,
+            admin,
+        })
+    }
+}
+
+pub fn process_migrate_market(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    max_allowed_slippage_margin: u64,
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let mut market_state = MarketState::unpack_v0(&accounts.market.data.borrow())?;
+
+    if &Pubkey::new(&market_state.admin_address) != accounts.admin.key {
+        msg!("Invalid admin account for the current market");
+        return Err(ProgramError::InvalidArgument);
+    }
+    market_state.check_max_allowed_slippage_margin(max_allowed_slippage_margin)?;
+    market_state.max_allowed_slippage_margin = max_allowed_slippage_margin;
+
+    let instance_addresses_len = (market_state.number_of_instances as usize)
+        .checked_mul(32)
+        .ok_or(PerpError::Overflow)?;
+    let new_len = MarketState::LEN
+        .checked_add(instance_addresses_len)
+        .ok_or(PerpError::Overflow)?;
+
+    // Market accounts are usually allocated with room to spare, they only grow when the header
+    // doesn't fit in front of the instance addresses anymore
+    if new_len > accounts.market.data_len() {
+        let rent_top_up = Rent::get()?
+            .minimum_balance(new_len)
+            .saturating_sub(accounts.market.lamports());
+        if rent_top_up > 0 {
+            invoke(
+                &system_instruction::transfer(accounts.admin.key, accounts.market.key, rent_top_up),
+                &[
+                    accounts.system_program.clone(),
+                    accounts.admin.clone(),
+                    accounts.market.clone(),
+                ],
+            )?;
+        }
+        accounts.market.realloc(new_len, true)?;
+    }
+
+    let mut market_data = accounts.market.data.borrow_mut();
+    market_data.copy_within(
+        MARKET_STATE_V0_LEN..MARKET_STATE_V0_LEN + instance_addresses_len,
+        MarketState::LEN,
+    );
+    market_state.pack_into_slice(&mut market_data);
+
+    msg!(
+        "Migrated the market and its {:?} instances",
+        market_state.number_of_instances
+    );
+
+    Ok(())
+}
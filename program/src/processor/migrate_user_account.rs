@@ -0,0 +1,106 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction, system_program,
+    sysvar::Sysvar,
+};
+
+use crate::{
+    error::PerpError,
+    state::user_account::{OpenPosition, UserAccountState, OPEN_POSITION_V0_LEN},
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    system_program: &'a AccountInfo<'b>,
+    user_account_owner: &'a AccountInfo<'b>,
+    user_account: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let system_program = next_account_info(accounts_iter)?;
+        let user_account_owner = next_account_info(accounts_iter)?;
+        let user_account = next_account_info(accounts_iter)?;
+
+        check_account_key(system_program, &system_program::ID)?;
+        check_signer(user_account_owner)?;
+        check_account_owner(user_account, program_id)?;
+
+        Ok(Self {
+            system_program,
+            user_account_owner,
+            user_account,
+        })
+    }
+}
+
+pub fn process_migrate_user_account(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let user_account_header = UserAccountState::unpack_v0(&accounts.user_account.data.borrow())?;
+
+    if *accounts.user_account_owner.key != Pubkey::new(&user_account_header.owner) {
+        msg!("The user account owner is invalid");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let number_of_open_positions = user_account_header.number_of_open_positions as usize;
+    let new_len = number_of_open_positions
+        .checked_mul(OpenPosition::LEN)
+        .and_then(|s| s.checked_add(UserAccountState::LEN))
+        .ok_or(PerpError::Overflow)?;
+
+    // The account only grows when its open positions don't fit in it with their stop-loss index
+    if new_len > accounts.user_account.data_len() {
+        let rent_top_up = Rent::get()?
+            .minimum_balance(new_len)
+            .saturating_sub(accounts.user_account.lamports());
+        if rent_top_up > 0 {
+            invoke(
+                &system_instruction::transfer(
+                    accounts.user_account_owner.key,
+                    accounts.user_account.key,
+                    rent_top_up,
+                ),
+                &[
+                    accounts.system_program.clone(),
+                    accounts.user_account_owner.clone(),
+                    accounts.user_account.clone(),
+                ],
+            )?;
+        }
+        accounts.user_account.realloc(new_len, true)?;
+    }
+
+    // The positions are moved from the last one so that none is overwritten before being moved
+    let mut user_account_data = accounts.user_account.data.borrow_mut();
+    for i in (0..number_of_open_positions).rev() {
+        let v0_offset = UserAccountState::LEN + i * OPEN_POSITION_V0_LEN;
+        let offset = UserAccountState::LEN + i * OpenPosition::LEN;
+        user_account_data.copy_within(v0_offset..v0_offset + OPEN_POSITION_V0_LEN, offset);
+        user_account_data[offset + OPEN_POSITION_V0_LEN..offset + OpenPosition::LEN].fill(0);
+    }
+    user_account_header.pack_into_slice(&mut user_account_data);
+
+    msg!(
+        "Migrated the user account and its {:?} positions",
+        number_of_open_positions
+    );
+
+    Ok(())
+}
@@ -13,8 +13,9 @@ use solana_program::{
 
 use crate::{
     error::PerpError,
+    events::PerpEvent,
     positions_book::{memory::parse_memory, positions_book_tree::PositionsBook},
-    processor::{MAX_LEVERAGE, MAX_POSITION_SIZE},
+    processor::MAX_LEVERAGE,
     state::PositionType,
     state::{
         instance::{parse_instance, write_instance_and_memory},
@@ -22,8 +23,9 @@ use crate::{
         user_account::{write_position, OpenPosition, UserAccountState},
     },
     utils::{
-        check_account_key, check_account_owner, check_signer, compute_fee_tier, compute_fees,
-        compute_liquidation_index, get_oracle_price,
+        check_account_key, check_account_owner, check_max_fee, check_signer, compute_fee_tier,
+        compute_fees, compute_leverage, compute_open_order_size, fill_open_order, get_oracle_price,
+        OpenOrderFill,
     },
 };
 
@@ -96,12 +98,19 @@ pub fn process_open_position(
     leverage: u64,                // 32 bit FP
     predicted_entry_price: u64,   // 32 bit FP
     maximum_slippage_margin: u64, // 32 bit FP
+    max_fee: Option<u64>,
+    has_discount_account: bool,
 ) -> ProgramResult {
     let mut accounts = Accounts::parse(program_id, accounts)?;
 
     // Parsing
     let mut market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
 
+    if market_state.paused {
+        msg!("Opening or increasing positions is paused on this market");
+        return Err(PerpError::MarketPaused.into());
+    }
+
     msg!(
         "Market_state before: v_coin {:?} - v_pc {:?}",
         market_state.v_coin_amount,
@@ -123,16 +132,9 @@ pub fn process_open_position(
     let (mut instance, mut page_infos) = parse_instance(&accounts.instance.data.borrow())?;
     let memory = parse_memory(&instance, &page_infos, &mut accounts.remaining)?;
     let mut book = PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
+    instance.record_funding(&book, &market_state)?;
 
     //Verifications
-    if leverage > MAX_LEVERAGE {
-        msg!(
-            "Leverage cannot be higher than: {:?}. Found: {:?}",
-            MAX_LEVERAGE >> 32,
-            leverage >> 32
-        );
-        return Err(PerpError::MarginTooLow.into());
-    }
     if accounts.user_account_owner.key != &Pubkey::new(&user_account_header.owner) {
         msg!("The user account owner doesn't match");
         return Err(ProgramError::InvalidArgument);
@@ -156,13 +158,15 @@ pub fn process_open_position(
         return Err(ProgramError::InvalidArgument);
     }
 
-    let v_pc_amount = ((collateral as u128 * (leverage as u128)) >> 32) as u64;
+    let v_pc_amount = compute_open_order_size(&market_state, side, collateral, leverage)?;
 
     // Fees
-    let fee_tier = compute_fee_tier(&mut accounts.remaining)?;
+    let fee_tier = compute_fee_tier(&mut accounts.remaining, has_discount_account)?;
     msg!("Fee tier: {:?}", fee_tier);
     let mut fees = compute_fees(fee_tier, v_pc_amount, leverage)?;
+    check_max_fee(&fees, max_fee)?;
     let referrer_account_opt = next_account_info(&mut accounts.remaining).ok();
+    let referrer_record_opt = next_account_info(&mut accounts.remaining).ok();
     if (user_account_header.balance as i64) < collateral as i64 + fees.total {
         msg!("The user budget is not sufficient");
         return Err(PerpError::NoMoreFunds.into());
@@ -178,6 +182,7 @@ pub fn process_open_position(
             accounts.market_signer,
             accounts.bnb_bonfida,
             referrer_account_opt,
+            referrer_record_opt,
         )
         .unwrap();
 
@@ -188,53 +193,24 @@ pub fn process_open_position(
     market_state.total_collateral += collateral;
     user_account_header.balance -= collateral;
 
-    if v_pc_amount >= market_state.v_pc_amount && side == PositionType::Long {
-        msg!("The given order size is too large!");
-        return Err(PerpError::AmountTooLarge.into());
-    }
-    if v_pc_amount >= MAX_POSITION_SIZE {
-        msg!(
-            "The given order size is too large! The maximum size is: {:?}",
-            MAX_POSITION_SIZE
-        );
-        return Err(PerpError::AmountTooLarge.into());
-    }
-
-    let signed_v_pc_amount = side.get_sign() * (v_pc_amount as i64);
-    let signed_v_coin_amount = market_state.compute_add_v_coin(signed_v_pc_amount)?;
-
     let oracle_price = get_oracle_price(
         &accounts.oracle.data.borrow(),
         market_state.coin_decimals,
         market_state.quote_decimals,
     )?;
 
-    let (balanced_v_pc_amount, balanced_v_coin_amount) =
-        market_state.balance_operation(signed_v_pc_amount, signed_v_coin_amount, oracle_price)?;
-
-    market_state.add_v_pc(balanced_v_pc_amount)?;
-    market_state.add_v_coin(balanced_v_coin_amount)?;
-
-    let v_coin_amount = signed_v_coin_amount.abs() as u64;
-    market_state.add_open_interest(v_coin_amount, v_pc_amount, side)?;
-
-    msg!("Add_v_pc_amount: {:?}", signed_v_pc_amount);
-    msg!("Add_v_coin_amount: {:?}", signed_v_coin_amount);
-
-    if v_coin_amount == 0 {
-        msg!("The given order size is not sufficient!");
-        return Err(PerpError::AmountTooLow.into());
-    }
-
-    let current_slot = Clock::from_account_info(accounts.clock_sysvar)?.slot;
-
-    let liquidation_index = compute_liquidation_index(
-        collateral,
+    let OpenOrderFill {
         v_coin_amount,
-        v_pc_amount,
+        liquidation_index,
+    } = fill_open_order(
+        &mut market_state,
         side,
-        market_state.get_k(),
-    );
+        collateral,
+        v_pc_amount,
+        oracle_price,
+    )?;
+
+    let current_slot = Clock::from_account_info(accounts.clock_sysvar)?.slot;
     msg!(
         "Liquidation Index for this position: {:?}",
         liquidation_index
@@ -246,6 +222,12 @@ pub fn process_open_position(
         v_coin_amount,
         side
     );
+    market_state.fill_price_protection(
+        predicted_entry_price,
+        maximum_slippage_margin,
+        v_pc_amount,
+        v_coin_amount,
+    )?;
 
     let insertion_leaf = book.open_position(
         liquidation_index,
@@ -265,6 +247,7 @@ pub fn process_open_position(
         slot_number: insertion_leaf.get_slot_number(&book.memory)?,
         v_coin_amount,
         v_pc_amount,
+        stop_loss_index: 0,
     };
     msg!(
         "Transaction info: v_coin_amount {:?}, v_pc_amount {:?}",
@@ -297,5 +280,60 @@ pub fn process_open_position(
 
     market_state.pack_into_slice(&mut accounts.market.data.borrow_mut());
 
+    PerpEvent::OpenedPosition {
+        market: accounts.market.key.to_bytes(),
+        user_account: accounts.user_account.key.to_bytes(),
+        instance_index,
+        side,
+        collateral,
+        v_coin_amount,
+        v_pc_amount,
+        liquidation_index,
+        fees: fees.total,
+    }
+    .emit();
+
     Ok(())
 }
+
+#[allow(clippy::too_many_arguments)]
+pub fn process_open_position_by_size(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo<'_>],
+    side: PositionType,
+    instance_index: u8,
+    collateral: u64,
+    target_v_pc: u64,
+    predicted_entry_price: u64,   // 32 bit FP
+    maximum_slippage_margin: u64, // 32 bit FP
+    has_discount_account: bool,
+) -> ProgramResult {
+    if collateral == 0 {
+        msg!("The collateral cannot be zero");
+        return Err(PerpError::AmountTooLow.into());
+    }
+    // Rounded down, the resulting position size can be slightly below the target
+    let leverage = compute_leverage(target_v_pc, collateral);
+    if leverage > MAX_LEVERAGE {
+        msg!(
+            "Leverage cannot be higher than: {:?}. Found: {:?}",
+            MAX_LEVERAGE >> 32,
+            leverage >> 32
+        );
+        return Err(PerpError::MarginTooLow.into());
+    }
+    msg!("Leverage for this position (FP32): {:?}", leverage);
+
+    process_open_position(
+        program_id,
+        accounts,
+        side,
+        instance_index,
+        collateral,
+        leverage,
+        predicted_entry_price,
+        maximum_slippage_margin,
+        None,
+        has_discount_account,
+    )
+}
@@ -0,0 +1,81 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+};
+
+use crate::{
+    state::{
+        is_initialized,
+        user_account::{TransferProposal, UserAccountState},
+    },
+    utils::{check_account_owner, check_signer},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    user_account_owner: &'a AccountInfo<'b>,
+    user_account: &'a AccountInfo<'b>,
+    transfer_proposal: &'a AccountInfo<'b>,
+    new_user_account_owner: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let user_account_owner = next_account_info(accounts_iter)?;
+        let user_account = next_account_info(accounts_iter)?;
+        let transfer_proposal = next_account_info(accounts_iter)?;
+        let new_user_account_owner = next_account_info(accounts_iter)?;
+
+        check_signer(user_account_owner)?;
+        check_account_owner(user_account, program_id)?;
+        check_account_owner(transfer_proposal, program_id)?;
+
+        Ok(Self {
+            user_account_owner,
+            user_account,
+            transfer_proposal,
+            new_user_account_owner,
+        })
+    }
+}
+
+pub fn process_propose_user_account_transfer(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let user_account_header =
+        UserAccountState::unpack_from_slice(&accounts.user_account.data.borrow())?;
+
+    // Verifications
+    if user_account_header.owner != accounts.user_account_owner.key.to_bytes() {
+        msg!("Invalid user account owner provided");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if is_initialized(accounts.transfer_proposal) {
+        msg!("The transfer proposal account is already in use");
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+    if accounts.transfer_proposal.data_len() < TransferProposal::LEN {
+        msg!("The transfer proposal account is too small");
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+
+    let transfer_proposal = TransferProposal {
+        user_account: accounts.user_account.key.to_bytes(),
+        current_owner: user_account_header.owner,
+        new_owner: accounts.new_user_account_owner.key.to_bytes(),
+    };
+    transfer_proposal.pack_into_slice(&mut accounts.transfer_proposal.data.borrow_mut());
+
+    Ok(())
+}
@@ -14,7 +14,7 @@ use solana_program::{
 use crate::{
     error::PerpError,
     positions_book::{memory::parse_memory, positions_book_tree::PositionsBook},
-    processor::MAX_LEVERAGE,
+    processor::{MAX_LEVERAGE, REBALANCING_COOLDOWN, REBALANCING_MARGIN},
     state::PositionType,
     state::{
         instance::{parse_instance, write_instance_and_memory},
@@ -22,8 +22,8 @@ use crate::{
         user_account::{write_position, OpenPosition, UserAccountState},
     },
     utils::{
-        check_account_key, check_account_owner, check_signer, compute_fee_tier, compute_fees,
-        compute_liquidation_index,
+        check_account_key, check_account_owner, check_signer, compute_bias, compute_fee_tier,
+        compute_fees, compute_leverage, compute_liquidation_index, get_oracle_price,
     },
 };
 
@@ -40,13 +40,17 @@ pub struct Accounts<'a, 'b: 'a> {
     user_account_owner: &'a AccountInfo<'b>,
     user_account: &'a AccountInfo<'b>,
     remaining: Iter<'a, AccountInfo<'b>>,
-    admin_account: &'a AccountInfo<'b>,
+    // The signing market admin, only given to the admin rebalancing
+    admin_account: Option<&'a AccountInfo<'b>>,
+    // The market's oracle, only given to the permissionless rebalancing to compute the market bias
+    oracle: Option<&'a AccountInfo<'b>>,
 }
 
 impl<'a, 'b: 'a> Accounts<'a, 'b> {
     pub fn parse(
         program_id: &Pubkey,
         accounts: &'a [AccountInfo<'b>],
+        permissionless: bool,
     ) -> Result<Self, ProgramError> {
         let mut accounts_iter = accounts.iter();
 
@@ -59,14 +63,20 @@ impl<'a, 'b: 'a> Accounts<'a, 'b> {
         let bnb_bonfida = next_account_info(&mut accounts_iter)?;
         let user_account_owner = next_account_info(&mut accounts_iter)?;
         let user_account = next_account_info(&mut accounts_iter)?;
-        let admin_account = next_account_info(&mut accounts_iter)?;
-        check_account_key(clock_sysvar, &solana_program::sysvar::clock::ID).unwrap();
-        check_account_owner(user_account, program_id).unwrap();
-        check_account_owner(market, program_id).unwrap();
-        check_account_key(bnb_bonfida, &Pubkey::from_str(&FIDA_BNB).unwrap()).unwrap();
-
-        check_signer(user_account_owner).unwrap();
-        check_signer(admin_account).unwrap();
+        let (admin_account, oracle) = if permissionless {
+            (None, Some(next_account_info(&mut accounts_iter)?))
+        } else {
+            (Some(next_account_info(&mut accounts_iter)?), None)
+        };
+        check_account_key(clock_sysvar, &solana_program::sysvar::clock::ID)?;
+        check_account_owner(user_account, program_id)?;
+        check_account_owner(market, program_id)?;
+        check_account_key(bnb_bonfida, &Pubkey::from_str(&FIDA_BNB).unwrap())?;
+
+        check_signer(user_account_owner)?;
+        if let Some(admin_account) = admin_account {
+            check_signer(admin_account)?;
+        }
 
         Ok(Self {
             spl_token_program,
@@ -80,6 +90,7 @@ impl<'a, 'b: 'a> Accounts<'a, 'b> {
             user_account,
             remaining: accounts_iter,
             admin_account,
+            oracle,
         })
     }
 }
@@ -89,8 +100,9 @@ pub fn process_rebalance(
     accounts: &[AccountInfo<'_>],
     instance_index: u8,
     collateral: u64,
+    permissionless: bool,
 ) -> ProgramResult {
-    let mut accounts = Accounts::parse(program_id, accounts)?;
+    let mut accounts = Accounts::parse(program_id, accounts, permissionless)?;
 
     // Parsing
     let mut market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
@@ -98,22 +110,62 @@ pub fn process_rebalance(
     let signed_v_coin_amount =
         (market_state.open_longs_v_coin as i64) - (market_state.open_shorts_v_coin as i64);
 
+    if let Some(oracle) = accounts.oracle {
+        let current_timestamp =
+            Clock::from_account_info(accounts.clock_sysvar)?.unix_timestamp as u64;
+        if current_timestamp < market_state.last_rebalance_timestamp + REBALANCING_COOLDOWN {
+            msg!(
+                "The market can't be rebalanced without the admin's signature before {:?}",
+                market_state.last_rebalance_timestamp + REBALANCING_COOLDOWN
+            );
+            return Err(PerpError::RebalancingCooldown.into());
+        }
+        if market_state.oracle_address != oracle.key.to_bytes() {
+            msg!("Provided oracle account is incorrect.");
+            return Err(ProgramError::InvalidArgument);
+        }
+        let oracle_price = get_oracle_price(
+            &oracle.data.borrow(),
+            market_state.coin_decimals,
+            market_state.quote_decimals,
+        )?;
+        let market_bias = compute_bias(
+            signed_v_coin_amount,
+            market_state.v_coin_amount,
+            market_state.v_pc_amount,
+            oracle_price,
+        );
+        if market_bias.abs() <= REBALANCING_MARGIN {
+            msg!(
+                "The market bias {:?} doesn't exceed the rebalancing margin",
+                market_bias
+            );
+            return Err(PerpError::Nop.into());
+        }
+        market_state.last_rebalance_timestamp = current_timestamp;
+    } else if &Pubkey::new(&market_state.admin_address) != accounts.admin_account.unwrap().key {
+        msg!("Incorrect admin account");
+        return Err(ProgramError::InvalidArgument);
+    }
+
     let signed_v_pc_amount = market_state.compute_add_v_pc(signed_v_coin_amount)?;
 
-    let leverage = ((signed_v_pc_amount.abs() as u128) << 32) / (collateral as u128);
-    if leverage as u64 > MAX_LEVERAGE {
+    if collateral == 0 {
+        msg!("The collateral cannot be zero");
+        return Err(PerpError::AmountTooLow.into());
+    }
+    let leverage = compute_leverage(signed_v_pc_amount.abs() as u64, collateral);
+    if leverage > MAX_LEVERAGE {
         msg!("Attempting to rebalance with excessive leverage");
         return Err(PerpError::MarginTooLow.into());
     }
 
-    let side = match signed_v_coin_amount.signum() {
-        -1 => PositionType::Long,
-        1 => PositionType::Short,
-        0 => {
+    let side = match PositionType::from_sign(-signed_v_coin_amount) {
+        Some(side) => side,
+        None => {
             msg!("The market is already balanced!");
             return Ok(());
         }
-        _ => unreachable!(),
     };
 
     msg!(
@@ -132,14 +184,10 @@ pub fn process_rebalance(
         return Err(ProgramError::InvalidArgument);
     }
 
-    if &Pubkey::new(&market_state.admin_address) != accounts.admin_account.key {
-        msg!("Incorrect admin account");
-        return Err(ProgramError::InvalidArgument);
-    }
-
     let (mut instance, mut page_infos) = parse_instance(&accounts.instance.data.borrow())?;
     let memory = parse_memory(&instance, &page_infos, &mut accounts.remaining)?;
     let mut book = PositionsBook::new(instance.shorts_pointer, instance.longs_pointer, memory);
+    instance.record_funding(&book, &market_state)?;
 
     //Verifications
     if accounts.user_account_owner.key != &Pubkey::new(&user_account_header.owner) {
@@ -160,9 +208,11 @@ pub fn process_rebalance(
     }
 
     // Fees (leverage is set to 0 to minimize fees)
-    let fee_tier = compute_fee_tier(&mut accounts.remaining)?;
+    // The rebalancing instructions don't take a discount account
+    let fee_tier = compute_fee_tier(&mut accounts.remaining, false)?;
     let mut fees = compute_fees(fee_tier, 0, 0)?;
     let referrer_account_opt = next_account_info(&mut accounts.remaining).ok();
+    let referrer_record_opt = next_account_info(&mut accounts.remaining).ok();
     if (user_account_header.balance as i64) < collateral as i64 + fees.total {
         msg!("The user budget is not sufficient");
         return Err(PerpError::NoMoreFunds.into());
@@ -230,6 +280,7 @@ pub fn process_rebalance(
         slot_number: insertion_leaf.get_slot_number(&book.memory)?,
         v_coin_amount,
         v_pc_amount,
+        stop_loss_index: 0,
     };
     msg!(
         "Transaction info: v_coin_amount {:?}, v_pc_amount {:?}",
@@ -269,6 +320,7 @@ pub fn process_rebalance(
         accounts.market_signer,
         accounts.bnb_bonfida,
         referrer_account_opt,
+        referrer_record_opt,
     )?;
 
     Ok(())
@@ -0,0 +1,99 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke_signed,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction, system_program,
+    sysvar::Sysvar,
+};
+
+use crate::{
+    state::referral::{get_referrer_record_address, ReferrerRecord, REFERRER_RECORD_SEED},
+    utils::{check_account_key, check_account_owner, check_signer},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    system_program: &'a AccountInfo<'b>,
+    fee_payer: &'a AccountInfo<'b>,
+    market: &'a AccountInfo<'b>,
+    referrer_account: &'a AccountInfo<'b>,
+    referrer_record: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let system_program = next_account_info(accounts_iter)?;
+        let fee_payer = next_account_info(accounts_iter)?;
+        let market = next_account_info(accounts_iter)?;
+        let referrer_account = next_account_info(accounts_iter)?;
+        let referrer_record = next_account_info(accounts_iter)?;
+
+        check_account_key(system_program, &system_program::ID)?;
+        check_signer(fee_payer)?;
+        check_account_owner(market, program_id)?;
+        check_account_owner(referrer_account, &spl_token::id())?;
+
+        Ok(Self {
+            system_program,
+            fee_payer,
+            market,
+            referrer_account,
+            referrer_record,
+        })
+    }
+}
+
+pub fn process_register_referrer(program_id: &Pubkey, accounts: &[AccountInfo]) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let (referrer_record_key, nonce) = get_referrer_record_address(
+        program_id,
+        accounts.market.key,
+        accounts.referrer_account.key,
+    );
+    if &referrer_record_key != accounts.referrer_record.key {
+        msg!("Invalid referrer record account provided");
+        return Err(ProgramError::InvalidSeeds);
+    }
+
+    // Fails if the referrer is already registered
+    invoke_signed(
+        &system_instruction::create_account(
+            accounts.fee_payer.key,
+            accounts.referrer_record.key,
+            Rent::get()?.minimum_balance(ReferrerRecord::LEN),
+            ReferrerRecord::LEN as u64,
+            program_id,
+        ),
+        &[
+            accounts.system_program.clone(),
+            accounts.fee_payer.clone(),
+            accounts.referrer_record.clone(),
+        ],
+        &[&[
+            REFERRER_RECORD_SEED,
+            &accounts.market.key.to_bytes(),
+            &accounts.referrer_account.key.to_bytes(),
+            &[nonce],
+        ]],
+    )?;
+
+    let referrer_record = ReferrerRecord {
+        market: accounts.market.key.to_bytes(),
+        referrer_account: accounts.referrer_account.key.to_bytes(),
+        total_fees: 0,
+        number_of_referrals: 0,
+    };
+    referrer_record.pack_into_slice(&mut accounts.referrer_record.data.borrow_mut());
+
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+};
+
+use crate::{
+    state::{
+        market::MarketState,
+        user_account::{get_position, write_position, UserAccountState},
+        PositionType,
+    },
+    utils::{check_account_owner, check_signer, get_oracle_price},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    market: &'a AccountInfo<'b>,
+    oracle: &'a AccountInfo<'b>,
+    user_account_owner: &'a AccountInfo<'b>,
+    user_account: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let market = next_account_info(accounts_iter)?;
+        let oracle = next_account_info(accounts_iter)?;
+        let user_account_owner = next_account_info(accounts_iter)?;
+        let user_account = next_account_info(accounts_iter)?;
+
+        check_account_owner(market, program_id)?;
+        check_signer(user_account_owner)?;
+        check_account_owner(user_account, program_id)?;
+
+        Ok(Self {
+            market,
+            oracle,
+            user_account_owner,
+            user_account,
+        })
+    }
+}
+
+pub fn process_set_stop_loss(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    position_index: u16,
+    stop_loss_index: u64, // 32 bit FP
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
+    let mut user_account_header =
+        UserAccountState::unpack_from_slice(&accounts.user_account.data.borrow())?;
+
+    // Verifications
+    if market_state.oracle_address != accounts.oracle.key.to_bytes() {
+        msg!("Provided oracle account is incorrect.");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if &Pubkey::new(&user_account_header.market) != accounts.market.key {
+        msg!("The user account market doesn't match the given market account");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if *accounts.user_account_owner.key != Pubkey::new(&user_account_header.owner) {
+        msg!("The user account owner is invalid");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    let mut open_position = get_position(
+        &accounts.user_account.data.borrow(),
+        &user_account_header,
+        position_index,
+    )?;
+
+    // A null index removes the stop-loss
+    if stop_loss_index != 0 {
+        let oracle_price = get_oracle_price(
+            &accounts.oracle.data.borrow(),
+            market_state.coin_decimals,
+            market_state.quote_decimals,
+        )?;
+        // The stop-loss has to sit strictly between the liquidation index and the current price,
+        // otherwise it would either trigger right away or never before the liquidation.
+        let valid = match open_position.side {
+            PositionType::Long => {
+                open_position.liquidation_index < stop_loss_index && stop_loss_index < oracle_price
+            }
+            PositionType::Short => {
+                oracle_price < stop_loss_index && stop_loss_index < open_position.liquidation_index
+            }
+        };
+        if !valid {
+            msg!(
+                "The stop-loss index must lie between the oracle price {:?} and the liquidation index {:?}",
+                oracle_price,
+                open_position.liquidation_index
+            );
+            return Err(ProgramError::InvalidArgument);
+        }
+    }
+
+    open_position.stop_loss_index = stop_loss_index;
+    write_position(
+        &mut accounts.user_account.data.borrow_mut(),
+        position_index,
+        &mut user_account_header,
+        &open_position,
+        true,
+    )?;
+
+    Ok(())
+}
@@ -8,7 +8,11 @@ use solana_program::{
 };
 
 use crate::{
-    state::user_account::{get_position, remove_position, write_position, UserAccountState},
+    error::PerpError,
+    state::{
+        market::MarketState,
+        user_account::{get_position, remove_position, write_position, UserAccountState},
+    },
     utils::{check_account_owner, check_signer},
 };
 
@@ -17,6 +21,7 @@ struct Accounts<'a, 'b: 'a> {
     source_user_account: &'a AccountInfo<'b>,
     destination_user_account_owner: &'a AccountInfo<'b>,
     destination_user_account: &'a AccountInfo<'b>,
+    market: &'a AccountInfo<'b>,
 }
 
 impl<'a, 'b: 'a> Accounts<'a, 'b> {
@@ -30,17 +35,20 @@ impl<'a, 'b: 'a> Accounts<'a, 'b> {
         let source_user_account = next_account_info(accounts_iter)?;
         let destination_user_account_owner = next_account_info(accounts_iter)?;
         let destination_user_account = next_account_info(accounts_iter)?;
+        let market = next_account_info(accounts_iter)?;
 
         check_signer(source_user_account_owner).unwrap();
         check_signer(destination_user_account_owner).unwrap();
         check_account_owner(source_user_account, program_id).unwrap();
         check_account_owner(destination_user_account, program_id).unwrap();
+        check_account_owner(market, program_id).unwrap();
 
         Ok(Self {
             source_user_account_owner,
             source_user_account,
             destination_user_account_owner,
             destination_user_account,
+            market,
         })
     }
 }
@@ -52,6 +60,8 @@ pub fn process_transfer_position(
 ) -> ProgramResult {
     let accounts = Accounts::parse(program_id, accounts)?;
 
+    let market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
+
     let mut source_user_account_header =
         UserAccountState::unpack_from_slice(&accounts.source_user_account.data.borrow())?;
     let mut destination_user_account_header =
@@ -73,6 +83,19 @@ pub fn process_transfer_position(
         msg!("The user accounts should be associated to the same market");
         return Err(ProgramError::InvalidArgument);
     }
+    if &Pubkey::new(&source_user_account_header.market) != accounts.market.key {
+        msg!("The user accounts market doesn't match the given market account");
+        return Err(ProgramError::InvalidArgument);
+    }
+
+    // The position's funding would otherwise be extracted from the destination account at a different offset
+    if source_user_account_header.last_funding_offset != market_state.funding_history_offset
+        || destination_user_account_header.last_funding_offset
+            != market_state.funding_history_offset
+    {
+        msg!("Funding must be processed for both accounts.");
+        return Err(PerpError::PendingFunding.into());
+    }
 
     let position = get_position(
         &accounts.source_user_account.data.borrow_mut(),
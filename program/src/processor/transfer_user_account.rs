@@ -43,9 +43,15 @@ impl<'a, 'b: 'a> Accounts<'a, 'b> {
 pub fn process_transfer_user_account(
     program_id: &Pubkey,
     accounts: &[AccountInfo],
+    allow_immediate: bool,
 ) -> ProgramResult {
     let accounts = Accounts::parse(program_id, accounts)?;
 
+    if !allow_immediate {
+        msg!("Immediate transfers must be explicitly allowed, use a transfer proposal instead");
+        return Err(ProgramError::InvalidArgument);
+    }
+
     let mut user_account_header =
         UserAccountState::unpack_from_slice(&accounts.user_account.data.borrow())?;
 
@@ -0,0 +1,73 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+};
+
+use crate::{
+    error::PerpError,
+    state::market::MarketState,
+    utils::{check_account_owner, check_pyth_market_symbol, check_signer, pack_market_symbol},
+};
+
+struct Accounts<'a, 'b: 'a> {
+    market: &'a AccountInfo<'b>,
+    admin: &'a AccountInfo<'b>,
+    oracle: &'a AccountInfo<'b>,
+    pyth_product: Option<&'a AccountInfo<'b>>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+        let market = next_account_info(accounts_iter)?;
+        let admin = next_account_info(accounts_iter)?;
+        let oracle = next_account_info(accounts_iter)?;
+        let pyth_product = next_account_info(accounts_iter).ok();
+        check_account_owner(market, program_id)?;
+        check_signer(admin)?;
+        Ok(Self {
+            market,
+            admin,
+            oracle,
+            pyth_product,
+        })
+    }
+}
+
+pub fn process_update_market_symbol(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    new_symbol: String,
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let mut market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
+
+    if &Pubkey::new(&market_state.admin_address) != accounts.admin.key {
+        msg!("Invalid admin account for the current market");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if new_symbol.is_empty() {
+        msg!("The market symbol cannot be empty.");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if market_state.oracle_address != accounts.oracle.key.to_bytes() {
+        msg!("Provided oracle account is incorrect.");
+        return Err(PerpError::OracleMismatch.into());
+    }
+    check_pyth_market_symbol(accounts.oracle, accounts.pyth_product, &new_symbol)?;
+
+    msg!("New market symbol: {:?}", new_symbol);
+    market_state.market_symbol = pack_market_symbol(&new_symbol)?;
+
+    market_state.pack_into_slice(&mut accounts.market.data.borrow_mut());
+
+    Ok(())
+}
@@ -11,7 +11,10 @@ use spl_token::instruction::transfer;
 
 use crate::{
     error::PerpError,
-    state::{market::MarketState, user_account::UserAccountState},
+    state::{
+        market::MarketState,
+        user_account::{get_position, UserAccountState},
+    },
     utils::{check_account_key, check_account_owner, check_signer},
 };
 
@@ -64,6 +67,11 @@ pub fn process_withdraw_budget(
 ) -> ProgramResult {
     let accounts = Accounts::parse(program_id, accounts)?;
 
+    if amount == 0 {
+        msg!("The withdrawn amount cannot be zero");
+        return Err(PerpError::Nop.into());
+    }
+
     // Parsing
     let mut market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
 
@@ -88,6 +96,26 @@ pub fn process_withdraw_budget(
         return Err(PerpError::NoMoreFunds.into());
     }
 
+    // The remaining balance must be able to pay the funding which wasn't extracted yet
+    let mut pending_funding = 0u64;
+    for position_index in 0..user_account_header.number_of_open_positions as u16 {
+        let position = get_position(
+            &accounts.user_account.data.borrow(),
+            &user_account_header,
+            position_index,
+        )?;
+        pending_funding = pending_funding
+            .checked_add(market_state.get_max_pending_funding(&position)?)
+            .ok_or(PerpError::Overflow)?;
+    }
+    if user_account_header.balance - amount < pending_funding {
+        msg!(
+            "The remaining balance must cover the pending funding of {:?}",
+            pending_funding
+        );
+        return Err(PerpError::NoMoreFunds.into());
+    }
+
     user_account_header.balance -= amount;
     market_state.total_user_balances -= amount;
 
@@ -0,0 +1,123 @@
+use solana_program::{
+    account_info::{next_account_info, AccountInfo},
+    entrypoint::ProgramResult,
+    msg,
+    program::invoke_signed,
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+};
+use spl_token::instruction::transfer;
+
+use crate::{
+    error::PerpError,
+    state::market::MarketState,
+    utils::{
+        check_account_key, check_account_owner, check_distinct_accounts, check_signer,
+        check_target_token_account, get_vault_balance,
+    },
+};
+
+struct Accounts<'a, 'b: 'a> {
+    spl_token_program: &'a AccountInfo<'b>,
+    market: &'a AccountInfo<'b>,
+    market_signer: &'a AccountInfo<'b>,
+    market_vault: &'a AccountInfo<'b>,
+    admin: &'a AccountInfo<'b>,
+    target: &'a AccountInfo<'b>,
+}
+
+impl<'a, 'b: 'a> Accounts<'a, 'b> {
+    pub fn parse(
+        program_id: &Pubkey,
+        accounts: &'a [AccountInfo<'b>],
+    ) -> Result<Self, ProgramError> {
+        let accounts_iter = &mut accounts.iter();
+
+        let spl_token_program = next_account_info(accounts_iter)?;
+        let market = next_account_info(accounts_iter)?;
+        let market_signer = next_account_info(accounts_iter)?;
+        let market_vault = next_account_info(accounts_iter)?;
+        let admin = next_account_info(accounts_iter)?;
+        let target = next_account_info(accounts_iter)?;
+
+        check_account_key(spl_token_program, &spl_token::id())?;
+        check_account_owner(market, program_id)?;
+        check_signer(admin)?;
+        check_distinct_accounts(&[market_vault, target])?;
+        check_target_token_account(target, market_vault)?;
+
+        Ok(Self {
+            spl_token_program,
+            market,
+            market_signer,
+            market_vault,
+            admin,
+            target,
+        })
+    }
+}
+
+pub fn process_withdraw_fees(
+    program_id: &Pubkey,
+    accounts: &[AccountInfo],
+    amount: u64,
+) -> ProgramResult {
+    let accounts = Accounts::parse(program_id, accounts)?;
+
+    let mut market_state = MarketState::unpack_from_slice(&accounts.market.data.borrow())?;
+
+    // Verifications
+    if &Pubkey::new(&market_state.admin_address) != accounts.admin.key {
+        msg!("Invalid admin account for the current market");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if amount > market_state.protocol_fee_balance {
+        msg!(
+            "At most {:?} can be withdrawn from the protocol fee balance",
+            market_state.protocol_fee_balance
+        );
+        return Err(PerpError::NoMoreFunds.into());
+    }
+
+    // The protocol fees are a liability of the vault like the others, but they are only paid out once the
+    // positions are covered
+    let market_vault_balance = get_vault_balance(&market_state, accounts.market_vault)?;
+    let insurance_fund = market_state.get_insurance_fund(market_vault_balance)?;
+    if insurance_fund < 0 {
+        msg!(
+            "The fees cannot be withdrawn while the insurance fund is at {:?}",
+            insurance_fund
+        );
+        return Err(PerpError::InsuranceFundDepleted.into());
+    }
+
+    market_state.protocol_fee_balance -= amount;
+
+    let instruction = transfer(
+        &spl_token::id(),
+        accounts.market_vault.key,
+        accounts.target.key,
+        accounts.market_signer.key,
+        &[],
+        amount,
+    )?;
+
+    invoke_signed(
+        &instruction,
+        &[
+            accounts.spl_token_program.clone(),
+            accounts.market_vault.clone(),
+            accounts.target.clone(),
+            accounts.market_signer.clone(),
+        ],
+        &[&[
+            &accounts.market.key.to_bytes(),
+            &[market_state.signer_nonce],
+        ]],
+    )?;
+
+    market_state.pack_into_slice(&mut accounts.market.data.borrow_mut());
+
+    Ok(())
+}
@@ -1,8 +1,11 @@
+#[cfg(feature = "fuzz")]
+use arbitrary::Arbitrary;
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::account_info::AccountInfo;
 
 pub mod instance;
 pub mod market;
+pub mod referral;
 pub mod user_account;
 
 #[derive(BorshDeserialize, BorshSerialize)]
@@ -12,6 +15,8 @@ pub enum StateObject {
     UserAccount,
     MemoryPage,
     Instance,
+    TransferProposal,
+    ReferrerRecord,
 }
 pub fn is_initialized(account: &AccountInfo) -> bool {
     account.data.borrow()[0] != (StateObject::Uninitialized as u8)
@@ -25,14 +30,75 @@ pub struct Fees {
 }
 
 #[cfg_attr(feature = "fuzz", derive(Arbitrary))]
-#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, Debug, PartialEq, Eq)]
 pub enum PositionType {
     Short,
     Long,
 }
 
 impl PositionType {
+    // Longs are counted positively and shorts negatively throughout the protocol
     pub fn get_sign(&self) -> i64 {
         (2 * (*self as i64)) - 1
     }
+
+    // Inverse of get_sign, a null sign has no side
+    pub fn from_sign(sign: i64) -> Option<Self> {
+        match sign.signum() {
+            1 => Some(PositionType::Long),
+            -1 => Some(PositionType::Short),
+            _ => None,
+        }
+    }
+
+    pub fn flip(&self) -> Self {
+        match self {
+            PositionType::Short => PositionType::Long,
+            PositionType::Long => PositionType::Short,
+        }
+    }
+
+    // Longs are liquidated when the price falls to their liquidation index and shorts when it rises to it.
+    // Tells whether positions whose liquidation index lies above the price (or below it when false) are the liquidated ones.
+    pub fn is_liquidation_side(&self, index_above_price: bool) -> bool {
+        index_above_price ^ (*self == PositionType::Short)
+    }
+}
+
+/// How the cranker's reward for a liquidation is computed
+#[cfg_attr(feature = "fuzz", derive(Arbitrary))]
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize, Debug, PartialEq)]
+pub enum LiquidationRewardMode {
+    RemainingValue, // The remaining value of the liquidated positions
+    Notional,       // A share of the liquidated notional, in basis points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_position_type_sign() {
+        assert_eq!(PositionType::Long.get_sign(), 1);
+        assert_eq!(PositionType::Short.get_sign(), -1);
+        for side in [PositionType::Long, PositionType::Short] {
+            assert_eq!(side.flip().get_sign(), -side.get_sign());
+            assert_ne!(side.flip(), side);
+            assert_eq!(side.flip().flip(), side);
+            assert_eq!(PositionType::from_sign(side.get_sign()), Some(side));
+            assert_eq!(
+                PositionType::from_sign(-42 * side.get_sign()),
+                Some(side.flip())
+            );
+        }
+        assert_eq!(PositionType::from_sign(0), None);
+    }
+
+    #[test]
+    fn test_liquidation_side() {
+        assert!(PositionType::Long.is_liquidation_side(true));
+        assert!(!PositionType::Long.is_liquidation_side(false));
+        assert!(PositionType::Short.is_liquidation_side(false));
+        assert!(!PositionType::Short.is_liquidation_side(true));
+    }
 }
@@ -1,4 +1,8 @@
-use crate::positions_book::{memory::Pointer, positions_book_tree::PositionsBook};
+use crate::{
+    error::PerpError,
+    positions_book::{memory::Pointer, positions_book_tree::PositionsBook},
+    utils::compute_funding_balancing_factor,
+};
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
     entrypoint::ProgramResult,
@@ -8,7 +12,11 @@ use solana_program::{
     pubkey::Pubkey,
 };
 
-use super::StateObject;
+use super::{market::MarketState, StateObject};
+
+pub const INSTANCE_VERSION: u8 = 1;
+// The version 0 header ends with number_of_pages, the page infos directly follow it
+pub const INSTANCE_V0_LEN: usize = 21;
 
 #[derive(BorshDeserialize, BorshSerialize, Debug)]
 pub struct Instance {
@@ -17,9 +25,67 @@ pub struct Instance {
     pub longs_pointer: Option<Pointer>,
     pub garbage_pointer: Option<Pointer>,
     pub number_of_pages: u32,
+    pub funding_history_offset: u8, // Market funding history offset up to which the balancing factors below are recorded
+    pub funding_balancing_factors: [u64; 16], // FP 32 measure of payment capping computed from the open interest of this instance only
 }
 
 impl Instance {
+    /// Records the balancing factors of the funding periods cranked on the market since the last call,
+    /// using the current long and short open interest of the instance. This open interest is the one
+    /// the missed periods were paid on only as long as the positions book hasn't been modified since,
+    /// so every instruction which modifies it has to call this first.
+    /// Returns false when the instance is already up to date.
+    pub fn record_funding(
+        &mut self,
+        book: &PositionsBook,
+        market_state: &MarketState,
+    ) -> Result<bool, PerpError> {
+        if self.funding_history_offset == market_state.funding_history_offset {
+            return Ok(false);
+        }
+        let (longs_v_coin, shorts_v_coin) = book.get_v_coin()?;
+        let cycle = self.funding_balancing_factors.len();
+        let mut i = self.funding_history_offset as usize;
+        while i != market_state.funding_history_offset as usize {
+            self.funding_balancing_factors[i] = compute_funding_balancing_factor(
+                market_state.funding_history[i],
+                longs_v_coin,
+                shorts_v_coin,
+            );
+            i = (i + 1) % cycle;
+        }
+        self.funding_history_offset = market_state.funding_history_offset;
+        Ok(true)
+    }
+
+    // Reads the header of a version 0 instance, which lacks the funding fields
+    pub fn unpack_v0(src: &[u8]) -> Result<Self, ProgramError> {
+        let v0_header = src
+            .get(..INSTANCE_V0_LEN)
+            .ok_or(ProgramError::InvalidAccountData)?;
+        if v0_header[0] != StateObject::Instance as u8 || v0_header[1] != 0 {
+            msg!("The account isn't a version 0 instance");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let (shorts_pointer, longs_pointer, garbage_pointer, number_of_pages) =
+            <(Option<Pointer>, Option<Pointer>, Option<Pointer>, u32)>::deserialize(
+                &mut &v0_header[2..],
+            )
+            .map_err(|_| {
+                msg!("Failed to deserialize instance account");
+                ProgramError::InvalidAccountData
+            })?;
+        Ok(Self {
+            version: INSTANCE_VERSION,
+            shorts_pointer,
+            longs_pointer,
+            garbage_pointer,
+            number_of_pages,
+            funding_history_offset: 0,
+            funding_balancing_factors: [0; 16],
+        })
+    }
+
     pub fn update(&mut self, book: &PositionsBook, page_infos: &mut Vec<PageInfo>) {
         self.shorts_pointer = book.shorts_root;
         self.longs_pointer = book.longs_root;
@@ -34,7 +100,7 @@ impl Instance {
 impl Sealed for Instance {}
 
 impl Pack for Instance {
-    const LEN: usize = 21;
+    const LEN: usize = 150;
 
     fn pack_into_slice(&self, dst: &mut [u8]) {
         dst[0] = StateObject::Instance as u8;
@@ -49,6 +115,13 @@ impl Pack for Instance {
             }
             return Err(ProgramError::InvalidAccountData);
         };
+        if src[1] != INSTANCE_VERSION {
+            msg!(
+                "The instance account has to be migrated to version {:?}",
+                INSTANCE_VERSION
+            );
+            return Err(PerpError::OutdatedAccount.into());
+        }
         Instance::deserialize(&mut &src[1..]).map_err(|_| {
             msg!("Failed to deserialize market account");
             ProgramError::InvalidAccountData
@@ -59,8 +132,10 @@ impl Pack for Instance {
 pub fn parse_instance(
     instance_account_data: &[u8],
 ) -> Result<(Instance, Vec<PageInfo>), ProgramError> {
+    // Version 0 instances can be smaller than the current header, they are still reported as outdated
     let header_slice = instance_account_data
         .get(0..Instance::LEN)
+        .or_else(|| instance_account_data.get(0..INSTANCE_V0_LEN))
         .ok_or(ProgramError::InvalidAccountData)?;
     let instance = Instance::unpack_from_slice(header_slice)?;
     let mut offset = Instance::LEN;
@@ -1,11 +1,13 @@
 use crate::{
     error::{PerpError, PerpResult},
+    positions_book::memory::PageStats,
     processor::{
-        ALLOCATION_FEE, FEE_BUY_BURN_BONFIDA, FEE_REBALANCING_FUND, FEE_REFERRER,
-        REBALANCING_LEVERAGE, REBALANCING_MARGIN,
+        ALLOCATION_FEE, FEE_BUY_BURN_BONFIDA, FEE_PROTOCOL, FEE_REBALANCING_FUND, FEE_REFERRER,
+        FUNDING_NORMALIZATION, FUNDING_PERIOD, FUNDING_STALENESS_PERIODS, HISTORY_PERIOD,
+        MAX_SLIPPAGE_MARGIN_BPS, MINIMAL_FUNDING, REBALANCING_LEVERAGE, REBALANCING_MARGIN,
     },
-    state::PositionType,
-    utils::compute_bias,
+    state::{LiquidationRewardMode, PositionType},
+    utils::{check_distinct_accounts, compute_bias, compute_payout},
 };
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
@@ -18,11 +20,16 @@ use solana_program::{
     pubkey::Pubkey,
 };
 use spl_token::instruction::transfer;
+use std::convert::TryInto;
 
-use super::{Fees, StateObject};
+use super::{referral::record_referral, user_account::OpenPosition, Fees, StateObject};
 
 // Pubkeys are stored as [u8; 32] for use with borsh
 
+pub const MARKET_STATE_VERSION: u8 = 1;
+// The version 0 header ends with number_of_instances, the instance addresses directly follow it
+pub const MARKET_STATE_V0_LEN: usize = 507;
+
 #[derive(BorshDeserialize, BorshSerialize, Debug, Clone)]
 pub struct MarketState {
     pub version: u8,
@@ -50,14 +57,25 @@ pub struct MarketState {
     pub funding_samples_sum: i64,
     pub funding_history_offset: u8,
     pub funding_history: [i64; 16],
-    pub funding_balancing_factors: [u64; 16], // FP 32 measure of payment capping to ensure that the insurance fund does not pay funding.
+    pub funding_balancing_factors: [u64; 16], // No longer maintained, kept for the layout. The funding extraction uses the factors recorded by each instance.
     pub number_of_instances: u32,
+    pub max_allowed_slippage_margin: u64, // FP32 upper bound on the slippage margin that traders can request
+    pub liquidation_insurance_share_bps: u16, // Part of the cranker's liquidation reward which is kept by the insurance fund, in basis points
+    pub paused: bool,                         // When set, no position can be opened or increased
+    pub min_collateral: u64, // Positions cannot be opened or partially closed with less collateral than this
+    pub max_open_longs_v_coin: u64, // Upper bound on open_longs_v_coin, no new long exposure is accepted above it
+    pub max_open_shorts_v_coin: u64, // Upper bound on open_shorts_v_coin, no new short exposure is accepted above it
+    pub last_rebalance_timestamp: u64, // Time of the last rebalancing which wasn't signed by the admin
+    pub liquidation_reward_mode: LiquidationRewardMode,
+    pub liquidation_reward_bps: u16, // Share of the liquidated v_pc paid out as a reward in the Notional mode
+    pub protocol_fee_balance: u64, // Share of the trading fees which the admin can withdraw, it is held in the vault until then
+    pub reserved: [u64; 7], // Zeroed, taken by future fields without moving the instance addresses
 }
 
 impl Sealed for MarketState {}
 
 impl Pack for MarketState {
-    const LEN: usize = 507;
+    const LEN: usize = 617;
 
     fn pack_into_slice(&self, dst: &mut [u8]) {
         dst[0] = StateObject::MarketState as u8;
@@ -71,6 +89,13 @@ impl Pack for MarketState {
             }
             return Err(ProgramError::InvalidAccountData);
         };
+        if src[1] != MARKET_STATE_VERSION {
+            msg!(
+                "The market account has to be migrated to version {:?}",
+                MARKET_STATE_VERSION
+            );
+            return Err(PerpError::OutdatedAccount.into());
+        }
         MarketState::deserialize(&mut &src[1..]).map_err(|_| {
             msg!("Failed to deserialize market account");
             ProgramError::InvalidAccountData
@@ -79,6 +104,31 @@ impl Pack for MarketState {
 }
 
 impl MarketState {
+    // Reads the header of a version 0 market. The fields which were added since then are set to
+    // the values which keep the market's behaviour unchanged.
+    pub fn unpack_v0(src: &[u8]) -> Result<Self, ProgramError> {
+        let v0_header = src
+            .get(..MARKET_STATE_V0_LEN)
+            .ok_or(ProgramError::InvalidAccountData)?;
+        if v0_header[0] != StateObject::MarketState as u8 || v0_header[1] != 0 {
+            msg!("The account isn't a version 0 market");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        // The new fields are deserialized from zeroes and overwritten
+        let mut header = [0u8; MarketState::LEN];
+        header[..MARKET_STATE_V0_LEN].copy_from_slice(v0_header);
+        let mut market_state = MarketState::deserialize(&mut &header[1..]).map_err(|_| {
+            msg!("Failed to deserialize market account");
+            ProgramError::InvalidAccountData
+        })?;
+        market_state.version = MARKET_STATE_VERSION;
+        market_state.max_allowed_slippage_margin = u64::MAX;
+        market_state.max_open_longs_v_coin = u64::MAX;
+        market_state.max_open_shorts_v_coin = u64::MAX;
+        market_state.liquidation_reward_mode = LiquidationRewardMode::RemainingValue;
+        Ok(market_state)
+    }
+
     pub fn compute_add_v_coin(&self, v_pc_amount: i64) -> Result<i64, PerpError> {
         let final_v_pc = self.v_pc_amount as i64 + v_pc_amount;
         if final_v_pc.is_negative() {
@@ -129,10 +179,25 @@ impl MarketState {
         amount_v_pc: u64,
         side: PositionType,
     ) -> Result<(), PerpError> {
-        let (pt_v_coin, pt_v_pc) = match side {
-            PositionType::Long => (&mut self.open_longs_v_coin, &mut self.open_longs_v_pc),
-            PositionType::Short => (&mut self.open_shorts_v_coin, &mut self.open_shorts_v_pc),
+        let (pt_v_coin, pt_v_pc, max_v_coin) = match side {
+            PositionType::Long => (
+                &mut self.open_longs_v_coin,
+                &mut self.open_longs_v_pc,
+                self.max_open_longs_v_coin,
+            ),
+            PositionType::Short => (
+                &mut self.open_shorts_v_coin,
+                &mut self.open_shorts_v_pc,
+                self.max_open_shorts_v_coin,
+            ),
         };
+        if pt_v_coin.saturating_add(amount_v_coin) > max_v_coin {
+            msg!(
+                "The open interest on this side would exceed the market's maximum of {:?}",
+                max_v_coin
+            );
+            return Err(PerpError::OpenInterestTooLarge);
+        }
         pt_v_coin
             .checked_add(amount_v_coin)
             .map(|s| *pt_v_coin = s)
@@ -170,8 +235,13 @@ impl MarketState {
         v_pc_to_add: i64,
         v_coin_to_add: i64,
         oracle_price: u64,
-    ) -> Result<(i64, i64), ProgramError> {
-        let side_sign = -v_coin_to_add.signum();
+    ) -> Result<(i64, i64), PerpError> {
+        // Taking v_coin out of the vAMM is a long
+        let side = match PositionType::from_sign(-v_coin_to_add) {
+            Some(side) => side,
+            None => return Ok((v_pc_to_add, v_coin_to_add)),
+        };
+        let side_sign = side.get_sign();
         let mut balanced_pc_to_add = v_pc_to_add;
         let mut balanced_v_coin_to_add = v_coin_to_add;
         let open_longs = self.open_longs_v_coin as i64;
@@ -180,14 +250,15 @@ impl MarketState {
         let current_market_bias =
             compute_bias(delta, self.v_coin_amount, self.v_pc_amount, oracle_price);
 
-        if -side_sign * current_market_bias > REBALANCING_MARGIN {
+        // The operation has to worsen the market's bias for the vAMM to take the other side
+        if side.flip().get_sign() * current_market_bias > REBALANCING_MARGIN {
             let mut rebalancing_contribution_v_coin;
             let mut rebalancing_contribution_pc = 0;
             if (side_sign * self.rebalanced_v_coin) > 0 {
-                // Close the Vamm's opposite position
-                rebalancing_contribution_v_coin = side_sign
+                // Close the Vamm's opposite position, which shrinks by the v_coin it takes off the pool
+                rebalancing_contribution_v_coin = -side_sign
                     * core::cmp::min(balanced_v_coin_to_add.abs(), self.rebalanced_v_coin.abs());
-                balanced_v_coin_to_add += rebalancing_contribution_v_coin;
+                balanced_v_coin_to_add -= rebalancing_contribution_v_coin;
                 balanced_pc_to_add = self.compute_add_v_pc(balanced_v_coin_to_add)?;
             } else {
                 rebalancing_contribution_pc = core::cmp::min(
@@ -206,7 +277,7 @@ impl MarketState {
                 ((self.v_pc_amount as i64) + balanced_pc_to_add) as u64,
                 oracle_price,
             );
-            if -side_sign * updated_bias < REBALANCING_MARGIN {
+            if side.flip().get_sign() * updated_bias < REBALANCING_MARGIN {
                 // To avoid overshooting the margin, which might induce market instability and fast depletion of rebalancing funds, we
                 // cancel the rebalancing operation.
                 rebalancing_contribution_pc = 0;
@@ -231,8 +302,7 @@ impl MarketState {
         apply_allocation_fee: bool,
     ) -> Result<(), PerpError> {
         self.total_user_balances = self.total_user_balances.checked_sub(fees.fixed).unwrap();
-        self.rebalancing_funds +=
-            ((fees.fixed as u128) * (FEE_REBALANCING_FUND as u128) / 100) as u64 + 1;
+        self.credit_fees(fees);
 
         if apply_refunds {
             self.total_fee_balance = self.total_fee_balance.checked_sub(fees.refundable).unwrap();
@@ -247,6 +317,13 @@ impl MarketState {
         Ok(())
     }
 
+    // Sets aside the rebalancing and protocol shares of the fixed fees
+    pub fn credit_fees(&mut self, fees: &Fees) {
+        self.rebalancing_funds +=
+            ((fees.fixed as u128) * (FEE_REBALANCING_FUND as u128) / 100) as u64 + 1;
+        self.protocol_fee_balance += ((fees.fixed as u128) * (FEE_PROTOCOL as u128) / 100) as u64;
+    }
+
     #[allow(clippy::clippy::too_many_arguments)]
     pub fn transfer_fees<'a>(
         &mut self,
@@ -257,18 +334,68 @@ impl MarketState {
         market_signer_account: &AccountInfo<'a>,
         bnb_bonfida: &AccountInfo<'a>,
         referrer_account_opt: Option<&AccountInfo<'a>>,
+        referrer_record_opt: Option<&AccountInfo<'a>>,
     ) -> ProgramResult {
+        let referrer_fee = self.transfer_fees_with_insurance_share(
+            fees,
+            spl_token_program,
+            market_account,
+            market_vault_account,
+            market_signer_account,
+            bnb_bonfida,
+            referrer_account_opt,
+            0,
+        )?;
+        if let Some(referrer_account) = referrer_account_opt {
+            msg!(
+                "Referral: {{\"referrer\": \"{}\", \"fee\": {}}}",
+                referrer_account.key,
+                referrer_fee
+            );
+            if let Some(referrer_record) = referrer_record_opt {
+                record_referral(
+                    market_account,
+                    referrer_account,
+                    referrer_record,
+                    referrer_fee,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Transfers the fees like `transfer_fees`, but keeps `insurance_share_bps` basis points of the referrer fee
+    /// in the market vault, where they are accounted for as part of the insurance fund.
+    /// Returns the amount transferred to the referrer account.
+    #[allow(clippy::clippy::too_many_arguments)]
+    pub fn transfer_fees_with_insurance_share<'a>(
+        &mut self,
+        fees: &mut Fees,
+        spl_token_program: &AccountInfo<'a>,
+        market_account: &AccountInfo<'a>,
+        market_vault_account: &AccountInfo<'a>,
+        market_signer_account: &AccountInfo<'a>,
+        bnb_bonfida: &AccountInfo<'a>,
+        referrer_account_opt: Option<&AccountInfo<'a>>,
+        insurance_share_bps: u16,
+    ) -> Result<u64, ProgramError> {
+        let mut token_accounts = vec![market_vault_account, bnb_bonfida];
+        token_accounts.extend(referrer_account_opt);
+        check_distinct_accounts(&token_accounts)?;
+        let mut transferred_referrer_fee = 0;
         let mut buy_and_burn_fee =
             ((fees.fixed as u128) * (FEE_BUY_BURN_BONFIDA as u128) / 100) as u64;
         let referrer_fee = ((fees.fixed as u128) * (FEE_REFERRER as u128) / 100) as u64;
         if let Some(referrer_account) = referrer_account_opt {
+            let insurance_share =
+                ((referrer_fee as u128) * (insurance_share_bps as u128) / 10_000) as u64;
             let instruction = transfer(
                 &spl_token::id(),
                 market_vault_account.key,
                 referrer_account.key,
                 market_signer_account.key,
                 &[],
-                referrer_fee,
+                referrer_fee - insurance_share,
             )?;
             invoke_signed(
                 &instruction,
@@ -280,6 +407,7 @@ impl MarketState {
                 ],
                 &[&[&market_account.key.to_bytes(), &[self.signer_nonce]]],
             )?;
+            transferred_referrer_fee = referrer_fee - insurance_share;
         } else {
             // Referrer fee gets split between buy and burn and insurance fund when not specified
             buy_and_burn_fee += referrer_fee / 2;
@@ -302,24 +430,117 @@ impl MarketState {
             ],
             &[&[&market_account.key.to_bytes(), &[self.signer_nonce]]],
         )?;
+        Ok(transferred_referrer_fee)
+    }
+
+    /// The reward owed for a liquidation before it gets capped by the insurance fund.
+    /// `remaining_value` is what is left of the liquidated positions once closed, `liquidated_v_pc` their notional.
+    pub fn compute_liquidation_reward(&self, remaining_value: i64, liquidated_v_pc: u64) -> i64 {
+        match self.liquidation_reward_mode {
+            LiquidationRewardMode::RemainingValue => std::cmp::max(0, remaining_value),
+            LiquidationRewardMode::Notional => {
+                ((liquidated_v_pc as u128) * (self.liquidation_reward_bps as u128) / 10_000) as i64
+            }
+        }
+    }
+
+    /// The insurance fund is what remains in the market vault once every liability is accounted for.
+    /// The market vault balance has to be fetched from the market's vault token account.
+    pub fn get_insurance_fund(&self, market_vault_balance: u64) -> Result<i64, PerpError> {
+        Ok(self
+            .get_insurance_fund_breakdown(market_vault_balance)?
+            .insurance_fund)
+    }
+
+    /// Refuses operations which would leave the insurance fund negative, or make it worse when the market is already underwater.
+    /// `insurance_fund_before` is the value of the insurance fund before the operation was processed.
+    pub fn check_insurance_fund(
+        &self,
+        insurance_fund_before: i64,
+        market_vault_balance: u64,
+    ) -> Result<(), PerpError> {
+        let insurance_fund = self.get_insurance_fund(market_vault_balance)?;
+        if insurance_fund < 0 && insurance_fund < insurance_fund_before {
+            msg!(
+                "The insurance fund would go from {:?} to {:?}",
+                insurance_fund_before,
+                insurance_fund
+            );
+            return Err(PerpError::InsuranceFundDepleted);
+        }
         Ok(())
     }
 
-    pub fn get_insurance_fund(&self, market_vault_balance: u64) -> i64 {
-        let delta = -self
-            .compute_add_v_pc((self.open_longs_v_coin as i64) - (self.open_shorts_v_coin as i64))
-            .unwrap();
-        let total_payout = delta
-            .checked_add(self.total_collateral as i64)
-            .and_then(|s| s.checked_add(self.open_shorts_v_pc as i64))
-            .and_then(|s| s.checked_sub(self.open_longs_v_pc as i64))
-            .unwrap();
-        let total_payout = std::cmp::max(0, total_payout) as u64;
-        (market_vault_balance as i64)
-            - (total_payout as i64)
-            - (self.total_user_balances as i64)
-            - (self.total_fee_balance as i64)
-            - (self.rebalancing_funds as i64)
+    /// Details each of the liabilities that are deducted from the market vault to compute the insurance fund.
+    pub fn get_insurance_fund_breakdown(
+        &self,
+        market_vault_balance: u64,
+    ) -> Result<InsuranceFundBreakdown, PerpError> {
+        // The amounts are summed as i128 since the u64 balances of a large market don't fit in an i64
+        let open_v_coin = (self.open_longs_v_coin as i128) - (self.open_shorts_v_coin as i128);
+        let delta = -(self
+            .compute_add_v_pc(open_v_coin.try_into().map_err(|_| PerpError::Overflow)?)?
+            as i128);
+        let total_payout =
+            delta + (self.total_collateral as i128) + (self.open_shorts_v_pc as i128)
+                - (self.open_longs_v_pc as i128);
+        let total_payout = std::cmp::max(0, total_payout);
+        let insurance_fund = (market_vault_balance as i128)
+            - total_payout
+            - (self.total_user_balances as i128)
+            - (self.total_fee_balance as i128)
+            - (self.protocol_fee_balance as i128)
+            - (self.rebalancing_funds as i128);
+        Ok(InsuranceFundBreakdown {
+            market_vault_balance,
+            total_payout: total_payout.try_into().map_err(|_| PerpError::Overflow)?,
+            total_user_balances: self.total_user_balances,
+            total_fee_balance: self.total_fee_balance,
+            protocol_fee_balance: self.protocol_fee_balance,
+            rebalancing_funds: self.rebalancing_funds,
+            insurance_fund: insurance_fund.try_into().map_err(|_| PerpError::Overflow)?,
+        })
+    }
+
+    /// Number of positions that the given instances can still hold before new pages are needed.
+    /// A page's headroom is made of its never used slots and of the slots on its free list.
+    #[cfg(not(target_arch = "bpf"))]
+    pub fn remaining_capacity(page_stats: &[Vec<PageStats>]) -> RemainingCapacity {
+        let per_instance = page_stats
+            .iter()
+            .map(|pages| {
+                pages
+                    .iter()
+                    .map(|p| (p.uninitialized_slots as u64) + (p.free_slots as u64))
+                    .sum()
+            })
+            .collect::<Vec<u64>>();
+        RemainingCapacity {
+            total: per_instance.iter().sum(),
+            per_instance,
+        }
+    }
+
+    pub fn get_mark_price(&self) -> u64 {
+        (((self.v_pc_amount as u128) << 32) / (self.v_coin_amount as u128)) as u64
+    }
+
+    // The maximum slippage margin is bounded by a share of the mark price when it is set, so that
+    // the slippage checks keep protecting the trades
+    pub fn check_max_allowed_slippage_margin(
+        &self,
+        max_allowed_slippage_margin: u64,
+    ) -> ProgramResult {
+        let bound = (self.get_mark_price() as u128) * (MAX_SLIPPAGE_MARGIN_BPS as u128) / 10_000;
+        if (max_allowed_slippage_margin as u128) > bound {
+            msg!(
+                "The maximum slippage margin cannot exceed {:?} basis points of the mark price: {:?}",
+                MAX_SLIPPAGE_MARGIN_BPS,
+                bound
+            );
+            return Err(ProgramError::InvalidArgument);
+        }
+        Ok(())
     }
 
     pub fn slippage_protection(
@@ -327,8 +548,14 @@ impl MarketState {
         desired_mark_price: u64,
         slippage_margin: u64,
     ) -> Result<(), PerpError> {
-        let current_mark_price =
-            (((self.v_pc_amount as u128) << 32) / (self.v_coin_amount as u128)) as i64;
+        if slippage_margin > self.max_allowed_slippage_margin {
+            msg!(
+                "The slippage margin cannot exceed {:?}",
+                self.max_allowed_slippage_margin
+            );
+            return Err(PerpError::SlippageMarginTooLarge);
+        }
+        let current_mark_price = self.get_mark_price() as i64;
         let margin = (current_mark_price - (desired_mark_price as i64)).abs() as u64;
         if margin > slippage_margin {
             return Err(PerpError::NetworkSlippageTooLarge);
@@ -336,11 +563,224 @@ impl MarketState {
         Ok(())
     }
 
+    // Unlike slippage_protection, this is checked once the trade has moved the vAMM so that large orders
+    // are also protected against their own price impact.
+    pub fn fill_price_protection(
+        &self,
+        desired_mark_price: u64,
+        slippage_margin: u64,
+        fill_v_pc_amount: u64,
+        fill_v_coin_amount: u64,
+    ) -> Result<(), PerpError> {
+        if slippage_margin > self.max_allowed_slippage_margin {
+            msg!(
+                "The slippage margin cannot exceed {:?}",
+                self.max_allowed_slippage_margin
+            );
+            return Err(PerpError::SlippageMarginTooLarge);
+        }
+        let fill_price = ((fill_v_pc_amount as u128) << 32)
+            .checked_div(fill_v_coin_amount as u128)
+            .unwrap_or(0) as i128;
+        let margin = (fill_price - (desired_mark_price as i128)).abs();
+        if margin > slippage_margin as i128 {
+            msg!(
+                "The average fill price of {:?} (FP32) is too far from the predicted price",
+                fill_price
+            );
+            return Err(PerpError::NetworkSlippageTooLarge);
+        }
+        Ok(())
+    }
+
     pub fn get_k(&self) -> u128 {
         (self.v_coin_amount as u128)
             .checked_mul(self.v_pc_amount as u128)
             .unwrap()
     }
+
+    // Samples the deviation of the mark price from the index price once per history period, and once per funding
+    // period records the funding of the elapsed cycle in the next slot of the funding history ring buffer.
+    // Returns a Nop error when neither is due at the given unix timestamp.
+    pub fn record_funding_sample(
+        &mut self,
+        mark_price: u64,  // FP32
+        index_price: u64, // FP32
+        now: u64,
+    ) -> Result<(), PerpError> {
+        let mut nop = true;
+
+        if now > self.last_recording_timestamp + HISTORY_PERIOD {
+            let current_delta = (mark_price as i64) - (index_price as i64);
+            let current_value = current_delta.signum()
+                * ((((current_delta.abs() as u128) << 32) / (index_price as u128)) as i64);
+            self.funding_samples_sum += current_value;
+            self.funding_samples_count += 1;
+            self.last_recording_timestamp += HISTORY_PERIOD;
+            nop = false;
+        }
+
+        if now > self.last_funding_timestamp + FUNDING_PERIOD {
+            let funding_ratio = self.current_funding_rate_fp32();
+
+            let funding_history_offset = self.funding_history_offset as usize;
+            self.funding_history[funding_history_offset] =
+                (((funding_ratio as i128) * (mark_price as i128)) >> 32) as i64;
+            self.funding_history_offset =
+                (self.funding_history_offset + 1) % (self.funding_history.len() as u8);
+            let elapsed_funding_cycles = (now - self.last_funding_timestamp) / FUNDING_PERIOD;
+            self.last_funding_timestamp += elapsed_funding_cycles * FUNDING_PERIOD;
+            self.funding_samples_sum = 0;
+            self.funding_samples_count = 0;
+            nop = false;
+        }
+
+        if nop {
+            return Err(PerpError::Nop);
+        }
+        Ok(())
+    }
+
+    // FP32 funding ratio for the current funding cycle, as a fraction of the position size paid per funding period.
+    // A positive value means that longs pay shorts.
+    pub fn current_funding_rate_fp32(&self) -> i64 {
+        let s = self.funding_samples_sum;
+        let denom = (self.funding_samples_count as u64) * FUNDING_NORMALIZATION;
+        s.signum() * ((s.abs() as u64).checked_div(denom).unwrap_or(0)) as i64
+    }
+
+    // The last funding timestamp only moves by whole funding periods, it can lag behind a working crank by up to one period.
+    pub fn seconds_since_last_funding(&self, now: u64) -> u64 {
+        now.saturating_sub(self.last_funding_timestamp)
+    }
+
+    // Whether the funding crank looks stalled at the given unix timestamp, in which case positions stop paying funding.
+    pub fn is_funding_stalled(&self, now: u64) -> bool {
+        self.seconds_since_last_funding(now) > FUNDING_STALENESS_PERIODS * FUNDING_PERIOD
+    }
+
+    // Number of funding cycles recorded in the funding history ring buffer since the given offset
+    pub fn funding_cycles_since(&self, last_funding_offset: u8) -> u8 {
+        let cycle = self.funding_history.len() as u8;
+        (self.funding_history_offset + cycle - last_funding_offset % cycle) % cycle
+    }
+
+    // Offset of the funding history ring buffer lagging the given number of funding cycles behind the current one
+    pub fn funding_offset_before(&self, cycles: u8) -> u8 {
+        let cycle = self.funding_history.len() as u8;
+        (self.funding_history_offset + cycle - cycles % cycle) % cycle
+    }
+
+    // FP32 funding ratio accrued since the given offset by positions whose net exposure has the given sign.
+    // Funding received is inflated by the instance's balancing factors, with a floor, as an arbitrage incentive.
+    pub fn accrued_funding_ratio(
+        &self,
+        funding_balancing_factors: &[u64; 16],
+        last_funding_offset: u8,
+        side_sign: i64,
+    ) -> i128 {
+        let cycle = self.funding_history.len();
+        let mut funding_ratio = 0;
+        let mut i = last_funding_offset as usize % cycle;
+        while i != self.funding_history_offset as usize {
+            // The mark price is included in the funding history
+            let mut delta = (side_sign as i128) * (self.funding_history[i] as i128);
+            if delta.is_negative() {
+                delta = (delta
+                    * (std::cmp::max(funding_balancing_factors[i], MINIMAL_FUNDING) as i128))
+                    >> 32;
+            }
+            funding_ratio += delta;
+            i = (i + 1) % cycle;
+        }
+        funding_ratio
+    }
+
+    // Upper bound on the funding owed by a position for the funding cycles it wasn't charged for yet.
+    // Funding paid to the position is left out since the extraction nets it with the account's other positions.
+    pub fn get_max_pending_funding(&self, position: &OpenPosition) -> Result<u64, PerpError> {
+        let cycle = self.funding_history.len();
+        let mut debt_ratio = 0i128;
+        let mut i = position.last_funding_offset as usize % cycle;
+        while i != self.funding_history_offset as usize {
+            let delta = (position.side.get_sign() as i128) * (self.funding_history[i] as i128);
+            debt_ratio += delta.max(0);
+            i = (i + 1) % cycle;
+        }
+        (((position.v_coin_amount as i128) * debt_ratio) >> 32)
+            .try_into()
+            .map_err(|_| PerpError::Overflow)
+    }
+
+    // Payout of closing `closing_v_coin` of the position without withdrawing any collateral, before fees and rebalancing.
+    pub fn compute_closing_payout(
+        &self,
+        position: &OpenPosition,
+        closing_v_coin: u64,
+    ) -> Result<i64, PerpError> {
+        if closing_v_coin == 0 {
+            return Ok(0);
+        }
+        let v_pc_closing_amount =
+            self.compute_add_v_pc(position.side.get_sign() * (closing_v_coin as i64))?;
+        let v_pc_to_settle = ((closing_v_coin as u128) * (position.v_pc_amount as u128)
+            / (position.v_coin_amount as u128)) as u64;
+        Ok(compute_payout(
+            v_pc_closing_amount.abs() as u64,
+            v_pc_to_settle,
+            0,
+            &position.side,
+        ))
+    }
+
+    // Smallest amount of v_coin to close for the position's payout to reach `target_payout`, a negative target being a loss.
+    // This is meant for clients, the actual payout also depends on fees and on the market's rebalancing.
+    pub fn v_coin_for_target_payout(
+        &self,
+        position: &OpenPosition,
+        target_payout: i64,
+    ) -> Result<u64, PerpError> {
+        // The payout is a concave function of the closed amount which is null when nothing is closed
+        let (mut low, mut high) = (0, position.v_coin_amount);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if self.compute_closing_payout(position, mid + 1)?
+                > self.compute_closing_payout(position, mid)?
+            {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        let peak = low;
+
+        // Gains are reached before the peak, while losses are reached after it
+        let reached = |payout: i64| {
+            if target_payout >= 0 {
+                payout >= target_payout
+            } else {
+                payout <= target_payout
+            }
+        };
+        let (mut low, mut high) = if target_payout >= 0 {
+            (0, peak)
+        } else {
+            (peak, position.v_coin_amount)
+        };
+        if !reached(self.compute_closing_payout(position, high)?) {
+            msg!("The target payout cannot be reached by closing this position");
+            return Err(PerpError::AmountTooLarge);
+        }
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if reached(self.compute_closing_payout(position, mid)?) {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+        Ok(low)
+    }
 }
 
 // Getter and setter functions
@@ -375,12 +815,36 @@ pub fn write_instance_address(
     Ok(())
 }
 
-// Struct used to store data about markets for monitoring purposes
+/// Components of the insurance fund computation, used for off-chain monitoring
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InsuranceFundBreakdown {
+    pub market_vault_balance: u64,
+    pub total_payout: u64, // Amount owed to the open positions if they were all closed at the current mark price
+    pub total_user_balances: u64,
+    pub total_fee_balance: u64,
+    pub protocol_fee_balance: u64, // Owed to the admin, it isn't available to cover the positions
+    pub rebalancing_funds: u64,
+    pub insurance_fund: i64,
+}
+
+/// Free memory slots of a market, used to add pages before the instances run out of space
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RemainingCapacity {
+    pub total: u64,
+    pub per_instance: Vec<u64>,
+}
+
+/// Snapshot of a market's state used for off-chain monitoring only, it is never stored on-chain.
+/// Serialization is available with the `serde` feature.
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MarketDataPoint {
     pub total_collateral: u64,
     pub total_user_balances: u64,
     pub total_fee_balance: u64,
+    pub protocol_fee_balance: u64,
     pub rebalancing_funds: u64,
     pub rebalanced_v_coin: i64,
     pub v_coin_amount: u64,
@@ -393,7 +857,8 @@ pub struct MarketDataPoint {
     pub funding_samples_sum: i64,
     pub funding_history_offset: u8,
     pub funding_history: [i64; 16],
-    pub funding_balancing_factors: [u64; 16], // FP 32 measure of payment capping to ensure that the insurance fund does not pay funding.
+    pub seconds_since_last_funding: u64,
+    pub funding_stalled: bool,
     pub number_of_instances: u32,
     pub insurance_fund: i64,
     pub market_price: f64,
@@ -401,6 +866,475 @@ pub struct MarketDataPoint {
     pub equilibrium_price: f64,
     pub gc_list_lengths: Vec<u64>,
     pub page_full_ratios: Vec<Vec<f64>>,
+    pub page_stats: Vec<Vec<PageStats>>,
+    pub remaining_capacity: RemainingCapacity,
     pub longs_depths: Vec<u64>,
     pub shorts_depths: Vec<u64>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A market of 1_000_000 v_coin at a mark price of 10_000 with no open positions
+    fn market_state(rebalanced_v_coin: i64, rebalancing_funds: u64) -> MarketState {
+        MarketState {
+            version: MARKET_STATE_VERSION,
+            signer_nonce: 0,
+            market_symbol: [0; 32],
+            oracle_address: [0; 32],
+            admin_address: [0; 32],
+            vault_address: [0; 32],
+            quote_decimals: 6,
+            coin_decimals: 6,
+            total_collateral: 0,
+            total_user_balances: 0,
+            total_fee_balance: 0,
+            rebalancing_funds,
+            rebalanced_v_coin,
+            v_coin_amount: 1_000_000,
+            v_pc_amount: 10_000_000_000,
+            open_shorts_v_coin: 0,
+            open_longs_v_coin: 0,
+            open_shorts_v_pc: 0,
+            open_longs_v_pc: 0,
+            last_funding_timestamp: 0,
+            last_recording_timestamp: 0,
+            funding_samples_count: 0,
+            funding_samples_sum: 0,
+            funding_history_offset: 0,
+            funding_history: [0; 16],
+            funding_balancing_factors: [0; 16],
+            number_of_instances: 0,
+            max_allowed_slippage_margin: u64::MAX,
+            liquidation_insurance_share_bps: 0,
+            paused: false,
+            min_collateral: 0,
+            max_open_longs_v_coin: u64::MAX,
+            max_open_shorts_v_coin: u64::MAX,
+            last_rebalance_timestamp: 0,
+            liquidation_reward_mode: LiquidationRewardMode::RemainingValue,
+            liquidation_reward_bps: 0,
+            protocol_fee_balance: 0,
+            reserved: [0; 7],
+        }
+    }
+
+    // Returns the balanced amounts of a long order of the given v_pc size
+    fn balance_long(
+        market_state: &mut MarketState,
+        v_pc_amount: i64,
+        oracle_price: u64,
+    ) -> (i64, i64) {
+        let v_coin_amount = market_state.compute_add_v_coin(v_pc_amount).unwrap();
+        market_state
+            .balance_operation(v_pc_amount, v_coin_amount, oracle_price)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_balance_operation_within_margin() {
+        // The mark price matches the oracle price, the market isn't biased
+        let mut market_state = market_state(0, 1_000_000);
+        assert_eq!(
+            balance_long(&mut market_state, 10_000_000, 10_000 << 32),
+            (10_000_000, -999)
+        );
+        assert_eq!(market_state.rebalancing_funds, 1_000_000);
+        assert_eq!(market_state.rebalanced_v_coin, 0);
+    }
+
+    #[test]
+    fn test_balance_operation_closes_vamm_position() {
+        // The vAMM holds a position which a long order can close, so no rebalancing funds are used
+        let mut market_state = market_state(500, 0);
+        assert_eq!(
+            balance_long(&mut market_state, 10_000_000, 8_000 << 32),
+            (4_992_491, -499)
+        );
+        assert_eq!(market_state.rebalancing_funds, 0);
+        assert_eq!(market_state.rebalanced_v_coin, 0);
+    }
+
+    #[test]
+    fn test_balance_operation_unwinds_vamm_position() {
+        let oracle_price = 8_000 << 32;
+
+        // A long order smaller than the vAMM's long position is entirely filled by the vAMM
+        let mut market_state = market_state(1_500, 0);
+        let (v_pc, v_coin) = balance_long(&mut market_state, 10_000_000, oracle_price);
+        assert_eq!((v_pc, v_coin), (0, 0));
+        market_state.add_v_pc(v_pc).unwrap();
+        market_state.add_v_coin(v_coin).unwrap();
+        assert_eq!(market_state.v_coin_amount, 1_000_000);
+        assert_eq!(market_state.v_pc_amount, 10_000_000_000);
+        assert_eq!(market_state.rebalanced_v_coin, 501);
+
+        // The next one closes what remains of it, the rest of the order is taken from the pool
+        let (v_pc, v_coin) = balance_long(&mut market_state, 10_000_000, oracle_price);
+        assert_eq!((v_pc, v_coin), (4_982_481, -498));
+        market_state.add_v_pc(v_pc).unwrap();
+        market_state.add_v_coin(v_coin).unwrap();
+        assert_eq!(market_state.v_coin_amount, 999_502);
+        assert_eq!(market_state.v_pc_amount, 10_004_982_481);
+        assert_eq!(market_state.rebalanced_v_coin, 0);
+    }
+
+    #[test]
+    fn test_balance_operation_uses_rebalancing_funds() {
+        let mut market_state = market_state(0, 1_000_000);
+        assert_eq!(
+            balance_long(&mut market_state, 10_000_000, 8_000 << 32),
+            (9_000_000, -899)
+        );
+        assert_eq!(market_state.rebalancing_funds, 0);
+        assert_eq!(market_state.rebalanced_v_coin, -100);
+    }
+
+    #[test]
+    fn test_balance_operation_cancels_overshoot() {
+        // Deploying the rebalancing funds for this order would bring the bias back under the margin
+        let mut market_state = market_state(0, 1_000_000_000);
+        assert_eq!(
+            balance_long(&mut market_state, 100_000_000, 8_900 << 32),
+            (100_000_000, -9_900)
+        );
+        assert_eq!(market_state.rebalancing_funds, 1_000_000_000);
+        assert_eq!(market_state.rebalanced_v_coin, 0);
+    }
+
+    #[test]
+    fn test_balance_operation_without_v_coin() {
+        // An operation which doesn't trade any v_coin has no side and is left untouched, whatever the bias
+        let mut market_state = market_state(0, 1_000_000_000);
+        assert_eq!(
+            market_state.balance_operation(1_000, 0, 8_000 << 32),
+            Ok((1_000, 0))
+        );
+        assert_eq!(market_state.rebalancing_funds, 1_000_000_000);
+        assert_eq!(market_state.rebalanced_v_coin, 0);
+    }
+
+    #[test]
+    fn test_insurance_fund_large_balances() {
+        let mut market_state = market_state(0, 0);
+        market_state.total_user_balances = (1 << 63) - 5;
+        assert_eq!(market_state.get_insurance_fund(1 << 63), Ok(5));
+
+        market_state.total_user_balances = 0;
+        assert_eq!(
+            market_state.get_insurance_fund(u64::MAX),
+            Err(PerpError::Overflow)
+        );
+    }
+
+    #[test]
+    fn test_insurance_fund_breakdown() {
+        let mut market_state = market_state(0, 1_000);
+        market_state.total_user_balances = 10_000;
+        market_state.total_fee_balance = 500;
+        market_state.protocol_fee_balance = 200;
+        assert_eq!(
+            market_state.get_insurance_fund_breakdown(20_000),
+            Ok(InsuranceFundBreakdown {
+                market_vault_balance: 20_000,
+                total_payout: 0,
+                total_user_balances: 10_000,
+                total_fee_balance: 500,
+                protocol_fee_balance: 200,
+                rebalancing_funds: 1_000,
+                insurance_fund: 8_300,
+            })
+        );
+    }
+
+    #[test]
+    fn test_max_pending_funding() {
+        let mut market_state = market_state(0, 0);
+        market_state.funding_history[14] = 1 << 32;
+        market_state.funding_history[15] = -(1 << 31);
+        market_state.funding_history[0] = 1 << 31;
+        market_state.funding_history_offset = 1;
+        let position = |side, last_funding_offset| OpenPosition {
+            last_funding_offset,
+            instance_index: 0,
+            side,
+            liquidation_index: 0,
+            collateral: 0,
+            slot_number: 0,
+            v_coin_amount: 100,
+            v_pc_amount: 0,
+            stop_loss_index: 0,
+        };
+
+        // The history wraps around, and the funding received on the second cycle isn't deducted
+        assert_eq!(
+            market_state.get_max_pending_funding(&position(PositionType::Long, 14)),
+            Ok(150)
+        );
+        assert_eq!(
+            market_state.get_max_pending_funding(&position(PositionType::Short, 14)),
+            Ok(50)
+        );
+        assert_eq!(
+            market_state.get_max_pending_funding(&position(PositionType::Long, 1)),
+            Ok(0)
+        );
+    }
+
+    #[test]
+    fn test_v_coin_for_target_payout() {
+        let market_state = market_state(0, 0);
+        // Both positions were entered at a price of 9_000 while the mark price is now 10_000
+        let position = |side| OpenPosition {
+            last_funding_offset: 0,
+            instance_index: 0,
+            side,
+            liquidation_index: 0,
+            collateral: 1_000_000,
+            slot_number: 0,
+            v_coin_amount: 1_000,
+            v_pc_amount: 9_000_000,
+            stop_loss_index: 0,
+        };
+
+        let long = position(PositionType::Long);
+        let v_coin = market_state
+            .v_coin_for_target_payout(&long, 500_000)
+            .unwrap();
+        assert!(market_state.compute_closing_payout(&long, v_coin).unwrap() >= 500_000);
+        assert!(
+            market_state
+                .compute_closing_payout(&long, v_coin - 1)
+                .unwrap()
+                < 500_000
+        );
+        assert_eq!(market_state.v_coin_for_target_payout(&long, 0), Ok(0));
+        // The long can neither make 1_000_000 nor lose anything
+        assert_eq!(
+            market_state.v_coin_for_target_payout(&long, 1_000_000),
+            Err(PerpError::AmountTooLarge)
+        );
+        assert_eq!(
+            market_state.v_coin_for_target_payout(&long, -1),
+            Err(PerpError::AmountTooLarge)
+        );
+
+        let short = position(PositionType::Short);
+        let v_coin = market_state
+            .v_coin_for_target_payout(&short, -500_000)
+            .unwrap();
+        assert!(market_state.compute_closing_payout(&short, v_coin).unwrap() <= -500_000);
+        assert!(
+            market_state
+                .compute_closing_payout(&short, v_coin - 1)
+                .unwrap()
+                > -500_000
+        );
+        assert_eq!(
+            market_state.v_coin_for_target_payout(&short, 1),
+            Err(PerpError::AmountTooLarge)
+        );
+    }
+
+    #[test]
+    fn test_record_funding_sample() {
+        let mut market_state = market_state(0, 0);
+        let index_price = 10_000 << 32;
+        market_state.last_recording_timestamp = 100_000;
+        market_state.last_funding_timestamp = 100_000;
+
+        // Neither a sample nor a funding cycle is due yet
+        assert_eq!(
+            market_state.record_funding_sample(index_price, index_price, 100_000 + HISTORY_PERIOD),
+            Err(PerpError::Nop)
+        );
+
+        // A mark price 1% above the index price is sampled without closing the funding cycle
+        let now = 100_000 + HISTORY_PERIOD + 1;
+        assert_eq!(
+            market_state.record_funding_sample(index_price + index_price / 100, index_price, now),
+            Ok(())
+        );
+        assert_eq!(market_state.funding_samples_count, 1);
+        assert_eq!(market_state.funding_samples_sum, (1 << 32) / 100);
+        assert_eq!(market_state.funding_history_offset, 0);
+        assert_eq!(market_state.funding_history, [0; 16]);
+    }
+
+    #[test]
+    fn test_record_funding_sample_wraparound() {
+        let mut market_state = market_state(0, 0);
+        let cycle = market_state.funding_history.len() as u64;
+        let index_price = 10_000 << 32;
+        for k in 0..cycle + 3 {
+            let now = (k + 1) * FUNDING_PERIOD + 1;
+            let offset = market_state.funding_history_offset as usize;
+            let previous_history = market_state.funding_history;
+            // The mark price is k% above the index price, so that later cycles record a larger funding
+            let mark_price = index_price + k * (index_price / 100);
+            assert_eq!(
+                market_state.record_funding_sample(mark_price, index_price, now),
+                Ok(())
+            );
+
+            assert_eq!(offset as u64, k % cycle);
+            assert_eq!(market_state.funding_history_offset as u64, (k + 1) % cycle);
+            assert_eq!(
+                market_state.last_funding_timestamp,
+                (k + 1) * FUNDING_PERIOD
+            );
+            assert_eq!(market_state.funding_samples_count, 0);
+            assert_eq!(market_state.funding_samples_sum, 0);
+            // Only the slot at the previous offset is overwritten, past the end of the ring it replaces the oldest cycle
+            for (i, (&recorded, &previous)) in market_state
+                .funding_history
+                .iter()
+                .zip(previous_history.iter())
+                .enumerate()
+            {
+                if i == offset && k > 0 {
+                    assert!(recorded > previous);
+                } else if i != offset {
+                    assert_eq!(recorded, previous);
+                }
+            }
+        }
+        assert_eq!(market_state.funding_history_offset, 3);
+    }
+
+    #[test]
+    fn test_funding_ring_helpers() {
+        let mut market_state = market_state(0, 0);
+        for (i, f) in market_state.funding_history.iter_mut().enumerate() {
+            *f = (i as i64 + 1) << 32;
+        }
+        market_state.funding_history_offset = 2;
+        let balancing_factors = [1 << 31; 16];
+
+        // The pending cycles wrap around the end of the ring
+        assert_eq!(market_state.funding_cycles_since(14), 4);
+        assert_eq!(market_state.funding_cycles_since(2), 0);
+        assert_eq!(
+            market_state.accrued_funding_ratio(&balancing_factors, 14, 1),
+            (15 + 16 + 1 + 2) << 32
+        );
+        // Funding received is scaled by the balancing factors
+        assert_eq!(
+            market_state.accrued_funding_ratio(&balancing_factors, 14, -1),
+            -((15 + 16 + 1 + 2) << 31)
+        );
+        assert_eq!(
+            market_state.accrued_funding_ratio(&balancing_factors, 2, 1),
+            0
+        );
+
+        // Offsets lagging behind the current one wrap around the start of the ring
+        assert_eq!(market_state.funding_offset_before(4), 14);
+        assert_eq!(market_state.funding_offset_before(0), 2);
+    }
+
+    #[test]
+    fn test_funding_staleness() {
+        let mut market_state = market_state(0, 0);
+        market_state.last_funding_timestamp = 10 * FUNDING_PERIOD;
+        assert_eq!(market_state.seconds_since_last_funding(0), 0);
+        assert_eq!(
+            market_state.seconds_since_last_funding(11 * FUNDING_PERIOD + 5),
+            FUNDING_PERIOD + 5
+        );
+        assert!(!market_state.is_funding_stalled(12 * FUNDING_PERIOD));
+        assert!(market_state.is_funding_stalled(12 * FUNDING_PERIOD + 1));
+    }
+
+    #[test]
+    fn test_compute_liquidation_reward() {
+        let mut market_state = market_state(0, 0);
+        assert_eq!(
+            market_state.compute_liquidation_reward(1_000, 10_000_000),
+            1_000
+        );
+        assert_eq!(
+            market_state.compute_liquidation_reward(-1_000, 10_000_000),
+            0
+        );
+
+        market_state.liquidation_reward_mode = LiquidationRewardMode::Notional;
+        market_state.liquidation_reward_bps = 50;
+        assert_eq!(
+            market_state.compute_liquidation_reward(1_000, 10_000_000),
+            50_000
+        );
+        // The reward doesn't depend on what is left of the positions
+        assert_eq!(
+            market_state.compute_liquidation_reward(-1_000, 10_000_000),
+            50_000
+        );
+    }
+
+    #[test]
+    fn test_unpack_v0() {
+        let mut market_state = market_state(-500, 1_000);
+        market_state.max_allowed_slippage_margin = 100 << 32;
+        market_state.paused = true;
+        market_state.liquidation_reward_mode = LiquidationRewardMode::Notional;
+        let mut data = [0u8; MarketState::LEN];
+        market_state.pack_into_slice(&mut data);
+        assert!(MarketState::unpack_v0(&data).is_err());
+
+        // A version 0 header is the prefix of the current one
+        data[1] = 0;
+        assert_eq!(
+            MarketState::unpack_from_slice(&data).unwrap_err(),
+            PerpError::OutdatedAccount.into()
+        );
+        let migrated = MarketState::unpack_v0(&data[..MARKET_STATE_V0_LEN]).unwrap();
+        assert_eq!(migrated.version, MARKET_STATE_VERSION);
+        assert_eq!(migrated.rebalanced_v_coin, -500);
+        assert_eq!(migrated.rebalancing_funds, 1_000);
+        assert_eq!(migrated.v_pc_amount, market_state.v_pc_amount);
+        assert_eq!(migrated.max_allowed_slippage_margin, u64::MAX);
+        assert!(!migrated.paused);
+        assert_eq!(
+            migrated.liquidation_reward_mode,
+            LiquidationRewardMode::RemainingValue
+        );
+    }
+
+    #[test]
+    fn test_check_max_allowed_slippage_margin() {
+        let market_state = market_state(0, 0);
+        assert_eq!(market_state.get_mark_price(), 10_000 << 32);
+        // Up to 10% of the mark price
+        market_state
+            .check_max_allowed_slippage_margin(1_000 << 32)
+            .unwrap();
+        assert_eq!(
+            market_state
+                .check_max_allowed_slippage_margin((1_000 << 32) + 1)
+                .unwrap_err(),
+            ProgramError::InvalidArgument
+        );
+        assert!(market_state
+            .check_max_allowed_slippage_margin(u64::MAX)
+            .is_err());
+    }
+
+    #[test]
+    fn test_remaining_capacity() {
+        let page = |page_size, allocated_slots, free_slots| PageStats {
+            page_size,
+            allocated_slots,
+            free_slots,
+            uninitialized_slots: page_size - allocated_slots - free_slots,
+        };
+        let page_stats = vec![
+            vec![page(100, 100, 0), page(100, 40, 10)],
+            vec![],
+            vec![page(50, 0, 0)],
+        ];
+        let capacity = MarketState::remaining_capacity(&page_stats);
+        assert_eq!(capacity.per_instance, vec![60, 0, 50]);
+        assert_eq!(capacity.total, 110);
+    }
+}
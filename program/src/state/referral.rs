@@ -0,0 +1,93 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{
+    account_info::AccountInfo,
+    entrypoint::ProgramResult,
+    msg,
+    program_error::ProgramError,
+    program_pack::{Pack, Sealed},
+    pubkey::Pubkey,
+};
+
+use crate::error::PerpError;
+
+use super::{is_initialized, StateObject};
+
+pub const REFERRER_RECORD_SEED: &[u8] = b"referrer";
+
+// Lifetime earnings of a referrer on a market, updated whenever the record is passed along with the referrer account
+#[derive(BorshDeserialize, BorshSerialize, Debug)]
+pub struct ReferrerRecord {
+    pub market: [u8; 32],
+    pub referrer_account: [u8; 32],
+    pub total_fees: u64,
+    pub number_of_referrals: u64,
+}
+
+impl Sealed for ReferrerRecord {}
+
+impl Pack for ReferrerRecord {
+    const LEN: usize = 81;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        dst[0] = StateObject::ReferrerRecord as u8;
+        self.serialize(&mut &mut dst[1..]).unwrap();
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src[0] != StateObject::ReferrerRecord as u8 {
+            if src[0] == 0 {
+                return Err(ProgramError::UninitializedAccount);
+            }
+            return Err(ProgramError::InvalidAccountData);
+        };
+        ReferrerRecord::deserialize(&mut &src[1..]).map_err(|_| {
+            msg!("Failed to deserialize referrer record");
+            ProgramError::InvalidAccountData
+        })
+    }
+}
+
+pub fn get_referrer_record_address(
+    program_id: &Pubkey,
+    market: &Pubkey,
+    referrer_account: &Pubkey,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            REFERRER_RECORD_SEED,
+            &market.to_bytes(),
+            &referrer_account.to_bytes(),
+        ],
+        program_id,
+    )
+}
+
+pub fn record_referral(
+    market_account: &AccountInfo,
+    referrer_account: &AccountInfo,
+    referrer_record: &AccountInfo,
+    amount: u64,
+) -> ProgramResult {
+    // Clients can pass the record address of referrers which haven't registered
+    if referrer_record.owner != market_account.owner
+        || referrer_record.data_is_empty()
+        || !is_initialized(referrer_record)
+    {
+        msg!("The referrer isn't registered");
+        return Ok(());
+    }
+    let mut record = ReferrerRecord::unpack_from_slice(&referrer_record.data.borrow())?;
+    if record.market != market_account.key.to_bytes()
+        || record.referrer_account != referrer_account.key.to_bytes()
+    {
+        msg!("The referrer record doesn't match the referrer account");
+        return Err(ProgramError::InvalidArgument);
+    }
+    record.total_fees = record
+        .total_fees
+        .checked_add(amount)
+        .ok_or(PerpError::Overflow)?;
+    record.number_of_referrals += 1;
+    record.pack_into_slice(&mut referrer_record.data.borrow_mut());
+    Ok(())
+}
@@ -1,6 +1,7 @@
 use crate::{error::PerpError, processor::MAX_OPEN_POSITONS_PER_USER, state::PositionType};
 use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::{
+    account_info::AccountInfo,
     entrypoint::ProgramResult,
     msg,
     program_error::ProgramError,
@@ -11,7 +12,25 @@ use super::StateObject;
 
 // Pubkeys are stored as [u8; 32] for use with borsh
 
-#[derive(BorshDeserialize, BorshSerialize, Clone, Debug)]
+pub const USER_ACCOUNT_VERSION: u8 = 1;
+// Version 0 positions end with v_pc_amount, they have no stop_loss_index
+pub const OPEN_POSITION_V0_LEN: usize = 43;
+
+/// An open position, as stored in the user account right after the `UserAccountState` header.
+/// The position at index `i` starts at byte `UserAccountState::LEN + i * OpenPosition::LEN`.
+///
+/// Borsh layout (51 bytes), in field order:
+///
+///   - `last_funding_offset`: u8
+///   - `instance_index`: u8
+///   - `side`: u8 enum tag (0 for Long, 1 for Short)
+///   - `liquidation_index`: u64 (FP32)
+///   - `collateral`: u64
+///   - `slot_number`: u64
+///   - `v_coin_amount`: u64
+///   - `v_pc_amount`: u64
+///   - `stop_loss_index`: u64 (FP32, 0 when no stop-loss is set)
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq)]
 pub struct OpenPosition {
     pub last_funding_offset: u8,
     pub instance_index: u8,
@@ -21,10 +40,27 @@ pub struct OpenPosition {
     pub slot_number: u64,
     pub v_coin_amount: u64,
     pub v_pc_amount: u64,
+    // Oracle price at which anyone can close the position at market on behalf of its owner.
+    // It only matters while it lies between the oracle price and the liquidation index, a stop-loss
+    // left beyond the liquidation index after a position update is preempted by the liquidation.
+    pub stop_loss_index: u64,
 }
 
 impl OpenPosition {
     pub const INSTANCE_INDEX_OFFSET: usize = 1;
+
+    pub fn has_stop_loss(&self) -> bool {
+        self.stop_loss_index != 0
+    }
+
+    /// Whether the given oracle price (FP32) has crossed the position's stop-loss
+    pub fn is_stop_loss_triggered(&self, oracle_price: u64) -> bool {
+        self.has_stop_loss()
+            && match self.side {
+                PositionType::Long => oracle_price <= self.stop_loss_index,
+                PositionType::Short => oracle_price >= self.stop_loss_index,
+            }
+    }
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Clone, Copy, PartialEq, Debug)]
@@ -36,7 +72,7 @@ pub enum PositionState {
 impl Sealed for OpenPosition {}
 
 impl Pack for OpenPosition {
-    const LEN: usize = 43;
+    const LEN: usize = 51;
 
     fn pack_into_slice(&self, dst: &mut [u8]) {
         let mut p = dst;
@@ -52,7 +88,17 @@ impl Pack for OpenPosition {
     }
 }
 
-#[derive(BorshDeserialize, BorshSerialize, Debug)]
+/// The header of a user account. The account data starts with the `StateObject::UserAccount` tag byte,
+/// followed by the Borsh layout of this struct (79 bytes), in field order:
+///
+///   - `version`: u8 (`USER_ACCOUNT_VERSION`, version 0 accounts have to be migrated)
+///   - `owner`: [u8; 32]
+///   - `active`: bool
+///   - `market`: [u8; 32]
+///   - `balance`: u64
+///   - `last_funding_offset`: u8
+///   - `number_of_open_positions`: u32
+#[derive(BorshDeserialize, BorshSerialize, Debug, PartialEq)]
 pub struct UserAccountState {
     pub version: u8,
     pub owner: [u8; 32],
@@ -80,6 +126,13 @@ impl Pack for UserAccountState {
             }
             return Err(ProgramError::InvalidAccountData);
         };
+        if src[1] != USER_ACCOUNT_VERSION {
+            msg!(
+                "The user account has to be migrated to version {:?}",
+                USER_ACCOUNT_VERSION
+            );
+            return Err(PerpError::OutdatedAccount.into());
+        }
         UserAccountState::deserialize(&mut &src[1..]).map_err(|_| {
             msg!("Failed to deserialize user account");
             ProgramError::InvalidAccountData
@@ -87,10 +140,117 @@ impl Pack for UserAccountState {
     }
 }
 
+pub const POSITION_SNAPSHOT_VERSION: u8 = 2;
+
+// Returned by the GetPosition instruction. Indexers should decode this rather than slicing user accounts,
+// the version is bumped whenever the layout changes.
+#[derive(BorshDeserialize, BorshSerialize, Debug)]
+pub struct PositionSnapshot {
+    pub version: u8,
+    pub position: OpenPosition,
+    // Collateral plus the unrealized profit or loss at the current mark price
+    pub equity: i64,
+    // FP32 ratio between the equity and the current notional value of the position
+    pub margin_ratio: i64,
+    // FP32 distance between the oracle price and the liquidation index, negative once the position can be liquidated
+    pub liquidation_distance: i64,
+}
+
+// A pending transfer of a user account, which only takes effect once the new owner accepts it
+#[derive(BorshDeserialize, BorshSerialize, Debug)]
+pub struct TransferProposal {
+    pub user_account: [u8; 32],
+    pub current_owner: [u8; 32],
+    pub new_owner: [u8; 32],
+}
+
+impl Sealed for TransferProposal {}
+
+impl Pack for TransferProposal {
+    const LEN: usize = 97;
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        dst[0] = StateObject::TransferProposal as u8;
+        self.serialize(&mut &mut dst[1..]).unwrap();
+    }
+
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src[0] != StateObject::TransferProposal as u8 {
+            if src[0] == 0 {
+                return Err(ProgramError::UninitializedAccount);
+            }
+            return Err(ProgramError::InvalidAccountData);
+        };
+        TransferProposal::deserialize(&mut &src[1..]).map_err(|_| {
+            msg!("Failed to deserialize transfer proposal");
+            ProgramError::InvalidAccountData
+        })
+    }
+}
+
+pub fn close_transfer_proposal(transfer_proposal: &AccountInfo, lamports_target: &AccountInfo) {
+    let mut proposal_lamports = transfer_proposal.lamports.borrow_mut();
+    let mut target_lamports = lamports_target.lamports.borrow_mut();
+
+    **target_lamports += **proposal_lamports;
+    **proposal_lamports = 0;
+    transfer_proposal.data.borrow_mut()[0] = StateObject::Uninitialized as u8;
+}
+
 impl UserAccountState {
+    // Reads the header of a version 0 user account, which only differs by its version
+    pub fn unpack_v0(src: &[u8]) -> Result<Self, ProgramError> {
+        let header = src
+            .get(..UserAccountState::LEN)
+            .ok_or(ProgramError::InvalidAccountData)?;
+        if header[0] != StateObject::UserAccount as u8 || header[1] != 0 {
+            msg!("The account isn't a version 0 user account");
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let mut user_account_header =
+            UserAccountState::deserialize(&mut &header[1..]).map_err(|_| {
+                msg!("Failed to deserialize user account");
+                ProgramError::InvalidAccountData
+            })?;
+        user_account_header.version = USER_ACCOUNT_VERSION;
+        Ok(user_account_header)
+    }
+
     pub fn is_initialized(&self) -> bool {
         self.owner != [0u8; 32]
     }
+
+    pub fn position_at(
+        &self,
+        user_account_data: &[u8],
+        position_index: u16,
+    ) -> Result<OpenPosition, ProgramError> {
+        get_position(user_account_data, self, position_index)
+    }
+
+    pub fn positions<'a>(
+        &self,
+        user_account_data: &'a [u8],
+    ) -> impl Iterator<Item = Result<OpenPosition, ProgramError>> + 'a {
+        (0..self.number_of_open_positions as usize).map(move |i| {
+            let offset = i
+                .checked_mul(OpenPosition::LEN)
+                .and_then(|s| s.checked_add(UserAccountState::LEN))
+                .ok_or(PerpError::Overflow)?;
+            let offset_end = offset
+                .checked_add(OpenPosition::LEN)
+                .ok_or(PerpError::Overflow)?;
+            let slice = user_account_data
+                .get(offset..offset_end)
+                .ok_or(ProgramError::InvalidAccountData)?;
+            OpenPosition::unpack_unchecked(slice)
+        })
+    }
+}
+
+/// Number of positions which fit in a user account of the given data length
+pub fn max_positions(account_len: usize) -> usize {
+    account_len.saturating_sub(UserAccountState::LEN) / OpenPosition::LEN
 }
 
 pub fn write_position(
@@ -100,6 +260,10 @@ pub fn write_position(
     position: &OpenPosition,
     overwrite: bool,
 ) -> ProgramResult {
+    if position_index as usize >= max_positions(user_account_data.len()) {
+        msg!("The user account is full");
+        return Err(PerpError::OutOfSpace.into());
+    }
     let offset = (position_index as usize)
         .checked_mul(OpenPosition::LEN)
         .and_then(|s| s.checked_add(UserAccountState::LEN))
@@ -164,18 +328,143 @@ pub fn get_position(
     user_account_header: &UserAccountState,
     position_index: u16,
 ) -> Result<OpenPosition, ProgramError> {
-    if (user_account_header.number_of_open_positions as i32) - 1 < (position_index as i32) {
+    // All position taking instructions rely on this check to reject out of range indices
+    if position_index as u32 >= user_account_header.number_of_open_positions {
         msg!("The given position index is too large.");
         return Err(PerpError::PositionNotFound.into());
     }
     let offset = (position_index as usize)
         .checked_mul(OpenPosition::LEN)
         .and_then(|s| s.checked_add(UserAccountState::LEN))
-        .unwrap();
-    let offset_end = offset.checked_add(OpenPosition::LEN).unwrap();
+        .ok_or(PerpError::Overflow)?;
+    let offset_end = offset
+        .checked_add(OpenPosition::LEN)
+        .ok_or(PerpError::Overflow)?;
 
     let slice = user_account_data
         .get(offset..offset_end)
         .ok_or(ProgramError::InvalidArgument)?;
     OpenPosition::unpack_unchecked(slice)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(instance_index: u8, side: PositionType) -> OpenPosition {
+        OpenPosition {
+            last_funding_offset: 3,
+            instance_index,
+            side,
+            liquidation_index: 10_000 << 32,
+            collateral: 1_000_000,
+            slot_number: 42,
+            v_coin_amount: 200,
+            v_pc_amount: 2_000_000,
+            stop_loss_index: 0,
+        }
+    }
+
+    #[test]
+    fn test_user_account_state_round_trip() {
+        let header = UserAccountState {
+            version: USER_ACCOUNT_VERSION,
+            owner: [1; 32],
+            active: true,
+            market: [2; 32],
+            balance: 5_000_000,
+            last_funding_offset: 7,
+            number_of_open_positions: 2,
+        };
+        let mut data = [0u8; UserAccountState::LEN];
+        header.pack_into_slice(&mut data);
+        assert_eq!(data[0], StateObject::UserAccount as u8);
+        assert_eq!(&data[2..34], &[1; 32]);
+        assert_eq!(&data[35..67], &[2; 32]);
+        assert_eq!(UserAccountState::unpack_from_slice(&data).unwrap(), header);
+
+        assert_eq!(
+            UserAccountState::unpack_from_slice(&[0u8; UserAccountState::LEN]).unwrap_err(),
+            ProgramError::UninitializedAccount
+        );
+
+        // Version 0 headers are only read by the migration
+        data[1] = 0;
+        assert_eq!(
+            UserAccountState::unpack_from_slice(&data).unwrap_err(),
+            PerpError::OutdatedAccount.into()
+        );
+        assert_eq!(UserAccountState::unpack_v0(&data).unwrap(), header);
+    }
+
+    #[test]
+    fn test_open_position_round_trip() {
+        let p = position(1, PositionType::Short);
+        let mut data = [0u8; OpenPosition::LEN];
+        p.pack_into_slice(&mut data);
+        assert_eq!(data[OpenPosition::INSTANCE_INDEX_OFFSET], 1);
+        assert_eq!(data[2], PositionType::Short as u8);
+        assert_eq!(OpenPosition::unpack_from_slice(&data).unwrap(), p);
+    }
+
+    #[test]
+    fn test_stop_loss_trigger() {
+        let mut long = position(0, PositionType::Long);
+        assert!(!long.is_stop_loss_triggered(1));
+        long.stop_loss_index = 11_000 << 32;
+        assert!(long.is_stop_loss_triggered(11_000 << 32));
+        assert!(long.is_stop_loss_triggered(10_500 << 32));
+        assert!(!long.is_stop_loss_triggered(11_001 << 32));
+
+        let mut short = position(0, PositionType::Short);
+        assert!(!short.is_stop_loss_triggered(u64::MAX));
+        short.stop_loss_index = 9_000 << 32;
+        assert!(short.is_stop_loss_triggered(9_000 << 32));
+        assert!(short.is_stop_loss_triggered(9_500 << 32));
+        assert!(!short.is_stop_loss_triggered(8_999 << 32));
+    }
+
+    #[test]
+    fn test_positions_are_stored_after_the_header() {
+        let mut header = UserAccountState {
+            version: USER_ACCOUNT_VERSION,
+            owner: [1; 32],
+            active: true,
+            market: [2; 32],
+            balance: 0,
+            last_funding_offset: 0,
+            number_of_open_positions: 0,
+        };
+        let mut data = vec![0u8; UserAccountState::LEN + 2 * OpenPosition::LEN];
+        let positions = [
+            position(0, PositionType::Long),
+            position(1, PositionType::Short),
+        ];
+        for (i, p) in positions.iter().enumerate() {
+            write_position(&mut data, i as u16, &mut header, p, false).unwrap();
+        }
+        assert_eq!(header.number_of_open_positions, 2);
+        for (i, p) in positions.iter().enumerate() {
+            let offset = UserAccountState::LEN + i * OpenPosition::LEN;
+            let raw = OpenPosition::unpack_from_slice(&data[offset..]).unwrap();
+            assert_eq!(&raw, p);
+            assert_eq!(&get_position(&data, &header, i as u16).unwrap(), p);
+        }
+
+        assert_eq!(max_positions(data.len()), 2);
+        assert_eq!(max_positions(data.len() - 1), 1);
+        assert_eq!(max_positions(0), 0);
+        assert_eq!(
+            write_position(
+                &mut data,
+                2,
+                &mut header,
+                &position(0, PositionType::Long),
+                false
+            )
+            .unwrap_err(),
+            PerpError::OutOfSpace.into()
+        );
+        assert_eq!(header.number_of_open_positions, 2);
+    }
+}
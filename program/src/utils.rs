@@ -3,15 +3,17 @@ use crate::{
     positions_book::{
         memory::{Memory, Pointer, SLOT_SIZE, TAG_SIZE},
         page::{Page, SlotType},
+        positions_book_tree::PositionsBook,
         tree_nodes::{InnerNodeSchema, LeafNodeSchema},
     },
     processor::{
         ALLOCATION_FEE, FEES_HIGH_LEVERAGE, FEES_LOW_LEVERAGE, FEE_TIERS, FIDA_MINT,
-        HIGH_LEVERAGE_MIN, MARGIN_RATIO,
+        HIGH_LEVERAGE_MIN, MARGIN_RATIO, MAX_LEVERAGE, MAX_POSITION_SIZE,
     },
     state::{
         instance::parse_instance,
         market::{get_instance_address, MarketDataPoint, MarketState},
+        user_account::UserAccountState,
         Fees, PositionType,
     },
 };
@@ -25,9 +27,11 @@ use solana_program::{
     program_error::ProgramError,
     program_pack::Pack,
     pubkey::Pubkey,
+    rent::Rent,
+    sysvar::Sysvar,
 };
 use spl_token::state::Account;
-use std::{cell::RefCell, convert::TryInto, rc::Rc, slice::Iter};
+use std::{cell::RefCell, cmp, convert::TryInto, rc::Rc, slice::Iter};
 
 // Safety verification functions
 pub fn check_account_key(account: &AccountInfo, key: &Pubkey) -> ProgramResult {
@@ -51,6 +55,56 @@ pub fn check_signer(account: &AccountInfo) -> ProgramResult {
     Ok(())
 }
 
+// A token account passed twice, such as the market vault given as a transfer target, could misroute funds
+pub fn check_distinct_accounts(accounts: &[&AccountInfo]) -> ProgramResult {
+    for (i, account) in accounts.iter().enumerate() {
+        if accounts[..i].iter().any(|a| a.key == account.key) {
+            msg!("Account {:?} is provided more than once", account.key);
+            return Err(ProgramError::InvalidArgument);
+        }
+    }
+    Ok(())
+}
+
+// The rewards paid out of the market vault have to land in a token account of the market's quote mint
+pub fn check_target_token_account(
+    target: &AccountInfo,
+    market_vault: &AccountInfo,
+) -> ProgramResult {
+    check_account_owner(target, &spl_token::id())?;
+    let quote_mint = Account::unpack(&market_vault.data.borrow())?.mint;
+    let target_mint = Account::unpack(&target.data.borrow())
+        .map_err(|_| {
+            msg!("The target account isn't an initialized token account");
+            ProgramError::InvalidArgument
+        })?
+        .mint;
+    if target_mint != quote_mint {
+        msg!("The target token account doesn't hold the market's quote token");
+        return Err(ProgramError::InvalidArgument);
+    }
+    Ok(())
+}
+
+pub fn check_rent_exempt(account: &AccountInfo) -> ProgramResult {
+    if !Rent::get()?.is_exempt(account.lamports(), account.data_len()) {
+        msg!("Account {:?} is not rent exempt", account.key);
+        return Err(ProgramError::AccountNotRentExempt);
+    }
+    Ok(())
+}
+
+pub fn get_vault_balance(
+    market_state: &MarketState,
+    market_vault: &AccountInfo,
+) -> Result<u64, ProgramError> {
+    if market_state.vault_address != market_vault.key.to_bytes() {
+        msg!("Provided market vault account is incorrect.");
+        return Err(ProgramError::InvalidArgument);
+    }
+    Ok(Account::unpack(&market_vault.data.borrow())?.amount)
+}
+
 ////////////////////////////////////////
 // Numerical computations
 
@@ -74,65 +128,188 @@ pub fn compute_margin(
     ((numerator / denominator) >> 64) as u64
 }
 
-pub fn compute_fee_tier(accounts_iter: &mut Iter<AccountInfo>) -> Result<usize, ProgramError> {
-    let mut fee_tier = 0;
-    if accounts_iter.len() > 1 {
-        // The discount account and owner were given, calculate fee tier
-        let discount_account = next_account_info(accounts_iter)?;
-        let discount_owner = next_account_info(accounts_iter)?;
-        let discount_data = Account::unpack(&discount_account.data.borrow()).map_err(|e| {
-            msg!("The discount account is not initialized.");
-            e
-        })?;
-        if discount_data.mint.to_string() != FIDA_MINT {
-            msg!("The discount account should be a fida token account");
-            return Err(ProgramError::InvalidArgument);
-        }
-        if &discount_data.owner != discount_owner.key {
-            msg!("The discount owner should own the discount account");
+// The instruction states whether the discount account and its owner are given, they then come
+// before the optional referrer accounts
+pub fn compute_fee_tier(
+    accounts_iter: &mut Iter<AccountInfo>,
+    has_discount_account: bool,
+) -> Result<usize, ProgramError> {
+    let fida_account = |a: &AccountInfo| {
+        Account::unpack(&a.data.borrow())
+            .ok()
+            .filter(|d| d.mint.to_string() == FIDA_MINT)
+    };
+    if !has_discount_account {
+        // The referrer account is a quote token account
+        if accounts_iter
+            .as_slice()
+            .first()
+            .and_then(fida_account)
+            .is_some()
+        {
+            msg!("A discount account is given but the instruction doesn't flag it");
             return Err(ProgramError::InvalidArgument);
         }
-        if !discount_owner.is_signer {
-            msg!("The discount account owner should be a signer");
-            return Err(ProgramError::MissingRequiredSignature);
-        }
-        let discount_balance = discount_data.amount;
-        fee_tier = match FEE_TIERS
+        return Ok(0);
+    }
+    let discount_account = next_account_info(accounts_iter)?;
+    let discount_owner = next_account_info(accounts_iter)?;
+    check_account_owner(discount_account, &spl_token::id())?;
+    let discount_data = fida_account(discount_account).ok_or_else(|| {
+        msg!("The discount account should be a FIDA token account");
+        ProgramError::InvalidArgument
+    })?;
+    if &discount_data.owner != discount_owner.key {
+        msg!("The discount owner should own the discount account");
+        return Err(ProgramError::InvalidArgument);
+    }
+    if !discount_owner.is_signer {
+        msg!("The discount account owner should be a signer");
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+    Ok(fee_tier_for_balance(discount_data.amount))
+}
+
+// The fee tier granted by a discount account holding the given amount of FIDA tokens (with precision)
+pub fn fee_tier_for_balance(balance: u64) -> usize {
+    fee_schedule().tier(balance)
+}
+
+// The additional amount of FIDA tokens (with precision) needed to reach the next fee tier, if any
+pub fn amount_to_next_fee_tier(balance: u64) -> Option<u64> {
+    FEE_TIERS
+        .get(fee_tier_for_balance(balance))
+        .map(|threshold| threshold - balance)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FeeRounding {
+    // One unit is added to the rounded down fee, even when the division is exact. This is what the program charges.
+    Up,
+    Down,
+}
+
+// The fee schedule applied by the program, to let clients display the fees before trading
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FeeSchedule {
+    pub tier_thresholds: [u64; 5], // Amount of FIDA tokens (with precision) required for tiers 1 to 5
+    pub low_leverage_bps: &'static [u64],
+    pub high_leverage_bps: &'static [u64],
+    pub high_leverage_min: u64, // FP 32
+    pub allocation_fee: u64,
+}
+
+pub fn fee_schedule() -> FeeSchedule {
+    FeeSchedule {
+        tier_thresholds: FEE_TIERS,
+        low_leverage_bps: FEES_LOW_LEVERAGE,
+        high_leverage_bps: FEES_HIGH_LEVERAGE,
+        high_leverage_min: HIGH_LEVERAGE_MIN,
+        allocation_fee: ALLOCATION_FEE,
+    }
+}
+
+impl FeeSchedule {
+    pub fn tier(&self, discount_balance: u64) -> usize {
+        match self
+            .tier_thresholds
             .iter()
-            .position(|&t| discount_balance < (t as u64))
+            .position(|&t| discount_balance < t)
         {
             Some(i) => i,
-            None => FEE_TIERS.len(),
+            None => self.tier_thresholds.len(),
+        }
+    }
+
+    pub fn fee_bps(&self, fee_tier: usize, leverage: u64) -> u64 {
+        match leverage < self.high_leverage_min {
+            true => self.low_leverage_bps[fee_tier],
+            false => self.high_leverage_bps[fee_tier],
+        }
+    }
+
+    pub fn fixed_fee(
+        &self,
+        fee_tier: usize,
+        size: u64,
+        leverage: u64, // FP 32
+        rounding: FeeRounding,
+    ) -> Result<u64, PerpError> {
+        let fee = (size as u128) * (self.fee_bps(fee_tier, leverage) as u128) / 10_000;
+        let fee = match rounding {
+            FeeRounding::Up => fee + 1,
+            FeeRounding::Down => fee,
         };
+        if fee > u64::MAX as u128 {
+            return Err(PerpError::Overflow);
+        }
+        Ok(fee as u64)
     }
-    Ok(fee_tier)
 }
 
 pub fn compute_fees(
     fee_tier: usize,
     size: u64,
     leverage: u64, // FP 32
-) -> Result<Fees, ProgramError> {
-    // Compute the fees
-    let fee_tiers = match leverage < HIGH_LEVERAGE_MIN {
-        true => FEES_LOW_LEVERAGE,
-        false => FEES_HIGH_LEVERAGE,
-    };
-    // We add one to round up the results
-    let fixed_fee = ((size as u128) * (fee_tiers[fee_tier] as u128) / 10_000) + 1;
-    let refundable_fees = ALLOCATION_FEE;
-    let total_fees = (fixed_fee as u64) + ALLOCATION_FEE;
+) -> Result<Fees, PerpError> {
+    let schedule = fee_schedule();
+    let fixed_fee = schedule.fixed_fee(fee_tier, size, leverage, FeeRounding::Up)?;
+    let refundable_fees = schedule.allocation_fee;
+    let total_fees = fixed_fee
+        .checked_add(refundable_fees)
+        .ok_or(PerpError::Overflow)?;
 
     let fees = Fees {
         total: total_fees as i64,
         refundable: refundable_fees,
-        fixed: fixed_fee as u64,
+        fixed: fixed_fee,
     };
     msg!("Fees : {:?}", fees);
 
     Ok(fees)
 }
 
+/// Rejects a trade whose fees exceed the ceiling optionally given by the user.
+pub fn check_max_fee(fees: &Fees, max_fee: Option<u64>) -> Result<(), PerpError> {
+    if let Some(max_fee) = max_fee {
+        if fees.total > 0 && fees.total as u64 > max_fee {
+            msg!(
+                "The trade fees of {:?} exceed the given maximum of {:?}",
+                fees.total,
+                max_fee
+            );
+            return Err(PerpError::AmountTooLarge);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the FP32 fraction of its funding that the receiving side collects, so that the payments
+/// received never exceed the ones made by the paying side. A positive funding ratio means that longs pay shorts.
+pub fn compute_funding_balancing_factor(
+    funding_ratio: i64,
+    longs_v_coin: u64,
+    shorts_v_coin: u64,
+) -> u64 {
+    let (payers, receivers) = match funding_ratio.is_positive() {
+        true => (longs_v_coin, shorts_v_coin),
+        false => (shorts_v_coin, longs_v_coin),
+    };
+    let factor = ((payers as u128) << 32)
+        .checked_div(receivers as u128)
+        .unwrap_or(0);
+    cmp::min(1 << 32, factor) as u64
+}
+
+/// Returns the leverage of a position as a 32 bit fixed point, saturating at `u64::MAX`.
+/// A position without collateral, which is being closed, has a null leverage.
+pub fn compute_leverage(v_pc_amount: u64, collateral: u64) -> u64 {
+    ((v_pc_amount as u128) << 32)
+        .checked_div(collateral as u128)
+        .map(|l| l.min(u64::MAX as u128) as u64)
+        .unwrap_or(0)
+}
+
 pub fn compute_liquidation_index(
     // Returns the liquidation index as fixed point 32
     collateral: u64,
@@ -244,6 +421,19 @@ pub fn get_oracle_price(
     account_data: &[u8],
     coin_decimals: u8,
     quote_decimals: u8,
+) -> Result<u64, ProgramError> {
+    let price = get_raw_oracle_price(account_data, coin_decimals, quote_decimals)?;
+    if price == 0 {
+        msg!("The oracle price is zero, all price dependent operations are suspended.");
+        return Err(PerpError::OraclePriceZero.into());
+    }
+    Ok(price)
+}
+
+fn get_raw_oracle_price(
+    account_data: &[u8],
+    coin_decimals: u8,
+    quote_decimals: u8,
 ) -> Result<u64, ProgramError> {
     #[cfg(feature = "mock-oracle")]
     {
@@ -258,6 +448,18 @@ pub fn get_oracle_price(
     Ok(price)
 }
 
+// Market symbols are stored as zero padded 32 byte arrays
+pub fn pack_market_symbol(market_symbol: &str) -> Result<[u8; 32], ProgramError> {
+    let mut market_symbol_slice = [0u8; 32];
+    let market_symbol_bytes = market_symbol.as_bytes();
+    if market_symbol_bytes.len() > 32 {
+        msg!("Given market symbol is too long.");
+        return Err(ProgramError::InvalidAccountData);
+    }
+    market_symbol_slice[..market_symbol_bytes.len()].copy_from_slice(market_symbol_bytes);
+    Ok(market_symbol_slice)
+}
+
 pub fn get_pyth_market_symbol(pyth_product: &Product) -> Result<String, ProgramError> {
     let mut psz = pyth_product.size as usize - PROD_HDR_SIZE;
     let mut pit = (&pyth_product.attr[..]).iter();
@@ -276,6 +478,48 @@ pub fn get_pyth_market_symbol(pyth_product: &Product) -> Result<String, ProgramE
     Err(ProgramError::InvalidArgument)
 }
 
+// Mock oracles have no product account to read a symbol from, the check is skipped for them when no product is given
+pub fn check_pyth_market_symbol(
+    oracle: &AccountInfo,
+    pyth_product_opt: Option<&AccountInfo>,
+    market_symbol: &str,
+) -> ProgramResult {
+    let pyth_product = match pyth_product_opt {
+        Some(pyth_product) => pyth_product,
+        None if cfg!(feature = "mock-oracle") && oracle.data_len() == 8 => return Ok(()),
+        None => {
+            msg!("The oracle's Pyth product account is needed to check the market symbol");
+            return Err(ProgramError::NotEnoughAccountKeys);
+        }
+    };
+    if pyth_product.owner != oracle.owner {
+        msg!("The provided product account isn't owned by the oracle's program");
+        return Err(ProgramError::InvalidArgument);
+    }
+    let pyth_price_data = oracle.data.borrow();
+    if pyth_price_data.len() < std::mem::size_of::<Price>()
+        || cast::<Price>(&pyth_price_data).prod.val != pyth_product.key.to_bytes()
+    {
+        msg!("The provided product account isn't the oracle's Pyth product");
+        return Err(ProgramError::InvalidArgument);
+    }
+    let pyth_product_data = pyth_product.data.borrow();
+    if pyth_product_data.len() < std::mem::size_of::<Product>() {
+        msg!("The provided product account isn't a Pyth product");
+        return Err(ProgramError::InvalidArgument);
+    }
+    let pyth_symbol = get_pyth_market_symbol(cast::<Product>(&pyth_product_data))?;
+    if pyth_symbol != market_symbol {
+        msg!(
+            "The market symbol {:?} doesn't match the oracle's symbol {:?}",
+            market_symbol,
+            pyth_symbol
+        );
+        return Err(ProgramError::InvalidArgument);
+    }
+    Ok(())
+}
+
 pub fn get_attr_bytes<'a, T>(ite: &mut T) -> Vec<u8>
 where
     T: Iterator<Item = &'a u8>,
@@ -496,11 +740,30 @@ pub fn print_node(pt: Pointer, mem: &Memory, offset: u8) {
     }
 }
 
+// Off-chain monitoring reads the price of mock markets whatever the enabled features, the program
+// only accepts mock oracles when it is built with the mock-oracle feature
+#[cfg(not(target_arch = "bpf"))]
+pub fn get_off_chain_oracle_price(
+    account_data: &[u8],
+    coin_decimals: u8,
+    quote_decimals: u8,
+) -> Result<u64, ProgramError> {
+    if account_data.len() == 8 {
+        let price = u64::from_le_bytes(account_data.try_into().unwrap());
+        if price == 0 {
+            return Err(PerpError::OraclePriceZero.into());
+        }
+        return Ok(price);
+    }
+    get_oracle_price(account_data, coin_decimals, quote_decimals)
+}
+
 #[cfg(not(target_arch = "bpf"))]
 pub fn get_market_data(
     market_key: Pubkey,
     get_account_data: &dyn Fn(&Pubkey) -> Vec<u8>,
     correct_total_user_bal: u64,
+    now: u64, // unix timestamp
 ) -> Result<MarketDataPoint, ProgramError> {
     let market_account_data = get_account_data(&market_key);
     let mut market_state = MarketState::unpack_from_slice(&market_account_data)?;
@@ -521,6 +784,7 @@ pub fn get_market_data(
 
     let mut gc_list_lengths = Vec::with_capacity(market_state.number_of_instances as usize);
     let mut page_full_ratios = Vec::with_capacity(market_state.number_of_instances as usize);
+    let mut page_stats = Vec::with_capacity(market_state.number_of_instances as usize);
     for (instance, page_infos) in &instances {
         let mut page_datas = page_infos
             .iter()
@@ -551,25 +815,26 @@ pub fn get_market_data(
         page_full_ratios.push(instance_page_full_ratios);
         let mem = Memory::new(pages, instance.garbage_pointer);
         gc_list_lengths.push(mem.get_gc_list_len().unwrap());
+        page_stats.push(mem.stats().unwrap());
     }
-    let insurance_fund = market_state.get_insurance_fund(market_vault_balance);
+    let insurance_fund = market_state.get_insurance_fund(market_vault_balance)?;
 
     // Get the current index price
     let oracle_account_data = get_account_data(&Pubkey::new(&market_state.oracle_address));
-    let oracle_price = (get_oracle_price(
+    let oracle_price = (get_off_chain_oracle_price(
         &oracle_account_data,
         market_state.coin_decimals,
         market_state.quote_decimals,
-    )
-    .unwrap() as f64)
+    )? as f64)
         / (2u64.pow(32) as f64);
 
-    println!("Market vault balance: {}", market_vault_balance);
+    let remaining_capacity = MarketState::remaining_capacity(&page_stats);
 
     let market_data = MarketDataPoint {
         total_collateral: market_state.total_collateral,
         total_user_balances: market_state.total_user_balances,
         total_fee_balance: market_state.total_fee_balance,
+        protocol_fee_balance: market_state.protocol_fee_balance,
         rebalancing_funds: market_state.rebalancing_funds,
         rebalanced_v_coin: market_state.rebalanced_v_coin,
         v_coin_amount: market_state.v_coin_amount,
@@ -582,7 +847,8 @@ pub fn get_market_data(
         funding_samples_sum: market_state.funding_samples_sum,
         funding_history_offset: market_state.funding_history_offset,
         funding_history: market_state.funding_history,
-        funding_balancing_factors: market_state.funding_balancing_factors,
+        seconds_since_last_funding: market_state.seconds_since_last_funding(now),
+        funding_stalled: market_state.is_funding_stalled(now),
         number_of_instances: market_state.number_of_instances,
         insurance_fund,
         market_price: (market_state.v_pc_amount as f64) / (market_state.v_coin_amount as f64),
@@ -594,11 +860,261 @@ pub fn get_market_data(
                 .pow(2) as f64),
         gc_list_lengths,
         page_full_ratios,
+        page_stats,
+        remaining_capacity,
         longs_depths: vec![],
         shorts_depths: vec![],
     };
     Ok(market_data)
 }
+
+// Returns whether cranking the liquidation of an instance at the given oracle price would liquidate any position.
+// The instance's memory pages are read with get_account_data.
+#[cfg(not(target_arch = "bpf"))]
+pub fn simulate_liquidation(
+    instance_account_data: &[u8],
+    get_account_data: &dyn Fn(&Pubkey) -> Vec<u8>,
+    oracle_price: u64,
+) -> Result<bool, ProgramError> {
+    let (instance, page_infos) = parse_instance(instance_account_data)?;
+    let mut page_datas = page_infos
+        .iter()
+        .map(|p| get_account_data(&Pubkey::new(&p.address)))
+        .collect::<Vec<_>>();
+    let mut pages = Vec::with_capacity(page_datas.len());
+    for (page_data, page_info) in page_datas.iter_mut().zip(&page_infos) {
+        if page_data.len() < TAG_SIZE {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        pages.push(Page {
+            page_size: ((page_data.len() - TAG_SIZE) / SLOT_SIZE) as u32,
+            data: Rc::new(RefCell::new(page_data)),
+            uninitialized_memory: page_info.unitialized_memory_index,
+            free_slot_list_hd: page_info.free_slot_list_hd,
+        });
+    }
+    let book = PositionsBook::new(
+        instance.shorts_pointer,
+        instance.longs_pointer,
+        Memory::new(pages, instance.garbage_pointer),
+    );
+    Ok(book.should_liquidate(oracle_price)?)
+}
+
+// Returns the index of the least full instance of the market, to be used when opening a new position.
+// An instance's fullness is the average fullness of its pages, ties are broken by the lowest index.
+#[cfg(not(target_arch = "bpf"))]
+pub fn pick_instance(market_data: &MarketDataPoint) -> u8 {
+    least_full_instance(&market_data.page_full_ratios)
+}
+
+#[cfg(not(target_arch = "bpf"))]
+fn least_full_instance(page_full_ratios: &[Vec<f64>]) -> u8 {
+    let fullness = |ratios: &Vec<f64>| {
+        if ratios.is_empty() {
+            // An instance without pages cannot hold any position
+            return 1.0;
+        }
+        ratios.iter().sum::<f64>() / (ratios.len() as f64)
+    };
+    let mut best = 0;
+    for (i, ratios) in page_full_ratios.iter().enumerate().skip(1) {
+        if fullness(ratios) < fullness(&page_full_ratios[best]) {
+            best = i;
+        }
+    }
+    best as u8
+}
+
+// Returns the aggregate unrealized pnl of a user account's positions along with their total collateral.
+// Each position is marked at the price it would get by closing against the current vAMM state.
+// Positions which the oracle price has already pushed past their liquidation index are counted as a total loss.
+#[cfg(not(target_arch = "bpf"))]
+pub fn compute_account_pnl(
+    user_account_data: &[u8],
+    market_state: &MarketState,
+    oracle_price: u64,
+) -> Result<(i64, u64), ProgramError> {
+    let user_account_header = UserAccountState::unpack_from_slice(user_account_data)?;
+    let mut total_pnl: i64 = 0;
+    let mut total_collateral: u64 = 0;
+    for position in user_account_header.positions(user_account_data) {
+        let position = position?;
+        total_collateral = total_collateral
+            .checked_add(position.collateral)
+            .ok_or(PerpError::Overflow)?;
+        let is_liquidated = match position.side {
+            PositionType::Long => position.liquidation_index >= oracle_price,
+            PositionType::Short => position.liquidation_index <= oracle_price,
+        };
+        let position_pnl = if is_liquidated {
+            -(position.collateral as i64)
+        } else {
+            let v_coin_amount = position.side.get_sign() * (position.v_coin_amount as i64);
+            let v_pc_amount = market_state.compute_add_v_pc(v_coin_amount)?;
+            let payout = compute_payout(
+                v_pc_amount.abs() as u64,
+                position.v_pc_amount,
+                position.collateral,
+                &position.side,
+            );
+            payout
+                .checked_sub(position.collateral as i64)
+                .ok_or(PerpError::Overflow)?
+        };
+        total_pnl = total_pnl
+            .checked_add(position_pnl)
+            .ok_or(PerpError::Overflow)?;
+    }
+    Ok((total_pnl, total_collateral))
+}
+
+// Size of an order opening a position with the given collateral and leverage, rejected when it is out of
+// the market's bounds. The size is checked before the fees so that oversized orders are reported as such.
+pub fn compute_open_order_size(
+    market_state: &MarketState,
+    side: PositionType,
+    collateral: u64,
+    leverage: u64, // FP32
+) -> Result<u64, PerpError> {
+    if leverage > MAX_LEVERAGE {
+        msg!(
+            "Leverage cannot be higher than: {:?}. Found: {:?}",
+            MAX_LEVERAGE >> 32,
+            leverage >> 32
+        );
+        return Err(PerpError::MarginTooLow);
+    }
+    if collateral < market_state.min_collateral {
+        msg!(
+            "The position's collateral is below the market's minimum of {:?}",
+            market_state.min_collateral
+        );
+        return Err(PerpError::AmountTooLow);
+    }
+
+    let v_pc_amount = ((collateral as u128 * (leverage as u128)) >> 32) as u64;
+
+    if v_pc_amount >= market_state.v_pc_amount && side == PositionType::Long {
+        msg!("The given order size is too large!");
+        return Err(PerpError::AmountTooLarge);
+    }
+    if v_pc_amount >= MAX_POSITION_SIZE {
+        msg!(
+            "The given order size is too large! The maximum size is: {:?}",
+            MAX_POSITION_SIZE
+        );
+        return Err(PerpError::AmountTooLarge);
+    }
+    Ok(v_pc_amount)
+}
+
+pub struct OpenOrderFill {
+    pub v_coin_amount: u64,
+    pub liquidation_index: u64, // FP32
+}
+
+// Fills an order opening a position against the vAMM, once its fees have been applied to the market state.
+// This is the computation shared by process_open_position and simulate_open_position.
+pub fn fill_open_order(
+    market_state: &mut MarketState,
+    side: PositionType,
+    collateral: u64,
+    v_pc_amount: u64,
+    oracle_price: u64, // FP32
+) -> Result<OpenOrderFill, PerpError> {
+    let signed_v_pc_amount = side.get_sign() * (v_pc_amount as i64);
+    let signed_v_coin_amount = market_state.compute_add_v_coin(signed_v_pc_amount)?;
+
+    if signed_v_coin_amount == 0 {
+        msg!("The given order size is not sufficient!");
+        return Err(PerpError::AmountTooLow);
+    }
+
+    let (balanced_v_pc_amount, balanced_v_coin_amount) =
+        market_state.balance_operation(signed_v_pc_amount, signed_v_coin_amount, oracle_price)?;
+
+    market_state.add_v_pc(balanced_v_pc_amount)?;
+    market_state.add_v_coin(balanced_v_coin_amount)?;
+
+    let v_coin_amount = signed_v_coin_amount.abs() as u64;
+    market_state.add_open_interest(v_coin_amount, v_pc_amount, side)?;
+
+    msg!("Add_v_pc_amount: {:?}", signed_v_pc_amount);
+    msg!("Add_v_coin_amount: {:?}", signed_v_coin_amount);
+
+    let liquidation_index = compute_liquidation_index(
+        collateral,
+        v_coin_amount,
+        v_pc_amount,
+        side,
+        market_state.get_k(),
+    );
+    Ok(OpenOrderFill {
+        v_coin_amount,
+        liquidation_index,
+    })
+}
+
+#[cfg(not(target_arch = "bpf"))]
+#[derive(Debug)]
+pub struct OpenPreview {
+    pub fees: Fees,
+    pub v_coin_amount: u64,
+    pub v_pc_amount: u64,
+    pub entry_price: u64,       // FP32
+    pub liquidation_index: u64, // FP32
+}
+
+// Previews the outcome of an open_position instruction against the given market state
+#[cfg(not(target_arch = "bpf"))]
+pub fn simulate_open_position(
+    market_state: &MarketState,
+    side: PositionType,
+    collateral: u64,
+    leverage: u64, // FP32
+    fee_tier: usize,
+    oracle_price: u64, // FP32
+) -> Result<OpenPreview, PerpError> {
+    let mut market_state = market_state.clone();
+
+    if market_state.paused {
+        return Err(PerpError::MarketPaused);
+    }
+
+    let v_pc_amount = compute_open_order_size(&market_state, side, collateral, leverage)?;
+
+    let fees = compute_fees(fee_tier, v_pc_amount, leverage)?;
+    // The part of the fees which is set aside by apply_fees takes part in the rebalancing
+    market_state.credit_fees(&fees);
+
+    let OpenOrderFill {
+        v_coin_amount,
+        liquidation_index,
+    } = fill_open_order(
+        &mut market_state,
+        side,
+        collateral,
+        v_pc_amount,
+        oracle_price,
+    )?;
+
+    Ok(OpenPreview {
+        fees,
+        v_coin_amount,
+        v_pc_amount,
+        entry_price: (((v_pc_amount as u128) << 32) / (v_coin_amount as u128)) as u64,
+        liquidation_index,
+    })
+}
+
+// Converts an FP32 funding ratio per funding period into an annualized rate (0.1 is 10% per year)
+#[cfg(not(target_arch = "bpf"))]
+pub fn annualize_funding_rate(funding_rate_fp32: i64) -> f64 {
+    let periods_per_year = (365 * 86400 / crate::processor::FUNDING_PERIOD) as f64;
+    (funding_rate_fp32 as f64) / (2u64.pow(32) as f64) * periods_per_year
+}
+
 pub fn get_tree_depth(pt: Option<Pointer>, mem: &Memory) -> usize {
     let mut depth = 0;
     let mut stack = Vec::with_capacity(64);
@@ -630,7 +1146,54 @@ pub fn get_tree_depth(pt: Option<Pointer>, mem: &Memory) -> usize {
 
 #[cfg(test)]
 mod tests {
-    // use super::*;
+    use super::*;
+
+    #[test]
+    pub fn test_compute_leverage() {
+        assert_eq!(compute_leverage(10_000, 1_000), 10 << 32);
+        assert_eq!(compute_leverage(1_500, 1_000), 3 << 31);
+        assert_eq!(compute_leverage(10_000, 0), 0);
+        assert_eq!(compute_leverage(u64::MAX, 1), u64::MAX);
+    }
+
+    #[test]
+    pub fn test_compute_funding_balancing_factor() {
+        // Longs pay: the shorts collect at most what the longs paid
+        assert_eq!(compute_funding_balancing_factor(1, 1_000, 4_000), 1 << 30);
+        assert_eq!(compute_funding_balancing_factor(1, 4_000, 1_000), 1 << 32);
+        // Shorts pay
+        assert_eq!(compute_funding_balancing_factor(-1, 1_000, 4_000), 1 << 32);
+        assert_eq!(compute_funding_balancing_factor(-1, 4_000, 1_000), 1 << 30);
+        assert_eq!(compute_funding_balancing_factor(1, 1_000, 0), 0);
+    }
+
+    #[test]
+    pub fn test_fee_schedule() {
+        let schedule = fee_schedule();
+        assert_eq!(schedule.tier(0), 0);
+        assert_eq!(schedule.tier(FEE_TIERS[0]), 1);
+        assert_eq!(schedule.tier(u64::MAX), FEE_TIERS.len());
+        assert_eq!(fee_tier_for_balance(FEE_TIERS[1] - 1), 1);
+        assert_eq!(amount_to_next_fee_tier(FEE_TIERS[1] - 1), Some(1));
+        assert_eq!(amount_to_next_fee_tier(0), Some(FEE_TIERS[0]));
+        assert_eq!(amount_to_next_fee_tier(FEE_TIERS[4]), None);
+
+        // 10 bps of 1_000_000 is exactly 1_000, the program still charges one more unit
+        let size = 1_000_000;
+        let fees = compute_fees(3, size, 1 << 32).unwrap();
+        assert_eq!(fees.fixed, 1_001);
+        assert_eq!(
+            schedule
+                .fixed_fee(3, size, 1 << 32, FeeRounding::Down)
+                .unwrap(),
+            1_000
+        );
+        assert_eq!(fees.total, (1_001 + ALLOCATION_FEE) as i64);
+        assert_eq!(
+            schedule.fee_bps(3, HIGH_LEVERAGE_MIN),
+            FEES_HIGH_LEVERAGE[3]
+        );
+    }
 
     #[test]
     pub fn test_liq_index_inverse() {
@@ -658,4 +1221,23 @@ mod tests {
 
         // assert!(((predicted_v_pc_amount as i64) - (v_pc_ammount as i64)).abs() < 10);
     }
+
+    #[test]
+    fn test_least_full_instance() {
+        assert_eq!(least_full_instance(&[vec![0.9, 0.1], vec![0.4, 0.4]]), 1);
+        // Ties go to the lowest index
+        assert_eq!(least_full_instance(&[vec![0.5], vec![0.2], vec![0.2]]), 1);
+        assert_eq!(least_full_instance(&[vec![], vec![0.99]]), 1);
+        assert_eq!(least_full_instance(&[]), 0);
+    }
+
+    #[test]
+    fn test_mock_oracle_price() {
+        let price = 10_000u64 << 32;
+        assert_eq!(
+            get_off_chain_oracle_price(&price.to_le_bytes(), 6, 6).unwrap(),
+            price
+        );
+        assert!(get_off_chain_oracle_price(&0u64.to_le_bytes(), 6, 6).is_err());
+    }
 }
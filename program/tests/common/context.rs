@@ -2,17 +2,17 @@ use std::{rc::Rc, str::FromStr};
 
 use super::utils;
 use audaces_protocol::{
-    error::PerpError,
     instruction::MarketContext,
     positions_book::{
         memory::{Memory, SLOT_SIZE, TAG_SIZE},
         page::Page,
     },
-    processor::FIDA_BNB,
+    processor::{FIDA_BNB, MAX_SLIPPAGE_MARGIN_BPS},
     state::{
         instance::parse_instance,
         instance::Instance,
         instance::PageInfo,
+        instance::INSTANCE_V0_LEN,
         market::get_instance_address,
         market::{MarketDataPoint, MarketState},
         user_account::OpenPosition,
@@ -22,8 +22,8 @@ use audaces_protocol::{
 };
 use mock_oracle::instruction::change_price;
 use solana_program::{
-    entrypoint::ProgramResult, program_error::ProgramError, program_pack::Pack, pubkey::Pubkey,
-    system_instruction::create_account,
+    clock::Clock, entrypoint::ProgramResult, program_error::ProgramError, program_pack::Pack,
+    pubkey::Pubkey, system_instruction::create_account,
 };
 use solana_program_test::{processor, BanksClientError, ProgramTest, ProgramTestContext};
 use solana_sdk::signature::Keypair;
@@ -37,6 +37,9 @@ use utils::{
     create_and_get_associated_token_address, mint_init_transaction, sign_send_instructions,
 };
 
+// Largest slippage margin a market created at the default oracle price of 10_000 accepts
+pub const MAX_SLIPPAGE_MARGIN: u64 = (10_000 << 32) * MAX_SLIPPAGE_MARGIN_BPS / 10_000;
+
 pub struct TestContext {
     pub mock_oracle_program_id: Pubkey,
     pub usdc_mint: Keypair,
@@ -274,6 +277,19 @@ impl Context {
         };
     }
 
+    // A BTC/USD market at an oracle price of 10_000 with a single instance, the starting point
+    // of most tests
+    pub async fn init_market() -> Context {
+        let mut context = Context::init(0, 6, 6).await;
+        context.change_oracle_price(10_000 << 32u64).await.unwrap();
+        context
+            .create_market("BTC/USD".to_string(), 1e10f64 as u64, 6, 6)
+            .await
+            .unwrap();
+        context.add_instance(1, 1_000_000).await.unwrap();
+        context
+    }
+
     pub async fn change_oracle_price(&mut self, new_price: u64) -> Result<(), BanksClientError> {
         let change_price_instruction = change_price(
             self.test_ctx.mock_oracle_program_id,
@@ -303,18 +319,8 @@ impl Context {
             .await
             .unwrap()
             .unwrap();
-        let offset = (position_index as usize)
-            .checked_mul(OpenPosition::LEN)
-            .and_then(|s| s.checked_add(UserAccountState::LEN))
-            .ok_or(PerpError::Overflow)?;
-        let offset_end = offset
-            .checked_add(OpenPosition::LEN)
-            .ok_or(PerpError::Overflow)?;
-        let slice = user_account
-            .data
-            .get(offset..offset_end)
-            .ok_or(ProgramError::InvalidArgument)?;
-        OpenPosition::unpack_unchecked(slice)
+        let header = UserAccountState::unpack_from_slice(&user_account.data)?;
+        header.position_at(&user_account.data, position_index)
     }
 
     pub async fn get_user_account(
@@ -342,6 +348,18 @@ impl Context {
         Ok(MarketState::unpack_from_slice(&market_account.data)?)
     }
 
+    // Trades are sent with the current mark price as their predicted price and the largest slippage margin the
+    // market accepts. Markets which can't be read yet, such as outdated ones, get a zero prediction and margin.
+    pub async fn slippage_params(&mut self) -> (u64, u64) {
+        match self.get_market_state().await {
+            Ok(market_state) => (
+                market_state.get_mark_price(),
+                market_state.max_allowed_slippage_margin,
+            ),
+            Err(_) => (0, 0),
+        }
+    }
+
     pub async fn get_page_datas(
         &mut self,
         page_infos: &[PageInfo],
@@ -370,6 +388,8 @@ impl Context {
             .unwrap();
         let market_state = MarketState::unpack_from_slice(&market_account.data)?;
         let market_vault_balance = self.get_market_vault_balance().await.unwrap();
+        let clock: Clock = self.prg_test_ctx.banks_client.get_sysvar().await.unwrap();
+        let now = clock.unix_timestamp as u64;
 
         let mut instances = Vec::with_capacity(market_state.number_of_instances as usize);
         for i in 0..market_state.number_of_instances {
@@ -382,6 +402,7 @@ impl Context {
         let mut page_full_ratios = Vec::with_capacity(market_state.number_of_instances as usize);
         let mut longs_depths = Vec::with_capacity(market_state.number_of_instances as usize);
         let mut shorts_depths = Vec::with_capacity(market_state.number_of_instances as usize);
+        let mut page_stats = Vec::with_capacity(market_state.number_of_instances as usize);
         for (instance, page_infos) in &instances {
             let mut page_datas = self.get_page_datas(&page_infos).await?;
             let mut pages = Vec::with_capacity(page_datas.len());
@@ -405,13 +426,17 @@ impl Context {
             longs_depths.push(longs_depth as u64);
             shorts_depths.push(shorts_depth as u64);
             gc_list_lengths.push(mem.get_gc_list_len().unwrap());
+            page_stats.push(mem.stats().unwrap());
         }
-        let insurance_fund = market_state.get_insurance_fund(market_vault_balance);
+        let insurance_fund = market_state.get_insurance_fund(market_vault_balance)?;
+
+        let remaining_capacity = MarketState::remaining_capacity(&page_stats);
 
         let market_data = MarketDataPoint {
             total_collateral: market_state.total_collateral,
             total_user_balances: market_state.total_user_balances,
             total_fee_balance: market_state.total_fee_balance,
+            protocol_fee_balance: market_state.protocol_fee_balance,
             rebalancing_funds: market_state.rebalancing_funds,
             rebalanced_v_coin: market_state.rebalanced_v_coin,
             v_coin_amount: market_state.v_coin_amount,
@@ -424,11 +449,12 @@ impl Context {
             funding_samples_sum: market_state.funding_samples_sum,
             funding_history_offset: market_state.funding_history_offset,
             funding_history: market_state.funding_history,
-            funding_balancing_factors: market_state.funding_balancing_factors, // FP 32 measure of payment capping to ensure that the insurance fund does not pay funding.
+            seconds_since_last_funding: market_state.seconds_since_last_funding(now),
+            funding_stalled: market_state.is_funding_stalled(now),
             number_of_instances: market_state.number_of_instances,
             insurance_fund,
             market_price: (market_state.v_pc_amount as f64) / (market_state.v_coin_amount as f64),
-            oracle_price: (self.get_oracle_price().await.unwrap() as f64) / (2u64.pow(32) as f64),
+            oracle_price: (self.get_oracle_price().await? as f64) / (2u64.pow(32) as f64),
             equilibrium_price: ((market_state.v_pc_amount as f64)
                 * (market_state.v_coin_amount as f64))
                 / (((market_state.v_coin_amount + market_state.open_longs_v_coin
@@ -436,21 +462,63 @@ impl Context {
                     .pow(2) as f64),
             gc_list_lengths,
             page_full_ratios,
+            page_stats,
+            remaining_capacity,
             longs_depths,
             shorts_depths,
         };
         Ok(market_data)
     }
 
+    pub async fn get_page_fullness(&mut self, instance_index: u32, page_index: usize) -> f64 {
+        let instance_address = self.get_instance_address(instance_index).await.unwrap();
+        let (_, page_infos) = self.parse_instance(instance_address).await.unwrap();
+        let mut page_datas = self
+            .get_page_datas(&page_infos[page_index..page_index + 1])
+            .await
+            .unwrap();
+        let (page_data, u_mem_index, free_slot_list_hd) = &mut page_datas[0];
+        let page = Page {
+            page_size: ((page_data.data.len() - TAG_SIZE) / SLOT_SIZE) as u32,
+            data: Rc::new(RefCell::new(&mut page_data.data)),
+            uninitialized_memory: u_mem_index.to_owned(),
+            free_slot_list_hd: free_slot_list_hd.to_owned(),
+        };
+        ((page.uninitialized_memory as f64) - (page.get_nb_free_slots().unwrap() as f64))
+            / (page.page_size as f64)
+    }
+
+    pub async fn get_gc_list_len(&mut self, instance_index: u32) -> u64 {
+        let instance_address = self.get_instance_address(instance_index).await.unwrap();
+        let (instance, page_infos) = self.parse_instance(instance_address).await.unwrap();
+        let mut page_datas = self.get_page_datas(&page_infos).await.unwrap();
+        let pages = page_datas
+            .iter_mut()
+            .map(|(page_data, u_mem_index, free_slot_list_hd)| Page {
+                page_size: ((page_data.data.len() - TAG_SIZE) / SLOT_SIZE) as u32,
+                data: Rc::new(RefCell::new(&mut page_data.data)),
+                uninitialized_memory: u_mem_index.to_owned(),
+                free_slot_list_hd: free_slot_list_hd.to_owned(),
+            })
+            .collect();
+        Memory::new(pages, instance.garbage_pointer)
+            .get_gc_list_len()
+            .unwrap()
+    }
+
     pub async fn get_market_vault_balance(&mut self) -> Result<u64, ProgramError> {
-        let market_vault = self
+        self.get_token_balance(self.market_ctx.market_vault).await
+    }
+
+    pub async fn get_token_balance(&mut self, token_account: Pubkey) -> Result<u64, ProgramError> {
+        let token_account = self
             .prg_test_ctx
             .banks_client
-            .get_account(self.market_ctx.market_vault)
+            .get_account(token_account)
             .await
             .unwrap()
             .unwrap();
-        Ok(Account::unpack_from_slice(&market_vault.data)
+        Ok(Account::unpack_from_slice(&token_account.data)
             .unwrap()
             .amount)
     }
@@ -491,6 +559,7 @@ impl Context {
         let header_slice = instance_account
             .data
             .get(0..Instance::LEN)
+            .or_else(|| instance_account.data.get(0..INSTANCE_V0_LEN))
             .ok_or(ProgramError::InvalidAccountData)?;
         let instance = Instance::unpack_from_slice(header_slice)?;
         let mut offset = Instance::LEN;
@@ -517,6 +586,21 @@ impl Context {
         Ok(())
     }
 
+    pub async fn warp_to_slot(&mut self, slot: u64) -> Result<(), BanksClientError> {
+        self.prg_test_ctx.warp_to_slot(slot).unwrap();
+        self.update_blockhash().await
+    }
+
+    pub async fn advance_seconds(&mut self, secs: u64) -> Result<(), BanksClientError> {
+        let clock: Clock = self.prg_test_ctx.banks_client.get_sysvar().await?;
+        self.warp_to_slot(clock.slot + 1).await?;
+        // Warping recomputes the clock from the slot, the timestamp has to be overwritten afterwards
+        let mut new_clock: Clock = self.prg_test_ctx.banks_client.get_sysvar().await?;
+        new_clock.unix_timestamp = clock.unix_timestamp + secs as i64;
+        self.prg_test_ctx.set_sysvar(&new_clock);
+        Ok(())
+    }
+
     pub async fn get_oracle_price(&mut self) -> Result<u64, ProgramError> {
         let oracle_account = self
             .prg_test_ctx
@@ -1,15 +1,27 @@
-use super::utils::sign_send_instructions;
+use super::utils::{parse_return_data, sign_send_instructions, sign_send_instructions_with_logs};
 use crate::common::context::Context;
 use audaces_protocol::{
     instruction::{
-        add_budget, add_instance, add_page, close_account, close_position, collect_garbage,
-        crank_funding, crank_liquidation, create_market, extract_funding, increase_position,
-        open_position, rebalance, transfer_position, transfer_user_account, withdraw_budget,
+        accept_user_account_transfer, add_budget, add_budget_wrap_sol, add_instance, add_page,
+        cancel_user_account_transfer, change_admin, close_account, close_position,
+        close_position_at_stop_loss, collect_garbage, cpi, crank_funding, crank_instance_funding,
+        crank_liquidation, create_market, create_rent_exempt_account, extract_funding,
+        extract_funding_multi, get_position, grow_page, grow_user_account, increase_position,
+        increase_position_with_max_fee, migrate_instance, migrate_market, migrate_user_account,
+        open_position, open_position_by_size, open_position_with_max_fee, permissionless_rebalance,
+        propose_user_account_transfer, rebalance, register_referrer, set_pause, set_stop_loss,
+        transfer_position, transfer_user_account, update_market_symbol, withdraw_budget,
+        withdraw_fees,
     },
     instruction::{InstanceContext, PositionInfo},
-    state::PositionType,
+    processor::MAX_SLIPPAGE_MARGIN_BPS,
+    state::{
+        user_account::{PositionSnapshot, TransferProposal},
+        LiquidationRewardMode, PositionType,
+    },
 };
-use solana_program::{pubkey::Pubkey, system_instruction::create_account};
+use borsh::BorshDeserialize;
+use solana_program::{program_pack::Pack, pubkey::Pubkey, system_instruction::create_account};
 use solana_program_test::BanksClientError;
 use solana_sdk::{signature::Keypair, signer::Signer, transport::TransportError};
 
@@ -20,6 +32,39 @@ impl Context {
         initial_v_pc_amount: u64,
         coin_decimals: u8,
         quote_decimals: u8,
+    ) -> Result<(), BanksClientError> {
+        let max_allowed_slippage_margin =
+            self.get_oracle_price().await.unwrap() * MAX_SLIPPAGE_MARGIN_BPS / 10_000;
+        self.create_market_with_params(
+            market_symbol,
+            initial_v_pc_amount,
+            coin_decimals,
+            quote_decimals,
+            max_allowed_slippage_margin,
+            0,
+            0,
+            u64::MAX,
+            u64::MAX,
+            LiquidationRewardMode::RemainingValue,
+            0,
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create_market_with_params(
+        &mut self,
+        market_symbol: String,
+        initial_v_pc_amount: u64,
+        coin_decimals: u8,
+        quote_decimals: u8,
+        max_allowed_slippage_margin: u64,
+        liquidation_insurance_share_bps: u16,
+        min_collateral: u64,
+        max_open_longs_v_coin: u64,
+        max_open_shorts_v_coin: u64,
+        liquidation_reward_mode: LiquidationRewardMode,
+        liquidation_reward_bps: u16,
     ) -> Result<(), BanksClientError> {
         let create_market_instruction = create_market(
             &self.market_ctx,
@@ -27,6 +72,14 @@ impl Context {
             initial_v_pc_amount,
             coin_decimals,
             quote_decimals,
+            max_allowed_slippage_margin,
+            liquidation_insurance_share_bps,
+            min_collateral,
+            max_open_longs_v_coin,
+            max_open_shorts_v_coin,
+            liquidation_reward_mode,
+            liquidation_reward_bps,
+            None,
         );
         sign_send_instructions(
             &mut self.prg_test_ctx,
@@ -43,17 +96,13 @@ impl Context {
     ) -> Result<(), BanksClientError> {
         let instance_keypair = Keypair::new();
         let space = 1_000_000;
-        let mut instructions = vec![create_account(
-            &self.prg_test_ctx.payer.pubkey(),
-            &instance_keypair.pubkey(),
-            self.prg_test_ctx
-                .banks_client
-                .get_rent()
-                .await
-                .unwrap()
-                .minimum_balance(space),
-            space as u64,
-            &self.market_ctx.audaces_protocol_program_id,
+        let rent = self.prg_test_ctx.banks_client.get_rent().await.unwrap();
+        let mut instructions = vec![create_rent_exempt_account(
+            &self.market_ctx,
+            &rent,
+            self.prg_test_ctx.payer.pubkey(),
+            instance_keypair.pubkey(),
+            space,
         )];
         let mut signers = vec![];
         let mut signers_ref = vec![&instance_keypair];
@@ -61,17 +110,12 @@ impl Context {
         for _ in 0..nb_pages_per_instance {
             let page_keypair = Keypair::new();
             pages_pubkeys.push(page_keypair.pubkey());
-            instructions.push(create_account(
-                &self.prg_test_ctx.payer.pubkey(),
-                &page_keypair.pubkey(),
-                self.prg_test_ctx
-                    .banks_client
-                    .get_rent()
-                    .await
-                    .unwrap()
-                    .minimum_balance(space_per_page as usize),
+            instructions.push(create_rent_exempt_account(
+                &self.market_ctx,
+                &rent,
+                self.prg_test_ctx.payer.pubkey(),
+                page_keypair.pubkey(),
                 space_per_page,
-                &self.market_ctx.audaces_protocol_program_id,
             ));
             signers.push(page_keypair);
         }
@@ -115,6 +159,27 @@ impl Context {
         .await
     }
 
+    pub async fn add_budget_wrap_sol(
+        &mut self,
+        amount: u64,
+        wrapped_sol_account: &Keypair,
+        user_account_index: usize,
+    ) -> Result<(), BanksClientError> {
+        let add_budget_instruction = add_budget_wrap_sol(
+            &self.market_ctx,
+            amount,
+            self.user_ctx.owner_account.pubkey(),
+            wrapped_sol_account.pubkey(),
+            self.user_ctx.user_accounts[user_account_index],
+        );
+        sign_send_instructions(
+            &mut self.prg_test_ctx,
+            vec![add_budget_instruction],
+            vec![&self.user_ctx.owner_account, wrapped_sol_account],
+        )
+        .await
+    }
+
     pub async fn withdraw_budget(
         &mut self,
         amount: u64,
@@ -143,6 +208,7 @@ impl Context {
         instance_index: u8,
         user_account_index: usize,
     ) -> Result<(), BanksClientError> {
+        let (predicted_entry_price, maximum_slippage_margin) = self.slippage_params().await;
         let open_position_instruction = open_position(
             &self.market_ctx,
             &PositionInfo {
@@ -153,8 +219,74 @@ impl Context {
             },
             collateral,
             leverage,
-            0,
-            u64::MAX,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            None,
+            None,
+        );
+        sign_send_instructions(
+            &mut self.prg_test_ctx,
+            vec![open_position_instruction],
+            vec![&self.user_ctx.owner_account],
+        )
+        .await
+    }
+
+    pub async fn open_position_by_size(
+        &mut self,
+        side: PositionType,
+        collateral: u64,
+        target_v_pc: u64,
+        instance_index: u8,
+        user_account_index: usize,
+    ) -> Result<(), BanksClientError> {
+        let (predicted_entry_price, maximum_slippage_margin) = self.slippage_params().await;
+        let open_position_instruction = open_position_by_size(
+            &self.market_ctx,
+            &PositionInfo {
+                user_account: self.user_ctx.user_accounts[user_account_index],
+                user_account_owner: self.user_ctx.owner_account.pubkey(),
+                instance_index,
+                side,
+            },
+            collateral,
+            target_v_pc,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            None,
+            None,
+        );
+        sign_send_instructions(
+            &mut self.prg_test_ctx,
+            vec![open_position_instruction],
+            vec![&self.user_ctx.owner_account],
+        )
+        .await
+    }
+
+    pub async fn open_position_with_max_fee(
+        &mut self,
+        side: PositionType,
+        collateral: u64,
+        leverage: u64,
+        max_fee: u64,
+        instance_index: u8,
+        user_account_index: usize,
+    ) -> Result<(), BanksClientError> {
+        let (predicted_entry_price, maximum_slippage_margin) = self.slippage_params().await;
+        let open_position_instruction = open_position_with_max_fee(
+            &self.market_ctx,
+            &PositionInfo {
+                user_account: self.user_ctx.user_accounts[user_account_index],
+                user_account_owner: self.user_ctx.owner_account.pubkey(),
+                instance_index,
+                side,
+            },
+            collateral,
+            leverage,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            max_fee,
             None,
             None,
         );
@@ -174,6 +306,7 @@ impl Context {
         instance_index: u8,
         user_account_index: usize,
     ) -> Result<(), BanksClientError> {
+        let (predicted_entry_price, maximum_slippage_margin) = self.slippage_params().await;
         let increase_position_instruction = increase_position(
             &self.market_ctx,
             collateral,
@@ -182,8 +315,40 @@ impl Context {
             position_index,
             self.user_ctx.owner_account.pubkey(),
             self.user_ctx.user_accounts[user_account_index],
-            0,
-            u64::MAX,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            None,
+            None,
+        );
+        sign_send_instructions(
+            &mut self.prg_test_ctx,
+            vec![increase_position_instruction],
+            vec![&self.user_ctx.owner_account],
+        )
+        .await
+    }
+
+    pub async fn increase_position_with_max_fee(
+        &mut self,
+        collateral: u64,
+        leverage: u64,
+        max_fee: u64,
+        position_index: u16,
+        instance_index: u8,
+        user_account_index: usize,
+    ) -> Result<(), BanksClientError> {
+        let (predicted_entry_price, maximum_slippage_margin) = self.slippage_params().await;
+        let increase_position_instruction = increase_position_with_max_fee(
+            &self.market_ctx,
+            collateral,
+            leverage,
+            instance_index,
+            position_index,
+            self.user_ctx.owner_account.pubkey(),
+            self.user_ctx.user_accounts[user_account_index],
+            predicted_entry_price,
+            maximum_slippage_margin,
+            max_fee,
             None,
             None,
         );
@@ -206,6 +371,7 @@ impl Context {
             .get_position(position_index, user_account_index)
             .await
             .unwrap();
+        let (predicted_entry_price, maximum_slippage_margin) = self.slippage_params().await;
         let close_position_instruction = close_position(
             &self.market_ctx,
             &PositionInfo {
@@ -217,8 +383,8 @@ impl Context {
             closing_collateral,
             closing_v_coin,
             position_index,
-            0,
-            u64::MAX,
+            predicted_entry_price,
+            maximum_slippage_margin,
             None,
             None,
         );
@@ -230,6 +396,100 @@ impl Context {
         .await
     }
 
+    // Closes the position entirely, signed by the payer in place of the user account owner, as the cranker does
+    pub async fn close_position_at_stop_loss(
+        &mut self,
+        position_index: u16,
+        user_account_index: usize,
+    ) -> Result<(), BanksClientError> {
+        let user_account = self.user_ctx.user_accounts[user_account_index];
+        let user_account_data = self
+            .prg_test_ctx
+            .banks_client
+            .get_account(user_account)
+            .await?
+            .unwrap()
+            .data;
+        let close_position_instruction = close_position_at_stop_loss(
+            &self.market_ctx,
+            user_account,
+            &user_account_data,
+            position_index,
+            self.prg_test_ctx.payer.pubkey(),
+        )
+        .unwrap();
+        sign_send_instructions(
+            &mut self.prg_test_ctx,
+            vec![close_position_instruction],
+            vec![],
+        )
+        .await
+    }
+
+    // Closes the position and withdraws the payout to the user's token account
+    pub async fn close_withdraw(
+        &mut self,
+        closing_collateral: u64,
+        closing_v_coin: u64,
+        position_index: u16,
+        user_account_index: usize,
+    ) -> Result<(), BanksClientError> {
+        let position = self
+            .get_position(position_index, user_account_index)
+            .await
+            .unwrap();
+        let instance = &self.market_ctx.instances[position.instance_index as usize];
+        let (predicted_entry_price, maximum_slippage_margin) = self.slippage_params().await;
+        let close_withdraw_instruction = cpi::close_withdraw(
+            self.market_ctx.audaces_protocol_program_id,
+            self.market_ctx.market_account,
+            self.market_ctx.market_signer_account,
+            self.market_ctx.market_vault,
+            self.market_ctx.oracle_account,
+            instance.instance_account,
+            self.user_ctx.user_accounts[user_account_index],
+            self.user_ctx.usdc_account,
+            self.user_ctx.owner_account.pubkey(),
+            self.market_ctx.bonfida_bnb,
+            &instance.memory_pages,
+            closing_collateral,
+            closing_v_coin,
+            0,
+            position_index,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            None,
+            None,
+        );
+        sign_send_instructions(
+            &mut self.prg_test_ctx,
+            vec![close_withdraw_instruction],
+            vec![&self.user_ctx.owner_account],
+        )
+        .await
+    }
+
+    pub async fn set_stop_loss(
+        &mut self,
+        position_index: u16,
+        stop_loss_index: u64,
+        user_account_index: usize,
+    ) -> Result<(), BanksClientError> {
+        let set_stop_loss_instruction = set_stop_loss(
+            &self.market_ctx,
+            position_index,
+            stop_loss_index,
+            self.user_ctx.user_accounts[user_account_index],
+            self.user_ctx.owner_account.pubkey(),
+        );
+        sign_send_instructions(
+            &mut self.prg_test_ctx,
+            vec![set_stop_loss_instruction],
+            vec![&self.user_ctx.owner_account],
+        )
+        .await
+    }
+
     pub async fn liquidate(&mut self, instance_index: u8) -> Result<(), BanksClientError> {
         let liquidate_instruction =
             crank_liquidation(&self.market_ctx, instance_index, self.user_ctx.usdc_account);
@@ -265,6 +525,14 @@ impl Context {
         .await
     }
 
+    pub async fn crank_instance_funding(
+        &mut self,
+        instance_index: u8,
+    ) -> Result<(), BanksClientError> {
+        let instruction = crank_instance_funding(&self.market_ctx, instance_index);
+        sign_send_instructions(&mut self.prg_test_ctx, vec![instruction], vec![]).await
+    }
+
     pub async fn extract_funding(
         &mut self,
         instance_index: u8,
@@ -283,6 +551,24 @@ impl Context {
         .await
     }
 
+    pub async fn extract_funding_multi(
+        &mut self,
+        instance_indices: &[u8],
+        user_account_index: usize,
+    ) -> Result<(), BanksClientError> {
+        let extract_funding_instruction = extract_funding_multi(
+            &self.market_ctx,
+            instance_indices,
+            self.user_ctx.user_accounts[user_account_index],
+        );
+        sign_send_instructions(
+            &mut self.prg_test_ctx,
+            vec![extract_funding_instruction],
+            vec![],
+        )
+        .await
+    }
+
     pub async fn close_account(
         &mut self,
         lamports_target: Pubkey,
@@ -309,19 +595,15 @@ impl Context {
         space: u64,
     ) -> Result<(), BanksClientError> {
         let page_keypair = Keypair::new();
+        let rent = self.prg_test_ctx.banks_client.get_rent().await.unwrap();
 
         let instructions = vec![
-            create_account(
-                &self.prg_test_ctx.payer.pubkey(),
-                &page_keypair.pubkey(),
-                self.prg_test_ctx
-                    .banks_client
-                    .get_rent()
-                    .await
-                    .unwrap()
-                    .minimum_balance(space as usize),
+            create_rent_exempt_account(
+                &self.market_ctx,
+                &rent,
+                self.prg_test_ctx.payer.pubkey(),
+                page_keypair.pubkey(),
                 space,
-                &self.market_ctx.audaces_protocol_program_id,
             ),
             add_page(&self.market_ctx, instance_index, page_keypair.pubkey()),
         ];
@@ -334,6 +616,68 @@ impl Context {
         sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await
     }
 
+    pub async fn grow_page(
+        &mut self,
+        instance_index: u8,
+        page_index: u8,
+        additional_bytes: u64,
+    ) -> Result<(), BanksClientError> {
+        let instructions = vec![grow_page(
+            &self.market_ctx,
+            instance_index,
+            page_index,
+            additional_bytes,
+        )];
+        let signers = vec![&self.test_ctx.market_admin_keypair];
+        sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await
+    }
+
+    pub async fn grow_user_account(
+        &mut self,
+        user_account_index: usize,
+        additional_positions: u32,
+    ) -> Result<(), BanksClientError> {
+        let instructions = vec![grow_user_account(
+            &self.market_ctx,
+            self.user_ctx.user_accounts[user_account_index],
+            self.user_ctx.owner_account.pubkey(),
+            additional_positions,
+        )];
+        let signers = vec![&self.user_ctx.owner_account];
+        sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await
+    }
+
+    pub async fn set_pause(&mut self, paused: bool) -> Result<(), BanksClientError> {
+        let instructions = vec![set_pause(&self.market_ctx, paused)];
+        let signers = vec![&self.test_ctx.market_admin_keypair];
+        sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await
+    }
+
+    pub async fn update_market_symbol(
+        &mut self,
+        new_symbol: String,
+    ) -> Result<(), BanksClientError> {
+        let instructions = vec![update_market_symbol(&self.market_ctx, new_symbol, None)];
+        let signers = vec![&self.test_ctx.market_admin_keypair];
+        sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await
+    }
+
+    pub async fn change_admin(&mut self, new_admin: Pubkey) -> Result<(), BanksClientError> {
+        let instructions = vec![change_admin(&self.market_ctx, new_admin)];
+        let signers = vec![&self.test_ctx.market_admin_keypair];
+        sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await
+    }
+
+    pub async fn change_k(&mut self, factor: u64, admin: &Keypair) -> Result<(), BanksClientError> {
+        let instructions = vec![cpi::change_k(
+            self.market_ctx.audaces_protocol_program_id,
+            self.market_ctx.market_account,
+            admin.pubkey(),
+            factor,
+        )];
+        sign_send_instructions(&mut self.prg_test_ctx, instructions, vec![admin]).await
+    }
+
     pub async fn rebalance(
         &mut self,
         instance_index: u8,
@@ -354,23 +698,144 @@ impl Context {
         sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await
     }
 
+    pub async fn permissionless_rebalance(
+        &mut self,
+        instance_index: u8,
+        collateral: u64,
+        user_account_index: usize,
+    ) -> Result<(), BanksClientError> {
+        let instructions = vec![permissionless_rebalance(
+            &self.market_ctx,
+            self.user_ctx.user_accounts[user_account_index],
+            self.user_ctx.owner_account.pubkey(),
+            instance_index,
+            collateral,
+        )];
+        let signers = vec![&self.user_ctx.owner_account];
+        sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await
+    }
+
+    pub async fn withdraw_fees(&mut self, amount: u64) -> Result<(), BanksClientError> {
+        let instructions = vec![withdraw_fees(
+            &self.market_ctx,
+            self.user_ctx.usdc_account,
+            amount,
+        )];
+        let signers = vec![&self.test_ctx.market_admin_keypair];
+        sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await
+    }
+
     pub async fn transfer_user_account(
         &mut self,
         new_user_account_owner: Keypair,
         user_account_index: usize,
+        allow_immediate: bool,
     ) -> Result<(), BanksClientError> {
         let instructions = vec![transfer_user_account(
             &self.market_ctx,
             self.user_ctx.user_accounts[user_account_index],
             self.user_ctx.owner_account.pubkey(),
             new_user_account_owner.pubkey(),
+            allow_immediate,
         )];
         let signers = vec![&self.user_ctx.owner_account];
         let r = sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await;
-        self.user_ctx.owner_account = new_user_account_owner;
+        if r.is_ok() {
+            self.user_ctx.owner_account = new_user_account_owner;
+        }
         return r;
     }
 
+    pub async fn propose_user_account_transfer(
+        &mut self,
+        transfer_proposal: &Keypair,
+        new_user_account_owner: Pubkey,
+        user_account_index: usize,
+    ) -> Result<(), BanksClientError> {
+        let rent = self.prg_test_ctx.banks_client.get_rent().await.unwrap();
+        let instructions = vec![
+            create_rent_exempt_account(
+                &self.market_ctx,
+                &rent,
+                self.prg_test_ctx.payer.pubkey(),
+                transfer_proposal.pubkey(),
+                TransferProposal::LEN as u64,
+            ),
+            propose_user_account_transfer(
+                &self.market_ctx,
+                self.user_ctx.user_accounts[user_account_index],
+                self.user_ctx.owner_account.pubkey(),
+                transfer_proposal.pubkey(),
+                new_user_account_owner,
+            ),
+        ];
+        let signers = vec![&self.user_ctx.owner_account, transfer_proposal];
+        sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await
+    }
+
+    pub async fn accept_user_account_transfer(
+        &mut self,
+        transfer_proposal: Pubkey,
+        new_user_account_owner: Keypair,
+        user_account_index: usize,
+    ) -> Result<(), BanksClientError> {
+        let instructions = vec![accept_user_account_transfer(
+            &self.market_ctx,
+            self.user_ctx.user_accounts[user_account_index],
+            new_user_account_owner.pubkey(),
+            transfer_proposal,
+            self.user_ctx.owner_account.pubkey(),
+        )];
+        let signers = vec![&new_user_account_owner];
+        sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await?;
+        self.user_ctx.owner_account = new_user_account_owner;
+        Ok(())
+    }
+
+    pub async fn cancel_user_account_transfer(
+        &mut self,
+        transfer_proposal: Pubkey,
+        user_account_index: usize,
+    ) -> Result<(), BanksClientError> {
+        let instructions = vec![cancel_user_account_transfer(
+            &self.market_ctx,
+            self.user_ctx.user_accounts[user_account_index],
+            self.user_ctx.owner_account.pubkey(),
+            transfer_proposal,
+            self.user_ctx.owner_account.pubkey(),
+        )];
+        let signers = vec![&self.user_ctx.owner_account];
+        sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await
+    }
+
+    pub async fn register_referrer(
+        &mut self,
+        referrer_account: Pubkey,
+    ) -> Result<(), BanksClientError> {
+        let instructions = vec![register_referrer(
+            &self.market_ctx,
+            self.prg_test_ctx.payer.pubkey(),
+            referrer_account,
+        )];
+        sign_send_instructions(&mut self.prg_test_ctx, instructions, vec![]).await
+    }
+
+    pub async fn get_position_snapshot(
+        &mut self,
+        position_index: u16,
+        user_account_index: usize,
+    ) -> Result<PositionSnapshot, BanksClientError> {
+        let instructions = vec![get_position(
+            &self.market_ctx,
+            self.user_ctx.user_accounts[user_account_index],
+            position_index,
+        )];
+        let logs =
+            sign_send_instructions_with_logs(&mut self.prg_test_ctx, instructions, vec![]).await?;
+        let data = parse_return_data(&logs).unwrap();
+        Ok(PositionSnapshot::try_from_slice(&data).unwrap())
+    }
+
     pub async fn transfer_position_to_new_user(
         &mut self,
         position_index: u16,
@@ -416,21 +881,62 @@ impl Context {
         return r;
     }
 
+    pub async fn migrate_market(
+        &mut self,
+        max_allowed_slippage_margin: u64,
+    ) -> Result<(), BanksClientError> {
+        let instructions = vec![migrate_market(
+            &self.market_ctx,
+            max_allowed_slippage_margin,
+        )];
+        let signers = vec![&self.test_ctx.market_admin_keypair];
+        sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await
+    }
+
+    pub async fn migrate_user_account(
+        &mut self,
+        user_account_index: usize,
+    ) -> Result<(), BanksClientError> {
+        let instructions = vec![migrate_user_account(
+            &self.market_ctx,
+            self.user_ctx.user_accounts[user_account_index],
+            self.user_ctx.owner_account.pubkey(),
+        )];
+        let signers = vec![&self.user_ctx.owner_account];
+        sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await
+    }
+
+    pub async fn migrate_instance(&mut self, instance_index: u8) -> Result<(), BanksClientError> {
+        let instructions = vec![migrate_instance(&self.market_ctx, instance_index)];
+        let signers = vec![&self.test_ctx.market_admin_keypair];
+        sign_send_instructions(&mut self.prg_test_ctx, instructions, signers).await
+    }
+
     pub async fn create_user_accounts(
         &mut self,
         nb_new_accounts: usize,
+    ) -> Result<(), BanksClientError> {
+        self.create_user_accounts_with_space(nb_new_accounts, 1_000_000)
+            .await
+    }
+
+    pub async fn create_user_accounts_with_space(
+        &mut self,
+        nb_new_accounts: usize,
+        space: u64,
     ) -> Result<(), BanksClientError> {
         let mut instructions = vec![];
         let mut signers: Vec<Keypair> = vec![];
+        let rent = self.prg_test_ctx.banks_client.get_rent().await.unwrap();
         for _ in 0..nb_new_accounts {
             let new_user_account = Keypair::new();
 
-            instructions.push(create_account(
-                &self.prg_test_ctx.payer.pubkey(),
-                &new_user_account.pubkey(),
-                1_000_000,
-                1_000_000,
-                &self.market_ctx.audaces_protocol_program_id,
+            instructions.push(create_rent_exempt_account(
+                &self.market_ctx,
+                &rent,
+                self.prg_test_ctx.payer.pubkey(),
+                new_user_account.pubkey(),
+                space,
             ));
             signers.push(new_user_account);
         }
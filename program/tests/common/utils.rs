@@ -1,3 +1,5 @@
+use audaces_protocol::{error::PerpError, events::PerpEvent};
+use borsh::BorshDeserialize;
 use solana_program::{
     instruction::{Instruction, InstructionError},
     pubkey::Pubkey,
@@ -11,20 +13,61 @@ use spl_associated_token_account::{create_associated_token_account, get_associat
 use spl_token::instruction::initialize_mint;
 
 // Utils
-pub async fn sign_send_instructions(
-    ctx: &mut ProgramTestContext,
+fn sign_instructions(
+    ctx: &ProgramTestContext,
     instructions: Vec<Instruction>,
     signers: Vec<&Keypair>,
-) -> Result<(), BanksClientError> {
+) -> Transaction {
     let mut transaction = Transaction::new_with_payer(&instructions, Some(&ctx.payer.pubkey()));
     let mut payer_signers = vec![&ctx.payer];
     for s in signers {
         payer_signers.push(s);
     }
     transaction.partial_sign(&payer_signers, ctx.last_blockhash);
+    transaction
+}
+
+pub async fn sign_send_instructions(
+    ctx: &mut ProgramTestContext,
+    instructions: Vec<Instruction>,
+    signers: Vec<&Keypair>,
+) -> Result<(), BanksClientError> {
+    let transaction = sign_instructions(ctx, instructions, signers);
     ctx.banks_client.process_transaction(transaction).await
 }
 
+// Sends the instructions and returns the log messages of the transaction
+pub async fn sign_send_instructions_with_logs(
+    ctx: &mut ProgramTestContext,
+    instructions: Vec<Instruction>,
+    signers: Vec<&Keypair>,
+) -> Result<Vec<String>, BanksClientError> {
+    let transaction = sign_instructions(ctx, instructions, signers);
+    let result = ctx
+        .banks_client
+        .process_transaction_with_metadata(transaction)
+        .await?;
+    result.result.map_err(BanksClientError::TransactionError)?;
+    Ok(result.metadata.unwrap().log_messages)
+}
+
+// Decodes the events emitted by the program from the `Program data:` log lines
+pub fn parse_events(logs: &[String]) -> Vec<PerpEvent> {
+    logs.iter()
+        .filter_map(|l| l.strip_prefix("Program data: "))
+        .map(|data| PerpEvent::try_from_slice(&base64::decode(data).unwrap()).unwrap())
+        .collect()
+}
+
+// Decodes the data returned by the program from the `Program return:` log line
+pub fn parse_return_data(logs: &[String]) -> Option<Vec<u8>> {
+    logs.iter()
+        .filter_map(|l| l.strip_prefix("Program return: "))
+        .last()
+        .and_then(|l| l.split(' ').nth(1))
+        .map(|data| base64::decode(data).unwrap())
+}
+
 pub fn create_and_get_associated_token_address(
     ctx: &ProgramTestContext,
     parent_key: &Pubkey,
@@ -81,3 +124,22 @@ pub fn catch_noop(err: BanksClientError) -> Result<(), InstructionError> {
         }
     }
 }
+
+pub fn assert_instruction_error<T: std::fmt::Debug>(
+    result: Result<T, BanksClientError>,
+    error: InstructionError,
+) {
+    match result {
+        Err(BanksClientError::TransactionError(TransactionError::InstructionError(_, e))) => {
+            assert_eq!(e, error)
+        }
+        r => panic!("Expected a {:?} error, got {:?}", error, r),
+    }
+}
+
+pub fn assert_perp_error<T: std::fmt::Debug>(
+    result: Result<T, BanksClientError>,
+    error: PerpError,
+) {
+    assert_instruction_error(result, InstructionError::Custom(error as u32))
+}
@@ -1,8 +1,54 @@
-use audaces_protocol::state::PositionType;
-use solana_program::pubkey::Pubkey;
-use solana_sdk::signer::keypair::Keypair;
+use audaces_protocol::{
+    error::PerpError,
+    events::PerpEvent,
+    instruction::{
+        accept_user_account_transfer, add_instance, add_page, close_position,
+        close_position_from_account, collect_garbage, crank_liquidation, create_market,
+        ensure_funding_processed, extract_funding, increase_position, open_position, rebalance,
+        update_market_symbol, withdraw_fees, DiscountAccount, PositionInfo,
+    },
+    positions_book::memory::{SLOT_SIZE, TAG_SIZE},
+    processor::{FEE_REFERRER, FIDA_MINT, FUNDING_PERIOD, MAX_POSITION_SIZE},
+    state::{
+        instance::{Instance, PageInfo, INSTANCE_V0_LEN},
+        market::{MarketState, MARKET_STATE_V0_LEN},
+        referral::{get_referrer_record_address, ReferrerRecord},
+        user_account::{
+            max_positions, write_position, OpenPosition, UserAccountState, OPEN_POSITION_V0_LEN,
+            POSITION_SNAPSHOT_VERSION,
+        },
+        LiquidationRewardMode, PositionType,
+    },
+    utils::{compute_liquidation_index, compute_payout, simulate_open_position},
+};
+use borsh::BorshSerialize;
+use pyth_client::{cast, Price, Product, PROD_HDR_SIZE};
+use solana_program::{
+    instruction::InstructionError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    rent::Rent,
+    system_instruction::{create_account, transfer},
+};
+use solana_program_test::BanksClientError;
+use solana_sdk::{
+    account::{Account, AccountSharedData},
+    signature::Keypair,
+    signer::Signer,
+};
+use spl_token::{
+    native_mint,
+    state::{Account as TokenAccount, AccountState, Mint},
+};
+use std::str::FromStr;
 pub mod common;
-use crate::common::{context::Context, utils::catch_noop};
+use crate::common::{
+    context::{Context, MAX_SLIPPAGE_MARGIN},
+    utils::{
+        assert_instruction_error, assert_perp_error, catch_noop, parse_events,
+        sign_send_instructions, sign_send_instructions_with_logs,
+    },
+};
 
 #[tokio::test]
 async fn test_audaces_protocol() {
@@ -133,7 +179,7 @@ async fn test_audaces_protocol() {
         .unwrap();
 
     context
-        .transfer_user_account(Keypair::new(), 0)
+        .transfer_user_account(Keypair::new(), 0, true)
         .await
         .unwrap();
 
@@ -204,3 +250,3216 @@ async fn test_overflow_0() {
     let state = context.get_market_state().await.unwrap();
     println!("market_state : {:#?}", state);
 }
+
+#[tokio::test]
+async fn test_oracle_price_zero() {
+    let mut context = Context::init_market().await;
+
+    context.add_budget(5_000_000, 0).await.unwrap();
+
+    context.change_oracle_price(0).await.unwrap();
+
+    assert_perp_error(
+        context
+            .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+            .await,
+        PerpError::OraclePriceZero,
+    );
+    assert_eq!(
+        context.get_market_data().await.unwrap_err(),
+        PerpError::OraclePriceZero.into()
+    );
+}
+
+#[tokio::test]
+async fn test_max_slippage_margin() {
+    let mut context = Context::init(0, 6, 6).await;
+
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+
+    // Markets cannot allow a slippage margin above a share of their mark price
+    for max_allowed_slippage_margin in [u64::MAX, MAX_SLIPPAGE_MARGIN + 1] {
+        assert_instruction_error(
+            context
+                .create_market_with_params(
+                    "BTC/USD".to_string(),
+                    1e10f64 as u64,
+                    6,
+                    6,
+                    max_allowed_slippage_margin,
+                    0,
+                    0,
+                    u64::MAX,
+                    u64::MAX,
+                    LiquidationRewardMode::RemainingValue,
+                    0,
+                )
+                .await,
+            InstructionError::InvalidArgument,
+        );
+    }
+
+    context
+        .create_market_with_params(
+            "BTC/USD".to_string(),
+            1e10f64 as u64,
+            6,
+            6,
+            100 << 32,
+            0,
+            0,
+            u64::MAX,
+            u64::MAX,
+            LiquidationRewardMode::RemainingValue,
+            0,
+        )
+        .await
+        .unwrap();
+
+    context.add_instance(1, 1_000_000).await.unwrap();
+
+    context.add_budget(5_000_000, 0).await.unwrap();
+
+    let mark_price = context.get_market_state().await.unwrap().get_mark_price();
+    let open = |context: &Context, slippage_margin: u64| {
+        open_position(
+            &context.market_ctx,
+            &PositionInfo {
+                user_account: context.user_ctx.user_accounts[0],
+                user_account_owner: context.user_ctx.owner_account.pubkey(),
+                instance_index: 0,
+                side: PositionType::Long,
+            },
+            1_000_000,
+            10 << 32,
+            mark_price,
+            slippage_margin,
+            None,
+            None,
+        )
+    };
+
+    let instructions = vec![open(&context, (100 << 32) + 1)];
+    let signers = vec![&context.user_ctx.owner_account];
+    assert_perp_error(
+        sign_send_instructions(&mut context.prg_test_ctx, instructions, signers).await,
+        PerpError::SlippageMarginTooLarge,
+    );
+
+    let instructions = vec![open(&context, 100 << 32)];
+    let signers = vec![&context.user_ctx.owner_account];
+    sign_send_instructions(&mut context.prg_test_ctx, instructions, signers)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_migrate_market() {
+    let mut context = Context::init_market().await;
+    context.add_instance(1, 1_000_000).await.unwrap();
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 5 << 32u64, 1, 0)
+        .await
+        .unwrap();
+    let market_state = context.get_market_state().await.unwrap();
+
+    // Rewrite the market account with the version 0 layout, without any space to spare
+    let market_key = context.market_ctx.market_account;
+    let mut market_account = context
+        .prg_test_ctx
+        .banks_client
+        .get_account(market_key)
+        .await
+        .unwrap()
+        .unwrap();
+    let instance_addresses =
+        market_account.data[MarketState::LEN..MarketState::LEN + 2 * 32].to_vec();
+    let mut v0_data = market_account.data[..MARKET_STATE_V0_LEN].to_vec();
+    v0_data[1] = 0;
+    v0_data.extend_from_slice(&instance_addresses);
+    market_account.lamports = Rent::default().minimum_balance(v0_data.len());
+    market_account.data = v0_data;
+    context
+        .prg_test_ctx
+        .set_account(&market_key, &AccountSharedData::from(market_account));
+
+    assert_eq!(
+        context.get_market_state().await.unwrap_err(),
+        PerpError::OutdatedAccount.into()
+    );
+    assert_perp_error(
+        context
+            .open_position(PositionType::Short, 1_000_000, 5 << 32u64, 1, 0)
+            .await,
+        PerpError::OutdatedAccount,
+    );
+
+    // The admin pays for the market account's additional rent
+    let fund_admin_instruction = transfer(
+        &context.prg_test_ctx.payer.pubkey(),
+        &context.test_ctx.market_admin_keypair.pubkey(),
+        1_000_000_000,
+    );
+    sign_send_instructions(
+        &mut context.prg_test_ctx,
+        vec![fund_admin_instruction],
+        vec![],
+    )
+    .await
+    .unwrap();
+
+    assert_instruction_error(
+        context.migrate_market(u64::MAX).await,
+        InstructionError::InvalidArgument,
+    );
+    context.migrate_market(MAX_SLIPPAGE_MARGIN).await.unwrap();
+    assert_instruction_error(
+        context.migrate_market(MAX_SLIPPAGE_MARGIN - 1).await,
+        InstructionError::InvalidAccountData,
+    );
+
+    // The migrated market matches the original one and its instances are still found
+    let migrated_market_state = context.get_market_state().await.unwrap();
+    assert_eq!(
+        migrated_market_state.try_to_vec().unwrap(),
+        market_state.try_to_vec().unwrap()
+    );
+    for instance_index in 0..2 {
+        assert_eq!(
+            context.get_instance_address(instance_index).await.unwrap(),
+            context.market_ctx.instances[instance_index as usize].instance_account
+        );
+    }
+    context
+        .open_position(PositionType::Short, 1_000_000, 5 << 32u64, 1, 0)
+        .await
+        .unwrap();
+    context
+        .close_position(u64::MAX, u64::MAX, 0, 0)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_zero_v_coin_position() {
+    let mut context = Context::init_market().await;
+
+    context.add_budget(5_000_000, 0).await.unwrap();
+
+    // An order of a single quote unit is too small to buy any v_coin
+    assert_perp_error(
+        context
+            .open_position(PositionType::Long, 1, 1 << 32u64, 0, 0)
+            .await,
+        PerpError::AmountTooLow,
+    );
+
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    assert_perp_error(
+        context.increase_position(1, 1 << 32u64, 0, 0, 0).await,
+        PerpError::AmountTooLow,
+    );
+}
+
+#[tokio::test]
+async fn test_rent_exempt_instance_and_page() {
+    let mut context = Context::init_market().await;
+    let rent = context.prg_test_ctx.banks_client.get_rent().await.unwrap();
+    let payer = context.prg_test_ctx.payer.pubkey();
+    let program_id = context.market_ctx.audaces_protocol_program_id;
+    let space = 1_000_000;
+    // Funds the account with the rent exempt minimum less the given shortfall
+    let create = |account: &Keypair, shortfall: u64| {
+        create_account(
+            &payer,
+            &account.pubkey(),
+            rent.minimum_balance(space) - shortfall,
+            space as u64,
+            &program_id,
+        )
+    };
+
+    // Neither the instance nor its pages can be short of rent exemption
+    for (instance_shortfall, page_shortfall) in vec![(1, 0), (0, 1)] {
+        let instance = Keypair::new();
+        let page = Keypair::new();
+        let instructions = vec![
+            create(&instance, instance_shortfall),
+            create(&page, page_shortfall),
+            add_instance(&context.market_ctx, instance.pubkey(), &[page.pubkey()]),
+        ];
+        let signers = vec![&instance, &page, &context.test_ctx.market_admin_keypair];
+        assert_instruction_error(
+            sign_send_instructions(&mut context.prg_test_ctx, instructions, signers).await,
+            InstructionError::AccountNotRentExempt,
+        );
+    }
+
+    let page = Keypair::new();
+    let instructions = vec![
+        create(&page, 1),
+        add_page(&context.market_ctx, 0, page.pubkey()),
+    ];
+    let signers = vec![&page, &context.test_ctx.market_admin_keypair];
+    assert_instruction_error(
+        sign_send_instructions(&mut context.prg_test_ctx, instructions, signers).await,
+        InstructionError::AccountNotRentExempt,
+    );
+
+    // Rent exempt accounts are accepted
+    context.add_instance(1, space as u64).await.unwrap();
+    context.add_page(0, space as u64).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_grow_page() {
+    let mut context = Context::init(0, 6, 6).await;
+
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+
+    context
+        .create_market("BTC/USD".to_string(), 1e10f64 as u64, 6, 6)
+        .await
+        .unwrap();
+
+    let initial_page_len = TAG_SIZE + 10 * SLOT_SIZE;
+    context
+        .add_instance(1, initial_page_len as u64)
+        .await
+        .unwrap();
+
+    // The admin pays for the page's additional rent
+    let fund_admin_instruction = transfer(
+        &context.prg_test_ctx.payer.pubkey(),
+        &context.test_ctx.market_admin_keypair.pubkey(),
+        1_000_000_000,
+    );
+    sign_send_instructions(
+        &mut context.prg_test_ctx,
+        vec![fund_admin_instruction],
+        vec![],
+    )
+    .await
+    .unwrap();
+
+    context.add_budget(5_000_000, 0).await.unwrap();
+    assert_eq!(context.get_page_fullness(0, 0).await, 0.);
+
+    // Positions of distinct leverages don't share their slots, open them until the page is full
+    let mut leverage = 1;
+    loop {
+        let result = context
+            .open_position(PositionType::Long, 100_000, leverage << 32u64, 0, 0)
+            .await;
+        if result.is_err() {
+            assert_perp_error(result, PerpError::OutOfSpace);
+            break;
+        }
+        leverage += 1;
+    }
+    assert!(leverage > 1);
+    let page_stats = context.get_market_data().await.unwrap().page_stats;
+    assert_eq!(page_stats[0][0].page_size as usize, 10);
+
+    context
+        .grow_page(0, 0, 100 * SLOT_SIZE as u64)
+        .await
+        .unwrap();
+
+    let page_account = context
+        .prg_test_ctx
+        .banks_client
+        .get_account(context.market_ctx.instances[0].memory_pages[0])
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(page_account.data.len(), initial_page_len + 100 * SLOT_SIZE);
+
+    // The positions which didn't fit are now accepted
+    for leverage in leverage..leverage + 5 {
+        context
+            .open_position(PositionType::Long, 200_000, leverage << 32u64, 0, 0)
+            .await
+            .unwrap();
+    }
+
+    let page_stats = context.get_market_data().await.unwrap().page_stats;
+    assert_eq!(page_stats[0][0].page_size as usize, 110);
+    assert!(page_stats[0][0].allocated_slots > 10);
+    assert_eq!(context.get_gc_list_len(0).await, 0);
+}
+
+#[tokio::test]
+async fn test_funding_periods() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    let initial_offset = context
+        .get_market_state()
+        .await
+        .unwrap()
+        .funding_history_offset;
+
+    for period in 1..=2u8 {
+        context.advance_seconds(FUNDING_PERIOD + 1).await.unwrap();
+        context.crank_funding().await.unwrap();
+
+        let market_state = context.get_market_state().await.unwrap();
+        assert_eq!(
+            market_state.funding_history_offset,
+            (initial_offset + period) % (market_state.funding_history.len() as u8)
+        );
+
+        let user_account = context
+            .prg_test_ctx
+            .banks_client
+            .get_account(context.user_ctx.user_accounts[0])
+            .await
+            .unwrap()
+            .unwrap();
+        let instructions = ensure_funding_processed(
+            &context.market_ctx,
+            &market_state,
+            context.user_ctx.user_accounts[0],
+            &user_account.data,
+        )
+        .unwrap();
+        assert_eq!(instructions.len(), 1);
+
+        context.extract_funding(0, 0).await.unwrap();
+        let user_account = context.get_user_account(0).await.unwrap();
+        assert_eq!(
+            user_account.last_funding_offset,
+            market_state.funding_history_offset
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_liquidation_insurance_share() {
+    // Liquidates the same position in two identical markets which only differ by their insurance share
+    let mut rewards = vec![];
+    let mut vault_balances = vec![];
+    for insurance_share_bps in [0, 5_000] {
+        let mut context = Context::init(0, 6, 6).await;
+
+        context.change_oracle_price(10_000 << 32u64).await.unwrap();
+        context
+            .create_market_with_params(
+                "BTC/USD".to_string(),
+                1e10f64 as u64,
+                6,
+                6,
+                MAX_SLIPPAGE_MARGIN,
+                insurance_share_bps,
+                0,
+                u64::MAX,
+                u64::MAX,
+                LiquidationRewardMode::RemainingValue,
+                0,
+            )
+            .await
+            .unwrap();
+        context.add_instance(1, 1_000_000).await.unwrap();
+        context.add_budget(5_000_000, 0).await.unwrap();
+        context
+            .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+            .await
+            .unwrap();
+
+        context.change_oracle_price(1 << 32u64).await.unwrap();
+
+        let target = context.user_ctx.usdc_account;
+        let target_balance_before = context.get_token_balance(target).await.unwrap();
+        context.liquidate(0).await.unwrap();
+        let target_balance_after = context.get_token_balance(target).await.unwrap();
+
+        rewards.push(target_balance_after - target_balance_before);
+        vault_balances.push(context.get_market_vault_balance().await.unwrap());
+    }
+
+    let full_reward = rewards[0];
+    assert!(full_reward > 0);
+    assert_eq!(rewards[1], full_reward - full_reward / 2);
+    // The withheld part of the reward stays in the market vault
+    assert_eq!(vault_balances[1] - vault_balances[0], full_reward / 2);
+}
+
+#[tokio::test]
+async fn test_liquidation_reward_mode() {
+    // Liquidates the same position in a market paying out the remaining value and in one paying 1% of the notional
+    let mut rewards = vec![];
+    let mut liquidated_v_pc = 0;
+    for (reward_mode, reward_bps) in [
+        (LiquidationRewardMode::RemainingValue, 0),
+        (LiquidationRewardMode::Notional, 100),
+    ] {
+        let mut context = Context::init(0, 6, 6).await;
+
+        context.change_oracle_price(10_000 << 32u64).await.unwrap();
+        context
+            .create_market_with_params(
+                "BTC/USD".to_string(),
+                1e10f64 as u64,
+                6,
+                6,
+                MAX_SLIPPAGE_MARGIN,
+                0,
+                0,
+                u64::MAX,
+                u64::MAX,
+                reward_mode,
+                reward_bps,
+            )
+            .await
+            .unwrap();
+        context.add_instance(1, 1_000_000).await.unwrap();
+        context.add_budget(5_000_000, 0).await.unwrap();
+        context
+            .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+            .await
+            .unwrap();
+        liquidated_v_pc = context.get_position(0, 0).await.unwrap().v_pc_amount;
+
+        context.change_oracle_price(1 << 32u64).await.unwrap();
+
+        let target = context.user_ctx.usdc_account;
+        let target_balance_before = context.get_token_balance(target).await.unwrap();
+        context.liquidate(0).await.unwrap();
+        let target_balance_after = context.get_token_balance(target).await.unwrap();
+
+        rewards.push(target_balance_after - target_balance_before);
+    }
+
+    // The cranker receives the referrer share of the reward
+    let notional_reward = liquidated_v_pc * 100 / 10_000;
+    assert_eq!(rewards[1], notional_reward * FEE_REFERRER / 100);
+    // Most of the collateral is left once the position is liquidated, which outweighs 1% of the notional
+    assert!(rewards[0] > rewards[1]);
+}
+
+// Takes everything out of the market vault but the user balances, the fees, the protocol fees, the
+// rebalancing funds and the given surplus
+async fn drain_market_vault(context: &mut Context, surplus: u64) {
+    let market_state = context.get_market_state().await.unwrap();
+    let vault_key = context.market_ctx.market_vault;
+    let mut vault_account = context
+        .prg_test_ctx
+        .banks_client
+        .get_account(vault_key)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut vault = TokenAccount::unpack(&vault_account.data).unwrap();
+    vault.amount = market_state.total_user_balances
+        + market_state.total_fee_balance
+        + market_state.protocol_fee_balance
+        + market_state.rebalancing_funds
+        + surplus;
+    vault.pack_into_slice(&mut vault_account.data);
+    context
+        .prg_test_ctx
+        .set_account(&vault_key, &AccountSharedData::from(vault_account));
+}
+
+#[tokio::test]
+async fn test_insurance_fund_depleted() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    let position = context.get_position(0, 0).await.unwrap();
+
+    // The notional of the open longs is inflated until the open positions are worth nothing, their
+    // payout then has to come out of the empty insurance fund
+    let market_key = context.market_ctx.market_account;
+    let mut market_account = context
+        .prg_test_ctx
+        .banks_client
+        .get_account(market_key)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut market_state = MarketState::unpack_from_slice(&market_account.data).unwrap();
+    market_state.open_longs_v_pc += 2 * market_state.total_collateral;
+    market_state.pack_into_slice(&mut market_account.data);
+    context
+        .prg_test_ctx
+        .set_account(&market_key, &AccountSharedData::from(market_account));
+    drain_market_vault(&mut context, 0).await;
+    let vault_balance = context.get_market_vault_balance().await.unwrap();
+    assert_eq!(market_state.get_insurance_fund(vault_balance).unwrap(), 0);
+
+    assert_perp_error(
+        context
+            .close_position(position.collateral, position.v_coin_amount, 0, 0)
+            .await,
+        PerpError::InsuranceFundDepleted,
+    );
+    assert_perp_error(
+        context
+            .close_withdraw(position.collateral, position.v_coin_amount, 0, 0)
+            .await,
+        PerpError::InsuranceFundDepleted,
+    );
+    assert_eq!(context.get_position(0, 0).await.unwrap(), position);
+}
+
+#[tokio::test]
+async fn test_liquidation_reward_capped() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    context.change_oracle_price(1 << 32u64).await.unwrap();
+
+    // Most of the collateral is left once the position is liquidated, but the vault only holds 1_000
+    // on top of what it owes
+    drain_market_vault(&mut context, 1_000).await;
+
+    let target = context.user_ctx.usdc_account;
+    let target_balance_before = context.get_token_balance(target).await.unwrap();
+    context.liquidate(0).await.unwrap();
+    let target_balance_after = context.get_token_balance(target).await.unwrap();
+    assert_eq!(
+        target_balance_after - target_balance_before,
+        1_000 * FEE_REFERRER / 100
+    );
+
+    let market_state = context.get_market_state().await.unwrap();
+    let vault_balance = context.get_market_vault_balance().await.unwrap();
+    assert!(market_state.get_insurance_fund(vault_balance).unwrap() >= 0);
+}
+
+#[tokio::test]
+async fn test_pause_trading() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    context.set_pause(true).await.unwrap();
+    assert!(context.get_market_state().await.unwrap().paused);
+
+    assert_perp_error(
+        context
+            .open_position(PositionType::Long, 1_000_000, 5 << 32u64, 0, 0)
+            .await,
+        PerpError::MarketPaused,
+    );
+    assert_perp_error(
+        context
+            .increase_position(1_000_000, 5 << 32u64, 0, 0, 0)
+            .await,
+        PerpError::MarketPaused,
+    );
+
+    // Users can still exit their positions
+    let open_position = context.get_position(0, 0).await.unwrap();
+    context
+        .close_position(
+            open_position.collateral / 2,
+            open_position.v_coin_amount / 2,
+            0,
+            0,
+        )
+        .await
+        .unwrap();
+
+    context.set_pause(false).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 5 << 32u64, 0, 0)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_min_collateral() {
+    let mut context = Context::init(0, 6, 6).await;
+
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+    context
+        .create_market_with_params(
+            "BTC/USD".to_string(),
+            1e10f64 as u64,
+            6,
+            6,
+            MAX_SLIPPAGE_MARGIN,
+            0,
+            500_000,
+            u64::MAX,
+            u64::MAX,
+            LiquidationRewardMode::RemainingValue,
+            0,
+        )
+        .await
+        .unwrap();
+    context.add_instance(1, 1_000_000).await.unwrap();
+    context.add_budget(5_000_000, 0).await.unwrap();
+
+    assert_perp_error(
+        context
+            .open_position(PositionType::Long, 100_000, 10 << 32u64, 0, 0)
+            .await,
+        PerpError::AmountTooLow,
+    );
+
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    let open_position = context.get_position(0, 0).await.unwrap();
+
+    // Leaving dust collateral on the position is rejected
+    assert_perp_error(
+        context
+            .close_position(900_000, open_position.v_coin_amount / 2, 0, 0)
+            .await,
+        PerpError::AmountTooLow,
+    );
+
+    context
+        .close_position(400_000, open_position.v_coin_amount / 2, 0, 0)
+        .await
+        .unwrap();
+    context
+        .close_position(u64::MAX, u64::MAX, 0, 0)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_max_open_interest() {
+    let mut context = Context::init(0, 6, 6).await;
+
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+    // Each long below is worth roughly 1000 v_coin
+    context
+        .create_market_with_params(
+            "BTC/USD".to_string(),
+            1e10f64 as u64,
+            6,
+            6,
+            MAX_SLIPPAGE_MARGIN,
+            0,
+            0,
+            1_500,
+            u64::MAX,
+            LiquidationRewardMode::RemainingValue,
+            0,
+        )
+        .await
+        .unwrap();
+    context.add_instance(1, 1_000_000).await.unwrap();
+    context.add_budget(5_000_000, 0).await.unwrap();
+
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    assert_perp_error(
+        context
+            .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+            .await,
+        PerpError::OpenInterestTooLarge,
+    );
+
+    // The other side is not capped
+    context
+        .open_position(PositionType::Short, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    // Closing the long frees up capacity
+    context
+        .close_position(u64::MAX, u64::MAX, 0, 0)
+        .await
+        .unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_change_admin() {
+    let mut context = Context::init(0, 6, 6).await;
+
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+    context
+        .create_market("BTC/USD".to_string(), 1e10f64 as u64, 6, 6)
+        .await
+        .unwrap();
+
+    let new_admin = Keypair::new();
+    context.change_admin(new_admin.pubkey()).await.unwrap();
+    assert_eq!(
+        context.get_market_state().await.unwrap().admin_address,
+        new_admin.pubkey().to_bytes()
+    );
+
+    let old_admin = Keypair::from_bytes(&context.test_ctx.market_admin_keypair.to_bytes()).unwrap();
+    assert_instruction_error(
+        context.change_k(2 << 32, &old_admin).await,
+        InstructionError::InvalidArgument,
+    );
+
+    let v_coin_amount = context.get_market_state().await.unwrap().v_coin_amount;
+    context.change_k(2 << 32, &new_admin).await.unwrap();
+    assert_eq!(
+        context.get_market_state().await.unwrap().v_coin_amount,
+        2 * v_coin_amount
+    );
+}
+
+#[tokio::test]
+async fn test_change_k_bounds() {
+    let mut context = Context::init(0, 6, 6).await;
+
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+    context
+        .create_market("BTC/USD".to_string(), 1e10f64 as u64, 6, 6)
+        .await
+        .unwrap();
+    let admin = Keypair::from_bytes(&context.test_ctx.market_admin_keypair.to_bytes()).unwrap();
+    let market_state = context.get_market_state().await.unwrap();
+
+    for (factor, expected_error) in [
+        (u64::MAX, PerpError::AmountTooLarge),
+        (0, PerpError::AmountTooLow),
+    ] {
+        assert_perp_error(context.change_k(factor, &admin).await, expected_error);
+    }
+
+    let new_market_state = context.get_market_state().await.unwrap();
+    assert_eq!(new_market_state.v_coin_amount, market_state.v_coin_amount);
+    assert_eq!(new_market_state.v_pc_amount, market_state.v_pc_amount);
+}
+
+#[tokio::test]
+async fn test_simulate_open_position() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+
+    for (position_index, side) in vec![PositionType::Long, PositionType::Short]
+        .into_iter()
+        .enumerate()
+    {
+        let market_state = context.get_market_state().await.unwrap();
+        let oracle_price = context.get_oracle_price().await.unwrap();
+        let balance = context.get_user_account(0).await.unwrap().balance;
+        let preview =
+            simulate_open_position(&market_state, side, 1_000_000, 5 << 32, 0, oracle_price)
+                .unwrap();
+
+        context
+            .open_position(side, 1_000_000, 5 << 32, 0, 0)
+            .await
+            .unwrap();
+
+        let position = context
+            .get_position(position_index as u16, 0)
+            .await
+            .unwrap();
+        assert_eq!(position.v_coin_amount, preview.v_coin_amount);
+        assert_eq!(position.v_pc_amount, preview.v_pc_amount);
+        assert_eq!(position.liquidation_index, preview.liquidation_index);
+        assert_eq!(
+            context.get_user_account(0).await.unwrap().balance,
+            balance - 1_000_000 - preview.fees.total as u64
+        );
+    }
+
+    // The simulation rejects the orders the program rejects
+    let market_state = context.get_market_state().await.unwrap();
+    let oracle_price = context.get_oracle_price().await.unwrap();
+    for (collateral, leverage, error) in vec![
+        (1_000_000, 30 << 32, PerpError::MarginTooLow),
+        (1_000_000_000, 15 << 32, PerpError::AmountTooLarge),
+    ] {
+        assert_eq!(
+            simulate_open_position(
+                &market_state,
+                PositionType::Long,
+                collateral,
+                leverage,
+                0,
+                oracle_price
+            )
+            .unwrap_err(),
+            error
+        );
+        assert_perp_error(
+            context
+                .open_position(PositionType::Long, collateral, leverage, 0, 0)
+                .await,
+            error,
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_open_position_by_size() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+
+    context
+        .open_position_by_size(PositionType::Long, 1_000_000, 5_000_000, 0, 0)
+        .await
+        .unwrap();
+    assert_eq!(
+        context.get_position(0, 0).await.unwrap().v_pc_amount,
+        5_000_000
+    );
+
+    // 30x is above the maximum leverage
+    assert_perp_error(
+        context
+            .open_position_by_size(PositionType::Short, 1_000_000, 30_000_000, 0, 0)
+            .await,
+        PerpError::MarginTooLow,
+    );
+}
+
+#[tokio::test]
+async fn test_update_market_symbol() {
+    let mut context = Context::init(0, 6, 6).await;
+
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+    context
+        .create_market("BTC/USDT".to_string(), 1e10f64 as u64, 6, 6)
+        .await
+        .unwrap();
+
+    context
+        .update_market_symbol("BTC/USD".to_string())
+        .await
+        .unwrap();
+    let market_symbol = context.get_market_state().await.unwrap().market_symbol;
+    assert_eq!(&market_symbol[..7], b"BTC/USD");
+    assert!(market_symbol[7..].iter().all(|b| *b == 0));
+
+    // Symbols are limited to 32 bytes
+    assert!(context.update_market_symbol("A".repeat(33)).await.is_err());
+    assert!(context.update_market_symbol(String::new()).await.is_err());
+}
+
+#[tokio::test]
+async fn test_user_account_transfer_propose_accept() {
+    let mut context = Context::init(0, 6, 6).await;
+
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+    context
+        .create_market("BTC/USD".to_string(), 1e10f64 as u64, 6, 6)
+        .await
+        .unwrap();
+    context.add_budget(1_000_000, 0).await.unwrap();
+
+    let previous_owner = context.user_ctx.owner_account.pubkey();
+    let new_owner = Keypair::new();
+    let transfer_proposal = Keypair::new();
+    context
+        .propose_user_account_transfer(&transfer_proposal, new_owner.pubkey(), 0)
+        .await
+        .unwrap();
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(user_account.owner, previous_owner.to_bytes());
+
+    // Only the proposed owner can accept the transfer
+    assert!(context
+        .accept_user_account_transfer(transfer_proposal.pubkey(), Keypair::new(), 0)
+        .await
+        .is_err());
+
+    // The proposal account's lamports can only be returned to the proposer
+    let instructions = vec![accept_user_account_transfer(
+        &context.market_ctx,
+        context.user_ctx.user_accounts[0],
+        new_owner.pubkey(),
+        transfer_proposal.pubkey(),
+        new_owner.pubkey(),
+    )];
+    assert_instruction_error(
+        sign_send_instructions(&mut context.prg_test_ctx, instructions, vec![&new_owner]).await,
+        InstructionError::InvalidArgument,
+    );
+
+    let proposal_lamports = context
+        .prg_test_ctx
+        .banks_client
+        .get_balance(transfer_proposal.pubkey())
+        .await
+        .unwrap();
+    let previous_owner_lamports = context
+        .prg_test_ctx
+        .banks_client
+        .get_balance(previous_owner)
+        .await
+        .unwrap();
+    context
+        .accept_user_account_transfer(transfer_proposal.pubkey(), new_owner, 0)
+        .await
+        .unwrap();
+    assert_eq!(
+        context
+            .prg_test_ctx
+            .banks_client
+            .get_balance(previous_owner)
+            .await
+            .unwrap(),
+        previous_owner_lamports + proposal_lamports
+    );
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(
+        user_account.owner,
+        context.user_ctx.owner_account.pubkey().to_bytes()
+    );
+    assert!(context
+        .prg_test_ctx
+        .banks_client
+        .get_account(transfer_proposal.pubkey())
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_user_account_transfer_propose_cancel() {
+    let mut context = Context::init(0, 6, 6).await;
+
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+    context
+        .create_market("BTC/USD".to_string(), 1e10f64 as u64, 6, 6)
+        .await
+        .unwrap();
+    context.add_budget(1_000_000, 0).await.unwrap();
+
+    let owner = context.user_ctx.owner_account.pubkey();
+    let new_owner = Keypair::new();
+    let transfer_proposal = Keypair::new();
+    context
+        .propose_user_account_transfer(&transfer_proposal, new_owner.pubkey(), 0)
+        .await
+        .unwrap();
+    context
+        .cancel_user_account_transfer(transfer_proposal.pubkey(), 0)
+        .await
+        .unwrap();
+
+    // The cancelled proposal can't be accepted anymore
+    assert!(context
+        .accept_user_account_transfer(transfer_proposal.pubkey(), new_owner, 0)
+        .await
+        .is_err());
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(user_account.owner, owner.to_bytes());
+}
+
+#[tokio::test]
+async fn test_immediate_user_account_transfer_opt_in() {
+    let mut context = Context::init_market().await;
+    context.add_budget(1_000_000, 0).await.unwrap();
+
+    let owner = context.user_ctx.owner_account.pubkey();
+    let new_owner = Keypair::new();
+    let new_owner_key = new_owner.pubkey();
+    assert_instruction_error(
+        context
+            .transfer_user_account(Keypair::new(), 0, false)
+            .await,
+        InstructionError::InvalidArgument,
+    );
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(user_account.owner, owner.to_bytes());
+
+    context
+        .transfer_user_account(new_owner, 0, true)
+        .await
+        .unwrap();
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(user_account.owner, new_owner_key.to_bytes());
+}
+
+#[tokio::test]
+async fn test_out_of_range_position_index() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 2 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    // The user account holds a single position, at index 0
+    assert_perp_error(
+        context
+            .increase_position(1_000_000, 2 << 32u64, 1, 0, 0)
+            .await,
+        PerpError::PositionNotFound,
+    );
+
+    let (predicted_entry_price, maximum_slippage_margin) = context.slippage_params().await;
+    let instructions = vec![close_position(
+        &context.market_ctx,
+        &PositionInfo {
+            user_account: context.user_ctx.user_accounts[0],
+            user_account_owner: context.user_ctx.owner_account.pubkey(),
+            instance_index: 0,
+            side: PositionType::Long,
+        },
+        u64::MAX,
+        u64::MAX,
+        1,
+        predicted_entry_price,
+        maximum_slippage_margin,
+        None,
+        None,
+    )];
+    let signers = vec![&context.user_ctx.owner_account];
+    assert_perp_error(
+        sign_send_instructions(&mut context.prg_test_ctx, instructions, signers).await,
+        PerpError::PositionNotFound,
+    );
+
+    assert_perp_error(
+        context.transfer_position_to_new_user(1, 0).await,
+        PerpError::PositionNotFound,
+    );
+}
+
+#[tokio::test]
+async fn test_referrer_record() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+
+    let referrer_account = context.user_ctx.usdc_account;
+    context.register_referrer(referrer_account).await.unwrap();
+    // A referrer can only be registered once
+    assert!(context.register_referrer(referrer_account).await.is_err());
+
+    let (predicted_entry_price, maximum_slippage_margin) = context.slippage_params().await;
+    let instructions = vec![open_position(
+        &context.market_ctx,
+        &PositionInfo {
+            user_account: context.user_ctx.user_accounts[0],
+            user_account_owner: context.user_ctx.owner_account.pubkey(),
+            instance_index: 0,
+            side: PositionType::Long,
+        },
+        1_000_000,
+        2 << 32,
+        predicted_entry_price,
+        maximum_slippage_margin,
+        None,
+        Some(referrer_account),
+    )];
+    let signers = vec![&context.user_ctx.owner_account];
+    sign_send_instructions(&mut context.prg_test_ctx, instructions, signers)
+        .await
+        .unwrap();
+
+    let (referrer_record_key, _) = get_referrer_record_address(
+        &context.market_ctx.audaces_protocol_program_id,
+        &context.market_ctx.market_account,
+        &referrer_account,
+    );
+    let referrer_record_account = context
+        .prg_test_ctx
+        .banks_client
+        .get_account(referrer_record_key)
+        .await
+        .unwrap()
+        .unwrap();
+    let referrer_record = ReferrerRecord::unpack_from_slice(&referrer_record_account.data).unwrap();
+    // 10 percent of the 20 bps fee on a 2_000_000 position, rounded up
+    assert_eq!(referrer_record.total_fees, 400);
+    assert_eq!(referrer_record.number_of_referrals, 1);
+}
+
+#[tokio::test]
+async fn test_unsigned_discount_account() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+
+    let discount_owner = Keypair::new();
+    let discount_account = DiscountAccount {
+        owner: discount_owner.pubkey(),
+        address: Pubkey::new_unique(),
+    };
+    let mut data = vec![0; TokenAccount::LEN];
+    TokenAccount {
+        mint: Pubkey::from_str(FIDA_MINT).unwrap(),
+        owner: discount_owner.pubkey(),
+        amount: 0,
+        state: AccountState::Initialized,
+        ..TokenAccount::default()
+    }
+    .pack_into_slice(&mut data);
+    context.prg_test_ctx.set_account(
+        &discount_account.address,
+        &AccountSharedData::from(Account {
+            lamports: 1_000_000,
+            data,
+            owner: spl_token::id(),
+            executable: false,
+            rent_epoch: 0,
+        }),
+    );
+
+    let (predicted_entry_price, maximum_slippage_margin) = context.slippage_params().await;
+    let open = |context: &Context, discount_account: Option<&DiscountAccount>| {
+        open_position(
+            &context.market_ctx,
+            &PositionInfo {
+                user_account: context.user_ctx.user_accounts[0],
+                user_account_owner: context.user_ctx.owner_account.pubkey(),
+                instance_index: 0,
+                side: PositionType::Long,
+            },
+            1_000_000,
+            2 << 32,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            discount_account,
+            Some(context.user_ctx.usdc_account),
+        )
+    };
+
+    // The last byte of the instruction data flags the discount account, which has to match the given accounts
+    let mut instruction = open(&context, Some(&discount_account));
+    *instruction.data.last_mut().unwrap() = 0;
+    let signers = vec![&context.user_ctx.owner_account, &discount_owner];
+    assert_instruction_error(
+        sign_send_instructions(&mut context.prg_test_ctx, vec![instruction], signers).await,
+        InstructionError::InvalidArgument,
+    );
+    let mut instruction = open(&context, None);
+    *instruction.data.last_mut().unwrap() = 1;
+    let signers = vec![&context.user_ctx.owner_account];
+    assert_instruction_error(
+        sign_send_instructions(&mut context.prg_test_ctx, vec![instruction], signers).await,
+        InstructionError::InvalidArgument,
+    );
+
+    // The discount account isn't mistaken for a referrer account when its owner doesn't sign
+    let mut instruction = open(&context, Some(&discount_account));
+    instruction
+        .accounts
+        .iter_mut()
+        .find(|a| a.pubkey == discount_owner.pubkey())
+        .unwrap()
+        .is_signer = false;
+    let signers = vec![&context.user_ctx.owner_account];
+    assert_instruction_error(
+        sign_send_instructions(&mut context.prg_test_ctx, vec![instruction], signers).await,
+        InstructionError::MissingRequiredSignature,
+    );
+
+    let instructions = vec![open(&context, Some(&discount_account))];
+    let signers = vec![&context.user_ctx.owner_account, &discount_owner];
+    sign_send_instructions(&mut context.prg_test_ctx, instructions, signers)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_trade_events() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    let market = context.market_ctx.market_account.to_bytes();
+    let user_account = context.user_ctx.user_accounts[0];
+    let (predicted_entry_price, maximum_slippage_margin) = context.slippage_params().await;
+    let open = |context: &Context, leverage: u64| {
+        open_position(
+            &context.market_ctx,
+            &PositionInfo {
+                user_account,
+                user_account_owner: context.user_ctx.owner_account.pubkey(),
+                instance_index: 0,
+                side: PositionType::Long,
+            },
+            1_000_000,
+            leverage,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            None,
+            None,
+        )
+    };
+
+    let balance = context.get_user_account(0).await.unwrap().balance;
+    let instructions = vec![open(&context, 10 << 32)];
+    let signers = vec![&context.user_ctx.owner_account];
+    let logs = sign_send_instructions_with_logs(&mut context.prg_test_ctx, instructions, signers)
+        .await
+        .unwrap();
+    let position = context.get_position(0, 0).await.unwrap();
+    let fees = balance - context.get_user_account(0).await.unwrap().balance - 1_000_000;
+    assert!(fees > 0);
+    assert_eq!(
+        parse_events(&logs),
+        vec![PerpEvent::OpenedPosition {
+            market,
+            user_account: user_account.to_bytes(),
+            instance_index: 0,
+            side: PositionType::Long,
+            collateral: 1_000_000,
+            v_coin_amount: position.v_coin_amount,
+            v_pc_amount: position.v_pc_amount,
+            liquidation_index: position.liquidation_index,
+            fees: fees as i64,
+        }]
+    );
+
+    let balance = context.get_user_account(0).await.unwrap().balance;
+    let instructions = vec![increase_position(
+        &context.market_ctx,
+        500_000,
+        2 << 32,
+        0,
+        0,
+        context.user_ctx.owner_account.pubkey(),
+        user_account,
+        predicted_entry_price,
+        maximum_slippage_margin,
+        None,
+        None,
+    )];
+    let signers = vec![&context.user_ctx.owner_account];
+    let logs = sign_send_instructions_with_logs(&mut context.prg_test_ctx, instructions, signers)
+        .await
+        .unwrap();
+    let increased_position = context.get_position(0, 0).await.unwrap();
+    let fees = balance - context.get_user_account(0).await.unwrap().balance - 500_000;
+    assert!(fees > 0);
+    assert_eq!(
+        parse_events(&logs),
+        vec![PerpEvent::IncreasedPosition {
+            market,
+            user_account: user_account.to_bytes(),
+            instance_index: 0,
+            side: PositionType::Long,
+            added_collateral: 500_000,
+            added_v_coin_amount: increased_position.v_coin_amount - position.v_coin_amount,
+            added_v_pc_amount: increased_position.v_pc_amount - position.v_pc_amount,
+            liquidation_index: increased_position.liquidation_index,
+            fees: fees as i64,
+        }]
+    );
+    let position = increased_position;
+
+    let user_account_data = context
+        .prg_test_ctx
+        .banks_client
+        .get_account(user_account)
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+    let instructions = vec![close_position_from_account(
+        &context.market_ctx,
+        user_account,
+        &user_account_data,
+        position.collateral,
+        position.v_coin_amount,
+        0,
+        predicted_entry_price,
+        maximum_slippage_margin,
+        None,
+        None,
+    )
+    .unwrap()];
+    let signers = vec![&context.user_ctx.owner_account];
+    let logs = sign_send_instructions_with_logs(&mut context.prg_test_ctx, instructions, signers)
+        .await
+        .unwrap();
+    match &parse_events(&logs)[..] {
+        [PerpEvent::ClosedPosition {
+            market: event_market,
+            user_account: event_user_account,
+            instance_index: 0,
+            side: PositionType::Long,
+            closed_collateral,
+            closed_v_coin_amount,
+            closed_v_pc_amount,
+            payout,
+            fees,
+        }] => {
+            assert_eq!(*event_market, market);
+            assert_eq!(*event_user_account, user_account.to_bytes());
+            assert_eq!(*closed_collateral, position.collateral);
+            assert_eq!(*closed_v_coin_amount, position.v_coin_amount);
+            assert!(*closed_v_pc_amount > 0);
+            assert!(*payout > 0);
+            assert!(*fees > 0);
+        }
+        events => panic!("Expected a single ClosedPosition event, got {:?}", events),
+    }
+
+    let instructions = vec![open(&context, 9 << 32)];
+    let signers = vec![&context.user_ctx.owner_account];
+    sign_send_instructions(&mut context.prg_test_ctx, instructions, signers)
+        .await
+        .unwrap();
+    let position = context.get_position(0, 0).await.unwrap();
+    context.change_oracle_price(1 << 32u64).await.unwrap();
+
+    let instructions = vec![crank_liquidation(
+        &context.market_ctx,
+        0,
+        context.user_ctx.usdc_account,
+    )];
+    let logs = sign_send_instructions_with_logs(&mut context.prg_test_ctx, instructions, vec![])
+        .await
+        .unwrap();
+    match &parse_events(&logs)[..] {
+        [PerpEvent::Liquidated {
+            market: event_market,
+            instance_index: 0,
+            collateral,
+            longs_v_coin_amount,
+            shorts_v_coin_amount: 0,
+            reward,
+        }] => {
+            assert_eq!(*event_market, market);
+            assert_eq!(*collateral, position.collateral);
+            assert_eq!(*longs_v_coin_amount, position.v_coin_amount);
+            assert!(*reward > 0);
+        }
+        events => panic!("Expected a single Liquidated event, got {:?}", events),
+    }
+}
+
+#[tokio::test]
+async fn test_get_position() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 2 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    let position = context.get_position(0, 0).await.unwrap();
+    let market_state = context.get_market_state().await.unwrap();
+    let snapshot = context.get_position_snapshot(0, 0).await.unwrap();
+    assert_eq!(snapshot.version, POSITION_SNAPSHOT_VERSION);
+    assert_eq!(snapshot.position, position);
+
+    // The position is valued as if it were closed at the current mark price
+    let closing_v_pc_amount = market_state
+        .compute_add_v_pc(position.v_coin_amount as i64)
+        .unwrap()
+        .abs() as u64;
+    let equity = compute_payout(
+        closing_v_pc_amount,
+        position.v_pc_amount,
+        position.collateral,
+        &PositionType::Long,
+    );
+    assert!(equity > 0);
+    assert_eq!(snapshot.equity, equity);
+    assert_eq!(
+        snapshot.margin_ratio,
+        (((equity as i128) << 32) / (closing_v_pc_amount as i128)) as i64
+    );
+    assert_eq!(
+        snapshot.liquidation_distance,
+        (10_000i64 << 32) - position.liquidation_index as i64
+    );
+
+    assert_perp_error(
+        context.get_position_snapshot(1, 0).await,
+        PerpError::PositionNotFound,
+    );
+}
+
+#[tokio::test]
+async fn test_fill_price_slippage() {
+    let mut context = Context::init(0, 6, 6).await;
+
+    context.change_oracle_price(100 << 32u64).await.unwrap();
+    context
+        .create_market("BTC/USD".to_string(), 1e9f64 as u64, 6, 6)
+        .await
+        .unwrap();
+    context.add_instance(1, 1_000_000).await.unwrap();
+    context.add_budget(5_000_000, 0).await.unwrap();
+
+    // The pre-trade mark price matches the prediction, only the order's own price impact is at stake
+    let open = |context: &Context, leverage: u64| {
+        open_position(
+            &context.market_ctx,
+            &PositionInfo {
+                user_account: context.user_ctx.user_accounts[0],
+                user_account_owner: context.user_ctx.owner_account.pubkey(),
+                instance_index: 0,
+                side: PositionType::Long,
+            },
+            1_000_000,
+            leverage,
+            100 << 32,
+            1 << 32,
+            None,
+            None,
+        )
+    };
+
+    // A 2% order moves the average fill price by about 2%
+    let instructions = vec![open(&context, 20 << 32)];
+    let signers = vec![&context.user_ctx.owner_account];
+    assert_perp_error(
+        sign_send_instructions(&mut context.prg_test_ctx, instructions, signers).await,
+        PerpError::NetworkSlippageTooLarge,
+    );
+
+    let instructions = vec![open(&context, 1 << 32)];
+    let signers = vec![&context.user_ctx.owner_account];
+    sign_send_instructions(&mut context.prg_test_ctx, instructions, signers)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_max_position_size() {
+    let mut context = Context::init_market().await;
+    context.add_budget(30_000_000_000, 0).await.unwrap();
+
+    // Shorts aren't bounded by the vAMM's v_pc reserve, only by the position size cap
+    let collateral = MAX_POSITION_SIZE / 20 + 1;
+    assert_perp_error(
+        context
+            .open_position(PositionType::Short, collateral, 20 << 32u64, 0, 0)
+            .await,
+        PerpError::AmountTooLarge,
+    );
+}
+
+#[tokio::test]
+async fn test_increase_position_max_leverage() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 20 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    // Funding payments took half of the position's collateral
+    let user_account_key = context.user_ctx.user_accounts[0];
+    let mut user_account = context
+        .prg_test_ctx
+        .banks_client
+        .get_account(user_account_key)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut header = UserAccountState::unpack_from_slice(&user_account.data).unwrap();
+    let mut position = header.position_at(&user_account.data, 0).unwrap();
+    position.collateral /= 2;
+    write_position(&mut user_account.data, 0, &mut header, &position, true).unwrap();
+    context
+        .prg_test_ctx
+        .set_account(&user_account_key, &AccountSharedData::from(user_account));
+
+    assert_perp_error(
+        context
+            .increase_position(100_000, 1 << 32u64, 0, 0, 0)
+            .await,
+        PerpError::MarginTooLow,
+    );
+    context
+        .increase_position(1_000_000, 1 << 32u64, 0, 0, 0)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_full_close_removes_position() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+
+    let market_state_before = context.get_market_state().await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 2 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    let position = context.get_position(0, 0).await.unwrap();
+
+    // Closing the exact amounts of the position is the same as closing it entirely
+    context
+        .close_position(position.collateral, position.v_coin_amount, 0, 0)
+        .await
+        .unwrap();
+
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(user_account.number_of_open_positions, 0);
+    let market_state = context.get_market_state().await.unwrap();
+    assert_eq!(
+        market_state.total_collateral,
+        market_state_before.total_collateral
+    );
+    assert_eq!(market_state.open_longs_v_coin, 0);
+    assert_eq!(market_state.open_longs_v_pc, 0);
+}
+
+#[tokio::test]
+async fn test_permissionless_rebalance() {
+    let mut context = Context::init_market().await;
+    context.add_budget(10_000_000, 0).await.unwrap();
+
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    // The market price is in line with the oracle, so the bias is below the rebalancing margin
+    assert_perp_error(
+        context.permissionless_rebalance(0, 1_000_000, 0).await,
+        PerpError::Nop,
+    );
+
+    context.change_oracle_price(12_000 << 32u64).await.unwrap();
+    context
+        .permissionless_rebalance(0, 1_000_000, 0)
+        .await
+        .unwrap();
+    let market_state = context.get_market_state().await.unwrap();
+    assert_ne!(market_state.last_rebalance_timestamp, 0);
+
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    assert_perp_error(
+        context.permissionless_rebalance(0, 1_000_000, 0).await,
+        PerpError::RebalancingCooldown,
+    );
+}
+
+#[tokio::test]
+async fn test_rebalance_requires_admin_signature() {
+    let mut context = Context::init_market().await;
+    context.add_budget(10_000_000, 0).await.unwrap();
+
+    let admin = context.test_ctx.market_admin_keypair.pubkey();
+    let mut instruction = rebalance(
+        &context.market_ctx,
+        context.user_ctx.user_accounts[0],
+        context.user_ctx.owner_account.pubkey(),
+        0,
+        1_000_000,
+    );
+    instruction
+        .accounts
+        .iter_mut()
+        .find(|a| a.pubkey == admin)
+        .unwrap()
+        .is_signer = false;
+    let signers = vec![&context.user_ctx.owner_account];
+    assert_instruction_error(
+        sign_send_instructions(&mut context.prg_test_ctx, vec![instruction], signers).await,
+        InstructionError::MissingRequiredSignature,
+    );
+}
+
+#[tokio::test]
+async fn test_withdraw_fees() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 2 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    let protocol_fee_balance = context
+        .get_market_state()
+        .await
+        .unwrap()
+        .protocol_fee_balance;
+    assert!(protocol_fee_balance > 0);
+
+    assert_perp_error(
+        context.withdraw_fees(protocol_fee_balance + 1).await,
+        PerpError::NoMoreFunds,
+    );
+
+    // The fees have to be paid out to a separate token account of the quote mint
+    for target in [
+        context.market_ctx.market_vault,
+        context.market_ctx.market_account,
+    ] {
+        let instructions = vec![withdraw_fees(&context.market_ctx, target, 1)];
+        let signers = vec![&context.test_ctx.market_admin_keypair];
+        assert_instruction_error(
+            sign_send_instructions(&mut context.prg_test_ctx, instructions, signers).await,
+            InstructionError::InvalidArgument,
+        );
+    }
+
+    let target = context.user_ctx.usdc_account;
+    let target_balance_before = context.get_token_balance(target).await.unwrap();
+    let vault_balance_before = context.get_market_vault_balance().await.unwrap();
+    let amount = protocol_fee_balance / 2;
+    context.withdraw_fees(amount).await.unwrap();
+
+    assert_eq!(
+        context.get_token_balance(target).await.unwrap(),
+        target_balance_before + amount
+    );
+    assert_eq!(
+        context.get_market_vault_balance().await.unwrap(),
+        vault_balance_before - amount
+    );
+    let market_state = context.get_market_state().await.unwrap();
+    assert_eq!(
+        market_state.protocol_fee_balance,
+        protocol_fee_balance - amount
+    );
+
+    // The remaining fees are needed to pay out the open position
+    drain_market_vault(&mut context, 0).await;
+    assert_perp_error(
+        context.withdraw_fees(1).await,
+        PerpError::InsuranceFundDepleted,
+    );
+}
+
+#[tokio::test]
+async fn test_transfer_position_pending_funding() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    context.advance_seconds(FUNDING_PERIOD + 1).await.unwrap();
+    context.crank_funding().await.unwrap();
+
+    assert_perp_error(
+        context.transfer_position_to_new_user(0, 0).await,
+        PerpError::PendingFunding,
+    );
+
+    context.extract_funding(0, 0).await.unwrap();
+    context.transfer_position_to_new_user(0, 0).await.unwrap();
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(user_account.number_of_open_positions, 0);
+}
+
+#[tokio::test]
+async fn test_withdraw_budget_pending_funding() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    // The mark price is above the oracle price, so the long pays funding
+    context.change_oracle_price(9_000 << 32u64).await.unwrap();
+    context.advance_seconds(FUNDING_PERIOD + 1).await.unwrap();
+    context.crank_funding().await.unwrap();
+
+    let market_state = context.get_market_state().await.unwrap();
+    let position = context.get_position(0, 0).await.unwrap();
+    let pending_funding = market_state.get_max_pending_funding(&position).unwrap();
+    assert!(pending_funding > 0);
+
+    let balance = context.get_user_account(0).await.unwrap().balance;
+    assert_perp_error(
+        context.withdraw_budget(balance, 0).await,
+        PerpError::NoMoreFunds,
+    );
+
+    context
+        .withdraw_budget(balance - pending_funding, 0)
+        .await
+        .unwrap();
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(user_account.balance, pending_funding);
+}
+
+#[tokio::test]
+async fn test_add_budget_wrap_sol_requires_sol_market() {
+    let mut context = Context::init_market().await;
+    context.add_budget(1_000_000, 0).await.unwrap();
+
+    // The test market is quoted in USDC
+    assert_instruction_error(
+        context
+            .add_budget_wrap_sol(1_000_000, &Keypair::new(), 0)
+            .await,
+        InstructionError::InvalidArgument,
+    );
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(user_account.balance, 1_000_000);
+}
+
+#[tokio::test]
+async fn test_add_budget_wrap_sol() {
+    let mut context = Context::init_market().await;
+
+    // Quote the test market in wrapped SOL
+    let mut native_mint_data = vec![0; Mint::LEN];
+    Mint {
+        decimals: 9,
+        is_initialized: true,
+        ..Mint::default()
+    }
+    .pack_into_slice(&mut native_mint_data);
+    context.prg_test_ctx.set_account(
+        &native_mint::id(),
+        &AccountSharedData::from(Account {
+            lamports: Rent::default().minimum_balance(Mint::LEN),
+            data: native_mint_data,
+            owner: spl_token::id(),
+            executable: false,
+            rent_epoch: 0,
+        }),
+    );
+    let vault_key = context.market_ctx.market_vault;
+    let mut vault_account = context
+        .prg_test_ctx
+        .banks_client
+        .get_account(vault_key)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut vault = TokenAccount::unpack(&vault_account.data).unwrap();
+    vault.mint = native_mint::id();
+    vault.pack_into_slice(&mut vault_account.data);
+    context
+        .prg_test_ctx
+        .set_account(&vault_key, &AccountSharedData::from(vault_account));
+
+    let owner = context.user_ctx.owner_account.pubkey();
+    let payer = context.prg_test_ctx.payer.pubkey();
+    sign_send_instructions(
+        &mut context.prg_test_ctx,
+        vec![transfer(&payer, &owner, 1_000_000_000)],
+        vec![],
+    )
+    .await
+    .unwrap();
+
+    let amount = 100_000_000;
+    let vault_balance_before = context.get_market_vault_balance().await.unwrap();
+    let wrapped_sol_account = Keypair::new();
+    context
+        .add_budget_wrap_sol(amount, &wrapped_sol_account, 0)
+        .await
+        .unwrap();
+
+    // The temporary account is closed and its rent exemption returned to the owner
+    assert!(context
+        .prg_test_ctx
+        .banks_client
+        .get_account(wrapped_sol_account.pubkey())
+        .await
+        .unwrap()
+        .is_none());
+    assert_eq!(
+        context
+            .prg_test_ctx
+            .banks_client
+            .get_balance(owner)
+            .await
+            .unwrap(),
+        1_000_000_000 - amount
+    );
+    assert_eq!(
+        context.get_market_vault_balance().await.unwrap(),
+        vault_balance_before + amount
+    );
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(user_account.balance, amount);
+}
+
+#[tokio::test]
+async fn test_zero_budget_amount() {
+    let mut context = Context::init(0, 6, 6).await;
+
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+    context
+        .create_market("BTC/USD".to_string(), 1e10f64 as u64, 6, 6)
+        .await
+        .unwrap();
+
+    assert_perp_error(context.add_budget(0, 0).await, PerpError::Nop);
+
+    context.add_budget(1_000_000, 0).await.unwrap();
+    assert_perp_error(context.withdraw_budget(0, 0).await, PerpError::Nop);
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(user_account.balance, 1_000_000);
+}
+
+#[tokio::test]
+async fn test_max_fee() {
+    let mut context = Context::init_market().await;
+    context.add_budget(10_000_000, 0).await.unwrap();
+
+    assert_perp_error(
+        context
+            .open_position_with_max_fee(PositionType::Long, 1_000_000, 5 << 32u64, 1, 0, 0)
+            .await,
+        PerpError::AmountTooLarge,
+    );
+    assert_eq!(
+        context.get_user_account(0).await.unwrap().balance,
+        10_000_000
+    );
+
+    context
+        .open_position_with_max_fee(PositionType::Long, 1_000_000, 5 << 32u64, 1_000_000, 0, 0)
+        .await
+        .unwrap();
+
+    assert_perp_error(
+        context
+            .increase_position_with_max_fee(1_000_000, 5 << 32u64, 1, 0, 0, 0)
+            .await,
+        PerpError::AmountTooLarge,
+    );
+    context
+        .increase_position_with_max_fee(1_000_000, 5 << 32u64, 1_000_000, 0, 0, 0)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_stop_loss() {
+    let mut context = Context::init(0, 6, 6).await;
+
+    let oracle_price = 10_000 << 32u64;
+    context.change_oracle_price(oracle_price).await.unwrap();
+    context
+        .create_market("BTC/USD".to_string(), 1e10f64 as u64, 6, 6)
+        .await
+        .unwrap();
+    context.add_instance(1, 1_000_000).await.unwrap();
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    let liquidation_index = context.get_position(0, 0).await.unwrap().liquidation_index;
+
+    // The stop-loss has to sit between the liquidation index and the oracle price
+    assert_instruction_error(
+        context.set_stop_loss(0, liquidation_index, 0).await,
+        InstructionError::InvalidArgument,
+    );
+    assert_instruction_error(
+        context.set_stop_loss(0, oracle_price, 0).await,
+        InstructionError::InvalidArgument,
+    );
+
+    let stop_loss_index = (liquidation_index + oracle_price) / 2;
+    context.set_stop_loss(0, stop_loss_index, 0).await.unwrap();
+    assert_eq!(
+        context.get_position(0, 0).await.unwrap().stop_loss_index,
+        stop_loss_index
+    );
+
+    // Only the owner can close the position before the stop-loss is crossed
+    assert_instruction_error(
+        context.close_position_at_stop_loss(0, 0).await,
+        InstructionError::InvalidArgument,
+    );
+
+    context.change_oracle_price(stop_loss_index).await.unwrap();
+    context.close_position_at_stop_loss(0, 0).await.unwrap();
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(user_account.number_of_open_positions, 0);
+    assert!(user_account.balance > 4_000_000);
+}
+
+#[tokio::test]
+async fn test_migrate_user_account() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 5 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    context
+        .open_position(PositionType::Short, 1_000_000, 5 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    let header = context.get_user_account(0).await.unwrap();
+    let positions = vec![
+        context.get_position(0, 0).await.unwrap(),
+        context.get_position(1, 0).await.unwrap(),
+    ];
+
+    // Rewrite the user account with the version 0 layout, without any space to spare
+    let user_account_key = context.user_ctx.user_accounts[0];
+    let mut user_account = context
+        .prg_test_ctx
+        .banks_client
+        .get_account(user_account_key)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut v0_data = user_account.data[..UserAccountState::LEN].to_vec();
+    v0_data[1] = 0;
+    for p in &positions {
+        v0_data.extend_from_slice(&p.try_to_vec().unwrap()[..OPEN_POSITION_V0_LEN]);
+    }
+    user_account.lamports = Rent::default().minimum_balance(v0_data.len());
+    user_account.data = v0_data;
+    context
+        .prg_test_ctx
+        .set_account(&user_account_key, &AccountSharedData::from(user_account));
+
+    assert_eq!(
+        context.get_user_account(0).await.unwrap_err(),
+        PerpError::OutdatedAccount.into()
+    );
+    assert_perp_error(
+        context
+            .open_position(PositionType::Long, 1_000_000, 5 << 32u64, 0, 0)
+            .await,
+        PerpError::OutdatedAccount,
+    );
+
+    // The owner pays for the user account's additional rent
+    let fund_owner_instruction = transfer(
+        &context.prg_test_ctx.payer.pubkey(),
+        &context.user_ctx.owner_account.pubkey(),
+        1_000_000_000,
+    );
+    sign_send_instructions(
+        &mut context.prg_test_ctx,
+        vec![fund_owner_instruction],
+        vec![],
+    )
+    .await
+    .unwrap();
+
+    context.migrate_user_account(0).await.unwrap();
+    assert_instruction_error(
+        context.migrate_user_account(0).await,
+        InstructionError::InvalidAccountData,
+    );
+
+    // The migrated positions match the original ones, without a stop-loss
+    assert_eq!(context.get_user_account(0).await.unwrap(), header);
+    for (i, p) in positions.iter().enumerate() {
+        let migrated = context.get_position(i as u16, 0).await.unwrap();
+        assert_eq!(&migrated, p);
+        assert!(!migrated.has_stop_loss());
+    }
+    context
+        .close_position(u64::MAX, u64::MAX, 1, 0)
+        .await
+        .unwrap();
+    context
+        .close_position(u64::MAX, u64::MAX, 0, 0)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_funding_extraction_without_funding() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    // Without any spread between the mark and oracle prices, no funding is charged
+    let market_state = context.get_market_state().await.unwrap();
+    let mark_price =
+        (((market_state.v_pc_amount as u128) << 32) / (market_state.v_coin_amount as u128)) as u64;
+    context.change_oracle_price(mark_price).await.unwrap();
+    context.advance_seconds(FUNDING_PERIOD + 1).await.unwrap();
+    context.crank_funding().await.unwrap();
+
+    let market_state = context.get_market_state().await.unwrap();
+    let balance = context.get_user_account(0).await.unwrap().balance;
+    context.extract_funding(0, 0).await.unwrap();
+
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(
+        user_account.last_funding_offset,
+        market_state.funding_history_offset
+    );
+    assert_eq!(user_account.balance, balance);
+    assert_eq!(
+        context
+            .get_market_state()
+            .await
+            .unwrap()
+            .total_user_balances,
+        market_state.total_user_balances
+    );
+
+    // The account isn't left with pending funding
+    let position = context.get_position(0, 0).await.unwrap();
+    context
+        .close_position(position.collateral, position.v_coin_amount, 0, 0)
+        .await
+        .unwrap();
+}
+
+async fn extract_funding_events(context: &mut Context) -> Vec<PerpEvent> {
+    let instructions = vec![extract_funding(
+        &context.market_ctx,
+        0,
+        context.user_ctx.user_accounts[0],
+    )];
+    let logs = sign_send_instructions_with_logs(&mut context.prg_test_ctx, instructions, vec![])
+        .await
+        .unwrap();
+    parse_events(&logs)
+}
+
+#[tokio::test]
+async fn test_funding_extracted_event() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    context.change_oracle_price(9_900 << 32u64).await.unwrap();
+    context.advance_seconds(FUNDING_PERIOD + 1).await.unwrap();
+    context.crank_funding().await.unwrap();
+
+    let balance = context.get_user_account(0).await.unwrap().balance;
+    let events = extract_funding_events(&mut context).await;
+    let extracted = balance - context.get_user_account(0).await.unwrap().balance;
+    assert!(extracted > 0);
+    assert_eq!(
+        events,
+        vec![PerpEvent::FundingExtracted {
+            market: context.market_ctx.market_account.to_bytes(),
+            user_account: context.user_ctx.user_accounts[0].to_bytes(),
+            instance_index: 0,
+            amount: extracted as i64,
+        }]
+    );
+}
+
+// Opens a 10x long and leaves the user account without any balance, then cranks a funding period
+// at the given oracle price so that the account can't pay for its funding.
+async fn setup_insolvent_funding(oracle_price: u64) -> Context {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    let balance = context.get_user_account(0).await.unwrap().balance;
+    context.withdraw_budget(balance, 0).await.unwrap();
+
+    // The mark price is above the oracle price, so the long pays funding
+    context.change_oracle_price(oracle_price).await.unwrap();
+    context.advance_seconds(FUNDING_PERIOD + 1).await.unwrap();
+    context.crank_funding().await.unwrap();
+    context
+}
+
+#[tokio::test]
+async fn test_funding_extraction_reduces_insolvent_position() {
+    let mut context = setup_insolvent_funding(9_700 << 32u64).await;
+
+    let market_state = context.get_market_state().await.unwrap();
+    let position = context.get_position(0, 0).await.unwrap();
+    let debt = market_state.get_max_pending_funding(&position).unwrap();
+    assert!(debt > 0 && debt < position.collateral);
+
+    // The whole debt is extracted even though the account has no balance left
+    let events = extract_funding_events(&mut context).await;
+    assert_eq!(
+        events,
+        vec![PerpEvent::FundingExtracted {
+            market: context.market_ctx.market_account.to_bytes(),
+            user_account: context.user_ctx.user_accounts[0].to_bytes(),
+            instance_index: 0,
+            amount: debt as i64,
+        }]
+    );
+
+    // The debt is taken from the collateral and the position is reinserted at its new liquidation index
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(user_account.balance, 0);
+    assert_eq!(user_account.number_of_open_positions, 1);
+    let reduced_position = context.get_position(0, 0).await.unwrap();
+    assert_eq!(reduced_position.collateral, position.collateral - debt);
+    assert_eq!(reduced_position.v_coin_amount, position.v_coin_amount);
+    assert_eq!(reduced_position.v_pc_amount, position.v_pc_amount);
+    assert_eq!(
+        reduced_position.liquidation_index,
+        compute_liquidation_index(
+            position.collateral - debt,
+            position.v_coin_amount,
+            position.v_pc_amount,
+            PositionType::Long,
+            market_state.get_k(),
+        )
+    );
+    assert!(reduced_position.liquidation_index > position.liquidation_index);
+
+    let new_market_state = context.get_market_state().await.unwrap();
+    assert_eq!(
+        new_market_state.total_collateral,
+        market_state.total_collateral - debt
+    );
+    assert_eq!(
+        new_market_state.total_user_balances,
+        market_state.total_user_balances
+    );
+}
+
+#[tokio::test]
+async fn test_funding_extraction_liquidates_insolvent_position() {
+    // The collateral left after paying for the funding can't keep the position above its liquidation index
+    let mut context = setup_insolvent_funding(9_000 << 32u64).await;
+
+    let market_state = context.get_market_state().await.unwrap();
+    let position = context.get_position(0, 0).await.unwrap();
+    let debt = market_state.get_max_pending_funding(&position).unwrap();
+    assert!(debt > 0);
+
+    // The payout of the liquidated position covers the debt
+    let events = extract_funding_events(&mut context).await;
+    assert_eq!(
+        events,
+        vec![PerpEvent::FundingExtracted {
+            market: context.market_ctx.market_account.to_bytes(),
+            user_account: context.user_ctx.user_accounts[0].to_bytes(),
+            instance_index: 0,
+            amount: debt as i64,
+        }]
+    );
+
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(user_account.balance, 0);
+    assert_eq!(user_account.number_of_open_positions, 0);
+    assert!(!user_account.active);
+
+    let new_market_state = context.get_market_state().await.unwrap();
+    assert_eq!(
+        new_market_state.total_collateral,
+        market_state.total_collateral - position.collateral
+    );
+    assert_eq!(
+        new_market_state.total_user_balances,
+        market_state.total_user_balances
+    );
+    assert_eq!(
+        new_market_state.open_longs_v_coin,
+        market_state.open_longs_v_coin - position.v_coin_amount
+    );
+}
+
+#[tokio::test]
+async fn test_extract_funding_multi() {
+    let mut context = Context::init_market().await;
+    context.add_instance(1, 1_000_000).await.unwrap();
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 5 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 5 << 32u64, 1, 0)
+        .await
+        .unwrap();
+
+    context.change_oracle_price(9_900 << 32u64).await.unwrap();
+    context.advance_seconds(FUNDING_PERIOD + 1).await.unwrap();
+    context.crank_funding().await.unwrap();
+    let balance = context.get_user_account(0).await.unwrap().balance;
+
+    context.extract_funding_multi(&[0, 1], 0).await.unwrap();
+
+    let market_state = context.get_market_state().await.unwrap();
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(
+        user_account.last_funding_offset,
+        market_state.funding_history_offset
+    );
+    assert!(user_account.balance < balance);
+    for position_index in 0..2 {
+        assert_eq!(
+            context
+                .get_position(position_index, 0)
+                .await
+                .unwrap()
+                .last_funding_offset,
+            market_state.funding_history_offset
+        );
+    }
+
+    // Both instances are already up to date
+    assert_perp_error(
+        context.extract_funding_multi(&[0, 1], 0).await,
+        PerpError::Nop,
+    );
+}
+
+#[tokio::test]
+async fn test_instance_funding_balancing() {
+    let mut context = Context::init_market().await;
+    context.add_instance(1, 1_000_000).await.unwrap();
+    context.create_user_accounts(2).await.unwrap();
+    for user_account_index in 0..3 {
+        context
+            .add_budget(5_000_000, user_account_index)
+            .await
+            .unwrap();
+    }
+
+    // The market is balanced, but all the longs are on the first instance
+    context
+        .open_position(PositionType::Long, 2_000_000, 5 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    context
+        .open_position(PositionType::Short, 1_000_000, 5 << 32u64, 0, 1)
+        .await
+        .unwrap();
+    context
+        .open_position(PositionType::Short, 1_000_000, 5 << 32u64, 1, 2)
+        .await
+        .unwrap();
+
+    // The mark price is above the oracle price, the longs pay the shorts
+    context.change_oracle_price(9_900 << 32u64).await.unwrap();
+    context.advance_seconds(FUNDING_PERIOD + 1).await.unwrap();
+    context.crank_funding().await.unwrap();
+
+    context.crank_instance_funding(0).await.unwrap();
+    assert_perp_error(context.crank_instance_funding(0).await, PerpError::Nop);
+
+    let instance_address = context.market_ctx.instances[0].instance_account;
+    let (instance, _) = context.parse_instance(instance_address).await.unwrap();
+    let market_state = context.get_market_state().await.unwrap();
+    let last_offset = (market_state.funding_history_offset as usize + 15) % 16;
+    assert_eq!(
+        instance.funding_history_offset,
+        market_state.funding_history_offset
+    );
+    assert_eq!(instance.funding_balancing_factors[last_offset], 1 << 32);
+
+    // The second instance isn't cranked, the extraction records its factors
+    let balances = [
+        context.get_user_account(1).await.unwrap().balance,
+        context.get_user_account(2).await.unwrap().balance,
+    ];
+    context.extract_funding(0, 1).await.unwrap();
+    context.extract_funding(1, 2).await.unwrap();
+    let received = [
+        context.get_user_account(1).await.unwrap().balance - balances[0],
+        context.get_user_account(2).await.unwrap().balance - balances[1],
+    ];
+
+    let instance_address = context.market_ctx.instances[1].instance_account;
+    let (instance, _) = context.parse_instance(instance_address).await.unwrap();
+    assert_eq!(
+        instance.funding_history_offset,
+        market_state.funding_history_offset
+    );
+    assert_eq!(instance.funding_balancing_factors[last_offset], 0);
+
+    // Without longs to pay them, the shorts of the second instance only receive the minimal funding
+    assert!(received[1] > 0);
+    assert!(received[0] > 3 * received[1]);
+}
+
+#[tokio::test]
+async fn test_instance_funding_recorded_before_book_changes() {
+    let mut context = Context::init_market().await;
+    context.create_user_accounts(2).await.unwrap();
+    for user_account_index in 0..3 {
+        context
+            .add_budget(5_000_000, user_account_index)
+            .await
+            .unwrap();
+    }
+    context
+        .open_position(PositionType::Long, 1_000_000, 5 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    context
+        .open_position(PositionType::Short, 1_000_000, 5 << 32u64, 0, 1)
+        .await
+        .unwrap();
+
+    // The longs pay for a period during which the instance is balanced, but it isn't cranked
+    context.change_oracle_price(9_900 << 32u64).await.unwrap();
+    context.advance_seconds(FUNDING_PERIOD + 1).await.unwrap();
+    context.crank_funding().await.unwrap();
+
+    // Doubling the shorts records the missed period with the open interest it was paid on
+    context
+        .open_position(PositionType::Short, 1_000_000, 5 << 32u64, 0, 2)
+        .await
+        .unwrap();
+    assert_perp_error(context.crank_instance_funding(0).await, PerpError::Nop);
+
+    let instance_address = context.market_ctx.instances[0].instance_account;
+    let (instance, _) = context.parse_instance(instance_address).await.unwrap();
+    let market_state = context.get_market_state().await.unwrap();
+    let last_offset = (market_state.funding_history_offset as usize + 15) % 16;
+    assert_eq!(instance.funding_balancing_factors[last_offset], 1 << 32);
+}
+
+#[tokio::test]
+async fn test_migrate_instance() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 5 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    let instance_key = context.market_ctx.instances[0].instance_account;
+    let (instance, page_infos) = context.parse_instance(instance_key).await.unwrap();
+
+    // Rewrite the instance account with the version 0 layout, without any space to spare
+    let mut instance_account = context
+        .prg_test_ctx
+        .banks_client
+        .get_account(instance_key)
+        .await
+        .unwrap()
+        .unwrap();
+    let page_infos_len = page_infos.len() * PageInfo::LEN;
+    let mut v0_data = vec![instance_account.data[0], 0];
+    v0_data.extend_from_slice(
+        &(
+            instance.shorts_pointer,
+            instance.longs_pointer,
+            instance.garbage_pointer,
+            instance.number_of_pages,
+        )
+            .try_to_vec()
+            .unwrap(),
+    );
+    v0_data.resize(INSTANCE_V0_LEN, 0);
+    v0_data
+        .extend_from_slice(&instance_account.data[Instance::LEN..Instance::LEN + page_infos_len]);
+    instance_account.lamports = Rent::default().minimum_balance(v0_data.len());
+    instance_account.data = v0_data;
+    context
+        .prg_test_ctx
+        .set_account(&instance_key, &AccountSharedData::from(instance_account));
+
+    assert_eq!(
+        context.parse_instance(instance_key).await.unwrap_err(),
+        PerpError::OutdatedAccount.into()
+    );
+    assert_perp_error(
+        context
+            .open_position(PositionType::Short, 1_000_000, 5 << 32u64, 0, 0)
+            .await,
+        PerpError::OutdatedAccount,
+    );
+
+    // The admin pays for the instance account's additional rent
+    let fund_admin_instruction = transfer(
+        &context.prg_test_ctx.payer.pubkey(),
+        &context.test_ctx.market_admin_keypair.pubkey(),
+        1_000_000_000,
+    );
+    sign_send_instructions(
+        &mut context.prg_test_ctx,
+        vec![fund_admin_instruction],
+        vec![],
+    )
+    .await
+    .unwrap();
+
+    context.migrate_instance(0).await.unwrap();
+    assert_instruction_error(
+        context.migrate_instance(0).await,
+        InstructionError::InvalidAccountData,
+    );
+
+    // The migrated instance still points to the same positions book
+    let (migrated, migrated_page_infos) = context.parse_instance(instance_key).await.unwrap();
+    assert_eq!(
+        migrated.try_to_vec().unwrap(),
+        instance.try_to_vec().unwrap()
+    );
+    assert_eq!(
+        migrated_page_infos.try_to_vec().unwrap(),
+        page_infos.try_to_vec().unwrap()
+    );
+    context
+        .open_position(PositionType::Short, 1_000_000, 5 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    context
+        .close_position(u64::MAX, u64::MAX, 1, 0)
+        .await
+        .unwrap();
+    context
+        .close_position(u64::MAX, u64::MAX, 0, 0)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_user_account_capacity() {
+    let mut context = Context::init_market().await;
+
+    let space = UserAccountState::LEN + 2 * OpenPosition::LEN;
+    assert_eq!(max_positions(space), 2);
+    context
+        .create_user_accounts_with_space(1, space as u64)
+        .await
+        .unwrap();
+    context.add_budget(5_000_000, 1).await.unwrap();
+
+    for _ in 0..max_positions(space) {
+        context
+            .open_position(PositionType::Long, 100_000, 5 << 32u64, 0, 1)
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        context
+            .get_user_account(1)
+            .await
+            .unwrap()
+            .number_of_open_positions,
+        2
+    );
+
+    assert_perp_error(
+        context
+            .open_position(PositionType::Long, 100_000, 5 << 32u64, 0, 1)
+            .await,
+        PerpError::OutOfSpace,
+    );
+}
+
+#[tokio::test]
+async fn test_grow_user_account() {
+    let mut context = Context::init_market().await;
+
+    let space = UserAccountState::LEN + OpenPosition::LEN;
+    context
+        .create_user_accounts_with_space(1, space as u64)
+        .await
+        .unwrap();
+    context.add_budget(5_000_000, 1).await.unwrap();
+    context
+        .open_position(PositionType::Long, 100_000, 5 << 32u64, 0, 1)
+        .await
+        .unwrap();
+    context
+        .open_position(PositionType::Short, 100_000, 5 << 32u64, 0, 1)
+        .await
+        .unwrap_err();
+    let position = context.get_position(0, 1).await.unwrap();
+
+    // The owner pays for the account's additional rent
+    let fund_owner_instruction = transfer(
+        &context.prg_test_ctx.payer.pubkey(),
+        &context.user_ctx.owner_account.pubkey(),
+        1_000_000_000,
+    );
+    sign_send_instructions(
+        &mut context.prg_test_ctx,
+        vec![fund_owner_instruction],
+        vec![],
+    )
+    .await
+    .unwrap();
+
+    // The account is full, it can't grow by nothing
+    context.grow_user_account(1, 0).await.unwrap_err();
+    context.grow_user_account(1, 2).await.unwrap();
+
+    let user_account = context
+        .prg_test_ctx
+        .banks_client
+        .get_account(context.user_ctx.user_accounts[1])
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(max_positions(user_account.data.len()), 3);
+    assert_eq!(context.get_position(0, 1).await.unwrap(), position);
+
+    context
+        .open_position(PositionType::Short, 100_000, 5 << 32u64, 0, 1)
+        .await
+        .unwrap();
+    let user_account = context.get_user_account(1).await.unwrap();
+    assert_eq!(user_account.number_of_open_positions, 2);
+}
+
+#[tokio::test]
+async fn test_funding_extraction_after_crank_liquidation() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 2 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    let balance = context.get_user_account(0).await.unwrap().balance;
+    context.withdraw_budget(balance, 0).await.unwrap();
+
+    // The longs owe funding, then the most leveraged one is liquidated by the crank
+    context.change_oracle_price(9_000 << 32u64).await.unwrap();
+    context.advance_seconds(FUNDING_PERIOD + 1).await.unwrap();
+    context.crank_funding().await.unwrap();
+    context.liquidate(0).await.unwrap();
+
+    let market_state = context.get_market_state().await.unwrap();
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(user_account.number_of_open_positions, 2);
+    let position = context.get_position(0, 0).await.unwrap();
+    let debt = market_state.get_max_pending_funding(&position).unwrap();
+    assert!(debt > 0 && debt < position.collateral);
+
+    context.extract_funding(0, 0).await.unwrap();
+
+    // The liquidated position is dropped without being charged or accounted for a second time
+    let user_account = context.get_user_account(0).await.unwrap();
+    assert_eq!(user_account.balance, 0);
+    assert_eq!(user_account.number_of_open_positions, 1);
+    let reduced_position = context.get_position(0, 0).await.unwrap();
+    assert_eq!(reduced_position.collateral, position.collateral - debt);
+    assert_eq!(reduced_position.v_coin_amount, position.v_coin_amount);
+
+    let new_market_state = context.get_market_state().await.unwrap();
+    assert_eq!(
+        new_market_state.total_collateral,
+        market_state.total_collateral - debt
+    );
+    assert_eq!(
+        new_market_state.total_user_balances,
+        market_state.total_user_balances
+    );
+    assert_eq!(
+        new_market_state.open_longs_v_coin,
+        market_state.open_longs_v_coin
+    );
+}
+
+#[tokio::test]
+async fn test_funding_staleness() {
+    let mut context = Context::init_market().await;
+
+    let market_data = context.get_market_data().await.unwrap();
+    assert!(!market_data.funding_stalled);
+
+    context.advance_seconds(3 * FUNDING_PERIOD).await.unwrap();
+    let market_data = context.get_market_data().await.unwrap();
+    assert!(market_data.funding_stalled);
+    assert!(market_data.seconds_since_last_funding >= 3 * FUNDING_PERIOD);
+
+    // The crank catches up with the missed periods
+    context.crank_funding().await.unwrap();
+    let market_data = context.get_market_data().await.unwrap();
+    assert!(!market_data.funding_stalled);
+    assert!(market_data.seconds_since_last_funding < FUNDING_PERIOD);
+}
+
+#[tokio::test]
+async fn test_aliased_accounts() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    context.change_oracle_price(1 << 32u64).await.unwrap();
+
+    // The rewards can't be sent back to the market vault
+    let market_vault = context.market_ctx.market_vault;
+    let instruction = crank_liquidation(&context.market_ctx, 0, market_vault);
+    assert_instruction_error(
+        sign_send_instructions(&mut context.prg_test_ctx, vec![instruction], vec![]).await,
+        InstructionError::InvalidArgument,
+    );
+    context.liquidate(0).await.unwrap();
+
+    let instruction = collect_garbage(&context.market_ctx, 0, 10, market_vault);
+    assert_instruction_error(
+        sign_send_instructions(&mut context.prg_test_ctx, vec![instruction], vec![]).await,
+        InstructionError::InvalidArgument,
+    );
+    if let Err(err) = context.collect_garbage(0, 10).await {
+        catch_noop(err).unwrap();
+    }
+
+    // A memory page can't be listed twice in an instance
+    let page = context.market_ctx.instances[0].memory_pages[0];
+    let instruction = add_page(&context.market_ctx, 0, page);
+    let admin = Keypair::from_bytes(&context.test_ctx.market_admin_keypair.to_bytes()).unwrap();
+    assert_instruction_error(
+        sign_send_instructions(&mut context.prg_test_ctx, vec![instruction], vec![&admin]).await,
+        InstructionError::InvalidArgument,
+    );
+}
+
+#[tokio::test]
+async fn test_reward_target_token_account() {
+    let mut context = Context::init_market().await;
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    context.change_oracle_price(1 << 32u64).await.unwrap();
+
+    // Neither a missing account nor an account of the program are token accounts
+    let user_account = context.user_ctx.user_accounts[0];
+    for target in [Pubkey::new_unique(), user_account] {
+        let instruction = crank_liquidation(&context.market_ctx, 0, target);
+        assert_instruction_error(
+            sign_send_instructions(&mut context.prg_test_ctx, vec![instruction], vec![]).await,
+            InstructionError::InvalidArgument,
+        );
+        let instruction = collect_garbage(&context.market_ctx, 0, 10, target);
+        assert_instruction_error(
+            sign_send_instructions(&mut context.prg_test_ctx, vec![instruction], vec![]).await,
+            InstructionError::InvalidArgument,
+        );
+    }
+
+    context.liquidate(0).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_mismatch_errors() {
+    let mut context = Context::init_market().await;
+    context.add_instance(1, 1_000_000).await.unwrap();
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 5 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    // Wrong oracle
+    let oracle_account = context.market_ctx.oracle_account;
+    context.market_ctx.oracle_account = Pubkey::new_unique();
+    assert_perp_error(context.crank_funding().await, PerpError::OracleMismatch);
+    assert_perp_error(
+        context.close_position(100_000, 0, 0, 0).await,
+        PerpError::OracleMismatch,
+    );
+    context.market_ctx.oracle_account = oracle_account;
+
+    // Wrong instance
+    context.market_ctx.instances.swap(0, 1);
+    assert_perp_error(
+        context.crank_instance_funding(0).await,
+        PerpError::InstanceMismatch,
+    );
+    assert_perp_error(
+        context.close_position(100_000, 0, 0, 0).await,
+        PerpError::InstanceMismatch,
+    );
+    context.market_ctx.instances.swap(0, 1);
+
+    // User account of another market
+    let user_account_key = context.user_ctx.user_accounts[0];
+    let mut user_account = context
+        .prg_test_ctx
+        .banks_client
+        .get_account(user_account_key)
+        .await
+        .unwrap()
+        .unwrap();
+    let mut header = UserAccountState::unpack_from_slice(&user_account.data).unwrap();
+    header.market = Pubkey::new_unique().to_bytes();
+    header.pack_into_slice(&mut user_account.data);
+    context
+        .prg_test_ctx
+        .set_account(&user_account_key, &AccountSharedData::from(user_account));
+    assert_perp_error(
+        context.close_position(100_000, 0, 0, 0).await,
+        PerpError::MarketMismatch,
+    );
+    assert_perp_error(
+        context.extract_funding(0, 0).await,
+        PerpError::MarketMismatch,
+    );
+}
+
+#[tokio::test]
+async fn test_close_position_from_account() {
+    let mut context = Context::init_market().await;
+    context.add_instance(1, 1_000_000).await.unwrap();
+    context.add_budget(5_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Short, 1_000_000, 5 << 32u64, 1, 0)
+        .await
+        .unwrap();
+    let position = context.get_position(0, 0).await.unwrap();
+    let user_account = context.user_ctx.user_accounts[0];
+    let user_account_data = context
+        .prg_test_ctx
+        .banks_client
+        .get_account(user_account)
+        .await
+        .unwrap()
+        .unwrap()
+        .data;
+    let (predicted_entry_price, maximum_slippage_margin) = context.slippage_params().await;
+    let build = |data: &[u8], position_index: u16| {
+        close_position_from_account(
+            &context.market_ctx,
+            user_account,
+            data,
+            u64::MAX,
+            u64::MAX,
+            position_index,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            None,
+            None,
+        )
+    };
+
+    // The owner, instance and side are read from the user account
+    let instruction = build(&user_account_data, 0).unwrap();
+    assert_eq!(
+        instruction,
+        close_position(
+            &context.market_ctx,
+            &PositionInfo {
+                user_account,
+                user_account_owner: context.user_ctx.owner_account.pubkey(),
+                instance_index: 1,
+                side: PositionType::Short,
+            },
+            u64::MAX,
+            u64::MAX,
+            0,
+            predicted_entry_price,
+            maximum_slippage_margin,
+            None,
+            None,
+        )
+    );
+    assert_eq!(
+        build(&user_account_data, 1).unwrap_err(),
+        PerpError::PositionNotFound.into()
+    );
+
+    // User account of another market
+    let mut header = UserAccountState::unpack_from_slice(&user_account_data).unwrap();
+    let mut data = user_account_data.clone();
+    header.market = Pubkey::new_unique().to_bytes();
+    header.pack_into_slice(&mut data);
+    assert_eq!(
+        build(&data, 0).unwrap_err(),
+        PerpError::MarketMismatch.into()
+    );
+
+    // Position on an instance which isn't in the market context
+    let mut header = UserAccountState::unpack_from_slice(&user_account_data).unwrap();
+    let mut data = user_account_data.clone();
+    let mut unknown_instance_position = position;
+    unknown_instance_position.instance_index = 2;
+    write_position(&mut data, 0, &mut header, &unknown_instance_position, true).unwrap();
+    assert_eq!(
+        build(&data, 0).unwrap_err(),
+        PerpError::InstanceMismatch.into()
+    );
+
+    sign_send_instructions(
+        &mut context.prg_test_ctx,
+        vec![instruction],
+        vec![&context.user_ctx.owner_account],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        context
+            .get_user_account(0)
+            .await
+            .unwrap()
+            .number_of_open_positions,
+        0
+    );
+}
+
+#[tokio::test]
+async fn test_unordered_memory_pages() {
+    let mut context = Context::init(0, 6, 6).await;
+
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+    context
+        .create_market("BTC/USD".to_string(), 1e10f64 as u64, 6, 6)
+        .await
+        .unwrap();
+    context.add_instance(3, 100_000).await.unwrap();
+    context.add_budget(5_000_000, 0).await.unwrap();
+
+    // The memory pages can be provided in any order
+    context.market_ctx.instances[0].memory_pages.reverse();
+    context
+        .open_position(PositionType::Long, 1_000_000, 5 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    context.market_ctx.instances[0].memory_pages.swap(0, 1);
+    context
+        .open_position(PositionType::Short, 1_000_000, 5 << 32u64, 0, 0)
+        .await
+        .unwrap();
+
+    // But all of them have to be there
+    let missing_page = context.market_ctx.instances[0].memory_pages[2];
+    context.market_ctx.instances[0].memory_pages[2] = Pubkey::new_unique();
+    assert_instruction_error(
+        context.close_position(u64::MAX, u64::MAX, 0, 0).await,
+        InstructionError::InvalidArgument,
+    );
+    context.market_ctx.instances[0].memory_pages[2] = missing_page;
+
+    context
+        .close_position(u64::MAX, u64::MAX, 0, 0)
+        .await
+        .unwrap();
+    assert_eq!(
+        context
+            .get_user_account(0)
+            .await
+            .unwrap()
+            .number_of_open_positions,
+        1
+    );
+}
+
+#[tokio::test]
+async fn test_create_market_symbol() {
+    async fn create_market_with_symbol(
+        context: &mut Context,
+        market_symbol: &str,
+        pyth_product: Option<Pubkey>,
+    ) -> Result<(), BanksClientError> {
+        let instruction = create_market(
+            &context.market_ctx,
+            market_symbol.to_string(),
+            1e10f64 as u64,
+            6,
+            6,
+            MAX_SLIPPAGE_MARGIN,
+            0,
+            0,
+            u64::MAX,
+            u64::MAX,
+            LiquidationRewardMode::RemainingValue,
+            0,
+            pyth_product,
+        );
+        sign_send_instructions(&mut context.prg_test_ctx, vec![instruction], vec![]).await
+    }
+    async fn update_market_symbol_with_product(
+        context: &mut Context,
+        new_symbol: &str,
+        pyth_product: Option<Pubkey>,
+    ) -> Result<(), BanksClientError> {
+        let instruction =
+            update_market_symbol(&context.market_ctx, new_symbol.to_string(), pyth_product);
+        sign_send_instructions(
+            &mut context.prg_test_ctx,
+            vec![instruction],
+            vec![&context.test_ctx.market_admin_keypair],
+        )
+        .await
+    }
+    fn set_pyth_product(context: &mut Context, key: &Pubkey, symbol: &str, owner: Pubkey) {
+        let mut attributes = vec![];
+        for attribute in ["symbol", symbol] {
+            attributes.push(attribute.len() as u8);
+            attributes.extend_from_slice(attribute.as_bytes());
+        }
+        let mut product_data = vec![0u8; std::mem::size_of::<Product>()];
+        product_data[12..16]
+            .copy_from_slice(&((PROD_HDR_SIZE + attributes.len()) as u32).to_le_bytes());
+        product_data[PROD_HDR_SIZE..PROD_HDR_SIZE + attributes.len()].copy_from_slice(&attributes);
+        context.prg_test_ctx.set_account(
+            key,
+            &Account {
+                lamports: 1_000_000_000,
+                data: product_data,
+                owner,
+                executable: false,
+                rent_epoch: 0,
+            }
+            .into(),
+        );
+    }
+    let mut context = Context::init(0, 6, 6).await;
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+    let mock_oracle = context.market_ctx.oracle_account;
+
+    // A Pyth price account quoting 10_000 and linked to an ETH/USD product
+    let pyth_program = Pubkey::new_unique();
+    let pyth_price = Pubkey::new_unique();
+    let pyth_product = Pubkey::new_unique();
+    let mut price_data = vec![0u8; std::mem::size_of::<Price>()];
+    let offsets = {
+        let price = cast::<Price>(&price_data);
+        let offset = |field: *const u8| field as usize - price_data.as_ptr() as usize;
+        [
+            offset(&price.magic as *const _ as *const u8),
+            offset(&price.ver as *const _ as *const u8),
+            offset(&price.atype as *const _ as *const u8),
+            offset(&price.ptype as *const _ as *const u8),
+            offset(&price.expo as *const _ as *const u8),
+            offset(&price.prod as *const _ as *const u8),
+            offset(&price.agg.price as *const _ as *const u8),
+            offset(&price.agg.status as *const _ as *const u8),
+        ]
+    };
+    let [magic, ver, atype, ptype, expo, prod, agg_price, agg_status] = offsets;
+    price_data[magic..magic + 4].copy_from_slice(&0xa1b2c3d4u32.to_le_bytes());
+    price_data[ver..ver + 4].copy_from_slice(&2u32.to_le_bytes());
+    price_data[atype..atype + 4].copy_from_slice(&3u32.to_le_bytes());
+    price_data[ptype..ptype + 4].copy_from_slice(&1u32.to_le_bytes());
+    price_data[expo..expo + 4].copy_from_slice(&(-8i32).to_le_bytes());
+    price_data[prod..prod + 32].copy_from_slice(&pyth_product.to_bytes());
+    price_data[agg_price..agg_price + 8].copy_from_slice(&1_000_000_000_000i64.to_le_bytes());
+    price_data[agg_status..agg_status + 4].copy_from_slice(&1u32.to_le_bytes());
+    context.prg_test_ctx.set_account(
+        &pyth_price,
+        &Account {
+            lamports: 1_000_000_000,
+            data: price_data,
+            owner: pyth_program,
+            executable: false,
+            rent_epoch: 0,
+        }
+        .into(),
+    );
+    set_pyth_product(&mut context, &pyth_product, "ETH/USD", pyth_program);
+    // A product listing the same symbol which the price account doesn't link to
+    let unlinked_product = Pubkey::new_unique();
+    set_pyth_product(&mut context, &unlinked_product, "ETH/USD", pyth_program);
+
+    assert_instruction_error(
+        create_market_with_symbol(&mut context, "", None).await,
+        InstructionError::InvalidArgument,
+    );
+    assert_instruction_error(
+        create_market_with_symbol(&mut context, &"A".repeat(33), None).await,
+        InstructionError::InvalidArgument,
+    );
+
+    // Mock oracles aren't linked to any product
+    assert_instruction_error(
+        create_market_with_symbol(&mut context, "ETH/USD", Some(pyth_product)).await,
+        InstructionError::InvalidArgument,
+    );
+
+    context.market_ctx.oracle_account = pyth_price;
+    assert_instruction_error(
+        create_market_with_symbol(&mut context, "ETH/USD", None).await,
+        InstructionError::NotEnoughAccountKeys,
+    );
+    assert_instruction_error(
+        create_market_with_symbol(&mut context, "BTC/USD", Some(pyth_product)).await,
+        InstructionError::InvalidArgument,
+    );
+    assert_instruction_error(
+        create_market_with_symbol(&mut context, "ETH/USD", Some(unlinked_product)).await,
+        InstructionError::InvalidArgument,
+    );
+    set_pyth_product(&mut context, &pyth_product, "ETH/USD", Pubkey::new_unique());
+    assert_instruction_error(
+        create_market_with_symbol(&mut context, "ETH/USD", Some(pyth_product)).await,
+        InstructionError::InvalidArgument,
+    );
+    set_pyth_product(&mut context, &pyth_product, "ETH/USD", pyth_program);
+
+    create_market_with_symbol(&mut context, "ETH/USD", Some(pyth_product))
+        .await
+        .unwrap();
+    let market_symbol = context.get_market_state().await.unwrap().market_symbol;
+    assert_eq!(&market_symbol[..7], b"ETH/USD");
+
+    // Renaming the market goes through the same check
+    assert_instruction_error(
+        update_market_symbol_with_product(&mut context, "BTC/USD", Some(pyth_product)).await,
+        InstructionError::InvalidArgument,
+    );
+    assert_instruction_error(
+        update_market_symbol_with_product(&mut context, "ETH/USD", Some(unlinked_product)).await,
+        InstructionError::InvalidArgument,
+    );
+    assert_instruction_error(
+        update_market_symbol_with_product(&mut context, "ETH/USD", None).await,
+        InstructionError::NotEnoughAccountKeys,
+    );
+    set_pyth_product(&mut context, &pyth_product, "ETH/USDC", pyth_program);
+    update_market_symbol_with_product(&mut context, "ETH/USDC", Some(pyth_product))
+        .await
+        .unwrap();
+    let market_symbol = context.get_market_state().await.unwrap().market_symbol;
+    assert_eq!(&market_symbol[..8], b"ETH/USDC");
+
+    context.market_ctx.oracle_account = mock_oracle;
+    assert_perp_error(
+        context.update_market_symbol("ETH/USD".to_string()).await,
+        PerpError::OracleMismatch,
+    );
+}
+
+#[tokio::test]
+async fn test_create_market_decimals() {
+    let mut context = Context::init(0, 6, 6).await;
+    context.change_oracle_price(10_000 << 32u64).await.unwrap();
+
+    // Such decimals would overflow the price conversions
+    assert_instruction_error(
+        context
+            .create_market("BTC/USD".to_string(), 1e10f64 as u64, 40, 6)
+            .await,
+        InstructionError::InvalidArgument,
+    );
+    assert_instruction_error(
+        context
+            .create_market("BTC/USD".to_string(), 1e10f64 as u64, 6, 19)
+            .await,
+        InstructionError::InvalidArgument,
+    );
+
+    context
+        .create_market("BTC/USD".to_string(), 1e10f64 as u64, 18, 18)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn test_rebalancing_lifecycle() {
+    let mut context = Context::init_market().await;
+    context.add_budget(100_000_000, 0).await.unwrap();
+
+    // Balanced trades fill up the rebalancing funds with their fees
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    context
+        .open_position(PositionType::Short, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    let market_state = context.get_market_state().await.unwrap();
+    assert_eq!(market_state.rebalanced_v_coin, 0);
+    assert!(market_state.rebalancing_funds > 0);
+
+    // The mark price is now 25% above the oracle price, the vAMM takes the other side of a long
+    context.change_oracle_price(8_000 << 32u64).await.unwrap();
+    context
+        .open_position(PositionType::Long, 2_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    let market_state = context.get_market_state().await.unwrap();
+    assert!(market_state.rebalanced_v_coin < 0);
+    assert_eq!(market_state.rebalancing_funds, 0);
+
+    // Once the mark price is below the oracle price, a short closes the vAMM's position
+    // without drawing on the rebalancing funds
+    context.change_oracle_price(12_000 << 32u64).await.unwrap();
+    context
+        .open_position(PositionType::Short, 2_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    let market_state = context.get_market_state().await.unwrap();
+    assert_eq!(market_state.rebalanced_v_coin, 0);
+    assert!(market_state.rebalancing_funds > 0);
+}
+
+#[tokio::test]
+async fn test_funding_extraction_wraparound() {
+    let mut context = Context::init_market().await;
+    context.add_budget(10_000_000, 0).await.unwrap();
+    context
+        .open_position(PositionType::Long, 1_000_000, 10 << 32u64, 0, 0)
+        .await
+        .unwrap();
+    let v_coin_amount = context.get_position(0, 0).await.unwrap().v_coin_amount as i128;
+
+    // Cranks the funding once, with the mark price above the oracle price so that the long pays a different
+    // funding every period. Returns the funding recorded for the period.
+    async fn crank_period(context: &mut Context, period: u64) -> i64 {
+        context
+            .change_oracle_price((9_000 + 10 * period) << 32)
+            .await
+            .unwrap();
+        context.advance_seconds(FUNDING_PERIOD + 1).await.unwrap();
+        context.crank_funding().await.unwrap();
+        let market_state = context.get_market_state().await.unwrap();
+        let cycle = market_state.funding_history.len();
+        let funding = market_state.funding_history
+            [(market_state.funding_history_offset as usize + cycle - 1) % cycle];
+        assert!(funding > 0);
+        funding
+    }
+
+    // Extracts the funding of the account and checks that it paid for the given periods exactly
+    async fn extract_and_check(context: &mut Context, v_coin_amount: i128, periods: &[i64]) {
+        let balance = context.get_user_account(0).await.unwrap().balance;
+        context.extract_funding(0, 0).await.unwrap();
+        let debt = (v_coin_amount * periods.iter().map(|f| *f as i128).sum::<i128>()) >> 32;
+        let user_account = context.get_user_account(0).await.unwrap();
+        assert_eq!(balance - user_account.balance, debt as u64);
+
+        let market_state = context.get_market_state().await.unwrap();
+        assert_eq!(
+            user_account.last_funding_offset,
+            market_state.funding_history_offset
+        );
+        assert_eq!(
+            context
+                .get_position(0, 0)
+                .await
+                .unwrap()
+                .last_funding_offset,
+            market_state.funding_history_offset
+        );
+    }
+
+    // 17 periods go around the ring, the account skips up to 7 extractions in a row
+    let mut period = 0;
+    for missed in [4, 6, 7] {
+        let mut funding = vec![];
+        for _ in 0..missed {
+            funding.push(crank_period(&mut context, period).await);
+            period += 1;
+        }
+        extract_and_check(&mut context, v_coin_amount, &funding).await;
+    }
+    let market_state = context.get_market_state().await.unwrap();
+    assert_eq!(market_state.funding_history_offset, 17 % 16);
+
+    // Past a whole ring of missed periods, only the last 18 % 16 = 2 periods are charged
+    let mut funding = vec![];
+    for _ in 0..18 {
+        funding.push(crank_period(&mut context, period).await);
+        period += 1;
+    }
+    extract_and_check(&mut context, v_coin_amount, &funding[16..]).await;
+}
@@ -92,7 +92,7 @@ fn simulation() {
     rt.block_on(context.change_oracle_price(GLOBAL_VARS.initial_oracle_price))
         .unwrap();
     let mut previous_oracle_price = GLOBAL_VARS.initial_oracle_price;
-    rt.block_on(context.create_market("".to_string(), GLOBAL_VARS.initial_vpc_amount, 6, 6))
+    rt.block_on(context.create_market("BTC/USD".to_string(), GLOBAL_VARS.initial_vpc_amount, 6, 6))
         .unwrap();
     rt.block_on(context.add_instance(5, 10_000)).unwrap();
 